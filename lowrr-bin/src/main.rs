@@ -1,39 +1,312 @@
 // SPDX-License-Identifier: MPL-2.0
 
-use lowrr::img::crop::{crop, recover_original_motion, Crop};
+mod serve;
+
+// Implementation of LAPACK used by the lapack feature of lowrr.
+#[cfg(feature = "lapack")]
+extern crate lapack_src;
+
+use lowrr::affine2d;
+use lowrr::drift::Drift;
+use lowrr::img::bayer::{self, CfaPattern};
+use lowrr::img::calibration;
+use lowrr::img::crop::{crop, Crop, CropError, CropSpec};
+use lowrr::img::defects;
+use lowrr::img::deformable::{self, Deformation};
+use lowrr::img::distortion::{self, Distortion};
+use lowrr::img::flat_field;
+use lowrr::img::fusion::{fuse_gray, fuse_rgb};
 use lowrr::img::interpolation::CanLinearInterpolate;
-use lowrr::img::registration::{self, CanRegister};
-use lowrr::interop::{IntoDMatrix, ToImage};
-use lowrr::utils::CanEqualize;
+use lowrr::img::merge::{merge, Merge};
+use lowrr::img::metrics;
+use lowrr::img::normalization::CanNormalize;
+use lowrr::img::registration::{self, CanRegister, LevelProfile, RegistrationError};
+use lowrr::img::residual;
+use lowrr::img::roi::{self, Roi};
+use lowrr::img::size::{self, SizePolicy};
+use lowrr::img::superres::super_resolve;
+use lowrr::img::transfer::{self, Transfer};
+use lowrr::img::viz;
+use lowrr::img::white_balance::{self, WhiteBalance};
+use lowrr::interop::{coerce, into_supported_layout, CoerceInto, Depth, IntoDMatrix, ToImage};
+use lowrr::io::archive;
+use lowrr::io::checkpoint;
+use lowrr::io::fiji;
+use lowrr::io::icc;
+use lowrr::io::motions;
+use lowrr::io::npy::NpyElement;
+use lowrr::io::sink::{DirSink, OutputSink, SinkError, TarSink, ZipSink};
+use lowrr::io::tie_points;
+use lowrr::io::tiff::TiffCompression;
+use lowrr::pipeline::{self, Pipeline};
+use lowrr::tune;
+use lowrr::units::{self, MotionGeometry, PixelSize};
+use lowrr::utils::{
+    split_channels, to_gray, CanEqualize, GrayConversion, ImgFormat, ImgWriter, PngCompression,
+};
+use lowrr::verbosity::{self, Verbosity};
 
 use anyhow::Context;
 use glob::glob;
 use image::DynamicImage;
-use nalgebra::{DMatrix, Scalar, Vector6};
+use nalgebra::{DMatrix, Scalar, Vector2, Vector3, Vector6};
 use std::convert::TryFrom;
+use std::mem::discriminant;
 use std::ops::{Add, Mul};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 
 // Default values for some of the program arguments.
 const DEFAULT_OUT_DIR: &str = "out";
+const DEFAULT_OUT_FORMAT: &str = "png";
+const DEFAULT_PROGRESS: &str = "bar";
+const DEFAULT_PNG_COMPRESSION: &str = "fast";
+const DEFAULT_TIFF_COMPRESSION: &str = "lzw";
 
 const DEFAULT_LEVELS: &str = "4";
+const DEFAULT_ESTIMATE_SCALE: &str = "1";
 const DEFAULT_SPARSE_RATIO_THRESHOLD: &str = "0.5";
+const DEFAULT_SPARSE_STRATEGY: &str = "threshold";
+const DEFAULT_SPARSE_FRACTION: &str = "0.1";
 
 const DEFAULT_LAMBDA: &str = "1.5";
 const DEFAULT_RHO: &str = "0.1";
 
 const DEFAULT_THRESHOLD: &str = "1e-3";
+const DEFAULT_MOTION_THRESHOLD: &str = "0";
+const DEFAULT_DAMPING: &str = "0";
+const DEFAULT_COMPOSITIONAL: &str = "forwards";
+const DEFAULT_MAX_TRANSLATION: &str = "0";
+const DEFAULT_MAX_DEFORMATION: &str = "0";
 const DEFAULT_MAX_ITERATIONS: &str = "40";
+const DEFAULT_PRECISION: &str = "single";
+const DEFAULT_MODE: &str = "lowrank";
+const DEFAULT_SVD_BACKEND: &str = "full";
+const DEFAULT_SVD_LIBRARY: &str = "nalgebra";
+const DEFAULT_INPUT_MAX: &str = "auto";
+const DEFAULT_NORMALIZATION: &str = "none";
+const DEFAULT_DATA_TERM: &str = "intensity";
+const DEFAULT_DENOISE: &str = "none";
+const DEFAULT_GRAY: &str = "green";
+const DEFAULT_TRANSFER: &str = "linear";
+const DEFAULT_WHITE_BALANCE: &str = "none";
+const DEFAULT_DENOISE_LEVELS: &str = "1";
+const DEFAULT_TEMPORAL_SMOOTHNESS: &str = "0";
+const DEFAULT_OUTLIER_THRESHOLD: &str = "0";
+const DEFAULT_NON_RIGID_THRESHOLD: &str = "0.5";
+const DEFAULT_DEFORMABLE_SPACING: &str = "32";
+const DEFAULT_DEFORMABLE_SMOOTHNESS: &str = "1";
+const DEFAULT_CHUNK_SIZE: &str = "0";
+const DEFAULT_PIXEL_ASPECT_RATIO: &str = "1";
+const DEFAULT_SIZE_POLICY: &str = "error";
+const DEFAULT_ONLINE_ITERATIONS: &str = "5";
+const DEFAULT_SUPERRES_FACTOR: &str = "2";
+const DEFAULT_DECONVOLUTION_ITERATIONS: &str = "10";
+const DEFAULT_MERGE_METHOD: &str = "trimmed-mean";
+const DEFAULT_GRID_FACTORS: &str = "0.25,0.5,1,2,4";
+const DEFAULT_FRAME_INTERVAL: &str = "0";
+const DEFAULT_BATCH_JOBS: &str = "1";
+const WATCH_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+const DEFAULT_SERVE_ADDRESS: &str = "127.0.0.1:8080";
+const DEFAULT_JOBS_DIR: &str = "jobs";
+
+/// Entry point of the program, exiting with one of the exit codes below.
+fn main() {
+    let code = match run_cli() {
+        Ok(()) if NOT_CONVERGED.load(Ordering::Relaxed) => EXIT_NOT_CONVERGED,
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            exit_code(&err)
+        }
+    };
+    std::process::exit(code);
+}
+
+// Exit codes of the program, for scripts, other errors exiting with 1.
+/// The registration did not converge, with --fail-on-nonconvergence.
+const EXIT_NOT_CONVERGED: i32 = 2;
+/// Invalid arguments, images or other input files.
+const EXIT_INVALID_INPUT: i32 = 3;
+/// The registration itself failed.
+const EXIT_SOLVER_FAILURE: i32 = 4;
+
+/// Set when the registration did not converge, with --fail-on-nonconvergence.
+static NOT_CONVERGED: AtomicBool = AtomicBool::new(false);
+
+/// Error of the inputs of the program, added as the context of their errors.
+#[derive(Debug)]
+struct InvalidInput;
+
+impl std::fmt::Display for InvalidInput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid input")
+    }
+}
+
+/// Exit code of an error of the program.
+fn exit_code(err: &anyhow::Error) -> i32 {
+    if err.chain().any(|cause| cause.is::<RegistrationError>()) {
+        EXIT_SOLVER_FAILURE
+    } else if err.downcast_ref::<InvalidInput>().is_some()
+        || err.chain().any(|cause| cause.is::<CropError>())
+    {
+        EXIT_INVALID_INPUT
+    } else {
+        1
+    }
+}
 
-/// Entry point of the program.
-fn main() -> anyhow::Result<()> {
+/// Parse the command line arguments and run the program.
+fn run_cli() -> anyhow::Result<()> {
     // CLI arguments related to the core parameters of the algorithm.
     let core_args = vec![
         clap::Arg::with_name("equalize")
             .long("equalize")
             .value_name("x")
             .help("Value in [0.0, 1.0]. Equalize the mean intensity of all images. This improves the registration by making all images equally important to compute the aggregated singular values."),
+        clap::Arg::with_name("input-max")
+            .long("input-max")
+            .value_name("auto|max")
+            .default_value(DEFAULT_INPUT_MAX)
+            .validator(|max| match max.as_str() {
+                "auto" => Ok(()),
+                _ => match max.parse::<f32>() {
+                    Ok(x) if x > 0.0 => Ok(()),
+                    _ => Err(format!("expecting auto or a positive number, got \"{}\"", max)),
+                },
+            })
+            .help("Maximum intensity of the images, stretched to the whole range of their pixel type before registration, such as 4095 for 12 bits data stored in 16 bits images. With auto, 16 bits images get the maximum of the smallest bit depth among 10, 12, 14 and 16 bits holding all their pixels"),
+        clap::Arg::with_name("normalization")
+            .long("normalization")
+            .value_name("method")
+            .possible_values(&["none", "histogram", "clahe"])
+            .default_value(DEFAULT_NORMALIZATION)
+            .help("Intensity normalization before registration: histogram matching to the first image (histogram) or local contrast equalization (clahe), helping with unevenly lit images"),
+        clap::Arg::with_name("data-term")
+            .long("data-term")
+            .value_name("images")
+            .possible_values(&["intensity", "gradient", "census"])
+            .default_value(DEFAULT_DATA_TERM)
+            .help("Images compared by the registration: raw intensities, gradient magnitudes or census transforms. The last two are more robust to lighting changes between images"),
+        clap::Arg::with_name("flat")
+            .long("flat")
+            .value_name("file")
+            .help("Flat-field image (uniformly lit target) dividing all images before registration, to remove vignetting"),
+        clap::Arg::with_name("flat-radial")
+            .long("flat-radial")
+            .conflicts_with("flat")
+            .help("Remove vignetting before registration, with a radial polynomial fitted on the mean image of the stack"),
+        clap::Arg::with_name("distortion")
+            .long("distortion")
+            .value_name("k1,k2")
+            .conflicts_with_all(&["bayer", "online", "deformable"])
+            .help("Radial lens distortion of the images, from a calibration: a point at normalized distance r to the center is seen at 1 + k1 r^2 + k2 r^4 times its distance, with r = 1 at the corners. Images are undistorted before registration, and registered images are undistorted"),
+        clap::Arg::with_name("estimate-distortion")
+            .long("estimate-distortion")
+            .conflicts_with_all(&["distortion", "bayer", "online", "deformable"])
+            .help("Estimate the radial lens distortion shared by all images from a first registration, then register the undistorted images. This needs images moving significantly relative to each other"),
+        clap::Arg::with_name("dark")
+            .long("dark")
+            .value_name("file")
+            .help("Dark frame (capture with the lens capped and the same exposure) subtracted from all images before registration, clamping intensities at zero. A dark frame that already includes the bias should be given without --bias"),
+        clap::Arg::with_name("bias")
+            .long("bias")
+            .value_name("file")
+            .help("Bias frame (shortest exposure with the lens capped) subtracted from all images before registration, clamping intensities at zero"),
+        clap::Arg::with_name("defects")
+            .long("defects")
+            .value_name("file")
+            .help("Map of the defective pixels of the sensor, non-zero for dead or hot pixels. They are replaced by the mean of their neighbors before registration and in the registered images, instead of pulling motions toward zero"),
+        clap::Arg::with_name("hot-pixels")
+            .long("hot-pixels")
+            .value_name("dark frame")
+            .conflicts_with("defects")
+            .help("Detect hot pixels in a dark frame (capture with the lens capped), and handle them like the pixels of --defects"),
+        clap::Arg::with_name("denoise")
+            .long("denoise")
+            .value_name("filter")
+            .possible_values(&["none", "gaussian", "median", "bilateral"])
+            .default_value(DEFAULT_DENOISE)
+            .help("Denoising of the images used by the registration, for noisy captures. Registered images are not denoised"),
+        clap::Arg::with_name("denoise-levels")
+            .long("denoise-levels")
+            .value_name("N")
+            .default_value(DEFAULT_DENOISE_LEVELS)
+            .help("Number of multi-resolution levels denoised, starting from the original resolution"),
+        clap::Arg::with_name("gray")
+            .long("gray")
+            .value_name("conversion")
+            .default_value(DEFAULT_GRAY)
+            .help("Conversion of RGB images into the gray images used for registration: green, luma, average or channel:N (0 for red, 1 for green, 2 for blue). Registered images keep their colors"),
+        clap::Arg::with_name("white-balance")
+            .long("white-balance")
+            .value_name("method")
+            .default_value(DEFAULT_WHITE_BALANCE)
+            .conflicts_with("online")
+            .help("White balance normalization of RGB images captured with varying white balances, applied before registration and kept in the saved images: none, median (give all images the median color balance of the stack) or patch:x,y,w,h (make a gray patch visible in all images neutral). Green channels are kept unchanged"),
+        clap::Arg::with_name("transfer")
+            .long("transfer")
+            .value_name("function")
+            .default_value(DEFAULT_TRANSFER)
+            .help("Transfer function of gamma-encoded images, linearized before registration so that bright parts of the scene are not underweighted: linear (no conversion), srgb or gamma:<exponent> such as gamma:2.2. Saved images keep the encoding of the inputs, and the ICC profile of PNG inputs"),
+        clap::Arg::with_name("joint-channels")
+            .long("joint-channels")
+            .conflicts_with("online")
+            .help("Register the three channels of RGB images jointly instead of converting them to gray, for scenes where a single channel has little contrast. This is about three times slower"),
+        clap::Arg::with_name("bayer")
+            .long("bayer")
+            .value_name("pattern")
+            .possible_values(&["rggb", "bggr", "grbg", "gbrg"])
+            .conflicts_with_all(&["joint-channels", "stack"])
+            .help("Register gray images of RAW Bayer mosaics without demosaicing them, with the given color pattern of their top left pixels. Motions are estimated on the green pixels, and each color plane is warped separately so that registered images remain mosaics"),
+        clap::Arg::with_name("gain-bias")
+            .long("gain-bias")
+            .help("Estimate a gain and bias for each image during the registration, to compensate global exposure differences"),
+        clap::Arg::with_name("no-image-correction")
+            .long("no-image-correction")
+            .help("Do not estimate the sparse errors of the images, which absorb shadows, highlights and occlusions. Clean datasets may converge faster with a purely low-rank model, but any such artifact then pulls the registration. Only available in the lowrank mode"),
+        clap::Arg::with_name("temporal-smoothness")
+            .long("temporal-smoothness")
+            .value_name("x")
+            .default_value(DEFAULT_TEMPORAL_SMOOTHNESS)
+            .help("Weight of a prior penalizing motion differences between consecutive images, for time-lapses or video frames. It prevents single bad frames from getting wild motions. Try values between 1e-4 and 1e-3, too high values prevent the images from moving"),
+        clap::Arg::with_name("outlier-threshold")
+            .long("outlier-threshold")
+            .value_name("z")
+            .default_value(DEFAULT_OUTLIER_THRESHOLD)
+            .help("Flag images with outlier sparse errors or motion after the coarsest level, such as when the tripod was bumped, and exclude them from the next levels. They keep their coarse motion. It needs at least 8 images. A value of 3.5 robust standard deviations is common, 0 disables it"),
+        clap::Arg::with_name("non-rigid-threshold")
+            .long("non-rigid-threshold")
+            .value_name("pixels")
+            .default_value(DEFAULT_NON_RIGID_THRESHOLD)
+            .help("Warn about images where blocks of the registered crop are still shifted by more than this number of pixels relative to the first image, indicating that the scene needs a non-rigid model (parallax, deformations). 0 disables it"),
+        clap::Arg::with_name("deformable")
+            .long("deformable")
+            .conflicts_with_all(&["bayer", "online"])
+            .help("Refine the affine registration with a smooth deformation of each image, interpolating a coarse grid of control points, for slightly deformable subjects such as skin or fabric. Registered images are warped with their deformation"),
+        clap::Arg::with_name("deformable-spacing")
+            .long("deformable-spacing")
+            .value_name("pixels")
+            .default_value(DEFAULT_DEFORMABLE_SPACING)
+            .help("Spacing between the control points of the deformations, in pixels of the registered crop"),
+        clap::Arg::with_name("deformable-smoothness")
+            .long("deformable-smoothness")
+            .value_name("x")
+            .default_value(DEFAULT_DEFORMABLE_SMOOTHNESS)
+            .help("Weight of the differences between neighbor control points of the deformations. Higher values give stiffer deformations"),
+        clap::Arg::with_name("chunk-size")
+            .long("chunk-size")
+            .value_name("N")
+            .default_value(DEFAULT_CHUNK_SIZE)
+            .help("Register long sequences by overlapping chunks of N images, which bounds the memory and time of each low-rank registration. Motions are stitched through the images shared by consecutive chunks, so errors accumulate slowly along the sequence. 0 registers all images at once"),
+        clap::Arg::with_name("pixel-aspect-ratio")
+            .long("pixel-aspect-ratio")
+            .value_name("ratio")
+            .default_value(DEFAULT_PIXEL_ASPECT_RATIO)
+            .help("Width divided by height of the pixels, for cameras with non-square pixels. Saved and printed motions are then in square pixels of the pixel height, so that their rotations and translations are the physical ones, and so are the motions of --apply-motions. Registered images keep the pixels of the original images"),
         clap::Arg::with_name("lambda")
             .long("lambda")
             .value_name("x")
@@ -51,11 +324,52 @@ fn main() -> anyhow::Result<()> {
             .help(
                 "Stop when relative diff between two estimate of corrected image falls below this",
             ),
+        clap::Arg::with_name("motion-threshold")
+            .long("motion-threshold")
+            .value_name("pixels")
+            .default_value(DEFAULT_MOTION_THRESHOLD)
+            .help("Also stop when no image corner moves more than this during an iteration, in pixels at the current level. Use 0 to disable it"),
+        clap::Arg::with_name("damping")
+            .long("damping")
+            .value_name("x")
+            .default_value(DEFAULT_DAMPING)
+            .help("Levenberg-Marquardt damping of the Gauss-Newton steps of the motions, scaling the diagonal of their Hessian by 1 + x. Try 0.1 to 1 when motions oscillate or diverge at fine levels on low-texture crops. Use 0 to disable it"),
+        clap::Arg::with_name("line-search")
+            .long("line-search")
+            .help("Halve the Gauss-Newton step of each motion until the residuals of its image decrease, in the lowrank mode"),
+        clap::Arg::with_name("compositional")
+            .long("compositional")
+            .value_name("step")
+            .possible_values(&["forwards", "inverse"])
+            .default_value(DEFAULT_COMPOSITIONAL)
+            .help("Gauss-Newton steps of the motions in the lowrank mode: with gradients of the registered images recomputed at each iteration (forwards), or computed once per level (inverse). Inverse iterations are substantially cheaper, especially at sparse levels, but images moving a lot within a level may need a few more of them"),
+        clap::Arg::with_name("max-translation")
+            .long("max-translation")
+            .value_name("pixels")
+            .default_value(DEFAULT_MAX_TRANSLATION)
+            .help("Maximum displacement of the center of each image, in pixels of the original images, enforced after each iteration so that a bad coarse estimate cannot send an image off the canvas. Use 0 for no limit"),
+        clap::Arg::with_name("max-deformation")
+            .long("max-deformation")
+            .value_name("x")
+            .default_value(DEFAULT_MAX_DEFORMATION)
+            .help("Maximum deviation from the identity of each scale, rotation and shear coefficient of the motions, such as 0.1 for scale changes up to 10%, enforced after each iteration. Coefficients are bounded separately, so combined with shear points may move up to about 14% along the diagonal. Use 0 for no limit"),
         clap::Arg::with_name("max-iterations")
             .long("max-iterations")
             .default_value(DEFAULT_MAX_ITERATIONS)
             .value_name("N")
             .help("Maximum number of iterations"),
+        clap::Arg::with_name("mode")
+            .long("mode")
+            .value_name("model")
+            .possible_values(&["lowrank", "pairwise", "sequential"])
+            .default_value(DEFAULT_MODE)
+            .help("Registration of all images together by minimizing their rank (lowrank), or of each image to the first one (pairwise) or to the previous one (sequential) with a robust Gauss-Newton. Pairwise modes are a baseline, and fit larger stacks in memory"),
+        clap::Arg::with_name("precision")
+            .long("precision")
+            .value_name("single|double")
+            .possible_values(&["single", "double"])
+            .default_value(DEFAULT_PRECISION)
+            .help("Floating point precision of the optimization. Double precision is slower but may converge better on low-contrast 16 bits images"),
     ];
     // CLI arguments related to algorithm speedup techniques.
     let speed_args = vec![
@@ -64,7 +378,16 @@ fn main() -> anyhow::Result<()> {
             .number_of_values(4)
             .value_names(&["left", "top", "right", "bottom"])
             .use_delimiter(true)
-            .help("Crop image into a restricted working area"),
+            .help("Crop image into a restricted working area. Coordinates are in pixels or in percents of the image size, such as 10%,10%,90%,90%. The area can also be given by its center and size, such as center:50%,50%,400,300"),
+        clap::Arg::with_name("crop-clamp")
+            .long("crop-clamp")
+            .requires("crop")
+            .help("Restrict the crop frame to the images when it exceeds them, instead of stopping with an error"),
+        clap::Arg::with_name("estimate-scale")
+            .long("estimate-scale")
+            .default_value(DEFAULT_ESTIMATE_SCALE)
+            .value_name("scale")
+            .help("Estimate motions on images downscaled by this power of two, such as 0.5, and apply them to the original images. A scale of 0.5 is about 4 times faster, with a precision of about one pixel"),
         clap::Arg::with_name("levels")
             .long("levels")
             .default_value(DEFAULT_LEVELS)
@@ -75,6 +398,36 @@ fn main() -> anyhow::Result<()> {
             .value_name("ratio")
             .default_value(DEFAULT_SPARSE_RATIO_THRESHOLD)
             .help("Sparse ratio threshold to switch between dense and sparse resolution. Use dense resolution if the ratio at current level is higher than this threshold"),
+        clap::Arg::with_name("sparse-strategy")
+            .long("sparse-strategy")
+            .value_name("strategy")
+            .possible_values(&["threshold", "percentile", "grid", "fast"])
+            .default_value(DEFAULT_SPARSE_STRATEGY)
+            .help("Selection of the sparse pixels: highest gradients of each 2x2 bloc (threshold), highest gradients of each level (percentile), regular grid (grid) or FAST corners (fast)"),
+        clap::Arg::with_name("sparse-fraction")
+            .long("sparse-fraction")
+            .value_name("ratio")
+            .default_value(DEFAULT_SPARSE_FRACTION)
+            .help("Fraction of pixels kept at each level by the percentile and grid sparse strategies"),
+        clap::Arg::with_name("pixel-budget")
+            .long("pixel-budget")
+            .value_name("N")
+            .help("Use at most N pixels per level, keeping those with the highest gradients. This replaces the sparse switch and strategy, and makes runtime predictable"),
+        clap::Arg::with_name("svd-backend")
+            .long("svd-backend")
+            .value_name("backend")
+            .possible_values(&["full", "incremental"])
+            .default_value(DEFAULT_SVD_BACKEND)
+            .help("SVD of the low-rank approximation: recomputed at each iteration (full), or updated with the images that changed (incremental). Incremental is faster for stacks with many images, at the cost of approximating the smallest changes"),
+        clap::Arg::with_name("svd-library")
+            .long("svd-library")
+            .value_name("library")
+            .possible_values(&["nalgebra", "faer", "lapack"])
+            .default_value(DEFAULT_SVD_LIBRARY)
+            .help("Linear algebra library computing the SVDs. faer and lapack require lowrr to be built with the cargo feature of the same name"),
+        clap::Arg::with_name("pack-observations")
+            .long("pack-observations")
+            .help("Store the registered images of the low-rank model as 16 bits integers instead of floats, to save memory on large stacks"),
     ];
     // CLI arguments related to input, output and the rest.
     let input_output_args = vec![
@@ -82,32 +435,336 @@ fn main() -> anyhow::Result<()> {
             .short("v")
             .multiple(true)
             .help("Multiple levels of verbosity (up to -vvv)"),
+        clap::Arg::with_name("quiet")
+            .short("q")
+            .long("quiet")
+            .conflicts_with("verbose")
+            .help("Only print errors, without progress bars, JSON progress events nor the motions on stdout"),
+        clap::Arg::with_name("progress")
+            .long("progress")
+            .value_name("bar|json")
+            .possible_values(&["bar", "json"])
+            .default_value(DEFAULT_PROGRESS)
+            .help("Progress reported on stderr: human progress bars with -v, or newline-delimited JSON events with the stage, level, iteration, percentage and estimated remaining seconds (eta), for GUIs and scripts"),
+        clap::Arg::with_name("fail-on-nonconvergence")
+            .long("fail-on-nonconvergence")
+            .help("Exit with code 2 after saving the results if a level of the registration stopped at --max-iterations before converging"),
+        clap::Arg::with_name("profile")
+            .long("profile")
+            .conflicts_with("online")
+            .help("Print to stderr the time spent in each part of the registration, at each level"),
+        clap::Arg::with_name("dry-run")
+            .long("dry-run")
+            .conflicts_with("online")
+            .help("Load and check the images, the crop frame and the calibration files, then print the registration plan, the estimated memory and the resolved parameters, without registering the images"),
+        clap::Arg::with_name("checkpoint")
+            .long("checkpoint")
+            .value_name("dir")
+            .conflicts_with("online")
+            .help("Save the state of the registration at the end of each level in this directory (checkpoint.txt), to resume it later with --resume. Chunked registrations are not checkpointed"),
+        clap::Arg::with_name("resume")
+            .long("resume")
+            .requires("checkpoint")
+            .help("Resume the registration after the last level saved in the --checkpoint directory, if there is one"),
         clap::Arg::with_name("out-dir")
             .long("out-dir")
             .default_value(DEFAULT_OUT_DIR)
             .value_name("path")
             .help("Output directory to save registered images"),
+        clap::Arg::with_name("out-sink")
+            .long("out-sink")
+            .value_name("s3://bucket/prefix")
+            .conflicts_with("online")
+            .help("Upload the saved images (registered, cropped, previews and checkerboards) to an S3 bucket instead of writing them in --out-dir, with the credentials and region of the AWS_* environment variables. Other outputs are still written in --out-dir. Needs lowrr to be built with the s3 feature"),
+        clap::Arg::with_name("out-archive")
+            .long("out-archive")
+            .value_name("file.zip|file.tar")
+            .conflicts_with_all(&["online", "out-sink", "npy", "tiff-stack"])
+            .help("Bundle the registered images, the motions (motions.txt) and their report (report.json) in a zip or tar archive, with the other saved images. Other outputs are still written in --out-dir"),
         clap::Arg::with_name("save-crop")
             .long("save-crop")
             .help("Save the cropped images and their registered counterpart"),
         clap::Arg::with_name("save-imgs")
             .long("save-imgs")
             .help("Save the registered images"),
-        clap::Arg::with_name("IMAGE or GLOB")
+        clap::Arg::with_name("out-format")
+            .long("out-format")
+            .value_name("png|tiff")
+            .possible_values(&["png", "tiff"])
+            .default_value(DEFAULT_OUT_FORMAT)
+            .help("File format of the saved images. Compressed TIFF images are much faster to encode than PNG images, especially with 16 bits"),
+        clap::Arg::with_name("tiff-compression")
+            .long("tiff-compression")
+            .value_name("none|lzw|deflate")
+            .possible_values(&["none", "lzw", "deflate"])
+            .default_value(DEFAULT_TIFF_COMPRESSION)
+            .help("Lossless compression of the saved TIFF images and TIFF stacks. Deflate files are smaller but cannot be loaded back by lowrr"),
+        clap::Arg::with_name("png-compression")
+            .long("png-compression")
+            .value_name("fast|default|best|huffman|rle")
+            .possible_values(&["fast", "default", "best", "huffman", "rle"])
+            .default_value(DEFAULT_PNG_COMPRESSION)
+            .help("Compression of the saved PNG images, best giving the smallest files but being the slowest. Images are encoded and written in background threads"),
+        clap::Arg::with_name("save-sparse-mask")
+            .long("save-sparse-mask")
+            .help("Save the sparse pixels used at each level with sparse resolution, overlaid in red on the first image (sparse_mask/level_N.png)"),
+        clap::Arg::with_name("save-level-motions")
+            .long("save-level-motions")
+            .help("Save the motions estimated at the end of each level, in the frame of the full images at original resolution (level_motions/level_N.txt)"),
+        clap::Arg::with_name("save-uncertainty")
+            .long("save-uncertainty")
+            .help("Save the uncertainty of each motion, as the standard deviation in pixels of the displacement of its worst constrained corner, nan for outliers (uncertainty.txt)"),
+        clap::Arg::with_name("save-report")
+            .long("save-report")
+            .help("Save a JSON report of the motions (report.json), with the translation, drift of the image center and rotation of each image, also in physical units with --pixel-size"),
+        clap::Arg::with_name("save-fiji")
+            .long("save-fiji")
+            .help("Save the motions for the ImageJ / Fiji plugins: a transformation file to load in MultiStackReg (multistackreg.txt), and the track of the image center as TrackMate spots (trackmate.csv), in the unit of --pixel-size"),
+        clap::Arg::with_name("pixel-size")
+            .long("pixel-size")
+            .value_name("size")
+            .help("Calibrated size of a pixel for the report of --save-report and the tracks of --save-fiji and --init-motions: the pixel pitch of a microscope (such as 0.65um, in nm, um or mm) or the plate scale of a telescope (such as 1.2arcsec, in arcsec, arcmin or deg). The height of the pixels with --pixel-aspect-ratio"),
+        clap::Arg::with_name("metrics")
+            .long("metrics")
+            .help("Print the similarity of each cropped image to the first one before and after registration on stderr (normalized cross-correlation, structural similarity and PSNR), and save them in metrics.csv"),
+        clap::Arg::with_name("save-contact-sheet")
+            .long("save-contact-sheet")
+            .help("Save a single image with the thumbnails of the cropped images and of their registration side by side, and a strip colored from blue to red with their residual (contact_sheet.png), to spot bad frames quickly"),
+        clap::Arg::with_name("save-animation")
+            .long("save-animation")
+            .value_name("toggle|stack")
+            .possible_values(&["toggle", "stack"])
+            .help("Save animated GIFs of the registered images, restricted to the crop frame, to eyeball the alignment: alternating between the first image and each other image (toggle/N.gif), or cycling through the whole stack (registered.gif)"),
+        clap::Arg::with_name("save-checkerboard")
+            .long("save-checkerboard")
+            .help("Save checkerboard composites of the first registered image and each registered image (checkerboard/N.png), where misalignments break the edges at the borders of the tiles"),
+        clap::Arg::with_name("save-previews")
+            .long("save-previews")
+            .help("Save registered thumbnails at the end of each level (previews/level_N/), to check early whether the registration is heading the right way"),
+        clap::Arg::with_name("tiff-stack")
+            .long("tiff-stack")
+            .requires("save-imgs")
+            .help("Save the registered images as a single multi-page TIFF file (registered.tif) instead of one PNG per image"),
+        clap::Arg::with_name("stack")
+            .long("stack")
+            .value_name("combiner")
+            .possible_values(&["fusion"])
+            .conflicts_with("online")
+            .help("Combine the registered images into a single image: exposure fusion of bracketed images (fusion), saved as fused.png."),
+        clap::Arg::with_name("motions-convention")
+            .long("motions-convention")
+            .value_name("lowrr|opencv|matlab")
+            .possible_values(&["lowrr", "opencv", "matlab"])
+            .default_value("lowrr")
+            .help("Convention of the motions printed on stdout, saved with --save-motions and read with --apply-motions: lowrr motion vectors, 2x3 matrices of OpenCV's warpAffine, or the first two columns of the T matrices of Matlab's affine2d for imwarp. Each motion is a line of 6 numbers"),
+        clap::Arg::with_name("save-motions")
+            .long("save-motions")
+            .help("Save the motions in a text file (motions.txt), in the convention of --motions-convention"),
+        clap::Arg::with_name("apply-motions")
+            .long("apply-motions")
+            .value_name("file")
+            .conflicts_with("online")
+            .help("Apply the motions of a text file, in the convention of --motions-convention, instead of registering the images"),
+        clap::Arg::with_name("init-motions")
+            .long("init-motions")
+            .value_name("file")
+            .conflicts_with_all(&["online", "apply-motions"])
+            .help("Start the registration from the motions of a file instead of the identity: a text file in the convention of --motions-convention, a MultiStackReg transformation file of Fiji, or a CSV export of TrackMate spots (translations, in the unit of --pixel-size). Ignored when resuming a checkpoint"),
+        clap::Arg::with_name("tie-points")
+            .long("tie-points")
+            .value_name("csv|pto")
+            .possible_values(&["csv", "pto"])
+            .conflicts_with("online")
+            .help("Export a grid of points of the registered frame and their positions in each image, for panorama and stitching software: a CSV file (tie_points.csv) or a Hugin project with control points (tie_points.pto)"),
+        clap::Arg::with_name("track-roi")
+            .long("track-roi")
+            .value_name("x,y,w,h")
+            .conflicts_with("online")
+            .help("Print on stderr the corners and bounding box in each image of a region of interest of the first image, and save them in roi.csv, to crop a moving specimen consistently"),
+        clap::Arg::with_name("npy")
+            .long("npy")
+            .conflicts_with("tiff-stack")
+            .help("Save the motion vectors as a NumPy array (motions.npy), and the registered images as a single (N,H,W) array (registered.npy) instead of one PNG per image"),
+        clap::Arg::with_name("npy-layout")
+            .long("npy-layout")
+            .value_name("nhw|hwn")
+            .possible_values(&["nhw", "hwn"])
+            .default_value("nhw")
+            .help("Layout of the .npy and .npz input arrays, with N the number of images"),
+        clap::Arg::with_name("coerce")
+            .long("coerce")
+            .value_name("depth")
+            .possible_values(&["u8", "u16", "f32"])
+            .help("Convert all images to the same pixel depth, to register together images of different types. Gray images are converted to RGB if any image is RGB. Floating point images are registered with 16 bits precision and saved as 16 bits images, or as float32 with --npy"),
+        clap::Arg::with_name("size-policy")
+            .long("size-policy")
+            .value_name("policy")
+            .possible_values(&["error", "crop", "pad"])
+            .default_value(DEFAULT_SIZE_POLICY)
+            .help("What to do when images do not all have the same size: stop with an error, center-crop them to their common size, or pad them to the largest size by replicating their borders"),
+        clap::Arg::with_name("online")
+            .long("online")
+            .help("After registering the images given as arguments, read the paths of newly captured images on stdin, one per line, and print the motion and residual of each one as soon as it is registered. A high residual suggests that the shot should be retaken"),
+        clap::Arg::with_name("online-iterations")
+            .long("online-iterations")
+            .value_name("N")
+            .default_value(DEFAULT_ONLINE_ITERATIONS)
+            .help("Number of iterations for each new image in online mode"),
+        clap::Arg::with_name("batch")
+            .long("batch")
+            .value_name("jobs.toml")
+            .conflicts_with_all(&["online", "IMAGE or GLOB"])
+            .help("Register the datasets of a manifest instead of the images given as arguments, each [[job]] entry giving its images glob, its output dir (out_dir) and optionally its crop frame, with the other arguments of the command line"),
+        clap::Arg::with_name("batch-jobs")
+            .long("batch-jobs")
+            .value_name("N")
+            .default_value(DEFAULT_BATCH_JOBS)
+            .help("Number of datasets of the --batch manifest registered at the same time"),
+    ];
+    // Images of the main program and of most subcommands.
+    let images_arg = clap::Arg::with_name("IMAGE or GLOB")
+        .multiple(true)
+        .required_unless("batch")
+        .help("Paths to images, or glob pattern such as \"img/*.png\"");
+    // Directory of the watch subcommand.
+    let watch_arg = clap::Arg::with_name("DIR")
+        .required(true)
+        .help("Directory where the captured images are saved");
+    // CLI arguments of the super-resolution subcommand.
+    let superres_args = vec![
+        clap::Arg::with_name("factor")
+            .long("factor")
+            .value_name("N")
+            .default_value(DEFAULT_SUPERRES_FACTOR)
+            .help("Upscaling factor of the super-resolved image, such as 2 or 3. It needs at least N x N images with varied sub-pixel motions"),
+        clap::Arg::with_name("deconvolution-iterations")
+            .long("deconvolution-iterations")
+            .value_name("N")
+            .default_value(DEFAULT_DECONVOLUTION_ITERATIONS)
+            .help("Number of Richardson-Lucy iterations sharpening the super-resolved image, 0 to only average the registered samples"),
+    ];
+    // CLI arguments of the merge subcommand.
+    let merge_args = vec![clap::Arg::with_name("method")
+        .long("method")
+        .value_name("method")
+        .possible_values(&["trimmed-mean", "wiener"])
+        .default_value(DEFAULT_MERGE_METHOD)
+        .help("Robust estimator of each pixel: mean without the lowest and highest quarter of the values (trimmed-mean), or average with the first image weighted by how well each image matches it around the pixel (wiener), which better preserves moving objects of the first image")];
+    // CLI arguments of the tune subcommand.
+    let tune_args = vec![clap::Arg::with_name("grid-factors")
+        .long("grid-factors")
+        .value_name("f1,f2,...")
+        .default_value(DEFAULT_GRID_FACTORS)
+        .help("Factors applied to lambda and rho to build the grid of values tried, all their combinations being registered")];
+    // CLI arguments of the drift subcommand.
+    let drift_args = vec![clap::Arg::with_name("frame-interval")
+        .long("frame-interval")
+        .value_name("seconds")
+        .default_value(DEFAULT_FRAME_INTERVAL)
+        .help("Time between two consecutive images, to also print the drift rates per second. Use 0 for rates per frame only")];
+    // CLI arguments of the serve subcommand.
+    let serve_args = vec![
+        clap::Arg::with_name("verbose")
+            .short("v")
             .multiple(true)
-            .required(true)
-            .help("Paths to images, or glob pattern such as \"img/*.png\""),
+            .help("Multiple levels of verbosity (up to -vvv)"),
+        clap::Arg::with_name("quiet")
+            .short("q")
+            .long("quiet")
+            .conflicts_with("verbose")
+            .help("Only print errors, without progress bars, JSON progress events nor the motions on stdout"),
+        clap::Arg::with_name("address")
+            .long("address")
+            .value_name("host:port")
+            .default_value(DEFAULT_SERVE_ADDRESS)
+            .help("Address of the HTTP server"),
+        clap::Arg::with_name("jobs-dir")
+            .long("jobs-dir")
+            .value_name("path")
+            .default_value(DEFAULT_JOBS_DIR)
+            .help("Directory of the uploaded images and of the results of each job"),
     ];
     // Read all CLI arguments.
     let matches = clap::App::new("lowrr")
         .version(std::env!("CARGO_PKG_VERSION"))
         .about("Low-rank registration of slightly misaligned images for photometric stereo")
+        .setting(clap::AppSettings::SubcommandsNegateReqs)
         .args(&core_args)
         .args(&speed_args)
         .args(&input_output_args)
-        .get_matches();
+        .arg(&images_arg)
+        .subcommand(
+            clap::SubCommand::with_name("superres")
+                .about("Register the images and reconstruct the first one at a higher resolution, saved as superres.png in the output directory")
+                .args(&superres_args)
+                .args(&core_args)
+                .args(&speed_args)
+                .args(&input_output_args)
+                .arg(&images_arg),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("merge")
+                .about("Register the images and merge them into a single frame with less noise, saved as merged.png in the output directory")
+                .args(&merge_args)
+                .args(&core_args)
+                .args(&speed_args)
+                .args(&input_output_args)
+                .arg(&images_arg),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("tune")
+                .about("Try a grid of lambda and rho values on the coarse levels of a downsampled crop, and print the objective of each pair, the lowest being the best")
+                .args(&tune_args)
+                .args(&core_args)
+                .args(&speed_args)
+                .args(&input_output_args)
+                .arg(&images_arg),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("drift")
+                .about("Register the images, or read their motions with --apply-motions, and fit the drift of their centers along the frame index. Drift rates are printed per axis, and the positions with their linear trends saved in drift.csv in the output directory, in physical units with --pixel-size")
+                .args(&drift_args)
+                .args(&core_args)
+                .args(&speed_args)
+                .args(&input_output_args)
+                .arg(&images_arg),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("watch")
+                .about("Register the images saved in a directory as they appear, starting with its first two images, and keep updating the motions file (motions.txt) and the registered previews (previews/N.png) in the output directory")
+                .args(&core_args)
+                .args(&speed_args)
+                .args(&input_output_args)
+                .arg(&watch_arg),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("serve")
+                .about("Serve registration jobs over HTTP: upload images, start a registration with a JSON config, poll its progress and download the motions and registered images (see the serve module documentation for the endpoints)")
+                .args(&serve_args),
+        )
+        .get_matches_safe()
+        .unwrap_or_else(|err| match err.kind {
+            clap::ErrorKind::HelpDisplayed | clap::ErrorKind::VersionDisplayed => err.exit(),
+            _ => {
+                eprintln!("{}", err.message);
+                std::process::exit(EXIT_INVALID_INPUT);
+            }
+        });
+    let serve = matches.subcommand_name() == Some("serve");
+    let matches = match matches.subcommand() {
+        ("superres", Some(sub_matches))
+        | ("merge", Some(sub_matches))
+        | ("tune", Some(sub_matches))
+        | ("drift", Some(sub_matches))
+        | ("watch", Some(sub_matches))
+        | ("serve", Some(sub_matches)) => sub_matches,
+        _ => &matches,
+    };
     // Set log verbosity.
-    let verbosity = 1 + matches.occurrences_of("verbose");
+    let verbosity = Verbosity::from_flags(
+        matches.occurrences_of("verbose"),
+        matches.is_present("quiet"),
+    );
     stderrlog::new()
         .quiet(false)
         .verbosity(verbosity as usize)
@@ -115,8 +772,169 @@ fn main() -> anyhow::Result<()> {
         .color(stderrlog::ColorChoice::Never)
         .init()
         .context("Failed to initialize log verbosity")?;
+    verbosity.set();
+    JSON_PROGRESS.store(
+        matches.value_of("progress") == Some("json") && verbosity > Verbosity::Quiet,
+        Ordering::Relaxed,
+    );
     // Start program.
-    run(get_args(&matches)?)
+    if serve {
+        return serve::serve(matches);
+    }
+    if let Some(manifest) = matches.value_of("batch") {
+        let batch_jobs = matches
+            .value_of("batch-jobs")
+            .unwrap()
+            .parse()
+            .context(InvalidInput)?;
+        return run_batch(matches, Path::new(manifest), batch_jobs);
+    }
+    run(get_args(matches).context(InvalidInput)?)
+}
+
+/// Whether progress is reported as JSON events instead of progress bars, with --progress json.
+static JSON_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+/// Newline-delimited JSON progress events of a stage on stderr, with --progress json.
+struct ProgressEvents {
+    stage: &'static str,
+    start: Instant,
+}
+
+impl ProgressEvents {
+    /// Events of the stage, None without --progress json.
+    fn new(stage: &'static str) -> Option<Self> {
+        if JSON_PROGRESS.load(Ordering::Relaxed) {
+            Some(ProgressEvents {
+                stage,
+                start: Instant::now(),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Print an event with the completed fraction of the stage and the given fields,
+    /// and the remaining time estimated from the time spent so far.
+    fn emit(&self, fraction: f32, fields: serde_json::Value) {
+        let fraction = fraction.max(0.0).min(1.0);
+        let elapsed = self.start.elapsed().as_secs_f32();
+        let mut event = serde_json::json!({
+            "stage": self.stage,
+            "percent": 100.0 * fraction,
+            "eta": if fraction > 0.0 { Some(elapsed * (1.0 - fraction) / fraction) } else { None },
+        });
+        if let (Some(event), serde_json::Value::Object(fields)) = (event.as_object_mut(), fields) {
+            event.extend(fields);
+        }
+        eprintln!("{}", event);
+    }
+}
+
+/// Length of the progress bar of the registration, whose position is the fraction of work done.
+const REGISTRATION_PROGRESS_STEPS: u64 = 1000;
+
+/// Fraction of the work of a registration done at the given progress,
+/// each level costing a quarter of the next finer one,
+/// and the iterations of a level being bounded by `max_iterations`.
+#[allow(clippy::cast_precision_loss)]
+fn registration_fraction(
+    levels: usize,
+    max_iterations: usize,
+    progress: &registration::Progress,
+) -> f32 {
+    let cost = |level: usize| 0.25_f32.powi(level as i32);
+    let total: f32 = (0..levels).map(cost).sum();
+    let done: f32 = (progress.level + 1..levels).map(cost).sum();
+    let level_fraction = progress.iteration as f32 / max_iterations.max(1) as f32;
+    (done + cost(progress.level) * level_fraction.min(1.0)) / total
+}
+
+/// Dataset of a batch manifest.
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BatchJob {
+    /// Glob pattern of the images, such as "capture_1/*.png".
+    images: String,
+    out_dir: String,
+    /// Crop frame, in the syntax of the --crop argument, replacing the one of the command line.
+    crop: Option<String>,
+}
+
+/// Manifest of the --batch argument, with a [[job]] table for each dataset.
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BatchManifest {
+    job: Vec<BatchJob>,
+}
+
+/// Register each dataset of a batch manifest with the arguments of the command line,
+/// `batch_jobs` of them at the same time.
+/// A failed dataset does not stop the others, and all failures are reported at the end.
+fn run_batch(
+    matches: &clap::ArgMatches,
+    manifest_path: &Path,
+    batch_jobs: usize,
+) -> anyhow::Result<()> {
+    let manifest: BatchManifest = toml::from_str(
+        &std::fs::read_to_string(manifest_path)
+            .context(format!("Failed to read {}", manifest_path.display()))?,
+    )
+    .context(format!(
+        "Invalid batch manifest {}",
+        manifest_path.display()
+    ))
+    .context(InvalidInput)?;
+    let mut queue = Vec::with_capacity(manifest.job.len());
+    for (i, job) in manifest.job.into_iter().enumerate() {
+        let mut args = get_args(matches).context(InvalidInput)?;
+        args.images_paths = absolute_file_paths(std::iter::once(&job.images))?;
+        if args.images_paths.is_empty() {
+            anyhow::bail!("No image matches {} in job {} of the batch", job.images, i);
+        }
+        args.out_dir = job.out_dir;
+        if let Some(crop) = job.crop {
+            args.crop_spec = Some(CropSpec::try_from(crop.as_str())?);
+        }
+        queue.push((i, job.images, args));
+    }
+    let count = queue.len();
+    let queue = std::sync::Arc::new(std::sync::Mutex::new(queue.into_iter()));
+    let failures = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let workers: Vec<_> = (0..batch_jobs.max(1).min(count))
+        .map(|_| {
+            let (queue, failures) = (queue.clone(), failures.clone());
+            std::thread::spawn(move || loop {
+                let next = queue.lock().unwrap().next();
+                let (i, images, args) = match next {
+                    None => break,
+                    Some(job) => job,
+                };
+                log::warn!("Batch job {}: {}", i, images);
+                if let Err(err) = run(args) {
+                    log::error!("Batch job {} failed: {:#}", i, err);
+                    failures.lock().unwrap().push(i);
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        if worker.join().is_err() {
+            anyhow::bail!("A batch worker panicked");
+        }
+    }
+    let mut failures = failures.lock().unwrap().clone();
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        failures.sort_unstable();
+        anyhow::bail!(
+            "{} of {} batch jobs failed: {:?}",
+            failures.len(),
+            count,
+            failures
+        )
+    }
 }
 
 #[derive(Debug)]
@@ -124,11 +942,172 @@ fn main() -> anyhow::Result<()> {
 struct Args {
     config: registration::Config,
     equalize: Option<f32>,
+    flat: Option<Flat>,
+    lens: Option<Lens>,
+    /// Lens distortion of the full images, resolved from `lens` once the images are loaded.
+    distortion: Option<Distortion>,
+    dark: Option<PathBuf>,
+    bias: Option<PathBuf>,
+    /// Defective pixels of the sensor, from --defects or --hot-pixels.
+    defects: Option<DMatrix<bool>>,
     out_dir: String,
+    /// Location of the saved images, instead of the output directory.
+    out_sink: Option<String>,
+    /// Archive of the registered images, motions and report.
+    out_archive: Option<PathBuf>,
+    dry_run: bool,
+    fail_on_nonconvergence: bool,
+    /// Directory of the checkpoints of the registration.
+    checkpoint: Option<PathBuf>,
+    resume: bool,
     save_crop: bool,
     save_imgs: bool,
+    img_format: ImgFormat,
+    tiff_compression: TiffCompression,
+    save_sparse_mask: bool,
+    save_level_motions: bool,
+    save_uncertainty: bool,
+    save_report: bool,
+    save_fiji: bool,
+    pixel_size: Option<PixelSize>,
+    non_rigid_threshold: f32,
+    deformable: bool,
+    deformable_spacing: usize,
+    deformable_smoothness: f32,
+    metrics: bool,
+    save_contact_sheet: bool,
+    save_animation: Option<Animation>,
+    save_checkerboard: bool,
+    save_previews: bool,
+    tiff_stack: bool,
+    tie_points: Option<tie_points::Format>,
+    track_roi: Option<Roi>,
+    motions_convention: motions::Convention,
+    save_motions: bool,
+    apply_motions: Option<PathBuf>,
+    /// Initial motions of the registration, from --init-motions.
+    init_motions: Option<PathBuf>,
+    npy: bool,
+    npy_layout: lowrr::io::npy::Layout,
+    size_policy: SizePolicy,
+    coerce: Option<Depth>,
+    gray: GrayConversion,
+    transfer: Transfer,
+    white_balance: WhiteBalance,
+    joint_channels: bool,
+    online: bool,
+    online_iterations: usize,
+    /// Directory of the watch subcommand, whose new images are registered online.
+    watch: Option<PathBuf>,
     images_paths: Vec<PathBuf>,
+    crop_spec: Option<CropSpec>,
+    /// Crop frame in pixels, resolved from `crop_spec` once the images are loaded.
     crop: Option<Crop>,
+    crop_clamp: bool,
+    halvings: usize,
+    bayer: Option<CfaPattern>,
+    superres: Option<SuperRes>,
+    merge: Option<Merge>,
+    tune: Option<tune::Grid>,
+    /// Time between consecutive images of the drift subcommand, 0 if unknown.
+    drift: Option<f32>,
+    stack: Option<Stack>,
+}
+
+/// Parameters of the super-resolution subcommand.
+#[derive(Debug, Clone, Copy)]
+struct SuperRes {
+    factor: usize,
+    deconvolution_iterations: usize,
+}
+
+/// Combination of the registered images into a single image.
+#[derive(Debug, Clone, Copy)]
+enum Stack {
+    /// Exposure fusion of bracketed images.
+    Fusion,
+}
+
+impl std::str::FromStr for Stack {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fusion" => Ok(Stack::Fusion),
+            _ => Err(format!(
+                "Unknown stack combiner \"{}\", expecting fusion",
+                s
+            )),
+        }
+    }
+}
+
+/// Animated GIF of the registered images.
+#[derive(Debug, Clone, Copy)]
+enum Animation {
+    /// Alternate between the first image and each other image.
+    Toggle,
+    /// Cycle through all the images.
+    Stack,
+}
+
+impl std::str::FromStr for Animation {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "toggle" => Ok(Animation::Toggle),
+            "stack" => Ok(Animation::Stack),
+            _ => Err(format!(
+                "Unknown animation \"{}\", expecting toggle or stack",
+                s
+            )),
+        }
+    }
+}
+
+/// Duration of each frame of the animations.
+const ANIMATION_DELAY_MS: u32 = 500;
+
+impl Args {
+    /// Crop, equalize and registration steps shared with the other frontends.
+    fn pipeline(&self) -> Pipeline {
+        Pipeline {
+            config: self.config,
+            equalize: self.equalize,
+            crop: self.crop,
+            halvings: self.halvings,
+            bayer: self.bayer,
+        }
+    }
+
+    /// Distance between the neighbors replacing defective pixels,
+    /// 2 for Bayer mosaics to only use pixels of the same color.
+    fn defects_step(&self) -> usize {
+        if self.bayer.is_some() {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// Resolve the crop frame in pixels for images of the given (height, width).
+    fn resolve_crop(&mut self, shape: Option<(usize, usize)>) -> anyhow::Result<()> {
+        if let (Some(spec), Some(shape)) = (self.crop_spec, shape) {
+            let mut crop = spec.resolve(shape)?;
+            if self.crop_clamp {
+                let clamped = crop.clamp_to(shape)?;
+                if clamped != crop {
+                    log::warn!("Crop frame {} clamped to the images", crop);
+                }
+                crop = clamped;
+            } else {
+                crop.check_inside(shape)
+                    .context("Use --crop-clamp to restrict the crop frame to the images")?;
+            }
+            log::info!("Crop frame: {}", crop);
+            self.crop = Some(crop);
+        }
+        Ok(())
+    }
 }
 
 /// Retrieve the program arguments from clap matches.
@@ -138,9 +1117,149 @@ fn get_args(matches: &clap::ArgMatches) -> anyhow::Result<Args> {
         lambda: matches.value_of("lambda").unwrap().parse()?,
         rho: matches.value_of("rho").unwrap().parse()?,
         threshold: matches.value_of("convergence-threshold").unwrap().parse()?,
+        motion_threshold: matches.value_of("motion-threshold").unwrap().parse()?,
+        damping: matches.value_of("damping").unwrap().parse()?,
+        line_search: matches.is_present("line-search"),
+        compositional: matches
+            .value_of("compositional")
+            .unwrap()
+            .parse()
+            .map_err(anyhow::Error::msg)?,
+        max_translation: matches.value_of("max-translation").unwrap().parse()?,
+        max_deformation: matches.value_of("max-deformation").unwrap().parse()?,
         sparse_ratio_threshold: matches.value_of("sparse-switch").unwrap().parse()?,
         max_iterations: matches.value_of("max-iterations").unwrap().parse()?,
         levels: matches.value_of("levels").unwrap().parse()?,
+        mode: matches
+            .value_of("mode")
+            .unwrap()
+            .parse()
+            .map_err(anyhow::Error::msg)?,
+        precision: matches
+            .value_of("precision")
+            .unwrap()
+            .parse()
+            .map_err(anyhow::Error::msg)?,
+        svd_backend: matches
+            .value_of("svd-backend")
+            .unwrap()
+            .parse()
+            .map_err(anyhow::Error::msg)?,
+        svd_library: matches
+            .value_of("svd-library")
+            .unwrap()
+            .parse()
+            .map_err(anyhow::Error::msg)?,
+        sparse_strategy: matches
+            .value_of("sparse-strategy")
+            .unwrap()
+            .parse()
+            .map_err(anyhow::Error::msg)?,
+        sparse_fraction: matches.value_of("sparse-fraction").unwrap().parse()?,
+        pixel_budget: match matches.value_of("pixel-budget") {
+            None => 0,
+            Some(budget) => budget.parse()?,
+        },
+        input_max: match matches.value_of("input-max").unwrap() {
+            "auto" => 0.0,
+            max => max
+                .parse()
+                .context(format!("Invalid --input-max \"{}\"", max))?,
+        },
+        normalization: matches
+            .value_of("normalization")
+            .unwrap()
+            .parse()
+            .map_err(anyhow::Error::msg)?,
+        data_term: matches
+            .value_of("data-term")
+            .unwrap()
+            .parse()
+            .map_err(anyhow::Error::msg)?,
+        gain_bias: matches.is_present("gain-bias"),
+        do_image_correction: !matches.is_present("no-image-correction"),
+        pack_observations: matches.is_present("pack-observations"),
+        denoise: matches
+            .value_of("denoise")
+            .unwrap()
+            .parse()
+            .map_err(anyhow::Error::msg)?,
+        denoise_levels: matches.value_of("denoise-levels").unwrap().parse()?,
+        temporal_smoothness: matches.value_of("temporal-smoothness").unwrap().parse()?,
+        outlier_threshold: matches.value_of("outlier-threshold").unwrap().parse()?,
+        chunk_size: matches.value_of("chunk-size").unwrap().parse()?,
+        pixel_aspect_ratio: matches.value_of("pixel-aspect-ratio").unwrap().parse()?,
+        profile: matches.is_present("profile"),
+    };
+    anyhow::ensure!(
+        config.do_image_correction || config.mode == registration::Mode::LowRank,
+        "--no-image-correction is only available in the lowrank mode"
+    );
+
+    // Only the superres subcommand has an upscaling factor.
+    let superres = match matches.value_of("factor") {
+        None => None,
+        Some(factor) => {
+            let factor = factor.parse()?;
+            anyhow::ensure!(
+                factor >= 1,
+                "The super-resolution factor must be at least 1"
+            );
+            anyhow::ensure!(
+                !matches.is_present("online"),
+                "Super-resolution is not available in online mode"
+            );
+            Some(SuperRes {
+                factor,
+                deconvolution_iterations: matches
+                    .value_of("deconvolution-iterations")
+                    .unwrap()
+                    .parse()?,
+            })
+        }
+    };
+
+    // Only the merge subcommand has a merge method.
+    let merge = match matches.value_of("method") {
+        None => None,
+        Some(method) => {
+            anyhow::ensure!(
+                !matches.is_present("online"),
+                "Merging is not available in online mode"
+            );
+            Some(method.parse().map_err(anyhow::Error::msg)?)
+        }
+    };
+
+    // Only the tune subcommand has grid factors.
+    let tune = match matches.value_of("grid-factors") {
+        None => None,
+        Some(factors) => {
+            anyhow::ensure!(
+                !matches.is_present("online"),
+                "Tuning is not available in online mode"
+            );
+            let factors: Vec<f32> = factors
+                .split(',')
+                .map(|f| f.trim().parse())
+                .collect::<Result<_, _>>()
+                .context(format!("Failed to parse \"{}\" into grid factors", factors))?;
+            Some(tune::Grid::around(&config, &factors))
+        }
+    };
+
+    // Only the drift subcommand has a frame interval.
+    let drift = match matches.value_of("frame-interval") {
+        None => None,
+        Some(interval) => {
+            anyhow::ensure!(
+                !matches.is_present("online"),
+                "Drift analysis is not available in online mode"
+            );
+            let interval: f32 = interval.parse()?;
+            anyhow::ensure!(interval >= 0.0, "The frame interval cannot be negative");
+            Some(interval)
+        }
     };
 
     // Retrieving the equalize argument.
@@ -157,20 +1276,194 @@ fn get_args(matches: &clap::ArgMatches) -> anyhow::Result<Args> {
         }
     };
 
+    // Retrieving the flat-field arguments.
+    let flat = match matches.value_of("flat") {
+        Some(path) => {
+            let img = image::open(path)
+                .context(format!("Failed to open flat-field {}", path))?
+                .into_luma16();
+            let img: DMatrix<u16> = DynamicImage::ImageLuma16(img).into_dmatrix();
+            Some(Flat::Image(flat_field::from_image(&img)?))
+        }
+        None if matches.is_present("flat-radial") => Some(Flat::Radial),
+        None => None,
+    };
+
+    // Retrieving the lens distortion arguments.
+    let lens = match matches.value_of("distortion") {
+        Some(coefs) => {
+            let coefs: Vec<f32> = coefs
+                .split(',')
+                .map(|k| k.trim().parse())
+                .collect::<Result<_, _>>()
+                .context(format!("Invalid distortion \"{}\", expecting k1,k2", coefs))?;
+            match *coefs.as_slice() {
+                [k1, k2] => Some(Lens::Coefficients(k1, k2)),
+                _ => anyhow::bail!("Invalid distortion, expecting two coefficients k1,k2"),
+            }
+        }
+        None if matches.is_present("estimate-distortion") => Some(Lens::Estimate),
+        None => None,
+    };
+
+    // Retrieving the defective pixels, from a map or from a dark frame.
+    let open_luma16 = |path: &str, name: &str| -> anyhow::Result<DMatrix<u16>> {
+        let img = image::open(path)
+            .context(format!("Failed to open {} {}", name, path))?
+            .into_luma16();
+        Ok(DynamicImage::ImageLuma16(img).into_dmatrix())
+    };
+    let defects = match (matches.value_of("defects"), matches.value_of("hot-pixels")) {
+        (Some(path), _) => Some(defects::from_mask(&open_luma16(path, "defect map")?)),
+        (None, Some(path)) => {
+            let dark = open_luma16(path, "dark frame")?;
+            let step = if matches.is_present("bayer") { 2 } else { 1 };
+            let hot = defects::hot_pixels(&dark, defects::DEFAULT_HOT_PIXEL_SIGMAS, step);
+            log::info!("Found {} hot pixels", hot.iter().filter(|&&h| h).count());
+            Some(hot)
+        }
+        (None, None) => None,
+    };
+
+    // Retrieving the format of saved images.
+    let tiff_compression: TiffCompression = matches
+        .value_of("tiff-compression")
+        .unwrap()
+        .parse()
+        .map_err(anyhow::Error::msg)?;
+    let img_format = match matches.value_of("out-format").unwrap() {
+        "tiff" => ImgFormat::Tiff(tiff_compression),
+        _ => {
+            let compression: PngCompression = matches
+                .value_of("png-compression")
+                .unwrap()
+                .parse()
+                .map_err(anyhow::Error::msg)?;
+            ImgFormat::Png(compression)
+        }
+    };
+
     // Retrieving the crop argument.
-    let crop = match matches.values_of("crop") {
+    let crop_spec = match matches.values_of("crop") {
         None => None,
-        Some(str_coords) => Some(Crop::try_from(str_coords.collect::<Vec<_>>())?),
+        Some(coords) => Some(CropSpec::try_from(
+            coords.collect::<Vec<_>>().join(",").as_str(),
+        )?),
     };
 
     Ok(Args {
         config,
         equalize,
+        flat,
+        lens,
+        distortion: None,
+        dark: matches.value_of("dark").map(PathBuf::from),
+        bias: matches.value_of("bias").map(PathBuf::from),
+        defects,
         out_dir: matches.value_of("out-dir").unwrap().to_string(),
+        out_sink: matches.value_of("out-sink").map(String::from),
+        out_archive: matches.value_of("out-archive").map(PathBuf::from),
+        dry_run: matches.is_present("dry-run"),
+        fail_on_nonconvergence: matches.is_present("fail-on-nonconvergence"),
+        checkpoint: matches.value_of("checkpoint").map(PathBuf::from),
+        resume: matches.is_present("resume"),
         save_crop: matches.is_present("save-crop"),
-        save_imgs: matches.is_present("save-imgs"),
-        images_paths: absolute_file_paths(matches.values_of("IMAGE or GLOB").unwrap())?,
-        crop,
+        save_imgs: matches.is_present("save-imgs") || matches.is_present("out-archive"),
+        img_format,
+        tiff_compression,
+        save_sparse_mask: matches.is_present("save-sparse-mask"),
+        save_level_motions: matches.is_present("save-level-motions"),
+        save_uncertainty: matches.is_present("save-uncertainty"),
+        save_report: matches.is_present("save-report"),
+        save_fiji: matches.is_present("save-fiji"),
+        pixel_size: match matches.value_of("pixel-size") {
+            None => None,
+            Some(size) => Some(size.parse().map_err(anyhow::Error::msg)?),
+        },
+        non_rigid_threshold: matches.value_of("non-rigid-threshold").unwrap().parse()?,
+        deformable: matches.is_present("deformable"),
+        deformable_spacing: matches.value_of("deformable-spacing").unwrap().parse()?,
+        deformable_smoothness: matches.value_of("deformable-smoothness").unwrap().parse()?,
+        metrics: matches.is_present("metrics"),
+        save_contact_sheet: matches.is_present("save-contact-sheet"),
+        save_checkerboard: matches.is_present("save-checkerboard"),
+        save_animation: match matches.value_of("save-animation") {
+            None => None,
+            Some(animation) => Some(animation.parse().map_err(anyhow::Error::msg)?),
+        },
+        save_previews: matches.is_present("save-previews"),
+        tiff_stack: matches.is_present("tiff-stack"),
+        tie_points: match matches.value_of("tie-points") {
+            None => None,
+            Some(format) => Some(format.parse().map_err(anyhow::Error::msg)?),
+        },
+        track_roi: match matches.value_of("track-roi") {
+            None => None,
+            Some(roi) => Some(roi.parse().map_err(anyhow::Error::msg)?),
+        },
+        motions_convention: matches
+            .value_of("motions-convention")
+            .unwrap()
+            .parse()
+            .map_err(anyhow::Error::msg)?,
+        save_motions: matches.is_present("save-motions"),
+        apply_motions: matches.value_of("apply-motions").map(PathBuf::from),
+        init_motions: matches.value_of("init-motions").map(PathBuf::from),
+        npy: matches.is_present("npy"),
+        npy_layout: matches
+            .value_of("npy-layout")
+            .unwrap()
+            .parse()
+            .map_err(anyhow::Error::msg)?,
+        size_policy: matches
+            .value_of("size-policy")
+            .unwrap()
+            .parse()
+            .map_err(anyhow::Error::msg)?,
+        coerce: match matches.value_of("coerce") {
+            None => None,
+            Some(depth) => Some(depth.parse().map_err(anyhow::Error::msg)?),
+        },
+        gray: matches
+            .value_of("gray")
+            .unwrap()
+            .parse()
+            .map_err(anyhow::Error::msg)?,
+        transfer: matches
+            .value_of("transfer")
+            .unwrap()
+            .parse()
+            .map_err(anyhow::Error::msg)?,
+        white_balance: matches
+            .value_of("white-balance")
+            .unwrap()
+            .parse()
+            .map_err(anyhow::Error::msg)?,
+        joint_channels: matches.is_present("joint-channels"),
+        online: matches.is_present("online"),
+        online_iterations: matches.value_of("online-iterations").unwrap().parse()?,
+        watch: matches.value_of("DIR").map(PathBuf::from),
+        images_paths: match matches.values_of("IMAGE or GLOB") {
+            None => Vec::new(),
+            Some(globs) => absolute_file_paths(globs)?,
+        },
+        crop_spec,
+        crop: None,
+        crop_clamp: matches.is_present("crop-clamp"),
+        halvings: pipeline::halvings(matches.value_of("estimate-scale").unwrap().parse()?)
+            .map_err(anyhow::Error::msg)?,
+        bayer: match matches.value_of("bayer") {
+            None => None,
+            Some(pattern) => Some(pattern.parse().map_err(anyhow::Error::msg)?),
+        },
+        superres,
+        merge,
+        tune,
+        drift,
+        stack: match matches.value_of("stack") {
+            None => None,
+            Some(stack) => Some(stack.parse().map_err(anyhow::Error::msg)?),
+        },
     })
 }
 
@@ -196,227 +1489,2450 @@ fn paths_from_glob(p: &str) -> anyhow::Result<Vec<PathBuf>> {
 }
 
 /// Start actual program with command line arguments successfully parsed.
-fn run(args: Args) -> anyhow::Result<()> {
+fn run(mut args: Args) -> anyhow::Result<()> {
+    if args.online || args.watch.is_some() {
+        return run_online(args);
+    }
+    let sink = out_sink(&args)?;
+
     // Load the dataset in memory.
-    let now = std::time::Instant::now();
-    let (dataset, _) = load_dataset(&args.images_paths)?;
-    log::info!("Loading images took {:.1} s", now.elapsed().as_secs_f32());
+    let (dataset, _) = load_inputs(&mut args).context(InvalidInput)?;
+    let dataset = balance_whites(&args, dataset)?;
+    if args.dry_run {
+        return print_plan(&args, &dataset);
+    }
+    args.distortion = resolve_distortion(&args, &dataset)?;
+
+    if let Some(grid) = &args.tune {
+        return tune_all(&args, grid, &dataset);
+    }
+
+    // Images are saved in background threads while the next ones are computed.
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let writer = ImgWriter::with_sink(
+        threads,
+        args.img_format,
+        input_profile(&args),
+        Arc::clone(&sink),
+    );
 
     // Use the algorithm corresponding to the type of data.
-    let motion_vec = match dataset {
+    let motion_vec = match &dataset {
+        _ if args.apply_motions.is_some() => apply_motions(&args, &writer, &dataset)?,
         Dataset::GrayImages(gray_imgs) => {
-            let (motion_vec_crop, cropped_eq_imgs) =
-                crop_and_register(&args, gray_imgs.clone(), 40)?;
-            original_motion(&args, motion_vec_crop, cropped_eq_imgs, &gray_imgs)?
+            let output = crop_and_register(&args, &writer, gray_imgs.clone(), 1, 40)?;
+            save_results(&args, &writer, output, gray_imgs)?
         }
         Dataset::GrayImagesU16(gray_imgs) => {
-            let (motion_vec_crop, cropped_eq_imgs) =
-                crop_and_register(&args, gray_imgs.clone(), 10 * 256)?;
-            original_motion(&args, motion_vec_crop, cropped_eq_imgs, &gray_imgs)?
+            let output = crop_and_register(&args, &writer, gray_imgs.clone(), 1, 10 * 256)?;
+            save_results(&args, &writer, output, gray_imgs)?
         }
         Dataset::RgbImages(imgs) => {
-            let gray_imgs: Vec<_> = imgs.iter().map(|im| im.map(|(_r, g, _b)| g)).collect();
-            let (motion_vec_crop, cropped_eq_imgs) = crop_and_register(&args, gray_imgs, 40)?;
-            original_motion(&args, motion_vec_crop, cropped_eq_imgs, &imgs)?
+            let (channel_imgs, channels) = registration_imgs(&args, imgs);
+            let output = crop_and_register(&args, &writer, channel_imgs, channels, 40)?;
+            save_results(&args, &writer, output, imgs)?
         }
         Dataset::RgbImagesU16(imgs) => {
-            let gray_imgs: Vec<_> = imgs.iter().map(|im| im.map(|(_r, g, _b)| g)).collect();
-            let (motion_vec_crop, cropped_eq_imgs) = crop_and_register(&args, gray_imgs, 10 * 256)?;
-            original_motion(&args, motion_vec_crop, cropped_eq_imgs, &imgs)?
+            let (channel_imgs, channels) = registration_imgs(&args, imgs);
+            let output = crop_and_register(&args, &writer, channel_imgs, channels, 10 * 256)?;
+            save_results(&args, &writer, output, imgs)?
+        }
+        // Floating point images are registered with 16 bits precision.
+        Dataset::GrayImagesF32(imgs) => {
+            let gray_imgs: Vec<DMatrix<u16>> = imgs.iter().map(coerce).collect();
+            let output = crop_and_register(&args, &writer, gray_imgs, 1, 10 * 256)?;
+            save_results(&args, &writer, output, imgs)?
+        }
+        Dataset::RgbImagesF32(imgs) => {
+            let imgs_u16: Vec<DMatrix<(u16, u16, u16)>> = imgs.iter().map(coerce).collect();
+            let (channel_imgs, channels) = registration_imgs(&args, &imgs_u16);
+            let output = crop_and_register(&args, &writer, channel_imgs, channels, 10 * 256)?;
+            save_results(&args, &writer, output, imgs)?
         }
     };
 
+    if let Some(superres) = args.superres {
+        save_superres(&args, superres, &dataset, &motion_vec)?;
+    }
+    if let Some(method) = args.merge {
+        save_merged(&args, method, &dataset, &motion_vec)?;
+    }
+    if let Some(Stack::Fusion) = args.stack {
+        save_fused(&args, &dataset, &motion_vec)?;
+    }
+    let written = writer.finish().context("Failed to save images")?;
+    log::info!("Saved {} images", written);
+
+    if args.npy {
+        let out_dir_path = Path::new(&args.out_dir);
+        std::fs::create_dir_all(out_dir_path).context(format!(
+            "Could not create output dir: {}",
+            out_dir_path.display()
+        ))?;
+        let square_motions: Vec<_> = motion_vec.iter().map(|m| square_pixels(&args, m)).collect();
+        lowrr::io::npy::save_motions(out_dir_path.join("motions.npy"), &square_motions)
+            .context("Failed to save motion vectors")?;
+    }
+    if let (Some(format), Some(shape)) = (args.tie_points, dataset.shape()) {
+        save_tie_points(&args, format, shape, &motion_vec)?;
+    }
+    if let Some(roi) = &args.track_roi {
+        save_roi_track(&args, roi, &motion_vec)?;
+    }
+    if let (true, Some(shape)) = (args.save_report, dataset.shape()) {
+        save_report(&args, shape, &motion_vec)?;
+    }
+    if let (true, Some(shape)) = (args.save_fiji, dataset.shape()) {
+        save_fiji(&args, shape, &motion_vec)?;
+    }
+    if let (Some(interval), Some(shape)) = (args.drift, dataset.shape()) {
+        save_drift(&args, interval, shape, &motion_vec)?;
+    }
+    if args.save_motions {
+        let out_dir_path = Path::new(&args.out_dir);
+        std::fs::create_dir_all(out_dir_path).context(format!(
+            "Could not create output dir: {}",
+            out_dir_path.display()
+        ))?;
+        let square_motions: Vec<_> = motion_vec.iter().map(|m| square_pixels(&args, m)).collect();
+        motions::save(
+            out_dir_path.join("motions.txt"),
+            args.motions_convention,
+            &square_motions,
+        )
+        .context("Failed to save motion vectors")?;
+    }
+
+    if let Some(path) = &args.out_archive {
+        archive_results(&args, sink.as_ref(), dataset.shape(), &motion_vec)?;
+        log::info!("Saved the results in {}", path.display());
+    }
+    sink.finish().context("Failed to save images")?;
+
     // Write motion_vec to stdout.
-    for v in motion_vec.iter() {
-        println!("{}, {}, {}, {}, {}, {}", v[0], v[1], v[2], v[3], v[4], v[5]);
+    if Verbosity::prints_results() {
+        for v in motion_vec.iter() {
+            println!("{}", format_motion(&args, v));
+        }
     }
     Ok(())
 }
 
-#[allow(clippy::type_complexity)]
-fn crop_and_register<T: CanEqualize + CanRegister>(
-    args: &Args,
-    gray_imgs: Vec<DMatrix<T>>,
-    sparse_diff_threshold: <T as CanRegister>::Bigger, // 50
-) -> anyhow::Result<(Vec<Vector6<f32>>, Vec<DMatrix<T>>)>
-where
-    DMatrix<T>: ToImage,
-{
-    // Extract the cropped area from the images.
-    let cropped_imgs: Result<Vec<DMatrix<T>>, _> = match args.crop {
-        None => Ok(gray_imgs),
-        Some(frame) => {
-            log::info!("Cropping images ...");
-            gray_imgs.iter().map(|im| crop(frame, im)).collect()
-        }
-    };
-    let mut cropped_imgs = cropped_imgs.context("Failed to crop images")?;
+/// Load the dataset, subtract its calibration frames, and check it against the arguments.
+/// The calibration frames are also returned for the images loaded afterwards.
+fn load_inputs(args: &mut Args) -> anyhow::Result<(Dataset, Vec<Dataset>)> {
+    let now = Instant::now();
+    let (dataset, _) = load_dataset(&args.images_paths, args.npy_layout, args.coerce)?;
+    log::info!("Loading images took {:.1} s", now.elapsed().as_secs_f32());
+    let dataset = unify_sizes(args.size_policy, dataset)?;
+    let frames = load_calibration(args, &dataset)?;
+    let dataset = subtract_calibration(&frames, dataset)?;
+    args.resolve_crop(dataset.shape())?;
+    check_bayer(args, &dataset)?;
+    Ok((dataset, frames))
+}
 
-    // Equalize mean intensities of cropped area.
-    if let Some(mean_intensity) = args.equalize {
-        log::info!("Equalizing images mean intensities ...");
-        lowrr::utils::equalize_mean(mean_intensity, &mut cropped_imgs);
+/// Print what the registration of the dataset would do, without registering it.
+#[allow(clippy::cast_precision_loss)]
+fn print_plan(args: &Args, dataset: &Dataset) -> anyhow::Result<()> {
+    let (height, width) = dataset.shape().context("There is no image to register")?;
+    let count = dataset.len();
+    let (depth, depth_bytes) = match dataset.depth() {
+        Depth::U8 => ("8 bits", 1),
+        Depth::U16 => ("16 bits", 2),
+        Depth::F32 => ("32 bits floating point", 4),
+    };
+    let (color, channels) = if dataset.is_rgb() {
+        ("RGB", 3)
+    } else {
+        ("gray", 1)
+    };
+    println!(
+        "Images: {} {} {} images of {}x{} pixels",
+        count, color, depth, width, height
+    );
+    if let Some(path) = &args.apply_motions {
+        let motion_vec = motions::load(path, args.motions_convention)
+            .context("Failed to load the motions to apply")?;
+        if motion_vec.len() != count {
+            anyhow::bail!(
+                "{} motions in {} for {} images",
+                motion_vec.len(),
+                path.display(),
+                count
+            );
+        }
+        println!("Motions applied from {}", path.display());
+        return Ok(());
     }
 
-    // Compute the motion of each image for registration.
-    log::info!("Registration of images ...");
-    registration::gray_affine(args.config, cropped_imgs, sparse_diff_threshold)
-        .context("Failed to register images")
-}
+    // Size of the images of the registration.
+    let pipeline = args.pipeline();
+    let (crop_width, crop_height) = match args.crop {
+        None => (width, height),
+        Some(frame) => {
+            println!("Crop frame: {}", frame);
+            (frame.right - frame.left, frame.bottom - frame.top)
+        }
+    };
+    let pixel_size = pipeline.pixel_size() as usize;
+    let (reg_width, reg_height) = (crop_width / pixel_size, crop_height / pixel_size);
+    let columns = if dataset.is_rgb() && args.joint_channels {
+        3 * count
+    } else {
+        count
+    };
+    println!(
+        "Registration: {} columns of {}x{} pixels, on {} levels",
+        columns,
+        reg_width,
+        reg_height,
+        pipeline.registration_config().levels
+    );
 
-fn original_motion<T: CanRegister, U: Scalar + Copy, V>(
-    args: &Args,
-    motion_vec_crop: Vec<Vector6<f32>>,
-    cropped_eq_imgs: Vec<DMatrix<T>>,
-    original_imgs: &[DMatrix<U>],
-) -> anyhow::Result<Vec<Vector6<f32>>>
-where
-    DMatrix<T>: ToImage,
-    U: CanLinearInterpolate<V, U>,
-    V: Add<Output = V>,
-    f32: Mul<V, Output = V>,
-    DMatrix<U>: ToImage,
-{
-    // Recover motion parameters in the frame of the full image from the one in the cropped frame.
-    let motion_vec = match args.crop {
-        None => motion_vec_crop.clone(),
-        Some(frame) => recover_original_motion(frame, &motion_vec_crop),
+    // The registration keeps about 6 matrices with a column per image.
+    let float_bytes = match args.config.precision {
+        registration::Precision::Single => 4,
+        registration::Precision::Double => 8,
     };
+    let dataset_bytes = count * height * width * channels * depth_bytes;
+    let registration_bytes = 6 * columns * reg_width * reg_height * float_bytes;
+    let gigabytes = |bytes: usize| bytes as f32 / 1e9;
+    println!(
+        "Estimated memory: {:.2} GB for the images, {:.2} GB for the registration",
+        gigabytes(dataset_bytes),
+        gigabytes(registration_bytes)
+    );
+    let config = pipeline.registration_config();
+    println!("Parameters: {:#?}", Pipeline { config, ..pipeline });
+    Ok(())
+}
 
-    // All that follows is just to help debugging.
+/// Motion printed on stdout, in the convention of --motions-convention,
+/// or NaN parameters if it cannot be converted.
+fn format_motion(args: &Args, motion: &Vector6<f32>) -> String {
+    args.motions_convention
+        .format(&square_pixels(args, motion))
+        .unwrap_or_else(|| ["NaN"; 6].join(", "))
+}
 
-    let out_dir_path = Path::new(&args.out_dir);
+/// Motion in square pixels with --pixel-aspect-ratio, as saved and printed,
+/// from a motion in the pixels of the images.
+fn square_pixels(args: &Args, motion: &Vector6<f32>) -> Vector6<f32> {
+    match args.config.pixel_aspect_ratio {
+        // Avoid rounding errors on the motions of square pixels.
+        aspect_ratio if aspect_ratio == 1.0 => *motion,
+        aspect_ratio => affine2d::to_square_pixels(motion, aspect_ratio),
+    }
+}
 
-    // Visualization of cropped and equalized images.
-    if args.save_crop {
-        log::info!("Saving cropped + equalized images ...");
-        let cropped_dir = out_dir_path.join("cropped");
-        lowrr::utils::save_all_imgs(&cropped_dir, &cropped_eq_imgs)
-            .context("Failed to save cropped images")?;
+/// Motion in the pixels of the images from a motion in square pixels (see [square_pixels]).
+fn image_pixels(args: &Args, motion: &Vector6<f32>) -> Vector6<f32> {
+    match args.config.pixel_aspect_ratio {
+        aspect_ratio if aspect_ratio == 1.0 => *motion,
+        aspect_ratio => affine2d::from_square_pixels(motion, aspect_ratio),
+    }
+}
 
-        // Visualization of registered cropped images.
-        log::info!("Applying registration on cropped images ...");
-        let registered_cropped_imgs: Vec<DMatrix<T>> =
-            registration::reproject::<T, f32, T>(&cropped_eq_imgs, &motion_vec_crop);
-        let cropped_aligned_dir = &out_dir_path.join("cropped_aligned");
-        log::info!("Saving registered cropped images ...");
-        lowrr::utils::save_all_imgs(&cropped_aligned_dir, &registered_cropped_imgs)
-            .context("Failed to save registered cropped images")?;
+/// Warp and save the images with the motions of --apply-motions.
+fn apply_motions(
+    args: &Args,
+    writer: &ImgWriter,
+    dataset: &Dataset,
+) -> anyhow::Result<Vec<Vector6<f32>>> {
+    let path = args.apply_motions.as_ref().expect("Motions file is given");
+    let motion_vec: Vec<_> = motions::load(path, args.motions_convention)
+        .context("Failed to load the motions to apply")?
+        .iter()
+        .map(|m| image_pixels(args, m))
+        .collect();
+    let imgs_count = dataset.len();
+    if motion_vec.len() != imgs_count {
+        anyhow::bail!(
+            "{} motions in {} for {} images",
+            motion_vec.len(),
+            path.display(),
+            imgs_count
+        );
     }
+    match dataset {
+        Dataset::GrayImages(imgs) => save_registered_imgs(args, writer, imgs, &motion_vec, &[]),
+        Dataset::GrayImagesU16(imgs) => save_registered_imgs(args, writer, imgs, &motion_vec, &[]),
+        Dataset::GrayImagesF32(imgs) => save_registered_imgs(args, writer, imgs, &motion_vec, &[]),
+        Dataset::RgbImages(imgs) => save_registered_imgs(args, writer, imgs, &motion_vec, &[]),
+        Dataset::RgbImagesU16(imgs) => save_registered_imgs(args, writer, imgs, &motion_vec, &[]),
+        Dataset::RgbImagesF32(imgs) => save_registered_imgs(args, writer, imgs, &motion_vec, &[]),
+    }?;
+    Ok(motion_vec)
+}
 
-    // Reproject (interpolation + extrapolation) images according to that motion.
-    // Write the registered images to the output directory.
-    if args.save_imgs {
-        log::info!("Applying registration on original images ...");
-        let registered_imgs = registration::reproject::<U, V, U>(original_imgs, &motion_vec);
-        log::info!("Saving registered images ...");
-        lowrr::utils::save_all_imgs(&out_dir_path, registered_imgs.as_slice())
-            .context("Failed to save registered images")?;
+/// ICC profile of the first image if it is a PNG image, kept in the saved PNG images.
+fn input_profile(args: &Args) -> Option<Vec<u8>> {
+    let path = args.images_paths.first()?;
+    let extension = path.extension().and_then(|e| e.to_str())?;
+    if !extension.eq_ignore_ascii_case("png") {
+        return None;
     }
+    match icc::read_png_profile(path) {
+        Ok(profile) => profile,
+        Err(err) => {
+            log::warn!("Failed to read the ICC profile of the images: {}", err);
+            None
+        }
+    }
+}
 
-    Ok(motion_vec)
+/// Sink of the saved images, the filesystem, the archive of --out-archive
+/// or the location of --out-sink.
+fn out_sink(args: &Args) -> anyhow::Result<Arc<dyn OutputSink>> {
+    let sink: Box<dyn OutputSink> = match (&args.out_archive, &args.out_sink) {
+        (Some(path), _) => {
+            let extension = path.extension().and_then(|e| e.to_str());
+            let zip = match extension.map(str::to_lowercase).as_deref() {
+                Some("zip") => true,
+                Some("tar") => false,
+                _ => anyhow::bail!(
+                    "The archive {} is neither a .zip nor a .tar file",
+                    path.display()
+                ),
+            };
+            let file = std::fs::File::create(path)
+                .context(format!("Failed to create {}", path.display()))?;
+            let writer = std::io::BufWriter::new(file);
+            if zip {
+                Box::new(ZipSink::new(writer))
+            } else {
+                Box::new(TarSink::new(writer))
+            }
+        }
+        (None, None) => return Ok(Arc::new(DirSink::default())),
+        #[cfg(feature = "s3")]
+        (None, Some(location)) => Box::new(lowrr::io::sink::S3Sink::from_url(location)?),
+        #[cfg(not(feature = "s3"))]
+        (None, Some(location)) => anyhow::bail!(
+            "Cannot upload images to {}, lowrr was built without the s3 feature",
+            location
+        ),
+    };
+    Ok(Arc::new(OutDirSink {
+        out_dir: PathBuf::from(&args.out_dir),
+        sink,
+    }))
 }
 
-enum Dataset {
-    GrayImages(Vec<DMatrix<u8>>),
-    GrayImagesU16(Vec<DMatrix<u16>>),
-    RgbImages(Vec<DMatrix<(u8, u8, u8)>>),
-    RgbImagesU16(Vec<DMatrix<(u16, u16, u16)>>),
+/// Sink receiving the paths in the output directory relative to it.
+struct OutDirSink {
+    out_dir: PathBuf,
+    sink: Box<dyn OutputSink>,
 }
 
-/// Load all images into memory.
-fn load_dataset<P: AsRef<Path>>(paths: &[P]) -> anyhow::Result<(Dataset, (usize, usize))> {
-    log::info!("Images to be processed:");
-    let mut images_types = Vec::with_capacity(paths.len());
-    for path in paths.iter() {
-        let path = path.as_ref();
-        log::info!("    {}", path.display());
-        let img_type = match path
-            .extension()
-            .and_then(|e| e.to_str())
-            .map(|e| e.to_lowercase())
-            .as_deref()
-        {
-            Some("nef") => "raw",
-            Some("png") => "image",
-            Some("jpg") => "image",
-            Some("jpeg") => "image",
-            Some("tif") => "image",
-            Some("tiff") => "image",
-            Some(ext) => anyhow::bail!("Unrecognized extension: {}", ext),
-            None => anyhow::bail!("Hum no extension for {}?", path.display()),
-        };
-        images_types.push(img_type);
+impl OutputSink for OutDirSink {
+    fn write(&self, path: &Path, content: &[u8]) -> Result<(), SinkError> {
+        let relative = path.strip_prefix(&self.out_dir).unwrap_or(path);
+        self.sink.write(relative, content)
     }
 
-    if images_types.is_empty() {
-        anyhow::bail!(
-            "Something is wrong, I didn't find any image. Use --help to know how to use this program."
-        )
-    } else if images_types.iter().all(|&t| t == "raw") {
-        unimplemented!("imread raw")
-    } else if images_types.iter().all(|&t| t == "image") {
-        // Open the first image to figure out the image type.
-        match image::open(&paths[0])? {
-            DynamicImage::ImageLuma8(img_0) => {
-                log::info!("Images are of type Gray u8");
-                let (imgs, (height, width)) =
-                    load_all(DynamicImage::ImageLuma8(img_0), &paths[1..])?;
-                Ok((Dataset::GrayImages(imgs), (width, height)))
-            }
-            DynamicImage::ImageLuma16(img_0) => {
-                log::info!("Images are of type Gray u16");
-                let (imgs, (height, width)) =
-                    load_all(DynamicImage::ImageLuma16(img_0), &paths[1..])?;
-                Ok((Dataset::GrayImagesU16(imgs), (width, height)))
-            }
-            DynamicImage::ImageRgb8(rgb_img_0) => {
-                log::info!("Images are of type RGB (u8, u8, u8)");
-                let (imgs, (height, width)) =
-                    load_all(DynamicImage::ImageRgb8(rgb_img_0), &paths[1..])?;
-                Ok((Dataset::RgbImages(imgs), (width, height)))
-            }
-            DynamicImage::ImageRgb16(rgb_img_0) => {
-                log::info!("Images are of type RGB (u16, u16, u16)");
-                let (imgs, (height, width)) =
-                    load_all(DynamicImage::ImageRgb16(rgb_img_0), &paths[1..])?;
-                Ok((Dataset::RgbImagesU16(imgs), (width, height)))
-            }
-            _ => anyhow::bail!("Unsupported image type"),
+    fn finish(&self) -> Result<(), SinkError> {
+        self.sink.finish()
+    }
+}
+
+/// Bayer mosaics are gray images, only warped plane by plane.
+fn check_bayer(args: &Args, dataset: &Dataset) -> anyhow::Result<()> {
+    if args.bayer.is_none() {
+        return Ok(());
+    }
+    match dataset {
+        Dataset::RgbImages(_) | Dataset::RgbImagesU16(_) | Dataset::RgbImagesF32(_) => {
+            anyhow::bail!("--bayer expects gray images of RAW mosaics, not RGB images")
         }
-    } else {
-        anyhow::bail!("There is a mix of image types")
+        _ if args.superres.is_some() || args.merge.is_some() => {
+            anyhow::bail!("--bayer is not supported by the superres and merge subcommands")
+        }
+        _ => Ok(()),
     }
 }
 
-#[allow(clippy::type_complexity)]
-fn load_all<P: AsRef<Path>, Pixel, T: Scalar>(
-    first_img: DynamicImage,
-    other_paths: &[P],
-) -> anyhow::Result<(Vec<DMatrix<T>>, (usize, usize))>
+/// Save the JSON report of the motions in the output directory.
+fn save_report(
+    args: &Args,
+    shape: (usize, usize),
+    motion_vec: &[Vector6<f32>],
+) -> anyhow::Result<()> {
+    log::info!("Saving the report of the motions ...");
+    let out_dir_path = Path::new(&args.out_dir);
+    std::fs::create_dir_all(out_dir_path).context(format!(
+        "Could not create output dir: {}",
+        out_dir_path.display()
+    ))?;
+    let report_path = out_dir_path.join("report.json");
+    std::fs::write(&report_path, report(args, shape, motion_vec)?)
+        .context(format!("Failed to save {}", report_path.display()))
+}
+
+/// JSON report of the motions, with their geometry.
+fn report(
+    args: &Args,
+    shape: (usize, usize),
+    motion_vec: &[Vector6<f32>],
+) -> anyhow::Result<String> {
+    // Geometry in square pixels, like the saved motions.
+    let mut center = units::image_center(shape);
+    center.x *= args.config.pixel_aspect_ratio;
+    let xy = |v: Vector2<f32>| [v.x, v.y];
+    let motions: Vec<serde_json::Value> = motion_vec
+        .iter()
+        .enumerate()
+        .map(|(i, motion)| {
+            let motion = square_pixels(args, motion);
+            let geometry = MotionGeometry::new(&motion, center);
+            let mut entry = serde_json::json!({
+                "image": i,
+                "motion": motion.as_slice(),
+                "translation_px": xy(geometry.translation),
+                "center_drift_px": xy(geometry.center_drift),
+                "rotation_deg": geometry.rotation_deg,
+            });
+            if let (Some(pixel_size), Some(entry)) = (&args.pixel_size, entry.as_object_mut()) {
+                let physical = geometry.in_units(pixel_size);
+                entry.insert("translation".into(), xy(physical.translation).into());
+                entry.insert("center_drift".into(), xy(physical.center_drift).into());
+                entry.insert("unit".into(), pixel_size.unit.symbol().into());
+            }
+            entry
+        })
+        .collect();
+    let report = serde_json::json!({
+        "width": shape.1,
+        "height": shape.0,
+        "pixel_aspect_ratio": args.config.pixel_aspect_ratio,
+        "pixel_size": args.pixel_size.map(|size| size.to_string()),
+        "motions": motions,
+    });
+    serde_json::to_string_pretty(&report).context("Failed to encode the report")
+}
+
+/// Add the motions and their report to the archive of --out-archive.
+fn archive_results(
+    args: &Args,
+    sink: &dyn OutputSink,
+    shape: Option<(usize, usize)>,
+    motion_vec: &[Vector6<f32>],
+) -> anyhow::Result<()> {
+    let out_dir_path = Path::new(&args.out_dir);
+    let square_motions: Vec<_> = motion_vec.iter().map(|m| square_pixels(args, m)).collect();
+    let motions = motions::to_text(args.motions_convention, &square_motions)
+        .context("Failed to save motion vectors")?;
+    sink.write(&out_dir_path.join("motions.txt"), motions.as_bytes())
+        .context("Failed to add the motions to the archive")?;
+    if let Some(shape) = shape {
+        let report = report(args, shape, motion_vec)?;
+        sink.write(&out_dir_path.join("report.json"), report.as_bytes())
+            .context("Failed to add the report to the archive")?;
+    }
+    Ok(())
+}
+
+/// Save the motions for the Fiji plugins MultiStackReg and TrackMate in the output directory.
+fn save_fiji(
+    args: &Args,
+    shape: (usize, usize),
+    motion_vec: &[Vector6<f32>],
+) -> anyhow::Result<()> {
+    log::info!("Saving the motions for Fiji ...");
+    let out_dir_path = Path::new(&args.out_dir);
+    std::fs::create_dir_all(out_dir_path).context(format!(
+        "Could not create output dir: {}",
+        out_dir_path.display()
+    ))?;
+    // MultiStackReg works in the pixels of the images.
+    fiji::save_multistackreg(out_dir_path.join("multistackreg.txt"), motion_vec, shape)
+        .context("Failed to save the MultiStackReg transformations")?;
+    // TrackMate positions are calibrated, in square pixels.
+    let square_motions: Vec<_> = motion_vec.iter().map(|m| square_pixels(args, m)).collect();
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    #[allow(clippy::cast_precision_loss)]
+    let square_shape = (
+        shape.0,
+        (shape.1 as f32 * args.config.pixel_aspect_ratio).round() as usize,
+    );
+    let pixel_size = args.pixel_size.map_or(1.0, |size| size.size);
+    fiji::save_trackmate(
+        out_dir_path.join("trackmate.csv"),
+        &square_motions,
+        square_shape,
+        pixel_size,
+    )
+    .context("Failed to save the TrackMate spots")
+}
+
+/// Load the initial motions of --init-motions, in the pixels of the images,
+/// guessing the format of the file from its first line.
+fn load_init_motions(args: &Args, path: &Path, count: usize) -> anyhow::Result<Vec<Vector6<f32>>> {
+    let content =
+        std::fs::read_to_string(path).context(format!("Failed to read {}", path.display()))?;
+    let first_line = content.lines().next().unwrap_or("").trim();
+    let motion_vec = if first_line == fiji::MULTISTACKREG_HEADER {
+        fiji::load_multistackreg(path, count)?
+    } else if first_line.split(',').any(|c| c.trim() == "POSITION_X") {
+        let pixel_size = args.pixel_size.map_or(1.0, |size| size.size);
+        let translations = fiji::load_trackmate(path, count, pixel_size)?;
+        translations.iter().map(|m| image_pixels(args, m)).collect()
+    } else {
+        let motion_vec = motions::load(path, args.motions_convention)?;
+        motion_vec.iter().map(|m| image_pixels(args, m)).collect()
+    };
+    if motion_vec.len() != count {
+        anyhow::bail!(
+            "{} motions in {} for {} images",
+            motion_vec.len(),
+            path.display(),
+            count
+        );
+    }
+    Ok(motion_vec)
+}
+
+/// Fit the drift of the centers of the images, print its rates and save drift.csv.
+#[allow(clippy::cast_precision_loss)]
+fn save_drift(
+    args: &Args,
+    interval: f32,
+    shape: (usize, usize),
+    motion_vec: &[Vector6<f32>],
+) -> anyhow::Result<()> {
+    // Positions in square pixels, like the saved motions.
+    let mut center = units::image_center(shape);
+    center.x *= args.config.pixel_aspect_ratio;
+    let positions: Vec<Vector2<f32>> = motion_vec
+        .iter()
+        .map(|motion| MotionGeometry::new(&square_pixels(args, motion), center).center_drift)
+        .collect();
+    let drift = Drift::fit(&positions).context("The drift needs at least two images")?;
+
+    // Rates per frame, and per second with the frame interval, in the unit of the pixel size.
+    let (scale, unit) = match &args.pixel_size {
+        None => (1.0, "px"),
+        Some(pixel_size) => (pixel_size.size, pixel_size.unit.symbol()),
+    };
+    for (axis, fit) in [("x", &drift.x), ("y", &drift.y)] {
+        let mut line = format!(
+            "Drift {}: {:.4} {}/frame, jitter {:.4} {} rms",
+            axis,
+            fit.rate * scale,
+            unit,
+            fit.rms * scale,
+            unit
+        );
+        if interval > 0.0 {
+            line.push_str(&format!(", {:.4} {}/s", fit.rate * scale / interval, unit));
+        }
+        eprintln!("{}", line);
+    }
+    let total = (drift.at(positions.len() - 1) - drift.at(0)) * scale;
+    eprintln!(
+        "Total drift: {:.4} {} over {} frames",
+        total.norm(),
+        unit,
+        positions.len()
+    );
+
+    let out_dir_path = Path::new(&args.out_dir);
+    std::fs::create_dir_all(out_dir_path).context(format!(
+        "Could not create output dir: {}",
+        out_dir_path.display()
+    ))?;
+    let mut csv = format!("frame,time_s,x_{0},y_{0},trend_x_{0},trend_y_{0}\n", unit);
+    for (i, position) in positions.iter().enumerate() {
+        let (p, trend) = (position * scale, drift.at(i) * scale);
+        csv.push_str(&format!(
+            "{},{},{},{},{},{}\n",
+            i,
+            i as f32 * interval,
+            p.x,
+            p.y,
+            trend.x,
+            trend.y
+        ));
+    }
+    let csv_path = out_dir_path.join("drift.csv");
+    std::fs::write(&csv_path, csv).context(format!("Failed to save {}", csv_path.display()))
+}
+
+/// Export the motions as tie points in the output directory.
+fn save_tie_points(
+    args: &Args,
+    format: tie_points::Format,
+    shape: (usize, usize),
+    motion_vec: &[Vector6<f32>],
+) -> anyhow::Result<()> {
+    log::info!("Saving tie points ...");
+    let out_dir_path = Path::new(&args.out_dir);
+    std::fs::create_dir_all(out_dir_path).context(format!(
+        "Could not create output dir: {}",
+        out_dir_path.display()
+    ))?;
+    let points = tie_points::tie_points(motion_vec, shape, tie_points::DEFAULT_GRID_SIZE);
+    let saved = match format {
+        tie_points::Format::Csv => {
+            tie_points::save_csv(out_dir_path.join("tie_points.csv"), &points)
+        }
+        tie_points::Format::Pto => {
+            // Hugin projects refer to each image by its file.
+            if args.images_paths.len() != motion_vec.len() {
+                anyhow::bail!("Hugin projects need one file per image, not image stacks");
+            }
+            let path = out_dir_path.join("tie_points.pto");
+            tie_points::save_pto(path, &args.images_paths, shape, &points)
+        }
+    };
+    saved.context("Failed to save tie points")
+}
+
+/// Print the region of interest in each image on stderr, and save it in roi.csv.
+fn save_roi_track(args: &Args, roi: &Roi, motion_vec: &[Vector6<f32>]) -> anyhow::Result<()> {
+    let quads = roi::track(roi, motion_vec);
+    let mut csv = String::from("image,x0,y0,x1,y1,x2,y2,x3,y3,left,top,right,bottom\n");
+    for (i, quad) in quads.iter().enumerate() {
+        let [c0, c1, c2, c3] = quad.corners;
+        let (left, top, right, bottom) = quad.bounding_box();
+        eprintln!(
+            "{:>5}: ({:.2}, {:.2}) ({:.2}, {:.2}) ({:.2}, {:.2}) ({:.2}, {:.2}), bounding box {:.2}, {:.2}, {:.2}, {:.2}",
+            i, c0.0, c0.1, c1.0, c1.1, c2.0, c2.1, c3.0, c3.1, left, top, right, bottom
+        );
+        csv.push_str(&format!(
+            "{},{},{},{},{},{},{},{},{},{},{},{},{}\n",
+            i, c0.0, c0.1, c1.0, c1.1, c2.0, c2.1, c3.0, c3.1, left, top, right, bottom
+        ));
+    }
+    let out_dir_path = Path::new(&args.out_dir);
+    std::fs::create_dir_all(out_dir_path).context(format!(
+        "Could not create output dir: {}",
+        out_dir_path.display()
+    ))?;
+    let roi_path = out_dir_path.join("roi.csv");
+    std::fs::write(&roi_path, csv).context(format!("Failed to save {}", roi_path.display()))
+}
+
+/// Score each pair of lambda and rho of the grid, and print them to stdout.
+fn tune_all(args: &Args, grid: &tune::Grid, dataset: &Dataset) -> anyhow::Result<()> {
+    let tuning = match dataset {
+        Dataset::GrayImages(gray_imgs) => tune_imgs(args, grid, gray_imgs.clone(), 1, 40)?,
+        Dataset::GrayImagesU16(gray_imgs) => tune_imgs(args, grid, gray_imgs.clone(), 1, 10 * 256)?,
+        Dataset::RgbImages(imgs) => {
+            let (channel_imgs, channels) = registration_imgs(args, imgs);
+            tune_imgs(args, grid, channel_imgs, channels, 40)?
+        }
+        Dataset::RgbImagesU16(imgs) => {
+            let (channel_imgs, channels) = registration_imgs(args, imgs);
+            tune_imgs(args, grid, channel_imgs, channels, 10 * 256)?
+        }
+        Dataset::GrayImagesF32(imgs) => {
+            let gray_imgs: Vec<DMatrix<u16>> = imgs.iter().map(coerce).collect();
+            tune_imgs(args, grid, gray_imgs, 1, 10 * 256)?
+        }
+        Dataset::RgbImagesF32(imgs) => {
+            let imgs_u16: Vec<DMatrix<(u16, u16, u16)>> = imgs.iter().map(coerce).collect();
+            let (channel_imgs, channels) = registration_imgs(args, &imgs_u16);
+            tune_imgs(args, grid, channel_imgs, channels, 10 * 256)?
+        }
+    };
+    println!("lambda, rho, objective");
+    for candidate in tuning.candidates.iter() {
+        println!(
+            "{}, {}, {}",
+            candidate.lambda, candidate.rho, candidate.objective
+        );
+    }
+    let best = tuning.best();
+    eprintln!(
+        "Best parameters: --lambda {} --rho {}",
+        best.lambda, best.rho
+    );
+    Ok(())
+}
+
+/// Crop and equalize images, then score each pair of lambda and rho of the grid.
+fn tune_imgs<T: CanEqualize + CanRegister>(
+    args: &Args,
+    grid: &tune::Grid,
+    mut gray_imgs: Vec<DMatrix<T>>,
+    channels: usize,
+    sparse_diff_threshold: <T as CanRegister>::Bigger,
+) -> anyhow::Result<tune::Tuning>
 where
-    DynamicImage: IntoDMatrix<Pixel, T>,
+    DMatrix<T>: ToImage,
+{
+    inpaint_defects::<T, f32>(args, &mut gray_imgs)?;
+    transfer::linearize(args.transfer, &mut gray_imgs);
+    flat_field_correct(args, &mut gray_imgs)?;
+    undistort_imgs(args, &mut gray_imgs);
+    let imgs = args
+        .pipeline()
+        .prepare(gray_imgs)
+        .context("Failed to crop images")?;
+    tune::tune(
+        args.pipeline().registration_config(),
+        channels,
+        imgs,
+        sparse_diff_threshold,
+        grid,
+    )
+    .context("Tuning failed")
+}
+
+/// Reconstruct the first image at a higher resolution from the registered images,
+/// and save it in the output directory.
+fn save_superres(
+    args: &Args,
+    superres: SuperRes,
+    dataset: &Dataset,
+    motion_vec: &[Vector6<f32>],
+) -> anyhow::Result<()> {
+    let SuperRes {
+        factor,
+        deconvolution_iterations: iterations,
+    } = superres;
+    log::info!("Super-resolution x{} ...", factor);
+    let img = match dataset {
+        Dataset::GrayImages(imgs) => super_resolve(imgs, motion_vec, factor, iterations).to_image(),
+        Dataset::GrayImagesU16(imgs) => {
+            super_resolve(imgs, motion_vec, factor, iterations).to_image()
+        }
+        Dataset::RgbImages(imgs) => super_resolve_rgb(imgs, motion_vec, superres).to_image(),
+        Dataset::RgbImagesU16(imgs) => super_resolve_rgb(imgs, motion_vec, superres).to_image(),
+        // Floating point images are super-resolved with 16 bits precision.
+        Dataset::GrayImagesF32(imgs) => {
+            let imgs_u16: Vec<DMatrix<u16>> = imgs.iter().map(coerce).collect();
+            super_resolve(&imgs_u16, motion_vec, factor, iterations).to_image()
+        }
+        Dataset::RgbImagesF32(imgs) => {
+            let imgs_u16: Vec<DMatrix<(u16, u16, u16)>> = imgs.iter().map(coerce).collect();
+            super_resolve_rgb(&imgs_u16, motion_vec, superres).to_image()
+        }
+    };
+    let out_dir_path = Path::new(&args.out_dir);
+    std::fs::create_dir_all(out_dir_path).context(format!(
+        "Could not create output dir: {}",
+        out_dir_path.display()
+    ))?;
+    let path = out_dir_path.join("superres.png");
+    img.save(&path)
+        .context(format!("Failed to save {}", path.display()))
+}
+
+/// Super-resolution of each channel of RGB images.
+fn super_resolve_rgb<T: CanNormalize>(
+    imgs: &[DMatrix<(T, T, T)>],
+    motion_vec: &[Vector6<f32>],
+    superres: SuperRes,
+) -> DMatrix<(T, T, T)> {
+    let channel = |c: fn(&(T, T, T)) -> T| -> DMatrix<T> {
+        let channel_imgs: Vec<DMatrix<T>> = imgs.iter().map(|im| im.map(|p| c(&p))).collect();
+        super_resolve(
+            &channel_imgs,
+            motion_vec,
+            superres.factor,
+            superres.deconvolution_iterations,
+        )
+    };
+    let (red, green, blue) = (channel(|p| p.0), channel(|p| p.1), channel(|p| p.2));
+    red.zip_zip_map(&green, &blue, |r, g, b| (r, g, b))
+}
+
+/// Merge the registered images into a single frame,
+/// and save it in the output directory.
+fn save_merged(
+    args: &Args,
+    method: Merge,
+    dataset: &Dataset,
+    motion_vec: &[Vector6<f32>],
+) -> anyhow::Result<()> {
+    log::info!("Merging registered images ...");
+    let img = match dataset {
+        Dataset::GrayImages(imgs) => {
+            let registered = registration::reproject::<u8, f32, u8>(imgs, motion_vec);
+            merge(method, &registered).to_image()
+        }
+        Dataset::GrayImagesU16(imgs) => {
+            let registered = registration::reproject::<u16, f32, u16>(imgs, motion_vec);
+            merge(method, &registered).to_image()
+        }
+        Dataset::RgbImages(imgs) => {
+            let registered: Vec<DMatrix<(u8, u8, u8)>> =
+                registration::reproject::<_, Vector3<f32>, _>(imgs, motion_vec);
+            merge_rgb(method, &registered).to_image()
+        }
+        Dataset::RgbImagesU16(imgs) => {
+            let registered: Vec<DMatrix<(u16, u16, u16)>> =
+                registration::reproject::<_, Vector3<f32>, _>(imgs, motion_vec);
+            merge_rgb(method, &registered).to_image()
+        }
+        // Floating point images are merged with 16 bits precision.
+        Dataset::GrayImagesF32(imgs) => {
+            let imgs_u16: Vec<DMatrix<u16>> = imgs.iter().map(coerce).collect();
+            let registered = registration::reproject::<u16, f32, u16>(&imgs_u16, motion_vec);
+            merge(method, &registered).to_image()
+        }
+        Dataset::RgbImagesF32(imgs) => {
+            let imgs_u16: Vec<DMatrix<(u16, u16, u16)>> = imgs.iter().map(coerce).collect();
+            let registered: Vec<DMatrix<(u16, u16, u16)>> =
+                registration::reproject::<_, Vector3<f32>, _>(&imgs_u16, motion_vec);
+            merge_rgb(method, &registered).to_image()
+        }
+    };
+    let out_dir_path = Path::new(&args.out_dir);
+    std::fs::create_dir_all(out_dir_path).context(format!(
+        "Could not create output dir: {}",
+        out_dir_path.display()
+    ))?;
+    let path = out_dir_path.join("merged.png");
+    img.save(&path)
+        .context(format!("Failed to save {}", path.display()))
+}
+
+/// Exposure fusion of the registered images,
+/// saved in the output directory.
+fn save_fused(args: &Args, dataset: &Dataset, motion_vec: &[Vector6<f32>]) -> anyhow::Result<()> {
+    log::info!("Exposure fusion of registered images ...");
+    let img = match dataset {
+        Dataset::GrayImages(imgs) => {
+            let registered = registration::reproject::<u8, f32, u8>(imgs, motion_vec);
+            fuse_gray(&registered).to_image()
+        }
+        Dataset::GrayImagesU16(imgs) => {
+            let registered = registration::reproject::<u16, f32, u16>(imgs, motion_vec);
+            fuse_gray(&registered).to_image()
+        }
+        Dataset::RgbImages(imgs) => {
+            let registered: Vec<DMatrix<(u8, u8, u8)>> =
+                registration::reproject::<_, Vector3<f32>, _>(imgs, motion_vec);
+            fuse_rgb(&registered).to_image()
+        }
+        Dataset::RgbImagesU16(imgs) => {
+            let registered: Vec<DMatrix<(u16, u16, u16)>> =
+                registration::reproject::<_, Vector3<f32>, _>(imgs, motion_vec);
+            fuse_rgb(&registered).to_image()
+        }
+        // Floating point images are fused with 16 bits precision.
+        Dataset::GrayImagesF32(imgs) => {
+            let imgs_u16: Vec<DMatrix<u16>> = imgs.iter().map(coerce).collect();
+            let registered = registration::reproject::<u16, f32, u16>(&imgs_u16, motion_vec);
+            fuse_gray(&registered).to_image()
+        }
+        Dataset::RgbImagesF32(imgs) => {
+            let imgs_u16: Vec<DMatrix<(u16, u16, u16)>> = imgs.iter().map(coerce).collect();
+            let registered: Vec<DMatrix<(u16, u16, u16)>> =
+                registration::reproject::<_, Vector3<f32>, _>(&imgs_u16, motion_vec);
+            fuse_rgb(&registered).to_image()
+        }
+    };
+    let out_dir_path = Path::new(&args.out_dir);
+    std::fs::create_dir_all(out_dir_path).context(format!(
+        "Could not create output dir: {}",
+        out_dir_path.display()
+    ))?;
+    let path = out_dir_path.join("fused.png");
+    img.save(&path)
+        .context(format!("Failed to save {}", path.display()))
+}
+
+/// Merge of each channel of registered RGB images.
+fn merge_rgb<T: CanNormalize>(method: Merge, imgs: &[DMatrix<(T, T, T)>]) -> DMatrix<(T, T, T)> {
+    let channel = |c: fn(&(T, T, T)) -> T| -> DMatrix<T> {
+        let channel_imgs: Vec<DMatrix<T>> = imgs.iter().map(|im| im.map(|p| c(&p))).collect();
+        merge(method, &channel_imgs)
+    };
+    let (red, green, blue) = (channel(|p| p.0), channel(|p| p.1), channel(|p| p.2));
+    red.zip_zip_map(&green, &blue, |r, g, b| (r, g, b))
+}
+
+/// Dark and bias frames of the command line,
+/// converted to the pixel type of the images.
+fn load_calibration(args: &Args, dataset: &Dataset) -> anyhow::Result<Vec<Dataset>> {
+    let (depth, rgb) = (dataset.depth(), dataset.is_rgb());
+    let mut frames = Vec::new();
+    for (name, path) in [("bias", &args.bias), ("dark", &args.dark)] {
+        if let Some(path) = path {
+            let (frame, _) = load_same_type(std::slice::from_ref(path), args.npy_layout)
+                .context(format!("Failed to load {} frame {}", name, path.display()))?;
+            frames.push(frame.coerce(depth, rgb));
+        }
+    }
+    Ok(frames)
+}
+
+/// Subtract calibration frames from all images, before any other correction.
+fn subtract_calibration(frames: &[Dataset], mut dataset: Dataset) -> anyhow::Result<Dataset> {
+    for frame in frames {
+        let subtracted = match (&mut dataset, frame) {
+            (Dataset::GrayImages(imgs), Dataset::GrayImages(f)) => {
+                calibration::subtract::<_, f32>(&f[0], imgs)
+            }
+            (Dataset::GrayImagesU16(imgs), Dataset::GrayImagesU16(f)) => {
+                calibration::subtract::<_, f32>(&f[0], imgs)
+            }
+            (Dataset::GrayImagesF32(imgs), Dataset::GrayImagesF32(f)) => {
+                calibration::subtract::<_, f32>(&f[0], imgs)
+            }
+            (Dataset::RgbImages(imgs), Dataset::RgbImages(f)) => {
+                calibration::subtract::<_, Vector3<f32>>(&f[0], imgs)
+            }
+            (Dataset::RgbImagesU16(imgs), Dataset::RgbImagesU16(f)) => {
+                calibration::subtract::<_, Vector3<f32>>(&f[0], imgs)
+            }
+            (Dataset::RgbImagesF32(imgs), Dataset::RgbImagesF32(f)) => {
+                calibration::subtract::<_, Vector3<f32>>(&f[0], imgs)
+            }
+            _ => anyhow::bail!("Calibration frames must be gray or RGB like the images"),
+        };
+        subtracted.context("Failed to subtract calibration frame")?;
+    }
+    Ok(dataset)
+}
+
+/// Normalize the white balance of RGB images.
+fn balance_whites(args: &Args, mut dataset: Dataset) -> anyhow::Result<Dataset> {
+    if args.white_balance == WhiteBalance::None {
+        return Ok(dataset);
+    }
+    log::info!("Normalizing the white balance of the images ...");
+    let gains = match &mut dataset {
+        Dataset::RgbImages(imgs) => white_balance::balance(args.white_balance, imgs),
+        Dataset::RgbImagesU16(imgs) => white_balance::balance(args.white_balance, imgs),
+        Dataset::RgbImagesF32(imgs) => white_balance::balance(args.white_balance, imgs),
+        _ => {
+            log::warn!("--white-balance only applies to RGB images, ignoring it");
+            return Ok(dataset);
+        }
+    };
+    let gains = gains.context("Failed to normalize the white balance")?;
+    for (i, gain) in gains.iter().enumerate() {
+        log::debug!(
+            "White balance gains of image {}: red {:.3}, blue {:.3}",
+            i,
+            gain.x,
+            gain.z
+        );
+    }
+    Ok(dataset)
+}
+
+/// Flat-field correction requested on the command line.
+#[derive(Debug)]
+enum Flat {
+    /// Flat-field loaded from an image.
+    Image(DMatrix<f32>),
+    /// Radial flat-field fitted on the images.
+    Radial,
+}
+
+/// Lens distortion requested on the command line.
+#[derive(Debug, Clone, Copy)]
+enum Lens {
+    /// Coefficients k1 and k2 of a calibration.
+    Coefficients(f32, f32),
+    /// Distortion estimated from a first registration.
+    Estimate,
+}
+
+/// Resolve the distortion of the full images, given or estimated with a first registration.
+fn resolve_distortion(args: &Args, dataset: &Dataset) -> anyhow::Result<Option<Distortion>> {
+    let (lens, shape) = match (args.lens, dataset.shape()) {
+        (Some(lens), Some(shape)) => (lens, shape),
+        _ => return Ok(None),
+    };
+    let distortion = match lens {
+        Lens::Coefficients(k1, k2) => Distortion::centered(k1, k2, shape),
+        Lens::Estimate => {
+            log::info!("Estimating lens distortion ...");
+            let initial = Distortion::centered(0.0, 0.0, shape);
+            let estimated = match dataset {
+                Dataset::GrayImages(gray_imgs) => {
+                    estimate_distortion(args, initial, gray_imgs.clone(), 1, 40)?
+                }
+                Dataset::GrayImagesU16(gray_imgs) => {
+                    estimate_distortion(args, initial, gray_imgs.clone(), 1, 10 * 256)?
+                }
+                Dataset::RgbImages(imgs) => {
+                    let (channel_imgs, channels) = registration_imgs(args, imgs);
+                    estimate_distortion(args, initial, channel_imgs, channels, 40)?
+                }
+                Dataset::RgbImagesU16(imgs) => {
+                    let (channel_imgs, channels) = registration_imgs(args, imgs);
+                    estimate_distortion(args, initial, channel_imgs, channels, 10 * 256)?
+                }
+                Dataset::GrayImagesF32(imgs) => {
+                    let gray_imgs: Vec<DMatrix<u16>> = imgs.iter().map(coerce).collect();
+                    estimate_distortion(args, initial, gray_imgs, 1, 10 * 256)?
+                }
+                Dataset::RgbImagesF32(imgs) => {
+                    let imgs_u16: Vec<DMatrix<(u16, u16, u16)>> = imgs.iter().map(coerce).collect();
+                    let (channel_imgs, channels) = registration_imgs(args, &imgs_u16);
+                    estimate_distortion(args, initial, channel_imgs, channels, 10 * 256)?
+                }
+            };
+            log::info!(
+                "Lens distortion: --distortion {},{}",
+                estimated.k1,
+                estimated.k2
+            );
+            estimated
+        }
+    };
+    Ok(Some(distortion))
+}
+
+/// Register the images, and estimate the lens distortion
+/// of the full images from the registered crop.
+fn estimate_distortion<T: CanEqualize + CanRegister>(
+    args: &Args,
+    initial: Distortion,
+    mut gray_imgs: Vec<DMatrix<T>>,
+    channels: usize,
+    sparse_diff_threshold: <T as CanRegister>::Bigger,
+) -> anyhow::Result<Distortion>
+where
+    DMatrix<T>: ToImage,
+{
+    inpaint_defects::<T, f32>(args, &mut gray_imgs)?;
+    flat_field_correct(args, &mut gray_imgs)?;
+    let pipeline = args.pipeline();
+    let output = pipeline
+        .register(gray_imgs, channels, sparse_diff_threshold)
+        .context("Registration pipeline failed")?;
+    // Same distortion in the frame of the downscaled crop.
+    let scale = 1.0 / pipeline.pixel_size();
+    let (left, top) = pipeline
+        .crop
+        .map_or((0.0, 0.0), |frame| (frame.left as f32, frame.top as f32));
+    let initial_crop = initial.transformed(scale, (-scale * left, -scale * top));
+    let imgs: Vec<DMatrix<T>> = output
+        .registered
+        .imgs
+        .into_iter()
+        .skip(channels / 2)
+        .step_by(channels)
+        .collect();
+    let estimated = distortion::estimate(&imgs, &output.registered.motion_vec, initial_crop);
+    Ok(Distortion {
+        k1: estimated.k1,
+        k2: estimated.k2,
+        ..initial
+    })
+}
+
+/// Undistort full images before their registration.
+fn undistort_imgs<T: CanRegister>(args: &Args, imgs: &mut [DMatrix<T>]) {
+    if let Some(distortion) = &args.distortion {
+        log::info!("Undistorting images ...");
+        for img in imgs.iter_mut() {
+            *img = distortion::undistort_img::<T, f32, T>(img, distortion);
+        }
+    }
+}
+
+/// Remove the vignetting of full images, before they are cropped.
+/// Return the flat-field used, to correct images registered later.
+fn flat_field_correct<T: CanRegister>(
+    args: &Args,
+    imgs: &mut [DMatrix<T>],
+) -> anyhow::Result<Option<DMatrix<f32>>>
+where
+    DMatrix<T>: ToImage,
+{
+    let flat = match &args.flat {
+        None => return Ok(None),
+        Some(Flat::Image(flat)) => flat.clone(),
+        Some(Flat::Radial) => {
+            log::info!("Fitting radial flat-field ...");
+            flat_field::fit_radial(imgs).context("Failed to fit the flat-field")?
+        }
+    };
+    log::info!("Flat-field correction ...");
+    flat_field::correct(&flat, imgs).context("Failed to apply the flat-field")?;
+    Ok(Some(flat))
+}
+
+/// Replace the defective pixels of full images by the mean of their neighbors.
+fn inpaint_defects<U, V>(args: &Args, imgs: &mut [DMatrix<U>]) -> anyhow::Result<()>
+where
+    U: Scalar + Copy + CanLinearInterpolate<V, U>,
+    V: Add<Output = V>,
+    f32: Mul<V, Output = V>,
+{
+    if let Some(defects) = &args.defects {
+        defects::inpaint_all::<U, V>(defects, args.defects_step(), imgs)
+            .context("Failed to inpaint defective pixels")?;
+    }
+    Ok(())
+}
+
+#[allow(clippy::type_complexity)]
+fn crop_and_register<T: CanEqualize + CanRegister>(
+    args: &Args,
+    writer: &ImgWriter,
+    mut gray_imgs: Vec<DMatrix<T>>,
+    channels: usize,
+    sparse_diff_threshold: <T as CanRegister>::Bigger, // 50
+) -> anyhow::Result<pipeline::Output<T>>
+where
+    DMatrix<T>: ToImage,
+{
+    inpaint_defects::<T, f32>(args, &mut gray_imgs)?;
+    transfer::linearize(args.transfer, &mut gray_imgs);
+    flat_field_correct(args, &mut gray_imgs)?;
+    undistort_imgs(args, &mut gray_imgs);
+
+    // Crop, equalize and compute the motion of each image for registration.
+    let previews_dir = Path::new(&args.out_dir).join("previews");
+    let save_preview = |preview: registration::Preview<T>| {
+        if !args.save_previews {
+            return;
+        }
+        let level_dir = previews_dir.join(format!("level_{}", preview.level));
+        // Keep a single channel of each image, like the registered images.
+        let mut imgs: Vec<_> = preview
+            .imgs
+            .into_iter()
+            .skip(channels / 2)
+            .step_by(channels)
+            .collect();
+        transfer::encode(args.transfer, &mut imgs);
+        if let Err(err) = writer.save_all(&level_dir, &imgs) {
+            log::warn!(
+                "Failed to save the previews of level {}: {}",
+                preview.level,
+                err
+            );
+        }
+    };
+    let checkpoint_path = match &args.checkpoint {
+        None => None,
+        Some(dir) => {
+            std::fs::create_dir_all(dir).context(format!(
+                "Could not create checkpoint dir: {}",
+                dir.display()
+            ))?;
+            Some(dir.join("checkpoint.txt"))
+        }
+    };
+    let resume = match &checkpoint_path {
+        Some(path) if args.resume && path.exists() => {
+            Some(checkpoint::load(path).context(format!("Failed to load {}", path.display()))?)
+        }
+        Some(path) if args.resume => {
+            log::warn!(
+                "No checkpoint {} to resume, starting from scratch",
+                path.display()
+            );
+            None
+        }
+        _ => None,
+    };
+    let pipeline = args.pipeline();
+    let resume = match (resume, &args.init_motions) {
+        (None, Some(path)) => {
+            let count = gray_imgs.len() / channels;
+            let motion_vec = load_init_motions(args, path, count)
+                .context("Failed to load the initial motions")?;
+            Some(pipeline.initial_checkpoint(&motion_vec))
+        }
+        (resume, _) => resume,
+    };
+    let save_checkpoint = |state: &registration::Checkpoint| {
+        if let Some(path) = &checkpoint_path {
+            if let Err(err) = checkpoint::save(path, state) {
+                log::warn!(
+                    "Failed to save the checkpoint of level {}: {}",
+                    state.level,
+                    err
+                );
+            }
+        }
+    };
+    let levels = pipeline.registration_config().levels;
+    let progress_events = ProgressEvents::new("register");
+    let pb = match progress_events {
+        None => verbosity::progress_bar(REGISTRATION_PROGRESS_STEPS),
+        Some(_) => indicatif::ProgressBar::hidden(),
+    };
+    pb.set_style(
+        indicatif::ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] {wide_bar} {percent}% (ETA {eta}) {msg}"),
+    );
+    let registration_start = Instant::now();
+    let report_progress = |progress: registration::Progress| {
+        let fraction = registration_fraction(levels, args.config.max_iterations, &progress);
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        pb.set_position((fraction * REGISTRATION_PROGRESS_STEPS as f32) as u64);
+        pb.set_message(&format!(
+            "level {}, iteration {}",
+            progress.level, progress.iteration
+        ));
+        if let Some(events) = &progress_events {
+            events.emit(
+                fraction,
+                serde_json::json!({
+                    "level": progress.level,
+                    "iteration": progress.iteration,
+                    "residual": progress.residual,
+                }),
+            );
+        }
+    };
+    let mut output = pipeline
+        .register_checkpoints(
+            gray_imgs,
+            channels,
+            sparse_diff_threshold,
+            report_progress,
+            save_preview,
+            resume.as_ref(),
+            save_checkpoint,
+        )
+        .context("Registration pipeline failed")?;
+    pb.finish_and_clear();
+    log::info!(
+        "Registration took {:.1} s",
+        registration_start.elapsed().as_secs_f32()
+    );
+    if args.config.profile {
+        print_profile(&output.registered.profile);
+    }
+    let unconverged_levels = &output.registered.unconverged_levels;
+    if !unconverged_levels.is_empty() {
+        log::warn!(
+            "Levels {:?} stopped at {} iterations before converging, see --max-iterations",
+            unconverged_levels,
+            args.config.max_iterations
+        );
+        if args.fail_on_nonconvergence {
+            NOT_CONVERGED.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // Visualization of the sparse pixels used.
+    if args.save_sparse_mask {
+        log::info!("Saving sparse masks ...");
+        let mask_dir = Path::new(&args.out_dir).join("sparse_mask");
+        std::fs::create_dir_all(&mask_dir).context(format!(
+            "Could not create output dir: {}",
+            mask_dir.display()
+        ))?;
+        for (level, overlay) in output.registered.sparse_mask_overlays() {
+            let mask_path = mask_dir.join(format!("level_{}.png", level));
+            overlay
+                .to_image()
+                .save(&mask_path)
+                .context(format!("Failed to save {}", mask_path.display()))?;
+        }
+    }
+    // Motions of each level, to find where the registration went wrong.
+    if args.save_level_motions {
+        log::info!("Saving motions of each level ...");
+        let motions_dir = Path::new(&args.out_dir).join("level_motions");
+        std::fs::create_dir_all(&motions_dir).context(format!(
+            "Could not create output dir: {}",
+            motions_dir.display()
+        ))?;
+        for (level, motion_vec) in output.registered.level_motions.iter() {
+            let motions_path = motions_dir.join(format!("level_{}.txt", level));
+            let lines: String = args
+                .pipeline()
+                .original_motion(motion_vec)
+                .iter()
+                .map(|v| {
+                    format!(
+                        "{}, {}, {}, {}, {}, {}\n",
+                        v[0], v[1], v[2], v[3], v[4], v[5]
+                    )
+                })
+                .collect();
+            std::fs::write(&motions_path, lines)
+                .context(format!("Failed to save {}", motions_path.display()))?;
+        }
+    }
+    // Uncertainty of each motion, to reject poorly constrained registrations.
+    if args.save_uncertainty {
+        let out_dir_path = Path::new(&args.out_dir);
+        std::fs::create_dir_all(out_dir_path).context(format!(
+            "Could not create output dir: {}",
+            out_dir_path.display()
+        ))?;
+        let uncertainty_path = out_dir_path.join("uncertainty.txt");
+        // Uncertainties are in pixels of the original images.
+        let pixel_size = args.pipeline().pixel_size();
+        let lines: String = output
+            .registered
+            .uncertainties
+            .iter()
+            .map(|uncertainty| match uncertainty {
+                Some(uncertainty) => format!("{}\n", uncertainty.pixel_std * pixel_size),
+                None => "nan\n".to_string(),
+            })
+            .collect();
+        std::fs::write(&uncertainty_path, lines)
+            .context(format!("Failed to save {}", uncertainty_path.display()))?;
+    }
+    // Keep a single channel of each image, the middle one being green for RGB images.
+    let imgs = std::mem::take(&mut output.registered.imgs);
+    output.registered.imgs = imgs
+        .into_iter()
+        .skip(channels / 2)
+        .step_by(channels)
+        .collect();
+    Ok(output)
+}
+
+/// Print the time spent at each level of the registration.
+fn print_profile(profile: &[LevelProfile]) {
+    eprintln!(
+        "{:>5} {:>10} {:>9} {:>9} {:>12} {:>10} {:>9}",
+        "level", "iterations", "svd", "gradients", "gauss-newton", "projection", "total"
+    );
+    for p in profile {
+        eprintln!(
+            "{:>5} {:>10} {:>8.3}s {:>8.3}s {:>11.3}s {:>9.3}s {:>8.3}s",
+            p.level,
+            p.iterations,
+            p.svd.as_secs_f32(),
+            p.gradients.as_secs_f32(),
+            p.gauss_newton.as_secs_f32(),
+            p.projection.as_secs_f32(),
+            p.total.as_secs_f32(),
+        );
+    }
+}
+
+/// Warn about images with residual local translations after registration,
+/// in pixels of the original images.
+fn warn_non_rigid<T: CanRegister>(args: &Args, imgs: &[DMatrix<T>], motion_vec: &[Vector6<f32>]) {
+    let pixel_size = args.pipeline().pixel_size();
+    let fields = residual::residual_fields(imgs, motion_vec, residual::DEFAULT_BLOCK_SIZE);
+    let non_rigid: Vec<String> = fields
+        .iter()
+        .map(|field| pixel_size * field.max_norm())
+        .enumerate()
+        .filter(|(_, residual)| *residual > args.non_rigid_threshold)
+        .map(|(i, residual)| format!("{} ({:.2} px)", i, residual))
+        .collect();
+    if !non_rigid.is_empty() {
+        log::warn!(
+            "Blocks of images (starting at 0) are still shifted after registration, the scene may need a non-rigid model: {}",
+            non_rigid.join(", ")
+        );
+    }
+}
+
+/// Print the similarity of each image to the first one, before and after registration.
+fn print_metrics(metrics: &[metrics::Metrics]) {
+    eprintln!(
+        "{:>5} {:>11} {:>11} {:>11} {:>11} {:>11} {:>11}",
+        "image",
+        "ncc before",
+        "ncc after",
+        "ssim before",
+        "ssim after",
+        "psnr before",
+        "psnr after"
+    );
+    for (i, m) in metrics.iter().enumerate() {
+        eprintln!(
+            "{:>5} {:>11.4} {:>11.4} {:>11.4} {:>11.4} {:>9.2}dB {:>9.2}dB",
+            i, m.before.ncc, m.after.ncc, m.before.ssim, m.after.ssim, m.before.psnr, m.after.psnr
+        );
+    }
+}
+
+/// Online registration of images captured one at a time.
+fn run_online(mut args: Args) -> anyhow::Result<()> {
+    use std::io::BufRead;
+    // New images are read on stdin, or found in the watched directory.
+    let stdin = std::io::stdin();
+    let new_paths: Box<dyn Iterator<Item = std::io::Result<PathBuf>> + '_> = match &args.watch {
+        None => Box::new(
+            stdin
+                .lock()
+                .lines()
+                .map(|line| line.map(|l| PathBuf::from(l.trim())))
+                .filter(|path| !matches!(path, Ok(p) if p.as_os_str().is_empty())),
+        ),
+        Some(dir) => {
+            let mut watched = WatchedDir::new(dir.clone());
+            log::warn!("Waiting for the first two images in {} ...", dir.display());
+            args.images_paths = watched.by_ref().take(2).collect();
+            Box::new(watched.map(Ok))
+        }
+    };
+    let (dataset, frames) = load_inputs(&mut args).context(InvalidInput)?;
+    let args = &args;
+    // Images registered online are calibrated and converted like the initial ones.
+    let gray_u8 = |dataset| match subtract_calibration(&frames, dataset)? {
+        Dataset::GrayImages(imgs) => Ok(imgs),
+        Dataset::RgbImages(imgs) => Ok(gray_channel(args.gray, &imgs)),
+        _ => anyhow::bail!("Expecting an 8 bits image"),
+    };
+    let gray_u16 = |dataset| match subtract_calibration(&frames, dataset)? {
+        Dataset::GrayImagesU16(imgs) => Ok(imgs),
+        Dataset::RgbImagesU16(imgs) => Ok(gray_channel(args.gray, &imgs)),
+        Dataset::GrayImagesF32(imgs) => Ok(imgs.iter().map(coerce).collect()),
+        Dataset::RgbImagesF32(imgs) => Ok(gray_channel_u16(args.gray, &imgs)),
+        _ => anyhow::bail!("Expecting a 16 bits or floating point image"),
+    };
+    match dataset {
+        Dataset::GrayImages(imgs) => online_loop(args, imgs, 40, gray_u8, new_paths),
+        Dataset::RgbImages(imgs) => {
+            online_loop(args, gray_channel(args.gray, &imgs), 40, gray_u8, new_paths)
+        }
+        Dataset::GrayImagesU16(imgs) => online_loop(args, imgs, 10 * 256, gray_u16, new_paths),
+        Dataset::RgbImagesU16(imgs) => online_loop(
+            args,
+            gray_channel(args.gray, &imgs),
+            10 * 256,
+            gray_u16,
+            new_paths,
+        ),
+        Dataset::GrayImagesF32(imgs) => online_loop(
+            args,
+            imgs.iter().map(coerce).collect(),
+            10 * 256,
+            gray_u16,
+            new_paths,
+        ),
+        Dataset::RgbImagesF32(imgs) => online_loop(
+            args,
+            gray_channel_u16(args.gray, &imgs),
+            10 * 256,
+            gray_u16,
+            new_paths,
+        ),
+    }
+}
+
+/// Register the initial images, then each image of `new_paths`.
+/// Motions are printed in the frame of the full images,
+/// followed by the residual for images registered online.
+/// In watch mode, the motions file and the registered previews are also updated.
+fn online_loop<T: CanRegister>(
+    args: &Args,
+    mut imgs: Vec<DMatrix<T>>,
+    sparse_diff_threshold: <T as CanRegister>::Bigger,
+    gray_imgs: impl Fn(Dataset) -> anyhow::Result<Vec<DMatrix<T>>>,
+    new_paths: impl Iterator<Item = std::io::Result<PathBuf>>,
+) -> anyhow::Result<()>
+where
+    DMatrix<T>: ToImage,
+{
+    use std::io::Write;
+    let pipeline = args.pipeline();
+    let to_original = |motion: &Vector6<f32>| pipeline.original_motion(&[*motion])[0];
+    inpaint_defects::<T, f32>(args, &mut imgs)?;
+    transfer::linearize(args.transfer, &mut imgs);
+    let flat = flat_field_correct(args, &mut imgs)?;
+    let shape = imgs.first().map(|img| img.shape());
+    let crop_img = |mut img: DMatrix<T>| {
+        // New images are fitted to the size of the initial ones.
+        if let (Some(shape), SizePolicy::Crop | SizePolicy::Pad) = (shape, args.size_policy) {
+            img = size::fit(shape, img);
+        }
+        inpaint_defects::<T, f32>(args, std::slice::from_mut(&mut img))?;
+        transfer::linearize(args.transfer, std::slice::from_mut(&mut img));
+        if let Some(flat) = &flat {
+            flat_field::correct(flat, std::slice::from_mut(&mut img))
+                .context("Failed to apply the flat-field")?;
+        }
+        let img = match args.crop {
+            None => img,
+            Some(frame) => crop(frame, &img).context("Failed to crop image")?,
+        };
+        Ok::<_, anyhow::Error>(pipeline.downscale(pipeline.green_plane(img)))
+    };
+    let mut watch_outputs = WatchOutputs::new(args)?;
+
+    let mut registration =
+        registration::Registration::new(pipeline.registration_config(), sparse_diff_threshold);
+    let mut initial_imgs = Vec::with_capacity(imgs.len());
+    for img in imgs {
+        let img = crop_img(img)?;
+        if watch_outputs.is_some() {
+            initial_imgs.push(img.clone());
+        }
+        registration.push_image(img)?;
+    }
+    log::info!("Registration of initial images ...");
+    let motion_vec = registration.refine().context("Failed to register images")?;
+    for (i, motion) in motion_vec.iter().enumerate() {
+        let original = to_original(motion);
+        if Verbosity::prints_results() {
+            println!("{}", format_motion(args, &original));
+        }
+        if let Some(outputs) = &mut watch_outputs {
+            outputs.push(&initial_imgs[i], motion, original)?;
+        }
+    }
+    std::io::stdout().flush()?;
+
+    match &args.watch {
+        None => log::info!("Waiting for new image paths on stdin ..."),
+        Some(dir) => log::warn!("Watching {} for new images ...", dir.display()),
+    }
+    for path in new_paths {
+        let path = path?;
+        let new_imgs = load_dataset(std::slice::from_ref(&path), args.npy_layout, args.coerce)
+            .and_then(|(d, _)| gray_imgs(d));
+        let new_imgs = match new_imgs {
+            Ok(imgs) => imgs,
+            Err(err) => {
+                log::error!("Skipping {}: {:#}", path.display(), err);
+                continue;
+            }
+        };
+        for img in new_imgs {
+            let img = crop_img(img)?;
+            let frame = registration
+                .register_online(img.clone(), args.online_iterations)
+                .context(format!("Failed to register {}", path.display()))?;
+            let motion = to_original(&frame.motion);
+            if Verbosity::prints_results() {
+                println!("{}, {}", format_motion(args, &motion), frame.residual);
+                std::io::stdout().flush()?;
+            }
+            if let Some(outputs) = &mut watch_outputs {
+                outputs.push(&img, &frame.motion, motion)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Image files of a directory, yielded in the order of their names as they appear.
+/// A file is only yielded once its size is the same in two consecutive scans,
+/// so that images still being written are not read.
+struct WatchedDir {
+    dir: PathBuf,
+    /// Size of the files not yielded yet, at the last scan.
+    sizes: std::collections::HashMap<PathBuf, u64>,
+    yielded: std::collections::HashSet<PathBuf>,
+    ready: std::collections::VecDeque<PathBuf>,
+}
+
+impl WatchedDir {
+    fn new(dir: PathBuf) -> Self {
+        WatchedDir {
+            dir,
+            sizes: Default::default(),
+            yielded: Default::default(),
+            ready: Default::default(),
+        }
+    }
+
+    fn scan(&mut self) {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                log::warn!("Failed to read {}: {}", self.dir.display(), err);
+                return;
+            }
+        };
+        let mut files: Vec<(PathBuf, u64)> = entries
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let metadata = entry.metadata().ok()?;
+                let hidden = entry.file_name().to_string_lossy().starts_with('.');
+                (metadata.is_file() && !hidden).then(|| (entry.path(), metadata.len()))
+            })
+            .filter(|(path, _)| !self.yielded.contains(path))
+            .collect();
+        files.sort();
+        let mut sizes = std::collections::HashMap::with_capacity(files.len());
+        for (path, size) in files {
+            if size > 0 && self.sizes.get(&path) == Some(&size) {
+                self.yielded.insert(path.clone());
+                self.ready.push_back(path);
+            } else {
+                sizes.insert(path, size);
+            }
+        }
+        self.sizes = sizes;
+    }
+}
+
+impl Iterator for WatchedDir {
+    type Item = PathBuf;
+    fn next(&mut self) -> Option<PathBuf> {
+        loop {
+            if let Some(path) = self.ready.pop_front() {
+                return Some(path);
+            }
+            self.scan();
+            if self.ready.is_empty() {
+                std::thread::sleep(WATCH_INTERVAL);
+            }
+        }
+    }
+}
+
+/// Outputs of the watch subcommand, updated with each registered image:
+/// the motions file of all images and the registered previews,
+/// which are the cropped and downscaled images used for registration.
+struct WatchOutputs<'a> {
+    args: &'a Args,
+    previews_dir: PathBuf,
+    motion_vec: Vec<Vector6<f32>>,
+}
+
+impl<'a> WatchOutputs<'a> {
+    /// Outputs in watch mode, None otherwise.
+    fn new(args: &'a Args) -> anyhow::Result<Option<Self>> {
+        if args.watch.is_none() {
+            return Ok(None);
+        }
+        let previews_dir = Path::new(&args.out_dir).join("previews");
+        std::fs::create_dir_all(&previews_dir).context(format!(
+            "Could not create output dir: {}",
+            previews_dir.display()
+        ))?;
+        Ok(Some(WatchOutputs {
+            args,
+            previews_dir,
+            motion_vec: Vec::new(),
+        }))
+    }
+
+    /// Save the preview of an image registered with `motion`, and the motions file
+    /// with its motion in the frame of the full images.
+    fn push<T: CanRegister>(
+        &mut self,
+        img: &DMatrix<T>,
+        motion: &Vector6<f32>,
+        original_motion: Vector6<f32>,
+    ) -> anyhow::Result<()>
+    where
+        DMatrix<T>: ToImage,
+    {
+        let preview_path = self
+            .previews_dir
+            .join(format!("{}.png", self.motion_vec.len()));
+        registration::warp::<T, f32, T>(img, motion)
+            .to_image()
+            .save(&preview_path)
+            .context(format!("Failed to save {}", preview_path.display()))?;
+        self.motion_vec
+            .push(square_pixels(self.args, &original_motion));
+        let motions_path = Path::new(&self.args.out_dir).join("motions.txt");
+        motions::save(
+            &motions_path,
+            self.args.motions_convention,
+            &self.motion_vec,
+        )
+        .context(format!("Failed to save {}", motions_path.display()))
+    }
+}
+
+/// Images used for the registration of RGB images, with their number of channels:
+/// either all their channels with `--joint-channels`, or their gray conversion.
+fn registration_imgs<T: CanNormalize>(
+    args: &Args,
+    imgs: &[DMatrix<(T, T, T)>],
+) -> (Vec<DMatrix<T>>, usize) {
+    if args.joint_channels {
+        (split_channels(imgs), 3)
+    } else {
+        (gray_channel(args.gray, imgs), 1)
+    }
+}
+
+/// Convert RGB images into the gray images used for registration.
+fn gray_channel<T: CanNormalize>(
+    conversion: GrayConversion,
+    imgs: &[DMatrix<(T, T, T)>],
+) -> Vec<DMatrix<T>> {
+    imgs.iter().map(|im| to_gray(conversion, im)).collect()
+}
+
+/// Gray conversion of floating point RGB images, with 16 bits precision.
+fn gray_channel_u16(
+    conversion: GrayConversion,
+    imgs: &[DMatrix<(f32, f32, f32)>],
+) -> Vec<DMatrix<u16>> {
+    imgs.iter()
+        .map(|im| to_gray(conversion, &coerce::<_, (u16, u16, u16)>(im)))
+        .collect()
+}
+
+/// Warp an original image, plane by plane for Bayer mosaics,
+/// with its deformation or the lens distortion if any,
+/// after replacing its defective pixels.
+fn warp_original<U, V>(
+    args: &Args,
+    img: &DMatrix<U>,
+    motion: &Vector6<f32>,
+    deformation: Option<&Deformation>,
+) -> anyhow::Result<DMatrix<U>>
+where
+    U: Scalar + Copy + CanLinearInterpolate<V, U>,
+    V: Add<Output = V>,
+    f32: Mul<V, Output = V>,
+{
+    let inpainted;
+    let img = match &args.defects {
+        None => img,
+        Some(defects) => {
+            inpainted = defects::inpaint::<U, V>(defects, args.defects_step(), img)
+                .context("Failed to inpaint defective pixels")?;
+            &inpainted
+        }
+    };
+    Ok(match (args.bayer, deformation, &args.distortion) {
+        (Some(_), _, _) => bayer::warp_mosaic::<U, V>(img, motion),
+        (None, Some(deformation), _) => deformable::warp::<U, V, U>(img, motion, deformation),
+        (None, None, Some(distortion)) => distortion::warp::<U, V, U>(img, motion, distortion),
+        (None, None, None) => registration::warp::<U, V, U>(img, motion),
+    })
+}
+
+fn save_results<T: CanRegister, U, V>(
+    args: &Args,
+    writer: &ImgWriter,
+    output: pipeline::Output<T>,
+    original_imgs: &[DMatrix<U>],
+) -> anyhow::Result<Vec<Vector6<f32>>>
+where
+    DMatrix<T>: ToImage,
+    U: Scalar + Copy + CanLinearInterpolate<V, U> + NpyElement,
+    V: Add<Output = V>,
+    f32: Mul<V, Output = V>,
+    DMatrix<U>: ToImage,
 {
-    let img_count = 1 + other_paths.len();
-    log::info!("Loading {} images ...", img_count);
-    let pb = if log::log_enabled!(log::Level::Info) {
-        indicatif::ProgressBar::new(img_count as u64)
+    // Motion parameters in the frame of the full image and in the cropped frame.
+    let motion_vec = output.motion_vec;
+    let motion_vec_crop = output.registered.motion_vec;
+    let cropped_eq_imgs = output.registered.imgs;
+    if !output.registered.outliers.is_empty() {
+        let indices: Vec<String> = output
+            .registered
+            .outliers
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        log::warn!(
+            "Outlier images (starting at 0), with motions only estimated at the coarsest level: {}",
+            indices.join(", ")
+        );
+    }
+
+    if args.non_rigid_threshold > 0.0 {
+        warn_non_rigid(args, &cropped_eq_imgs, &motion_vec_crop);
+    }
+    let deformations: Vec<Deformation> = if args.deformable {
+        log::info!("Refining the registration with deformations ...");
+        let pipeline = args.pipeline();
+        deformable::refine(
+            &args.config,
+            &cropped_eq_imgs,
+            &motion_vec_crop,
+            args.deformable_spacing,
+            args.deformable_smoothness,
+        )
+        .iter()
+        .map(|deformation| pipeline.original_deformation(deformation))
+        .collect()
     } else {
-        indicatif::ProgressBar::hidden()
+        Vec::new()
     };
-    let mut imgs = Vec::with_capacity(img_count);
-    let img_mat = first_img.into_dmatrix();
-    let shape = img_mat.shape();
-    imgs.push(img_mat);
-    pb.inc(1);
-    for img_path in other_paths.iter() {
-        let rgb_img = image::open(img_path).context(format!(
-            "Failed to open image {}",
-            img_path.as_ref().display()
+
+    // All that follows is just to help debugging.
+
+    let out_dir_path = Path::new(&args.out_dir);
+
+    // Visualization of cropped and equalized images.
+    if args.save_crop {
+        log::info!("Saving cropped + equalized images ...");
+        let cropped_dir = out_dir_path.join("cropped");
+        // Linearized images are encoded back like the inputs.
+        let mut cropped_imgs = cropped_eq_imgs.clone();
+        transfer::encode(args.transfer, &mut cropped_imgs);
+        writer
+            .save_all(&cropped_dir, &cropped_imgs)
+            .context("Failed to save cropped images")?;
+
+        // Visualization of registered cropped images.
+        log::info!("Applying registration on cropped images ...");
+        let mut registered_cropped_imgs: Vec<DMatrix<T>> =
+            registration::reproject::<T, f32, T>(&cropped_eq_imgs, &motion_vec_crop);
+        transfer::encode(args.transfer, &mut registered_cropped_imgs);
+        let cropped_aligned_dir = &out_dir_path.join("cropped_aligned");
+        log::info!("Saving registered cropped images ...");
+        writer
+            .save_all(cropped_aligned_dir, &registered_cropped_imgs)
+            .context("Failed to save registered cropped images")?;
+    }
+
+    // Similarity to the first image, to gate on the quality of the registration.
+    if args.metrics {
+        let metrics = metrics::metrics(&cropped_eq_imgs, &motion_vec_crop);
+        print_metrics(&metrics);
+        std::fs::create_dir_all(out_dir_path).context(format!(
+            "Could not create output dir: {}",
+            out_dir_path.display()
+        ))?;
+        let metrics_path = out_dir_path.join("metrics.csv");
+        let mut lines = String::from(
+            "image,ncc_before,ssim_before,psnr_before,ncc_after,ssim_after,psnr_after\n",
+        );
+        for (i, m) in metrics.iter().enumerate() {
+            lines.push_str(&format!(
+                "{},{},{},{},{},{},{}\n",
+                i,
+                m.before.ncc,
+                m.before.ssim,
+                m.before.psnr,
+                m.after.ncc,
+                m.after.ssim,
+                m.after.psnr
+            ));
+        }
+        std::fs::write(&metrics_path, lines)
+            .context(format!("Failed to save {}", metrics_path.display()))?;
+    }
+
+    // Thumbnails before and after registration, to review large datasets.
+    if args.save_contact_sheet {
+        log::info!("Saving contact sheet ...");
+        std::fs::create_dir_all(out_dir_path).context(format!(
+            "Could not create output dir: {}",
+            out_dir_path.display()
         ))?;
-        imgs.push(rgb_img.into_dmatrix());
+        let sheet = viz::contact_sheet(
+            &cropped_eq_imgs,
+            &motion_vec_crop,
+            viz::CONTACT_SHEET_THUMBNAIL_SIZE,
+        );
+        let sheet_path = out_dir_path.join("contact_sheet.png");
+        sheet
+            .to_image()
+            .save(&sheet_path)
+            .context(format!("Failed to save {}", sheet_path.display()))?;
+    }
+
+    if let Some(animation) = args.save_animation {
+        save_animation::<U, V>(args, animation, original_imgs, &motion_vec, &deformations)?;
+    }
+    if args.save_checkerboard {
+        save_checkerboards::<U, V>(args, writer, original_imgs, &motion_vec, &deformations)?;
+    }
+
+    save_registered_imgs(args, writer, original_imgs, &motion_vec, &deformations)?;
+    Ok(motion_vec)
+}
+
+/// Save animated GIFs of the registered images, restricted to the crop frame.
+fn save_animation<U, V>(
+    args: &Args,
+    animation: Animation,
+    original_imgs: &[DMatrix<U>],
+    motion_vec: &[Vector6<f32>],
+    deformations: &[Deformation],
+) -> anyhow::Result<()>
+where
+    U: Scalar + Copy + CanLinearInterpolate<V, U>,
+    V: Add<Output = V>,
+    f32: Mul<V, Output = V>,
+    DMatrix<U>: ToImage,
+{
+    log::info!("Saving animation of registered images ...");
+    let frames: Vec<image::RgbaImage> = original_imgs
+        .iter()
+        .zip(motion_vec)
+        .enumerate()
+        .map(|(i, (img, motion))| {
+            let registered = warp_original::<U, V>(args, img, motion, deformations.get(i))?;
+            let registered = match args.crop {
+                None => registered,
+                Some(frame) => crop(frame, &registered)?,
+            };
+            Ok(registered.to_image().to_rgba8())
+        })
+        .collect::<anyhow::Result<_>>()?;
+    let out_dir_path = Path::new(&args.out_dir);
+    match animation {
+        Animation::Stack => {
+            std::fs::create_dir_all(out_dir_path).context(format!(
+                "Could not create output dir: {}",
+                out_dir_path.display()
+            ))?;
+            save_gif(&out_dir_path.join("registered.gif"), &frames)
+        }
+        Animation::Toggle => {
+            let toggle_dir = out_dir_path.join("toggle");
+            std::fs::create_dir_all(&toggle_dir).context(format!(
+                "Could not create output dir: {}",
+                toggle_dir.display()
+            ))?;
+            for (i, frame) in frames.iter().enumerate().skip(1) {
+                let pair = [frames[0].clone(), frame.clone()];
+                save_gif(&toggle_dir.join(format!("{}.gif", i)), &pair)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Save checkerboard composites of the first registered image and each registered image.
+fn save_checkerboards<U, V>(
+    args: &Args,
+    writer: &ImgWriter,
+    original_imgs: &[DMatrix<U>],
+    motion_vec: &[Vector6<f32>],
+    deformations: &[Deformation],
+) -> anyhow::Result<()>
+where
+    U: Scalar + Copy + CanLinearInterpolate<V, U>,
+    V: Add<Output = V>,
+    f32: Mul<V, Output = V>,
+    DMatrix<U>: ToImage,
+{
+    let (first_img, first_motion) = match original_imgs.first().zip(motion_vec.first()) {
+        None => return Ok(()),
+        Some(first) => first,
+    };
+    log::info!("Saving checkerboards of registered images ...");
+    let checkerboard_dir = Path::new(&args.out_dir).join("checkerboard");
+    let reference = warp_original::<U, V>(args, first_img, first_motion, deformations.first())?;
+    for (i, (img, motion)) in original_imgs.iter().zip(motion_vec).enumerate().skip(1) {
+        let registered = warp_original::<U, V>(args, img, motion, deformations.get(i))?;
+        let composite = viz::checkerboard(&reference, &registered, viz::CHECKERBOARD_TILE_SIZE);
+        writer.save(
+            args.img_format.path(&checkerboard_dir, i),
+            composite.to_image(),
+        );
+    }
+    Ok(())
+}
+
+/// Save frames as an animated GIF, looping forever.
+fn save_gif(path: &Path, frames: &[image::RgbaImage]) -> anyhow::Result<()> {
+    use image::codecs::gif::{GifEncoder, Repeat};
+    let file =
+        std::fs::File::create(path).context(format!("Failed to create {}", path.display()))?;
+    let mut encoder = GifEncoder::new(std::io::BufWriter::new(file));
+    encoder
+        .set_repeat(Repeat::Infinite)
+        .context(format!("Failed to save {}", path.display()))?;
+    let delay = image::Delay::from_numer_denom_ms(ANIMATION_DELAY_MS, 1);
+    let frames = frames
+        .iter()
+        .map(|frame| image::Frame::from_parts(frame.clone(), 0, 0, delay));
+    encoder
+        .encode_frames(frames)
+        .context(format!("Failed to save {}", path.display()))
+}
+
+/// Reproject (interpolation + extrapolation) images according to their motion,
+/// and write the registered images to the output directory.
+fn save_registered_imgs<U, V>(
+    args: &Args,
+    writer: &ImgWriter,
+    original_imgs: &[DMatrix<U>],
+    motion_vec: &[Vector6<f32>],
+    deformations: &[Deformation],
+) -> anyhow::Result<()>
+where
+    U: Scalar + Copy + CanLinearInterpolate<V, U> + NpyElement,
+    V: Add<Output = V>,
+    f32: Mul<V, Output = V>,
+    DMatrix<U>: ToImage,
+{
+    let out_dir_path = Path::new(&args.out_dir);
+    if args.save_imgs && !args.npy && !args.tiff_stack {
+        // Each image is saved while the next ones are warped.
+        log::info!("Applying registration on original images and saving them ...");
+        for (i, (img, motion)) in original_imgs.iter().zip(motion_vec).enumerate() {
+            let registered = warp_original::<U, V>(args, img, motion, deformations.get(i))?;
+            writer.save(args.img_format.path(out_dir_path, i), registered.to_image());
+        }
+    } else if args.save_imgs {
+        log::info!("Applying registration on original images ...");
+        let registered_imgs: Vec<DMatrix<U>> = original_imgs
+            .iter()
+            .zip(motion_vec)
+            .enumerate()
+            .map(|(i, (img, motion))| warp_original::<U, V>(args, img, motion, deformations.get(i)))
+            .collect::<anyhow::Result<_>>()?;
+        if args.npy {
+            log::info!("Saving registered images in a NumPy array ...");
+            std::fs::create_dir_all(out_dir_path).context(format!(
+                "Could not create output dir: {}",
+                out_dir_path.display()
+            ))?;
+            lowrr::io::npy::save_stack(out_dir_path.join("registered.npy"), &registered_imgs)
+                .context("Failed to save registered images")?;
+        } else {
+            log::info!("Saving registered images in a TIFF stack ...");
+            std::fs::create_dir_all(out_dir_path).context(format!(
+                "Could not create output dir: {}",
+                out_dir_path.display()
+            ))?;
+            lowrr::io::tiff::save_stack(
+                out_dir_path.join("registered.tif"),
+                &registered_imgs,
+                args.tiff_compression,
+            )
+            .context("Failed to save registered images")?;
+        }
+    }
+    Ok(())
+}
+
+/// Give all images of the dataset the same size, according to the size policy.
+fn unify_sizes(policy: SizePolicy, dataset: Dataset) -> anyhow::Result<Dataset> {
+    let unified = match dataset {
+        Dataset::GrayImages(imgs) => size::unify(policy, imgs).map(Dataset::GrayImages),
+        Dataset::GrayImagesU16(imgs) => size::unify(policy, imgs).map(Dataset::GrayImagesU16),
+        Dataset::RgbImages(imgs) => size::unify(policy, imgs).map(Dataset::RgbImages),
+        Dataset::RgbImagesU16(imgs) => size::unify(policy, imgs).map(Dataset::RgbImagesU16),
+        Dataset::GrayImagesF32(imgs) => size::unify(policy, imgs).map(Dataset::GrayImagesF32),
+        Dataset::RgbImagesF32(imgs) => size::unify(policy, imgs).map(Dataset::RgbImagesF32),
+    };
+    unified.context("Images do not all have the same size, see --size-policy")
+}
+
+enum Dataset {
+    GrayImages(Vec<DMatrix<u8>>),
+    GrayImagesU16(Vec<DMatrix<u16>>),
+    GrayImagesF32(Vec<DMatrix<f32>>),
+    RgbImages(Vec<DMatrix<(u8, u8, u8)>>),
+    RgbImagesU16(Vec<DMatrix<(u16, u16, u16)>>),
+    RgbImagesF32(Vec<DMatrix<(f32, f32, f32)>>),
+}
+
+impl Dataset {
+    /// Number of images.
+    fn len(&self) -> usize {
+        match self {
+            Dataset::GrayImages(imgs) => imgs.len(),
+            Dataset::GrayImagesU16(imgs) => imgs.len(),
+            Dataset::GrayImagesF32(imgs) => imgs.len(),
+            Dataset::RgbImages(imgs) => imgs.len(),
+            Dataset::RgbImagesU16(imgs) => imgs.len(),
+            Dataset::RgbImagesF32(imgs) => imgs.len(),
+        }
+    }
+
+    fn is_rgb(&self) -> bool {
+        matches!(
+            self,
+            Dataset::RgbImages(_) | Dataset::RgbImagesU16(_) | Dataset::RgbImagesF32(_)
+        )
+    }
+
+    /// Pixel depth of the images.
+    fn depth(&self) -> Depth {
+        match self {
+            Dataset::GrayImages(_) | Dataset::RgbImages(_) => Depth::U8,
+            Dataset::GrayImagesU16(_) | Dataset::RgbImagesU16(_) => Depth::U16,
+            Dataset::GrayImagesF32(_) | Dataset::RgbImagesF32(_) => Depth::F32,
+        }
+    }
+
+    /// (height, width) of the first image.
+    fn shape(&self) -> Option<(usize, usize)> {
+        match self {
+            Dataset::GrayImages(imgs) => imgs.first().map(|im| im.shape()),
+            Dataset::GrayImagesU16(imgs) => imgs.first().map(|im| im.shape()),
+            Dataset::GrayImagesF32(imgs) => imgs.first().map(|im| im.shape()),
+            Dataset::RgbImages(imgs) => imgs.first().map(|im| im.shape()),
+            Dataset::RgbImagesU16(imgs) => imgs.first().map(|im| im.shape()),
+            Dataset::RgbImagesF32(imgs) => imgs.first().map(|im| im.shape()),
+        }
+    }
+
+    /// Convert all images to the given pixel depth, and to RGB if `rgb` is set.
+    /// RGB images stay RGB whatever the value of `rgb`.
+    fn coerce(self, depth: Depth, rgb: bool) -> Dataset {
+        match self {
+            Dataset::GrayImages(imgs) => coerce_gray(&imgs, depth, rgb),
+            Dataset::GrayImagesU16(imgs) => coerce_gray(&imgs, depth, rgb),
+            Dataset::GrayImagesF32(imgs) => coerce_gray(&imgs, depth, rgb),
+            Dataset::RgbImages(imgs) => coerce_rgb(&imgs, depth),
+            Dataset::RgbImagesU16(imgs) => coerce_rgb(&imgs, depth),
+            Dataset::RgbImagesF32(imgs) => coerce_rgb(&imgs, depth),
+        }
+    }
+
+    /// Append the images of another dataset of the same type.
+    fn append(&mut self, other: Dataset) {
+        match (self, other) {
+            (Dataset::GrayImages(imgs), Dataset::GrayImages(more)) => imgs.extend(more),
+            (Dataset::GrayImagesU16(imgs), Dataset::GrayImagesU16(more)) => imgs.extend(more),
+            (Dataset::GrayImagesF32(imgs), Dataset::GrayImagesF32(more)) => imgs.extend(more),
+            (Dataset::RgbImages(imgs), Dataset::RgbImages(more)) => imgs.extend(more),
+            (Dataset::RgbImagesU16(imgs), Dataset::RgbImagesU16(more)) => imgs.extend(more),
+            (Dataset::RgbImagesF32(imgs), Dataset::RgbImagesF32(more)) => imgs.extend(more),
+            _ => unreachable!("Datasets were coerced to the same type"),
+        }
+    }
+}
+
+fn coerce_gray<T>(imgs: &[DMatrix<T>], depth: Depth, rgb: bool) -> Dataset
+where
+    T: Scalar + CoerceInto<u8> + CoerceInto<u16> + CoerceInto<f32>,
+    T: CoerceInto<(u8, u8, u8)> + CoerceInto<(u16, u16, u16)> + CoerceInto<(f32, f32, f32)>,
+{
+    match (depth, rgb) {
+        (_, true) => coerce_rgb(imgs, depth),
+        (Depth::U8, false) => Dataset::GrayImages(imgs.iter().map(coerce).collect()),
+        (Depth::U16, false) => Dataset::GrayImagesU16(imgs.iter().map(coerce).collect()),
+        (Depth::F32, false) => Dataset::GrayImagesF32(imgs.iter().map(coerce).collect()),
+    }
+}
+
+fn coerce_rgb<T>(imgs: &[DMatrix<T>], depth: Depth) -> Dataset
+where
+    T: Scalar + CoerceInto<(u8, u8, u8)> + CoerceInto<(u16, u16, u16)>,
+    T: CoerceInto<(f32, f32, f32)>,
+{
+    match depth {
+        Depth::U8 => Dataset::RgbImages(imgs.iter().map(coerce).collect()),
+        Depth::U16 => Dataset::RgbImagesU16(imgs.iter().map(coerce).collect()),
+        Depth::F32 => Dataset::RgbImagesF32(imgs.iter().map(coerce).collect()),
+    }
+}
+
+/// Load all images into memory,
+/// converting them to the same pixel depth if `coerce` is set.
+fn load_dataset<P: AsRef<Path>>(
+    paths: &[P],
+    npy_layout: lowrr::io::npy::Layout,
+    coerce: Option<Depth>,
+) -> anyhow::Result<(Dataset, (usize, usize))> {
+    log::info!("Images to be processed:");
+    for path in paths.iter() {
+        log::info!("    {}", path.as_ref().display());
+    }
+    if paths.iter().any(archive::is_archive) {
+        return load_archives(paths, coerce);
+    }
+    match coerce {
+        None => load_same_type(paths, npy_layout),
+        Some(depth) => load_coerced(paths, npy_layout, depth),
+    }
+}
+
+/// Load the images of zip or tar archives, decoded in memory,
+/// ordered by name in each archive.
+fn load_archives<P: AsRef<Path>>(
+    paths: &[P],
+    coerce: Option<Depth>,
+) -> anyhow::Result<(Dataset, (usize, usize))> {
+    let mut entries = Vec::new();
+    for path in paths.iter() {
+        let path = path.as_ref();
+        if !archive::is_archive(path) {
+            anyhow::bail!("{} cannot be loaded with archives", path.display());
+        }
+        let archive_entries = archive::read_entries(path)
+            .context(format!("Failed to read the archive {}", path.display()))?;
+        log::info!("    {} files in {}", archive_entries.len(), path.display());
+        entries.extend(archive_entries);
+    }
+    let files: Vec<ImageFile> = entries
+        .iter()
+        .map(|entry| ImageFile {
+            path: Path::new(&entry.name),
+            content: Some(entry.content.as_slice()),
+        })
+        .collect();
+    if files.is_empty() {
+        anyhow::bail!("The archives do not contain any image");
+    }
+    for file in files.iter() {
+        if file_type(file.path)? != "image" {
+            anyhow::bail!(
+                "Archives can only contain images, not {}",
+                file.path.display()
+            );
+        }
+    }
+    match coerce {
+        None => load_images(&files),
+        Some(depth) => {
+            let mut datasets = Vec::with_capacity(files.len());
+            for file in files.iter() {
+                datasets.push(load_images(std::slice::from_ref(file))?.0);
+            }
+            coerce_datasets(datasets, depth)
+        }
+    }
+}
+
+/// Load files one by one and convert them all to the same pixel depth.
+fn load_coerced<P: AsRef<Path>>(
+    paths: &[P],
+    npy_layout: lowrr::io::npy::Layout,
+    depth: Depth,
+) -> anyhow::Result<(Dataset, (usize, usize))> {
+    let mut datasets = Vec::with_capacity(paths.len());
+    for path in paths.iter() {
+        datasets.push(load_same_type(std::slice::from_ref(path), npy_layout)?.0);
+    }
+    coerce_datasets(datasets, depth)
+}
+
+/// Convert datasets to the same pixel depth and concatenate them.
+/// Gray images are converted to RGB if there is any RGB image.
+fn coerce_datasets(
+    datasets: Vec<Dataset>,
+    depth: Depth,
+) -> anyhow::Result<(Dataset, (usize, usize))> {
+    let rgb = datasets.iter().any(Dataset::is_rgb);
+    log::info!(
+        "Converting images to {} {:?}",
+        if rgb { "RGB" } else { "Gray" },
+        depth
+    );
+    let mut coerced = datasets.into_iter().map(|d| d.coerce(depth, rgb));
+    let mut dataset = coerced
+        .next()
+        .context("Something is wrong, I didn't find any image")?;
+    coerced.for_each(|more| dataset.append(more));
+    let (height, width) = dataset
+        .shape()
+        .context("The files do not contain any image")?;
+    Ok((dataset, (width, height)))
+}
+
+/// Load images that all have the same type.
+fn load_same_type<P: AsRef<Path>>(
+    paths: &[P],
+    npy_layout: lowrr::io::npy::Layout,
+) -> anyhow::Result<(Dataset, (usize, usize))> {
+    let mut images_types = Vec::with_capacity(paths.len());
+    for path in paths.iter() {
+        images_types.push(file_type(path.as_ref())?);
+    }
+
+    if images_types.is_empty() {
+        anyhow::bail!(
+            "Something is wrong, I didn't find any image. Use --help to know how to use this program."
+        )
+    } else if images_types.iter().all(|&t| t == "raw") {
+        unimplemented!("imread raw")
+    } else if images_types.iter().all(|&t| t == "dicom") {
+        log::info!("Images are DICOM slices, loaded as Gray u16");
+        let imgs = lowrr::io::dicom::read_series(paths)?;
+        let (height, width) = imgs[0].shape();
+        Ok((Dataset::GrayImagesU16(imgs), (width, height)))
+    } else if images_types.iter().all(|&t| t == "npy") {
+        load_npy(paths, npy_layout)
+    } else if images_types.iter().all(|&t| t == "image") {
+        let files: Vec<ImageFile> = paths
+            .iter()
+            .map(|path| ImageFile {
+                path: path.as_ref(),
+                content: None,
+            })
+            .collect();
+        load_images(&files)
+    } else {
+        anyhow::bail!(
+            "There is a mix of image types, use --coerce to convert them to the same type"
+        )
+    }
+}
+
+/// Type of a file, from its extension: raw, image, dicom or npy.
+fn file_type(path: &Path) -> anyhow::Result<&'static str> {
+    match path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+        .as_deref()
+    {
+        Some("nef") => Ok("raw"),
+        Some("png") => Ok("image"),
+        Some("jpg") => Ok("image"),
+        Some("jpeg") => Ok("image"),
+        Some("tif") => Ok("image"),
+        Some("tiff") => Ok("image"),
+        Some("dcm") => Ok("dicom"),
+        Some("npy") => Ok("npy"),
+        Some("npz") => Ok("npy"),
+        Some(ext) => anyhow::bail!("Unrecognized extension: {}", ext),
+        None => anyhow::bail!("Hum no extension for {}?", path.display()),
+    }
+}
+
+/// Image file to decode, on disk or already in memory, like the files of archives.
+struct ImageFile<'a> {
+    path: &'a Path,
+    /// Content of the file, read from the path if None.
+    content: Option<&'a [u8]>,
+}
+
+/// Load image files that all have the same type.
+fn load_images(files: &[ImageFile]) -> anyhow::Result<(Dataset, (usize, usize))> {
+    // Open the first image to figure out the image type.
+    let first_frames = open_frames(&files[0])?;
+    match &first_frames[0] {
+        DynamicImage::ImageLuma8(_) => {
+            log::info!("Images are of type Gray u8");
+            let (imgs, (height, width)) = load_all(first_frames, &files[1..])?;
+            Ok((Dataset::GrayImages(imgs), (width, height)))
+        }
+        DynamicImage::ImageLuma16(_) => {
+            log::info!("Images are of type Gray u16");
+            let (imgs, (height, width)) = load_all(first_frames, &files[1..])?;
+            Ok((Dataset::GrayImagesU16(imgs), (width, height)))
+        }
+        DynamicImage::ImageRgb8(_) => {
+            log::info!("Images are of type RGB (u8, u8, u8)");
+            let (imgs, (height, width)) = load_all(first_frames, &files[1..])?;
+            Ok((Dataset::RgbImages(imgs), (width, height)))
+        }
+        DynamicImage::ImageRgb16(_) => {
+            log::info!("Images are of type RGB (u16, u16, u16)");
+            let (imgs, (height, width)) = load_all(first_frames, &files[1..])?;
+            Ok((Dataset::RgbImagesU16(imgs), (width, height)))
+        }
+        _ => anyhow::bail!("Unsupported image type"),
+    }
+}
+
+/// Load and concatenate the image stacks of NumPy arrays.
+fn load_npy<P: AsRef<Path>>(
+    paths: &[P],
+    layout: lowrr::io::npy::Layout,
+) -> anyhow::Result<(Dataset, (usize, usize))> {
+    use lowrr::io::npy::NpyStack;
+    let mut stacks = Vec::with_capacity(paths.len());
+    for path in paths.iter() {
+        stacks.push(lowrr::io::npy::read_stack(path, layout)?);
+    }
+    let dataset = match stacks.remove(0) {
+        NpyStack::U8(mut imgs) => {
+            log::info!("Images are of type Gray u8");
+            for stack in stacks.into_iter() {
+                match stack {
+                    NpyStack::U8(more) => imgs.extend(more),
+                    NpyStack::U16(_) => anyhow::bail!("There is a mix of array types"),
+                }
+            }
+            Dataset::GrayImages(imgs)
+        }
+        NpyStack::U16(mut imgs) => {
+            log::info!("Images are of type Gray u16");
+            for stack in stacks.into_iter() {
+                match stack {
+                    NpyStack::U16(more) => imgs.extend(more),
+                    NpyStack::U8(_) => anyhow::bail!("There is a mix of array types"),
+                }
+            }
+            Dataset::GrayImagesU16(imgs)
+        }
+    };
+    let (height, width) = dataset
+        .shape()
+        .context("The arrays do not contain any image")?;
+    Ok((dataset, (width, height)))
+}
+
+#[allow(clippy::type_complexity)]
+fn load_all<Pixel, T: Scalar>(
+    first_frames: Vec<DynamicImage>,
+    other_files: &[ImageFile],
+) -> anyhow::Result<(Vec<DMatrix<T>>, (usize, usize))>
+where
+    DynamicImage: IntoDMatrix<Pixel, T>,
+{
+    let file_count = 1 + other_files.len();
+    log::info!("Loading {} image files ...", file_count);
+    let progress_events = ProgressEvents::new("load");
+    let pb = match progress_events {
+        None => verbosity::progress_bar(file_count as u64),
+        Some(_) => indicatif::ProgressBar::hidden(),
+    };
+    let report_loaded = |loaded: usize| {
+        if let Some(events) = &progress_events {
+            #[allow(clippy::cast_precision_loss)]
+            let fraction = loaded as f32 / file_count as f32;
+            events.emit(
+                fraction,
+                serde_json::json!({ "done": loaded, "total": file_count }),
+            );
+        }
+    };
+    let first_type = discriminant(&first_frames[0]);
+    let first_color = first_frames[0].color();
+    let mut imgs = Vec::with_capacity(first_frames.len() * file_count);
+    imgs.extend(first_frames.into_iter().map(|img| img.into_dmatrix()));
+    let shape = imgs[0].shape();
+    pb.inc(1);
+    report_loaded(1);
+    for (i, file) in other_files.iter().enumerate() {
+        let frames = open_frames(file)?;
+        if let Some(frame) = frames.iter().find(|f| discriminant(*f) != first_type) {
+            anyhow::bail!(
+                "{} is of type {:?} unlike the first image of type {:?}, use --coerce to convert them to the same type",
+                file.path.display(),
+                frame.color(),
+                first_color
+            )
+        }
+        imgs.extend(frames.into_iter().map(|img| img.into_dmatrix()));
         pb.inc(1);
+        report_loaded(i + 2);
     }
     pb.finish();
+    log::info!("Loaded {} images", imgs.len());
     Ok((imgs, shape))
 }
+
+/// Convert an image to a layout supported for registration,
+/// as documented in [lowrr::interop::into_supported_layout].
+fn supported_layout(path: &Path, img: DynamicImage) -> DynamicImage {
+    let color = img.color();
+    let img = into_supported_layout(img);
+    if img.color() != color {
+        log::debug!(
+            "Converted {} from {:?} to {:?}",
+            path.display(),
+            color,
+            img.color()
+        );
+    }
+    img
+}
+
+/// Open an image file and decode all the images it contains.
+/// TIFF files may contain multiple pages, all other formats contain only one image.
+fn open_frames(file: &ImageFile) -> anyhow::Result<Vec<DynamicImage>> {
+    let path = file.path;
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase());
+    let is_tiff = matches!(extension.as_deref(), Some("tif") | Some("tiff"));
+    if is_tiff {
+        let pages = match file.content {
+            None => lowrr::io::tiff::read_pages(path)?,
+            Some(content) => lowrr::io::tiff::read_pages_from_memory(content, path)?,
+        };
+        if pages.len() > 1 {
+            log::info!("    {} pages in {}", pages.len(), path.display());
+        }
+        Ok(pages
+            .into_iter()
+            .map(|p| supported_layout(path, p))
+            .collect())
+    } else {
+        let img = match file.content {
+            None => image::open(path),
+            Some(content) => image::ImageFormat::from_path(path)
+                .and_then(|format| image::load_from_memory_with_format(content, format)),
+        };
+        let img = img.context(format!("Failed to open image {}", path.display()))?;
+        Ok(vec![supported_layout(path, img)])
+    }
+}
@@ -0,0 +1,485 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! HTTP server of the `lowrr serve` subcommand,
+//! to back a web service with registration jobs instead of running the program per job.
+//!
+//! Each job has its own directory in the jobs directory, with the uploaded images,
+//! and once registered, the motions and the registered images.
+//! Responses are JSON objects, except downloaded files.
+//!
+//! - `POST /jobs` creates a job: `{"id": 3}`.
+//! - `PUT /jobs/<id>/images/<name>` uploads an encoded image (PNG, JPEG, TIFF, ...) as the body.
+//!   Images are registered in the order of their names.
+//! - `POST /jobs/<id>/start` starts the registration, with optional JSON parameters as the body:
+//!   `{"config": {"lambda": 1.5, "levels": 4}, "crop": "10%,10%,90%,90%", "equalize": 0.15, "scale": 0.5}`.
+//!   Missing fields of the config take their default value.
+//! - `GET /jobs/<id>` polls the state of the job:
+//!   `{"state": "running", "images": 12, "levels": 4, "completed_levels": 1}`.
+//! - `GET /jobs/<id>/motions` downloads the motions, one line per image in the lowrr convention.
+//! - `GET /jobs/<id>/files/<i>.png` downloads the registered image `i`.
+//! - `DELETE /jobs/<id>` removes the job and its files.
+
+use lowrr::img::crop::CropSpec;
+use lowrr::img::interpolation::CanLinearInterpolate;
+use lowrr::img::registration::{self, Config};
+use lowrr::interop::{Depth, ToImage};
+use lowrr::io::motions;
+use lowrr::pipeline::{self, Pipeline, StackParams, StackPixel};
+
+use anyhow::Context;
+use nalgebra::{DMatrix, Vector3};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::{Add, Mul};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::{load_dataset, Dataset};
+
+/// Largest accepted request body, in bytes.
+const MAX_BODY_SIZE: usize = 1 << 30;
+
+/// Parameters of a job, given as the JSON body of its start request.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct JobParams {
+    config: Config,
+    /// Working area, in the syntax of the --crop argument.
+    crop: Option<String>,
+    equalize: Option<f32>,
+    /// Scale of the estimation, in the syntax of the --estimate-scale argument.
+    scale: f32,
+}
+
+impl Default for JobParams {
+    fn default() -> Self {
+        JobParams {
+            config: Config::default(),
+            crop: None,
+            equalize: None,
+            scale: 1.0,
+        }
+    }
+}
+
+/// State of a job.
+#[derive(Debug, Clone)]
+enum State {
+    Uploading,
+    Running {
+        levels: usize,
+        completed_levels: usize,
+    },
+    Done,
+    Failed(String),
+}
+
+#[derive(Debug)]
+struct Job {
+    dir: PathBuf,
+    state: State,
+}
+
+/// Jobs of the server, shared by the connections and the registrations.
+#[derive(Debug, Default)]
+struct Jobs {
+    next_id: usize,
+    jobs: HashMap<usize, Job>,
+}
+
+type SharedJobs = Arc<Mutex<Jobs>>;
+
+/// Parsed HTTP request.
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+/// HTTP response.
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: u16, value: serde_json::Value) -> Self {
+        Response {
+            status,
+            content_type: "application/json",
+            body: value.to_string().into_bytes(),
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Response::json(status, json!({ "error": message }))
+    }
+
+    fn file(path: &Path, content_type: &'static str) -> Self {
+        match std::fs::read(path) {
+            Ok(body) => Response {
+                status: 200,
+                content_type,
+                body,
+            },
+            Err(_) => Response::error(404, "File not found"),
+        }
+    }
+}
+
+/// Start the server and handle requests until the program is stopped.
+pub fn serve(matches: &clap::ArgMatches) -> anyhow::Result<()> {
+    let address = matches.value_of("address").unwrap();
+    let jobs_dir = PathBuf::from(matches.value_of("jobs-dir").unwrap());
+    std::fs::create_dir_all(&jobs_dir)
+        .context(format!("Could not create jobs dir: {}", jobs_dir.display()))?;
+    let listener =
+        TcpListener::bind(address).context(format!("Failed to listen on {}", address))?;
+    log::warn!("Serving registration jobs on http://{}", address);
+    let jobs: SharedJobs = Arc::default();
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                log::warn!("Failed to accept a connection: {}", err);
+                continue;
+            }
+        };
+        let (jobs, jobs_dir) = (jobs.clone(), jobs_dir.clone());
+        std::thread::spawn(move || {
+            if let Err(err) = handle_connection(stream, &jobs, &jobs_dir) {
+                log::warn!("Failed to handle a request: {:#}", err);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Answer the single request of a connection.
+fn handle_connection(stream: TcpStream, jobs: &SharedJobs, jobs_dir: &Path) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let response = match read_request(&mut reader)? {
+        None => Response::error(400, "Malformed request"),
+        Some(request) => {
+            log::info!("{} {}", request.method, request.path);
+            route(&request, jobs, jobs_dir)
+        }
+    };
+    write_response(stream, &response)
+}
+
+/// Read a request, None if it is malformed.
+fn read_request<R: BufRead>(reader: &mut R) -> anyhow::Result<Option<Request>> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut words = line.split_whitespace();
+    let (method, path) = match (words.next(), words.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => return Ok(None),
+    };
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        let mut header = line.splitn(2, ':');
+        if let (Some(name), Some(value)) = (header.next(), header.next()) {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = match value.trim().parse() {
+                    Ok(length) if length <= MAX_BODY_SIZE => length,
+                    _ => return Ok(None),
+                };
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(Request { method, path, body }))
+}
+
+fn write_response(mut stream: TcpStream, response: &Response) -> anyhow::Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        404 => "Not Found",
+        409 => "Conflict",
+        _ => "Internal Server Error",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason,
+        response.content_type,
+        response.body.len()
+    )?;
+    stream.write_all(&response.body)?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn route(request: &Request, jobs: &SharedJobs, jobs_dir: &Path) -> Response {
+    let segments: Vec<&str> = request
+        .path
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect();
+    let job_id = |id: &str| id.parse::<usize>().ok();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("POST", ["jobs"]) => create_job(jobs, jobs_dir),
+        (method, ["jobs", id, rest @ ..]) => match job_id(id) {
+            None => Response::error(404, "Unknown job"),
+            Some(id) => match (method, rest) {
+                ("GET", []) => job_status(jobs, id),
+                ("DELETE", []) => delete_job(jobs, id),
+                ("PUT", ["images", name]) => upload_image(jobs, id, name, &request.body),
+                ("POST", ["start"]) => start_job(jobs, id, &request.body),
+                ("GET", ["motions"]) => download(jobs, id, "motions.txt", "text/plain"),
+                ("GET", ["files", name]) if valid_name(name) => {
+                    download(jobs, id, &format!("registered/{}", name), "image/png")
+                }
+                _ => Response::error(404, "Unknown endpoint"),
+            },
+        },
+        _ => Response::error(404, "Unknown endpoint"),
+    }
+}
+
+/// File names without any path component.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+}
+
+fn create_job(jobs: &SharedJobs, jobs_dir: &Path) -> Response {
+    let mut jobs = jobs.lock().unwrap();
+    let id = jobs.next_id;
+    let dir = jobs_dir.join(id.to_string());
+    if let Err(err) = std::fs::create_dir_all(dir.join("images")) {
+        return Response::error(500, &format!("Could not create the job dir: {}", err));
+    }
+    jobs.next_id += 1;
+    jobs.jobs.insert(
+        id,
+        Job {
+            dir,
+            state: State::Uploading,
+        },
+    );
+    Response::json(201, json!({ "id": id }))
+}
+
+fn job_status(jobs: &SharedJobs, id: usize) -> Response {
+    let jobs = jobs.lock().unwrap();
+    let job = match jobs.jobs.get(&id) {
+        None => return Response::error(404, "Unknown job"),
+        Some(job) => job,
+    };
+    let images = image_paths(&job.dir).map_or(0, |paths| paths.len());
+    let status = match &job.state {
+        State::Uploading => json!({ "state": "uploading", "images": images }),
+        State::Running {
+            levels,
+            completed_levels,
+        } => json!({
+            "state": "running",
+            "images": images,
+            "levels": levels,
+            "completed_levels": completed_levels,
+        }),
+        State::Done => json!({ "state": "done", "images": images }),
+        State::Failed(error) => json!({ "state": "failed", "images": images, "error": error }),
+    };
+    Response::json(200, status)
+}
+
+fn delete_job(jobs: &SharedJobs, id: usize) -> Response {
+    let mut jobs = jobs.lock().unwrap();
+    match jobs.jobs.get(&id) {
+        None => return Response::error(404, "Unknown job"),
+        Some(Job {
+            state: State::Running { .. },
+            ..
+        }) => return Response::error(409, "The job is running"),
+        Some(_) => {}
+    }
+    let job = jobs.jobs.remove(&id).expect("The job exists");
+    match std::fs::remove_dir_all(&job.dir) {
+        Ok(()) => Response::json(200, json!({ "id": id })),
+        Err(err) => Response::error(500, &format!("Could not remove the job dir: {}", err)),
+    }
+}
+
+fn upload_image(jobs: &SharedJobs, id: usize, name: &str, body: &[u8]) -> Response {
+    if !valid_name(name) {
+        return Response::error(400, "Invalid image name");
+    }
+    let jobs = jobs.lock().unwrap();
+    let job = match jobs.jobs.get(&id) {
+        None => return Response::error(404, "Unknown job"),
+        Some(job) => job,
+    };
+    if !matches!(job.state, State::Uploading) {
+        return Response::error(409, "The job was already started");
+    }
+    match std::fs::write(job.dir.join("images").join(name), body) {
+        Ok(()) => Response::json(201, json!({ "id": id, "image": name })),
+        Err(err) => Response::error(500, &format!("Could not save the image: {}", err)),
+    }
+}
+
+fn start_job(shared_jobs: &SharedJobs, id: usize, body: &[u8]) -> Response {
+    let params: JobParams = if body.iter().all(u8::is_ascii_whitespace) {
+        JobParams::default()
+    } else {
+        match serde_json::from_slice(body) {
+            Ok(params) => params,
+            Err(err) => return Response::error(400, &format!("Invalid parameters: {}", err)),
+        }
+    };
+    let mut jobs = shared_jobs.lock().unwrap();
+    let job = match jobs.jobs.get_mut(&id) {
+        None => return Response::error(404, "Unknown job"),
+        Some(job) => job,
+    };
+    if !matches!(job.state, State::Uploading) {
+        return Response::error(409, "The job was already started");
+    }
+    job.state = State::Running {
+        levels: params.config.levels,
+        completed_levels: 0,
+    };
+    let (dir, shared_jobs) = (job.dir.clone(), shared_jobs.clone());
+    std::thread::spawn(move || {
+        let set_state = |state| {
+            if let Some(job) = shared_jobs.lock().unwrap().jobs.get_mut(&id) {
+                job.state = state;
+            }
+        };
+        let on_level = |levels, completed_levels| {
+            set_state(State::Running {
+                levels,
+                completed_levels,
+            })
+        };
+        match run_job(&dir, &params, on_level) {
+            Ok(()) => set_state(State::Done),
+            Err(err) => {
+                log::warn!("Job {} failed: {:#}", id, err);
+                set_state(State::Failed(format!("{:#}", err)));
+            }
+        }
+    });
+    Response::json(200, json!({ "id": id, "state": "running" }))
+}
+
+fn download(jobs: &SharedJobs, id: usize, file: &str, content_type: &'static str) -> Response {
+    let jobs = jobs.lock().unwrap();
+    match jobs.jobs.get(&id) {
+        None => Response::error(404, "Unknown job"),
+        Some(Job {
+            state: State::Done,
+            dir,
+        }) => Response::file(&dir.join(file), content_type),
+        Some(_) => Response::error(409, "The job is not done"),
+    }
+}
+
+/// Uploaded images of a job, in the order of their names.
+fn image_paths(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut paths = std::fs::read_dir(dir.join("images"))?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    paths.sort();
+    Ok(paths)
+}
+
+/// Register the images of a job, and save the motions and the registered images in its directory.
+/// Images are registered with 16 bits precision.
+fn run_job(dir: &Path, params: &JobParams, on_level: impl Fn(usize, usize)) -> anyhow::Result<()> {
+    let paths = image_paths(dir).context("Failed to list the images of the job")?;
+    if paths.is_empty() {
+        anyhow::bail!("The job does not have any image");
+    }
+    let (dataset, _) = load_dataset(&paths, lowrr::io::npy::Layout::Nhw, Some(Depth::U16))?;
+    match dataset {
+        Dataset::GrayImagesU16(imgs) => register_job::<_, f32>(dir, &imgs, params, on_level),
+        Dataset::RgbImagesU16(imgs) => {
+            register_job::<_, Vector3<f32>>(dir, &imgs, params, on_level)
+        }
+        _ => unreachable!("Images are converted to 16 bits"),
+    }
+}
+
+fn register_job<T, V>(
+    dir: &Path,
+    imgs: &[DMatrix<T>],
+    params: &JobParams,
+    on_level: impl Fn(usize, usize),
+) -> anyhow::Result<()>
+where
+    T: StackPixel + CanLinearInterpolate<V, T>,
+    V: Add<Output = V>,
+    f32: Mul<V, Output = V>,
+    DMatrix<T>: ToImage,
+    DMatrix<T::Gray>: ToImage,
+{
+    let crop_spec = match &params.crop {
+        None => None,
+        Some(crop) => Some(CropSpec::try_from(crop.as_str())?),
+    };
+    let stack_params = StackParams {
+        config: params.config,
+        equalize: params.equalize,
+        crop: crop_spec,
+        halvings: pipeline::halvings(params.scale).map_err(anyhow::Error::msg)?,
+        ..StackParams::default()
+    };
+    let pipeline = Pipeline {
+        config: stack_params.config,
+        equalize: stack_params.equalize,
+        crop: match crop_spec {
+            Some(spec) => Some(spec.resolve(imgs[0].shape())?),
+            None => None,
+        },
+        halvings: stack_params.halvings,
+        bayer: None,
+    };
+    let levels = pipeline.registration_config().levels;
+    let (registration_imgs, channels) = T::registration_imgs(imgs, &stack_params);
+    let output = pipeline.register_previews(
+        registration_imgs,
+        channels,
+        T::sparse_diff_threshold(),
+        |preview| on_level(levels, levels - preview.level),
+    )?;
+    motions::save(
+        dir.join("motions.txt"),
+        motions::Convention::Lowrr,
+        &output.motion_vec,
+    )?;
+    let registered_dir = dir.join("registered");
+    std::fs::create_dir_all(&registered_dir)?;
+    for (i, (img, motion)) in imgs.iter().zip(&output.motion_vec).enumerate() {
+        let registered: DMatrix<T> = registration::warp::<T, V, T>(img, motion);
+        let path = registered_dir.join(format!("{}.png", i));
+        registered
+            .to_image()
+            .save(&path)
+            .context(format!("Failed to save {}", path.display()))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,12 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Generate the C header `include/lowrr.h` from the exported items of the crate.
+
+fn main() {
+    let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    println!("cargo:rerun-if-changed=src/lib.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    cbindgen::generate(&crate_dir)
+        .expect("Unable to generate the C header")
+        .write_to_file(format!("{}/include/lowrr.h", crate_dir));
+}
@@ -0,0 +1,290 @@
+/* SPDX-License-Identifier: MPL-2.0 */
+
+/*
+ * C interface of the lowrr registration.
+ *
+ * All images of a stack are passed as one contiguous buffer of `count` images
+ * of `width x height` pixels, in row-major order (image after image).
+ * Motions are buffers of `6 * count` floats.
+ * Every function returns a status code, LOWRR_OK (0) on success.
+ * Configurations must be initialized with lowrr_default_config(),
+ * which sets their version to LOWRR_CONFIG_VERSION.
+ */
+
+#ifndef LOWRR_H
+#define LOWRR_H
+
+/* Generated by cbindgen from src/lib.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define LOWRR_OK 0
+
+#define LOWRR_NULL_POINTER 1
+
+#define LOWRR_INVALID_ARGUMENT 2
+
+#define LOWRR_REGISTRATION_FAILED 3
+
+#define LOWRR_PANIC 4
+
+#define LOWRR_VERSION_MISMATCH 5
+
+/**
+ * Version of the layout of `LowrrConfig`.
+ */
+#define LOWRR_CONFIG_VERSION 2
+
+#define LOWRR_PRECISION_SINGLE 0
+
+#define LOWRR_PRECISION_DOUBLE 1
+
+#define LOWRR_SPARSE_THRESHOLD 0
+
+#define LOWRR_SPARSE_PERCENTILE 1
+
+#define LOWRR_SPARSE_GRID 2
+
+#define LOWRR_SPARSE_FAST 3
+
+#define LOWRR_NORMALIZATION_NONE 0
+
+#define LOWRR_NORMALIZATION_HISTOGRAM 1
+
+#define LOWRR_NORMALIZATION_CLAHE 2
+
+#define LOWRR_DATA_TERM_INTENSITY 0
+
+#define LOWRR_DATA_TERM_GRADIENT 1
+
+#define LOWRR_DATA_TERM_CENSUS 2
+
+#define LOWRR_DENOISE_NONE 0
+
+#define LOWRR_DENOISE_GAUSSIAN 1
+
+#define LOWRR_DENOISE_MEDIAN 2
+
+#define LOWRR_DENOISE_BILATERAL 3
+
+#define LOWRR_MODE_LOWRANK 0
+
+#define LOWRR_MODE_PAIRWISE 1
+
+#define LOWRR_MODE_SEQUENTIAL 2
+
+#define LOWRR_SVD_FULL 0
+
+#define LOWRR_SVD_INCREMENTAL 1
+
+#define LOWRR_SVD_LIBRARY_NALGEBRA 0
+
+#define LOWRR_SVD_LIBRARY_FAER 1
+
+#define LOWRR_SVD_LIBRARY_LAPACK 2
+
+#define LOWRR_COMPOSITIONAL_FORWARDS 0
+
+#define LOWRR_COMPOSITIONAL_INVERSE 1
+
+/**
+ * Configuration (parameters) of the registration algorithm.
+ */
+typedef struct LowrrConfig {
+  /**
+   * Version of the layout of this struct, `LOWRR_CONFIG_VERSION` of the header it was built with.
+   * It is set by `lowrr_default_config`.
+   */
+  uint32_t version;
+  float lambda;
+  float rho;
+  size_t max_iterations;
+  float threshold;
+  float sparse_ratio_threshold;
+  size_t levels;
+  uint32_t verbosity;
+  /**
+   * Precision of the optimization, `LOWRR_PRECISION_SINGLE` or `LOWRR_PRECISION_DOUBLE`.
+   */
+  uint32_t precision;
+  /**
+   * Selection of sparse pixels, one of the `LOWRR_SPARSE_*` constants.
+   */
+  uint32_t sparse_strategy;
+  float sparse_fraction;
+  /**
+   * Maximum number of pixels used at each level, 0 for no limit.
+   */
+  size_t pixel_budget;
+  /**
+   * Intensity normalization, one of the `LOWRR_NORMALIZATION_*` constants.
+   */
+  uint32_t normalization;
+  /**
+   * Images compared by the registration, one of the `LOWRR_DATA_TERM_*` constants.
+   */
+  uint32_t data_term;
+  /**
+   * Non-zero to estimate a gain and bias for each image.
+   */
+  uint32_t gain_bias;
+  /**
+   * Denoising of the images used by the registration, one of the `LOWRR_DENOISE_*` constants.
+   */
+  uint32_t denoise;
+  /**
+   * Number of levels denoised, starting from the original resolution.
+   */
+  size_t denoise_levels;
+  /**
+   * Weight of the smoothness prior on the motions of consecutive images, 0 to disable it.
+   */
+  float temporal_smoothness;
+  /**
+   * Robust z-score above which images are excluded as outliers, 0 to disable it.
+   */
+  float outlier_threshold;
+  /**
+   * Registration model, one of the `LOWRR_MODE_*` constants.
+   */
+  uint32_t mode;
+  /**
+   * Number of images registered together in overlapping chunks, 0 for all of them.
+   */
+  size_t chunk_size;
+  /**
+   * SVD of the low-rank approximation, `LOWRR_SVD_FULL` or `LOWRR_SVD_INCREMENTAL`.
+   */
+  uint32_t svd_backend;
+  /**
+   * Non-zero to store the registered images as 16 bits integers.
+   */
+  uint32_t pack_observations;
+  /**
+   * Maximum displacement of the image corners under which iterations stop, 0 to disable it.
+   */
+  float motion_threshold;
+  /**
+   * Aspect ratio (width divided by height) of the pixels, 1 for square pixels.
+   */
+  float pixel_aspect_ratio;
+  /**
+   * Maximum intensity of the images, 0 to detect it from their pixels.
+   */
+  float input_max;
+  /**
+   * Non-zero to estimate the sparse errors of the images (default), zero for a purely low-rank model.
+   */
+  uint32_t do_image_correction;
+  /**
+   * Levenberg-Marquardt damping of the Gauss-Newton steps, 0 to disable it.
+   */
+  float damping;
+  /**
+   * Non-zero for a backtracking line search on the Gauss-Newton steps.
+   */
+  uint32_t line_search;
+  /**
+   * Maximum displacement of the image centers in pixels, 0 for no limit.
+   */
+  float max_translation;
+  /**
+   * Maximum deviation of the linear coefficients of the motions from the identity, 0 for no limit.
+   */
+  float max_deformation;
+  /**
+   * Gauss-Newton steps, `LOWRR_COMPOSITIONAL_FORWARDS` or `LOWRR_COMPOSITIONAL_INVERSE`.
+   */
+  uint32_t compositional;
+  /**
+   * Library computing the SVDs, one of the `LOWRR_SVD_LIBRARY_*` constants.
+   */
+  uint32_t svd_library;
+} LowrrConfig;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Default configuration, the same as the command line program.
+ */
+LowrrConfig lowrr_default_config(void);
+
+/**
+ * Static description of a status code.
+ */
+const char *lowrr_status_message(int32_t status);
+
+/**
+ * Register a stack of 8 bits gray images.
+ *
+ * # Safety
+ *
+ * `images` must point to `count * width * height` bytes,
+ * and `motions_out` to `6 * count` writable floats.
+ * If `config` is null, the default configuration is used,
+ * otherwise it must come from `lowrr_default_config` of the same header.
+ */
+int32_t lowrr_register_u8(const uint8_t *images,
+                          size_t count,
+                          size_t width,
+                          size_t height,
+                          const LowrrConfig *config,
+                          float *motions_out);
+
+/**
+ * Register a stack of 16 bits gray images.
+ *
+ * # Safety
+ *
+ * `images` must point to `count * width * height` u16 values,
+ * and `motions_out` to `6 * count` writable floats.
+ * If `config` is null, the default configuration is used,
+ * otherwise it must come from `lowrr_default_config` of the same header.
+ */
+int32_t lowrr_register_u16(const uint16_t *images,
+                           size_t count,
+                           size_t width,
+                           size_t height,
+                           const LowrrConfig *config,
+                           float *motions_out);
+
+/**
+ * Warp a stack of 8 bits gray images with their motions.
+ *
+ * # Safety
+ *
+ * `images` and `registered_out` must both point to `count * width * height` bytes,
+ * and `motions` to `6 * count` floats.
+ */
+int32_t lowrr_apply_motion_u8(const uint8_t *images,
+                              size_t count,
+                              size_t width,
+                              size_t height,
+                              const float *motions,
+                              uint8_t *registered_out);
+
+/**
+ * Warp a stack of 16 bits gray images with their motions.
+ *
+ * # Safety
+ *
+ * `images` and `registered_out` must both point to `count * width * height` u16 values,
+ * and `motions` to `6 * count` floats.
+ */
+int32_t lowrr_apply_motion_u16(const uint16_t *images,
+                               size_t count,
+                               size_t width,
+                               size_t height,
+                               const float *motions,
+                               uint16_t *registered_out);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* LOWRR_H */
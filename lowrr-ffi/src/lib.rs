@@ -0,0 +1,502 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! C interface of the lowrr registration.
+//!
+//! All images of a stack are passed as one contiguous buffer of `count` images
+//! of `width x height` pixels, in row-major order (image after image).
+//! Motions are buffers of `6 * count` floats.
+//! Every function returns a status code, `LOWRR_OK` (0) on success.
+//! The corresponding C header `include/lowrr.h` is generated by cbindgen when building the crate.
+//!
+//! Configurations start with their version, `LOWRR_CONFIG_VERSION`,
+//! which changes whenever fields are added to `LowrrConfig`.
+//! A configuration of another version is rejected with `LOWRR_VERSION_MISMATCH`,
+//! instead of being read past the end of the struct of an older header.
+
+use nalgebra::{DMatrix, Scalar, Vector6};
+use std::os::raw::c_char;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use lowrr::img::interpolation::CanLinearInterpolate;
+use lowrr::img::registration::{self, CanRegister};
+use lowrr::img::view::ImageView;
+use lowrr::interop::ToImage;
+
+pub const LOWRR_OK: i32 = 0;
+pub const LOWRR_NULL_POINTER: i32 = 1;
+pub const LOWRR_INVALID_ARGUMENT: i32 = 2;
+pub const LOWRR_REGISTRATION_FAILED: i32 = 3;
+pub const LOWRR_PANIC: i32 = 4;
+pub const LOWRR_VERSION_MISMATCH: i32 = 5;
+
+/// Version of the layout of `LowrrConfig`.
+pub const LOWRR_CONFIG_VERSION: u32 = 2;
+
+pub const LOWRR_PRECISION_SINGLE: u32 = 0;
+pub const LOWRR_PRECISION_DOUBLE: u32 = 1;
+
+pub const LOWRR_SPARSE_THRESHOLD: u32 = 0;
+pub const LOWRR_SPARSE_PERCENTILE: u32 = 1;
+pub const LOWRR_SPARSE_GRID: u32 = 2;
+pub const LOWRR_SPARSE_FAST: u32 = 3;
+
+pub const LOWRR_NORMALIZATION_NONE: u32 = 0;
+pub const LOWRR_NORMALIZATION_HISTOGRAM: u32 = 1;
+pub const LOWRR_NORMALIZATION_CLAHE: u32 = 2;
+
+pub const LOWRR_DATA_TERM_INTENSITY: u32 = 0;
+pub const LOWRR_DATA_TERM_GRADIENT: u32 = 1;
+pub const LOWRR_DATA_TERM_CENSUS: u32 = 2;
+
+pub const LOWRR_DENOISE_NONE: u32 = 0;
+pub const LOWRR_DENOISE_GAUSSIAN: u32 = 1;
+pub const LOWRR_DENOISE_MEDIAN: u32 = 2;
+pub const LOWRR_DENOISE_BILATERAL: u32 = 3;
+
+pub const LOWRR_MODE_LOWRANK: u32 = 0;
+pub const LOWRR_MODE_PAIRWISE: u32 = 1;
+pub const LOWRR_MODE_SEQUENTIAL: u32 = 2;
+
+pub const LOWRR_SVD_FULL: u32 = 0;
+pub const LOWRR_SVD_INCREMENTAL: u32 = 1;
+
+pub const LOWRR_SVD_LIBRARY_NALGEBRA: u32 = 0;
+pub const LOWRR_SVD_LIBRARY_FAER: u32 = 1;
+pub const LOWRR_SVD_LIBRARY_LAPACK: u32 = 2;
+
+pub const LOWRR_COMPOSITIONAL_FORWARDS: u32 = 0;
+pub const LOWRR_COMPOSITIONAL_INVERSE: u32 = 1;
+
+/// Configuration (parameters) of the registration algorithm.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct LowrrConfig {
+    /// Version of the layout of this struct, `LOWRR_CONFIG_VERSION` of the header it was built with.
+    /// It is set by `lowrr_default_config`.
+    pub version: u32,
+    pub lambda: f32,
+    pub rho: f32,
+    pub max_iterations: usize,
+    pub threshold: f32,
+    pub sparse_ratio_threshold: f32,
+    pub levels: usize,
+    pub verbosity: u32,
+    /// Precision of the optimization, `LOWRR_PRECISION_SINGLE` or `LOWRR_PRECISION_DOUBLE`.
+    pub precision: u32,
+    /// Selection of sparse pixels, one of the `LOWRR_SPARSE_*` constants.
+    pub sparse_strategy: u32,
+    pub sparse_fraction: f32,
+    /// Maximum number of pixels used at each level, 0 for no limit.
+    pub pixel_budget: usize,
+    /// Intensity normalization, one of the `LOWRR_NORMALIZATION_*` constants.
+    pub normalization: u32,
+    /// Images compared by the registration, one of the `LOWRR_DATA_TERM_*` constants.
+    pub data_term: u32,
+    /// Non-zero to estimate a gain and bias for each image.
+    pub gain_bias: u32,
+    /// Denoising of the images used by the registration, one of the `LOWRR_DENOISE_*` constants.
+    pub denoise: u32,
+    /// Number of levels denoised, starting from the original resolution.
+    pub denoise_levels: usize,
+    /// Weight of the smoothness prior on the motions of consecutive images, 0 to disable it.
+    pub temporal_smoothness: f32,
+    /// Robust z-score above which images are excluded as outliers, 0 to disable it.
+    pub outlier_threshold: f32,
+    /// Registration model, one of the `LOWRR_MODE_*` constants.
+    pub mode: u32,
+    /// Number of images registered together in overlapping chunks, 0 for all of them.
+    pub chunk_size: usize,
+    /// SVD of the low-rank approximation, `LOWRR_SVD_FULL` or `LOWRR_SVD_INCREMENTAL`.
+    pub svd_backend: u32,
+    /// Non-zero to store the registered images as 16 bits integers.
+    pub pack_observations: u32,
+    /// Maximum displacement of the image corners under which iterations stop, 0 to disable it.
+    pub motion_threshold: f32,
+    /// Aspect ratio (width divided by height) of the pixels, 1 for square pixels.
+    pub pixel_aspect_ratio: f32,
+    /// Maximum intensity of the images, 0 to detect it from their pixels.
+    pub input_max: f32,
+    /// Non-zero to estimate the sparse errors of the images (default), zero for a purely low-rank model.
+    pub do_image_correction: u32,
+    /// Levenberg-Marquardt damping of the Gauss-Newton steps, 0 to disable it.
+    pub damping: f32,
+    /// Non-zero for a backtracking line search on the Gauss-Newton steps.
+    pub line_search: u32,
+    /// Maximum displacement of the image centers in pixels, 0 for no limit.
+    pub max_translation: f32,
+    /// Maximum deviation of the linear coefficients of the motions from the identity, 0 for no limit.
+    pub max_deformation: f32,
+    /// Gauss-Newton steps, `LOWRR_COMPOSITIONAL_FORWARDS` or `LOWRR_COMPOSITIONAL_INVERSE`.
+    pub compositional: u32,
+    /// Library computing the SVDs, one of the `LOWRR_SVD_LIBRARY_*` constants.
+    pub svd_library: u32,
+}
+
+impl From<LowrrConfig> for registration::Config {
+    fn from(c: LowrrConfig) -> Self {
+        registration::Config {
+            lambda: c.lambda,
+            rho: c.rho,
+            max_iterations: c.max_iterations,
+            threshold: c.threshold,
+            sparse_ratio_threshold: c.sparse_ratio_threshold,
+            levels: c.levels,
+            mode: match c.mode {
+                LOWRR_MODE_PAIRWISE => registration::Mode::Pairwise,
+                LOWRR_MODE_SEQUENTIAL => registration::Mode::Sequential,
+                _ => registration::Mode::LowRank,
+            },
+            verbosity: c.verbosity,
+            precision: match c.precision {
+                LOWRR_PRECISION_DOUBLE => registration::Precision::Double,
+                _ => registration::Precision::Single,
+            },
+            svd_backend: match c.svd_backend {
+                LOWRR_SVD_INCREMENTAL => registration::Svd::Incremental,
+                _ => registration::Svd::Full,
+            },
+            svd_library: match c.svd_library {
+                LOWRR_SVD_LIBRARY_FAER => registration::SvdLibrary::Faer,
+                LOWRR_SVD_LIBRARY_LAPACK => registration::SvdLibrary::Lapack,
+                _ => registration::SvdLibrary::Nalgebra,
+            },
+            pack_observations: c.pack_observations != 0,
+            motion_threshold: c.motion_threshold,
+            sparse_strategy: match c.sparse_strategy {
+                LOWRR_SPARSE_PERCENTILE => registration::SparseStrategy::Percentile,
+                LOWRR_SPARSE_GRID => registration::SparseStrategy::Grid,
+                LOWRR_SPARSE_FAST => registration::SparseStrategy::Fast,
+                _ => registration::SparseStrategy::Threshold,
+            },
+            sparse_fraction: c.sparse_fraction,
+            pixel_budget: c.pixel_budget,
+            normalization: match c.normalization {
+                LOWRR_NORMALIZATION_HISTOGRAM => registration::Normalization::Histogram,
+                LOWRR_NORMALIZATION_CLAHE => registration::Normalization::Clahe,
+                _ => registration::Normalization::None,
+            },
+            data_term: match c.data_term {
+                LOWRR_DATA_TERM_GRADIENT => registration::DataTerm::Gradient,
+                LOWRR_DATA_TERM_CENSUS => registration::DataTerm::Census,
+                _ => registration::DataTerm::Intensity,
+            },
+            gain_bias: c.gain_bias != 0,
+            denoise: match c.denoise {
+                LOWRR_DENOISE_GAUSSIAN => registration::Denoise::Gaussian,
+                LOWRR_DENOISE_MEDIAN => registration::Denoise::Median,
+                LOWRR_DENOISE_BILATERAL => registration::Denoise::Bilateral,
+                _ => registration::Denoise::None,
+            },
+            denoise_levels: c.denoise_levels,
+            temporal_smoothness: c.temporal_smoothness,
+            outlier_threshold: c.outlier_threshold,
+            chunk_size: c.chunk_size,
+            pixel_aspect_ratio: c.pixel_aspect_ratio,
+            input_max: c.input_max,
+            do_image_correction: c.do_image_correction != 0,
+            damping: c.damping,
+            line_search: c.line_search != 0,
+            max_translation: c.max_translation,
+            max_deformation: c.max_deformation,
+            compositional: match c.compositional {
+                LOWRR_COMPOSITIONAL_INVERSE => registration::Compositional::Inverse,
+                _ => registration::Compositional::Forwards,
+            },
+            profile: false,
+        }
+    }
+}
+
+impl From<registration::Config> for LowrrConfig {
+    fn from(c: registration::Config) -> Self {
+        LowrrConfig {
+            version: LOWRR_CONFIG_VERSION,
+            lambda: c.lambda,
+            rho: c.rho,
+            max_iterations: c.max_iterations,
+            threshold: c.threshold,
+            sparse_ratio_threshold: c.sparse_ratio_threshold,
+            levels: c.levels,
+            verbosity: c.verbosity,
+            precision: match c.precision {
+                registration::Precision::Single => LOWRR_PRECISION_SINGLE,
+                registration::Precision::Double => LOWRR_PRECISION_DOUBLE,
+            },
+            sparse_strategy: match c.sparse_strategy {
+                registration::SparseStrategy::Threshold => LOWRR_SPARSE_THRESHOLD,
+                registration::SparseStrategy::Percentile => LOWRR_SPARSE_PERCENTILE,
+                registration::SparseStrategy::Grid => LOWRR_SPARSE_GRID,
+                registration::SparseStrategy::Fast => LOWRR_SPARSE_FAST,
+            },
+            sparse_fraction: c.sparse_fraction,
+            pixel_budget: c.pixel_budget,
+            normalization: match c.normalization {
+                registration::Normalization::None => LOWRR_NORMALIZATION_NONE,
+                registration::Normalization::Histogram => LOWRR_NORMALIZATION_HISTOGRAM,
+                registration::Normalization::Clahe => LOWRR_NORMALIZATION_CLAHE,
+            },
+            data_term: match c.data_term {
+                registration::DataTerm::Intensity => LOWRR_DATA_TERM_INTENSITY,
+                registration::DataTerm::Gradient => LOWRR_DATA_TERM_GRADIENT,
+                registration::DataTerm::Census => LOWRR_DATA_TERM_CENSUS,
+            },
+            gain_bias: c.gain_bias as u32,
+            denoise: match c.denoise {
+                registration::Denoise::None => LOWRR_DENOISE_NONE,
+                registration::Denoise::Gaussian => LOWRR_DENOISE_GAUSSIAN,
+                registration::Denoise::Median => LOWRR_DENOISE_MEDIAN,
+                registration::Denoise::Bilateral => LOWRR_DENOISE_BILATERAL,
+            },
+            denoise_levels: c.denoise_levels,
+            temporal_smoothness: c.temporal_smoothness,
+            outlier_threshold: c.outlier_threshold,
+            mode: match c.mode {
+                registration::Mode::LowRank => LOWRR_MODE_LOWRANK,
+                registration::Mode::Pairwise => LOWRR_MODE_PAIRWISE,
+                registration::Mode::Sequential => LOWRR_MODE_SEQUENTIAL,
+            },
+            chunk_size: c.chunk_size,
+            svd_backend: match c.svd_backend {
+                registration::Svd::Full => LOWRR_SVD_FULL,
+                registration::Svd::Incremental => LOWRR_SVD_INCREMENTAL,
+            },
+            pack_observations: c.pack_observations as u32,
+            motion_threshold: c.motion_threshold,
+            pixel_aspect_ratio: c.pixel_aspect_ratio,
+            input_max: c.input_max,
+            do_image_correction: c.do_image_correction as u32,
+            damping: c.damping,
+            line_search: c.line_search as u32,
+            max_translation: c.max_translation,
+            max_deformation: c.max_deformation,
+            compositional: match c.compositional {
+                registration::Compositional::Forwards => LOWRR_COMPOSITIONAL_FORWARDS,
+                registration::Compositional::Inverse => LOWRR_COMPOSITIONAL_INVERSE,
+            },
+            svd_library: match c.svd_library {
+                registration::SvdLibrary::Nalgebra => LOWRR_SVD_LIBRARY_NALGEBRA,
+                registration::SvdLibrary::Faer => LOWRR_SVD_LIBRARY_FAER,
+                registration::SvdLibrary::Lapack => LOWRR_SVD_LIBRARY_LAPACK,
+            },
+        }
+    }
+}
+
+/// Default configuration, the same as the command line program.
+#[no_mangle]
+pub extern "C" fn lowrr_default_config() -> LowrrConfig {
+    registration::Config::default().into()
+}
+
+/// Static description of a status code.
+#[no_mangle]
+pub extern "C" fn lowrr_status_message(status: i32) -> *const c_char {
+    let msg: &'static [u8] = match status {
+        LOWRR_OK => b"Success\0",
+        LOWRR_NULL_POINTER => b"Null pointer argument\0",
+        LOWRR_INVALID_ARGUMENT => b"Invalid argument\0",
+        LOWRR_REGISTRATION_FAILED => b"Registration failed\0",
+        LOWRR_PANIC => b"Unexpected internal error\0",
+        LOWRR_VERSION_MISMATCH => b"Configuration built with another version of lowrr.h\0",
+        _ => b"Unknown status code\0",
+    };
+    msg.as_ptr() as *const c_char
+}
+
+/// Register a stack of 8 bits gray images.
+///
+/// # Safety
+///
+/// `images` must point to `count * width * height` bytes,
+/// and `motions_out` to `6 * count` writable floats.
+/// If `config` is null, the default configuration is used,
+/// otherwise it must come from `lowrr_default_config` of the same header.
+#[no_mangle]
+pub unsafe extern "C" fn lowrr_register_u8(
+    images: *const u8,
+    count: usize,
+    width: usize,
+    height: usize,
+    config: *const LowrrConfig,
+    motions_out: *mut f32,
+) -> i32 {
+    register(images, count, width, height, config, motions_out, 40)
+}
+
+/// Register a stack of 16 bits gray images.
+///
+/// # Safety
+///
+/// `images` must point to `count * width * height` u16 values,
+/// and `motions_out` to `6 * count` writable floats.
+/// If `config` is null, the default configuration is used,
+/// otherwise it must come from `lowrr_default_config` of the same header.
+#[no_mangle]
+pub unsafe extern "C" fn lowrr_register_u16(
+    images: *const u16,
+    count: usize,
+    width: usize,
+    height: usize,
+    config: *const LowrrConfig,
+    motions_out: *mut f32,
+) -> i32 {
+    register(images, count, width, height, config, motions_out, 10 * 256)
+}
+
+/// Warp a stack of 8 bits gray images with their motions.
+///
+/// # Safety
+///
+/// `images` and `registered_out` must both point to `count * width * height` bytes,
+/// and `motions` to `6 * count` floats.
+#[no_mangle]
+pub unsafe extern "C" fn lowrr_apply_motion_u8(
+    images: *const u8,
+    count: usize,
+    width: usize,
+    height: usize,
+    motions: *const f32,
+    registered_out: *mut u8,
+) -> i32 {
+    apply_motion(images, count, width, height, motions, registered_out)
+}
+
+/// Warp a stack of 16 bits gray images with their motions.
+///
+/// # Safety
+///
+/// `images` and `registered_out` must both point to `count * width * height` u16 values,
+/// and `motions` to `6 * count` floats.
+#[no_mangle]
+pub unsafe extern "C" fn lowrr_apply_motion_u16(
+    images: *const u16,
+    count: usize,
+    width: usize,
+    height: usize,
+    motions: *const f32,
+    registered_out: *mut u16,
+) -> i32 {
+    apply_motion(images, count, width, height, motions, registered_out)
+}
+
+// Helpers ###########################################################
+
+unsafe fn register<T: CanRegister>(
+    images: *const T,
+    count: usize,
+    width: usize,
+    height: usize,
+    config: *const LowrrConfig,
+    motions_out: *mut f32,
+    sparse_diff_threshold: T::Bigger,
+) -> i32
+where
+    DMatrix<T>: ToImage,
+{
+    if images.is_null() || motions_out.is_null() {
+        return LOWRR_NULL_POINTER;
+    }
+    let (size, motions_size) = match buffer_sizes::<T>(count, width, height) {
+        Some(sizes) => sizes,
+        None => return LOWRR_INVALID_ARGUMENT,
+    };
+    let config = if config.is_null() {
+        lowrr_default_config()
+    } else if (*config).version != LOWRR_CONFIG_VERSION {
+        // Only the version is read, the struct of another header may be shorter.
+        return LOWRR_VERSION_MISMATCH;
+    } else {
+        *config
+    };
+    let images = std::slice::from_raw_parts(images, size);
+    let motions_out = std::slice::from_raw_parts_mut(motions_out, motions_size);
+    guard(move || {
+        let imgs = to_matrices(images, count, width, height);
+        match registration::gray_affine(config.into(), imgs, sparse_diff_threshold) {
+            Ok((motion_vec, _)) => {
+                let flat = motion_vec.iter().flat_map(|m| m.iter());
+                motions_out.iter_mut().zip(flat).for_each(|(o, x)| *o = *x);
+                LOWRR_OK
+            }
+            Err(err) => {
+                log::error!("{}", err);
+                LOWRR_REGISTRATION_FAILED
+            }
+        }
+    })
+}
+
+unsafe fn apply_motion<T>(
+    images: *const T,
+    count: usize,
+    width: usize,
+    height: usize,
+    motions: *const f32,
+    registered_out: *mut T,
+) -> i32
+where
+    T: Scalar + Copy + CanLinearInterpolate<f32, T>,
+{
+    if images.is_null() || motions.is_null() || registered_out.is_null() {
+        return LOWRR_NULL_POINTER;
+    }
+    let (size, motions_size) = match buffer_sizes::<T>(count, width, height) {
+        Some(sizes) => sizes,
+        None => return LOWRR_INVALID_ARGUMENT,
+    };
+    let images = std::slice::from_raw_parts(images, size);
+    let motions = std::slice::from_raw_parts(motions, motions_size);
+    let registered_out = std::slice::from_raw_parts_mut(registered_out, size);
+    guard(move || {
+        let pixels_count = width * height;
+        let images = images.chunks_exact(pixels_count);
+        let outputs = registered_out.chunks_exact_mut(pixels_count);
+        for ((img, motion), out) in images.zip(motions.chunks_exact(6)).zip(outputs) {
+            // Read the row major input directly, without converting it into a matrix.
+            let view = ImageView::from_row_major(img, width, height);
+            let motion = Vector6::from_row_slice(motion);
+            let registered: DMatrix<T> = registration::warp_view(view, &motion);
+            // DMatrix is column-major, transpose to write in row-major order.
+            out.copy_from_slice(registered.transpose().as_slice());
+        }
+        LOWRR_OK
+    })
+}
+
+/// Number of pixels and of motion parameters of a stack of `count` images,
+/// none if the stack is empty or if its buffers would not fit in memory.
+fn buffer_sizes<T>(count: usize, width: usize, height: usize) -> Option<(usize, usize)> {
+    if count == 0 || width == 0 || height == 0 {
+        return None;
+    }
+    let size = count.checked_mul(width)?.checked_mul(height)?;
+    let motions_size = count.checked_mul(6)?;
+    // Slices are limited to isize::MAX bytes.
+    let bytes = size.checked_mul(std::mem::size_of::<T>())?;
+    let motions_bytes = motions_size.checked_mul(std::mem::size_of::<f32>())?;
+    if bytes > isize::MAX as usize || motions_bytes > isize::MAX as usize {
+        return None;
+    }
+    Some((size, motions_size))
+}
+
+/// Split a contiguous row-major buffer into images.
+fn to_matrices<T: Scalar + Copy>(
+    buffer: &[T],
+    count: usize,
+    width: usize,
+    height: usize,
+) -> Vec<DMatrix<T>> {
+    buffer
+        .chunks_exact(width * height)
+        .take(count)
+        .map(|pixels| DMatrix::from_row_slice(height, width, pixels))
+        .collect()
+}
+
+/// Prevent panics from unwinding across the C boundary.
+/// Output buffers may be partially written if a panic occurs,
+/// but the status code tells the caller not to use them.
+fn guard<F: FnOnce() -> i32>(f: F) -> i32 {
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or(LOWRR_PANIC)
+}
@@ -0,0 +1,95 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Benchmarks of the hot loops of the registration, on synthetic stacks of several sizes.
+//!
+//! Run them with `cargo bench -p lowrr`, followed by a word to only run
+//! the benchmarks containing it in their name, such as `cargo bench -p lowrr -- svd`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use lowrr::img::multires::mean_pyramid;
+use lowrr::img::registration::{self, bench, Config, SvdLibrary};
+use lowrr::img::synthetic::translated_stack;
+use nalgebra::DMatrix;
+
+const SIZES: [usize; 3] = [256, 512, 1024];
+const IMAGES_COUNT: usize = 6;
+
+fn pyramid(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mean_pyramid");
+    for &size in SIZES.iter() {
+        let (imgs, _) = translated_stack(size, size, IMAGES_COUNT, 42);
+        group.bench_with_input(BenchmarkId::from_parameter(size), &imgs[0], |b, img| {
+            b.iter(|| mean_pyramid(4, black_box(img.clone())))
+        });
+    }
+    group.finish();
+}
+
+/// Projection of all the pixels of the images with their motions,
+/// done at each iteration of the algorithm.
+fn project(c: &mut Criterion) {
+    let mut group = c.benchmark_group("project_f32");
+    for &size in SIZES.iter() {
+        let (imgs, motions) = translated_stack(size, size, IMAGES_COUNT, 42);
+        let coordinates: Vec<(usize, usize)> = (0..size)
+            .flat_map(|y| (0..size).map(move |x| (x, y)))
+            .collect();
+        let mut registered = DMatrix::zeros(coordinates.len(), IMAGES_COUNT);
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter(|| bench::project_f32(&coordinates, &mut registered, &imgs, &motions))
+        });
+    }
+    group.finish();
+}
+
+/// A-update of the low-rank approximation, SVD of a pixels x images matrix,
+/// with each SVD library compiled in.
+fn svd(c: &mut Criterion) {
+    let mut group = c.benchmark_group("svd_update");
+    group.sample_size(10);
+    let libraries = [SvdLibrary::Nalgebra, SvdLibrary::Faer, SvdLibrary::Lapack];
+    for &size in SIZES.iter() {
+        let (imgs, _) = translated_stack(size, size, IMAGES_COUNT, 42);
+        let pixels: Vec<f32> = imgs.iter().flatten().map(|&x| x as f32 / 255.0).collect();
+        let mat = DMatrix::from_vec(size * size, IMAGES_COUNT, pixels);
+        let mut imgs_a = DMatrix::zeros(size * size, IMAGES_COUNT);
+        for library in libraries.iter().filter(|l| l.is_available()) {
+            let id = BenchmarkId::new(format!("{:?}", library), size);
+            group.bench_function(id, |b| {
+                b.iter_batched_ref(
+                    || mat.clone(),
+                    |temp| bench::svd_update(library, 0.1, temp, &mut imgs_a),
+                    criterion::BatchSize::LargeInput,
+                )
+            });
+        }
+    }
+    group.finish();
+}
+
+fn register(c: &mut Criterion) {
+    let mut group = c.benchmark_group("registration");
+    group.sample_size(10);
+    for &size in SIZES.iter() {
+        let (imgs, _) = translated_stack(size, size, IMAGES_COUNT, 42);
+        group.bench_with_input(BenchmarkId::from_parameter(size), &imgs, |b, imgs| {
+            b.iter(|| {
+                let registered = registration::gray_affine(config(size), imgs.clone(), 40);
+                registered.expect("Registration of a synthetic stack")
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Default configuration, with levels down to about 32x32 pixels.
+fn config(size: usize) -> Config {
+    let levels = (size / 32).max(1).trailing_zeros() as usize + 1;
+    Config {
+        levels,
+        ..Config::default()
+    }
+}
+
+criterion_group!(benches, pyramid, project, svd, register);
+criterion_main!(benches);
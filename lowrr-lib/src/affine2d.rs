@@ -1,23 +1,89 @@
 // SPDX-License-Identifier: MPL-2.0
 
-use nalgebra::{Matrix3, Vector6};
+use nalgebra::{Matrix2x3, Matrix3, RealField, Vector3, Vector6};
 
 #[rustfmt::skip]
-pub fn projection_mat(params: &Vector6<f32>) -> Matrix3<f32> {
+pub fn projection_mat<F: RealField>(params: &Vector6<F>) -> Matrix3<F> {
+    let (zero, one) = (F::zero(), F::one());
     Matrix3::new(
-        1.0 + params[0],       params[2], params[4],
-              params[1], 1.0 + params[3], params[5],
-                    0.0,             0.0,       1.0,
+        one + params[0],       params[2], params[4],
+              params[1], one + params[3], params[5],
+                   zero,            zero,       one,
     )
 }
 
-pub fn projection_params(mat: &Matrix3<f32>) -> Vector6<f32> {
+pub fn projection_params<F: RealField>(mat: &Matrix3<F>) -> Vector6<F> {
     Vector6::new(
-        mat.m11 - 1.0,
+        mat.m11 - F::one(),
         mat.m21,
         mat.m12,
-        mat.m22 - 1.0,
+        mat.m22 - F::one(),
         mat.m13,
         mat.m23,
     )
 }
+
+/// Matrix of OpenCV's `warpAffine`, which maps the pixels of an image into the registered frame,
+/// while motions map the registered frame into the image.
+/// None if the motion is not invertible.
+pub fn to_opencv<F: RealField>(params: &Vector6<F>) -> Option<Matrix2x3<F>> {
+    let m = projection_mat(params).try_inverse()?;
+    Some(Matrix2x3::new(m.m11, m.m12, m.m13, m.m21, m.m22, m.m23))
+}
+
+/// Motion from a matrix of OpenCV's `warpAffine` (see [to_opencv]).
+#[rustfmt::skip]
+pub fn from_opencv<F: RealField>(m: &Matrix2x3<F>) -> Option<Vector6<F>> {
+    let (zero, one) = (F::zero(), F::one());
+    let forward = Matrix3::new(
+        m.m11, m.m12, m.m13,
+        m.m21, m.m22, m.m23,
+         zero,  zero,   one,
+    );
+    Some(projection_params(&forward.try_inverse()?))
+}
+
+/// Matrix `T` of Matlab's `affine2d`, for `imwarp` with an `OutputView` of the registered frame.
+/// Matlab multiplies row vectors of coordinates starting at 1,
+/// so `T` is the transpose of the matrix of OpenCV, in coordinates shifted by one pixel.
+pub fn to_matlab<F: RealField>(params: &Vector6<F>) -> Option<Matrix3<F>> {
+    let forward = projection_mat(params).try_inverse()?;
+    let (to_matlab, from_matlab) = one_based::<F>();
+    Some((to_matlab * forward * from_matlab).transpose())
+}
+
+/// Motion from a matrix `T` of Matlab's `affine2d` (see [to_matlab]).
+pub fn from_matlab<F: RealField>(t: &Matrix3<F>) -> Option<Vector6<F>> {
+    let (to_matlab, from_matlab) = one_based::<F>();
+    let forward = from_matlab * t.transpose() * to_matlab;
+    Some(projection_params(&forward.try_inverse()?))
+}
+
+/// Motion in square pixels, for images of non-square pixels with the given aspect ratio
+/// (width divided by height of a pixel).
+/// Horizontal coordinates are scaled by the aspect ratio, so that both axes are in units
+/// of the pixel height, and rotations and translations are the physical ones.
+pub fn to_square_pixels<F: RealField>(params: &Vector6<F>, aspect_ratio: F) -> Vector6<F> {
+    let (scale, inverse) = aspect_scalings(aspect_ratio);
+    projection_params(&(scale * projection_mat(params) * inverse))
+}
+
+/// Motion in the pixels of the images from a motion in square pixels (see [to_square_pixels]).
+pub fn from_square_pixels<F: RealField>(params: &Vector6<F>, aspect_ratio: F) -> Vector6<F> {
+    let (scale, inverse) = aspect_scalings(aspect_ratio);
+    projection_params(&(inverse * projection_mat(params) * scale))
+}
+
+/// Scaling of horizontal coordinates by the aspect ratio, and its inverse.
+fn aspect_scalings<F: RealField>(aspect_ratio: F) -> (Matrix3<F>, Matrix3<F>) {
+    let one = F::one();
+    let scaling = |x: F| Matrix3::from_diagonal(&Vector3::new(x, one, one));
+    (scaling(aspect_ratio), scaling(one / aspect_ratio))
+}
+
+/// Translations from coordinates starting at 0 to coordinates starting at 1, and back.
+fn one_based<F: RealField>() -> (Matrix3<F>, Matrix3<F>) {
+    let (zero, one) = (F::zero(), F::one());
+    let shift = |t: F| projection_mat(&Vector6::new(zero, zero, zero, zero, t, t));
+    (shift(one), shift(-one))
+}
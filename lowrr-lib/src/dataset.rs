@@ -0,0 +1,199 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! # In-memory datasets
+//!
+//! Registration of encoded image files held in memory, without any filesystem,
+//! such as images uploaded to a server application.
+//! Images are decoded like in the web application, converted to a supported layout
+//! (see [into_supported_layout]), and registered images are encoded back in PNG.
+//!
+//! ```ignore
+//! let dataset = Dataset::from_encoded(files)?;
+//! let motion_vec = dataset.register(&StackParams::default())?;
+//! let pngs = dataset.registered_pngs(&motion_vec)?;
+//! ```
+
+use image::DynamicImage;
+use nalgebra::{DMatrix, Vector6};
+use std::io::Cursor;
+use thiserror::Error;
+
+use crate::img::registration;
+use crate::interop::{into_supported_layout, IntoDMatrix, ToImage};
+use crate::pipeline::{register_stack, PipelineError, StackParams};
+use crate::utils::{encode_img, ImgFormat, UtilsError};
+
+#[derive(Error, Debug)]
+pub enum DatasetError {
+    #[error("The dataset has no image")]
+    Empty,
+    #[error("Failed to decode image {id}: {source}")]
+    Decode {
+        id: String,
+        source: image::ImageError,
+    },
+    #[error("Image {id} is not of the same type as the first image")]
+    MismatchedType { id: String },
+    #[error("There is no image {0}")]
+    MissingImage(usize),
+    #[error("Expected {expected} motions but got {actual}")]
+    MotionCount { expected: usize, actual: usize },
+    #[error(transparent)]
+    Pipeline(#[from] PipelineError),
+    #[error(transparent)]
+    Encode(#[from] UtilsError),
+}
+
+/// Decoded images of a dataset, all of the same type.
+pub enum Images {
+    Gray(Vec<DMatrix<u8>>),
+    GrayU16(Vec<DMatrix<u16>>),
+    Rgb(Vec<DMatrix<(u8, u8, u8)>>),
+    RgbU16(Vec<DMatrix<(u16, u16, u16)>>),
+}
+
+/// Images decoded from memory, with the ids they were given.
+pub struct Dataset {
+    pub ids: Vec<String>,
+    pub images: Images,
+}
+
+impl Dataset {
+    /// Decode image files given as (id, encoded bytes), in that order,
+    /// the first one being the reference of the registration.
+    /// The format of each file is recognized from its content.
+    pub fn from_encoded(files: Vec<(String, Vec<u8>)>) -> Result<Self, DatasetError> {
+        let mut files = files.into_iter();
+        let (id, bytes) = files.next().ok_or(DatasetError::Empty)?;
+        let mut images = match decode(&id, &bytes)? {
+            img @ DynamicImage::ImageLuma8(_) => Images::Gray(vec![img.into_dmatrix()]),
+            img @ DynamicImage::ImageLuma16(_) => Images::GrayU16(vec![img.into_dmatrix()]),
+            img @ DynamicImage::ImageRgb8(_) => Images::Rgb(vec![img.into_dmatrix()]),
+            img @ DynamicImage::ImageRgb16(_) => Images::RgbU16(vec![img.into_dmatrix()]),
+            _ => return Err(DatasetError::MismatchedType { id }),
+        };
+        log::info!("Images are of type {}", images.type_name());
+        let mut ids = vec![id];
+        for (id, bytes) in files {
+            let img = decode(&id, &bytes)?;
+            match (&mut images, img) {
+                (Images::Gray(imgs), img @ DynamicImage::ImageLuma8(_)) => {
+                    imgs.push(img.into_dmatrix())
+                }
+                (Images::GrayU16(imgs), img @ DynamicImage::ImageLuma16(_)) => {
+                    imgs.push(img.into_dmatrix())
+                }
+                (Images::Rgb(imgs), img @ DynamicImage::ImageRgb8(_)) => {
+                    imgs.push(img.into_dmatrix())
+                }
+                (Images::RgbU16(imgs), img @ DynamicImage::ImageRgb16(_)) => {
+                    imgs.push(img.into_dmatrix())
+                }
+                _ => return Err(DatasetError::MismatchedType { id }),
+            }
+            ids.push(id);
+        }
+        Ok(Dataset { ids, images })
+    }
+
+    /// Number of images.
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// (height, width) of the first image.
+    pub fn shape(&self) -> Option<(usize, usize)> {
+        match &self.images {
+            Images::Gray(imgs) => imgs.first().map(|im| im.shape()),
+            Images::GrayU16(imgs) => imgs.first().map(|im| im.shape()),
+            Images::Rgb(imgs) => imgs.first().map(|im| im.shape()),
+            Images::RgbU16(imgs) => imgs.first().map(|im| im.shape()),
+        }
+    }
+
+    /// Register the images (see [register_stack]),
+    /// returning their motions in the frame of the full images.
+    pub fn register(&self, params: &StackParams) -> Result<Vec<Vector6<f32>>, DatasetError> {
+        let motion_vec = match &self.images {
+            Images::Gray(imgs) => register_stack(imgs, params)?.motion_vec,
+            Images::GrayU16(imgs) => register_stack(imgs, params)?.motion_vec,
+            Images::Rgb(imgs) => register_stack(imgs, params)?.motion_vec,
+            Images::RgbU16(imgs) => register_stack(imgs, params)?.motion_vec,
+        };
+        Ok(motion_vec)
+    }
+
+    /// Image `i` warped with its motion, at full resolution and encoded in PNG.
+    pub fn registered_png(
+        &self,
+        i: usize,
+        motion_vec: &[Vector6<f32>],
+    ) -> Result<Vec<u8>, DatasetError> {
+        if motion_vec.len() != self.len() {
+            return Err(DatasetError::MotionCount {
+                expected: self.len(),
+                actual: motion_vec.len(),
+            });
+        }
+        if i >= self.len() {
+            return Err(DatasetError::MissingImage(i));
+        }
+        let motion = &motion_vec[i];
+        let registered = match &self.images {
+            Images::Gray(imgs) => {
+                let warped: DMatrix<u8> = registration::warp(&imgs[i], motion);
+                warped.to_image()
+            }
+            Images::GrayU16(imgs) => {
+                let warped: DMatrix<u16> = registration::warp(&imgs[i], motion);
+                warped.to_image()
+            }
+            Images::Rgb(imgs) => {
+                let warped: DMatrix<(u8, u8, u8)> = registration::warp(&imgs[i], motion);
+                warped.to_image()
+            }
+            Images::RgbU16(imgs) => {
+                let warped: DMatrix<(u16, u16, u16)> = registration::warp(&imgs[i], motion);
+                warped.to_image()
+            }
+        };
+        Ok(encode_img(&registered, ImgFormat::default(), None)?)
+    }
+
+    /// All registered images encoded in PNG, with their ids.
+    pub fn registered_pngs(
+        &self,
+        motion_vec: &[Vector6<f32>],
+    ) -> Result<Vec<(String, Vec<u8>)>, DatasetError> {
+        (0..self.len())
+            .map(|i| Ok((self.ids[i].clone(), self.registered_png(i, motion_vec)?)))
+            .collect()
+    }
+}
+
+impl Images {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Images::Gray(_) => "Gray u8",
+            Images::GrayU16(_) => "Gray u16",
+            Images::Rgb(_) => "RGB (u8, u8, u8)",
+            Images::RgbU16(_) => "RGB (u16, u16, u16)",
+        }
+    }
+}
+
+/// Decode an image file, recognizing its format from its content.
+fn decode(id: &str, bytes: &[u8]) -> Result<DynamicImage, DatasetError> {
+    let reader = image::io::Reader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .expect("Cursor io never fails");
+    let img = reader.decode().map_err(|source| DatasetError::Decode {
+        id: id.to_string(),
+        source,
+    })?;
+    Ok(into_supported_layout(img))
+}
@@ -0,0 +1,90 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Drift of a sequence of images, such as the thermal drift of a capture rig,
+//! fitted on the displacements of their centers (see [units](crate::units)).
+//!
+//! Each axis is fitted with a line of the frame index by least squares,
+//! whose slope is the drift rate, and whose residuals are the jitter around the trend.
+
+use nalgebra::Vector2;
+
+/// Linear trend of the position along one axis.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AxisDrift {
+    /// Drift per frame.
+    pub rate: f32,
+    /// Fitted position of the first frame.
+    pub offset: f32,
+    /// Root mean square of the residuals around the trend.
+    pub rms: f32,
+}
+
+impl AxisDrift {
+    /// Least squares line of the positions along the frame index.
+    #[allow(clippy::cast_precision_loss)]
+    fn fit(positions: impl Iterator<Item = f32> + Clone) -> Self {
+        let (mut n, mut sum_i, mut sum_p, mut sum_ii, mut sum_ip) = (0.0, 0.0, 0.0, 0.0, 0.0);
+        for (i, p) in positions.clone().enumerate() {
+            let (i, p) = (i as f64, f64::from(p));
+            n += 1.0;
+            sum_i += i;
+            sum_p += p;
+            sum_ii += i * i;
+            sum_ip += i * p;
+        }
+        let denominator = n * sum_ii - sum_i * sum_i;
+        let rate = if denominator > 0.0 {
+            (n * sum_ip - sum_i * sum_p) / denominator
+        } else {
+            0.0
+        };
+        let offset = (sum_p - rate * sum_i) / n.max(1.0);
+        let mut drift = AxisDrift {
+            rate: rate as f32,
+            offset: offset as f32,
+            rms: 0.0,
+        };
+        let sum_sqr: f32 = positions
+            .enumerate()
+            .map(|(i, p)| (p - drift.at(i)).powi(2))
+            .sum();
+        drift.rms = (sum_sqr / n.max(1.0) as f32).sqrt();
+        drift
+    }
+
+    /// Position of the trend at a frame.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn at(&self, frame: usize) -> f32 {
+        self.offset + self.rate * frame as f32
+    }
+}
+
+/// Linear trends of the positions (x, y) of a sequence of images.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Drift {
+    pub x: AxisDrift,
+    pub y: AxisDrift,
+}
+
+impl Drift {
+    /// Trends of the positions of consecutive frames, None with less than two frames.
+    pub fn fit(positions: &[Vector2<f32>]) -> Option<Self> {
+        if positions.len() < 2 {
+            return None;
+        }
+        Some(Drift {
+            x: AxisDrift::fit(positions.iter().map(|p| p.x)),
+            y: AxisDrift::fit(positions.iter().map(|p| p.y)),
+        })
+    }
+
+    /// Position of the trends at a frame.
+    pub fn at(&self, frame: usize) -> Vector2<f32> {
+        Vector2::new(self.x.at(frame), self.y.at(frame))
+    }
+
+    /// Drift (x, y) per frame.
+    pub fn rate(&self) -> Vector2<f32> {
+        Vector2::new(self.x.rate, self.y.rate)
+    }
+}
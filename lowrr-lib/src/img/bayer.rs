@@ -0,0 +1,149 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Registration of RAW Bayer mosaics without demosaicing.
+//!
+//! Demosaicing interpolates the missing colors of each pixel from its neighbors,
+//! which biases sub-pixel motion estimates toward the sensor grid.
+//! Instead, motions are estimated on the green checkerboard of the mosaic,
+//! averaged in each 2x2 block of the color filter array (CFA)
+//! into an image of half resolution,
+//! and each of the four CFA planes is warped separately at output time,
+//! so that registered mosaics keep their pattern.
+
+use nalgebra::{DMatrix, Matrix3, Scalar, Vector6};
+use std::ops::{Add, Mul};
+
+use crate::affine2d::{projection_mat, projection_params};
+use crate::img::interpolation::CanLinearInterpolate;
+use crate::img::multires::Bigger;
+
+/// Arrangement of the colors in the 2x2 blocks of a Bayer mosaic,
+/// named after the first row then the second row.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CfaPattern {
+    Rggb,
+    Bggr,
+    Grbg,
+    Gbrg,
+}
+
+impl std::str::FromStr for CfaPattern {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "rggb" => Ok(CfaPattern::Rggb),
+            "bggr" => Ok(CfaPattern::Bggr),
+            "grbg" => Ok(CfaPattern::Grbg),
+            "gbrg" => Ok(CfaPattern::Gbrg),
+            _ => Err(format!(
+                "Unknown Bayer pattern \"{}\", expecting rggb, bggr, grbg or gbrg",
+                s
+            )),
+        }
+    }
+}
+
+impl CfaPattern {
+    /// Pattern of the mosaic starting at the pixel (left, top) of this one,
+    /// such as after cropping it.
+    pub fn shifted(self, left: usize, top: usize) -> Self {
+        let swap_columns = |p: CfaPattern| match p {
+            CfaPattern::Rggb => CfaPattern::Grbg,
+            CfaPattern::Grbg => CfaPattern::Rggb,
+            CfaPattern::Bggr => CfaPattern::Gbrg,
+            CfaPattern::Gbrg => CfaPattern::Bggr,
+        };
+        let swap_rows = |p: CfaPattern| match p {
+            CfaPattern::Rggb => CfaPattern::Gbrg,
+            CfaPattern::Gbrg => CfaPattern::Rggb,
+            CfaPattern::Bggr => CfaPattern::Grbg,
+            CfaPattern::Grbg => CfaPattern::Bggr,
+        };
+        let mut pattern = self;
+        if left % 2 == 1 {
+            pattern = swap_columns(pattern);
+        }
+        if top % 2 == 1 {
+            pattern = swap_rows(pattern);
+        }
+        pattern
+    }
+
+    /// Whether the two green pixels of a block are its top left and bottom right pixels,
+    /// instead of its top right and bottom left pixels.
+    fn green_on_diagonal(self) -> bool {
+        matches!(self, CfaPattern::Grbg | CfaPattern::Gbrg)
+    }
+}
+
+/// Green plane of a mosaic, with the mean of the two green pixels of each 2x2 block.
+///
+/// The pixel (i, j) of the plane is centered on the block, at (2i + 0.5, 2j + 0.5) in the mosaic,
+/// whatever the pattern. The last row and column of odd sized mosaics are dropped.
+pub fn green_plane<T: Scalar + Copy + Bigger>(
+    pattern: CfaPattern,
+    img: &DMatrix<T>,
+) -> DMatrix<T> {
+    let (nrows, ncols) = img.shape();
+    let diagonal = pattern.green_on_diagonal();
+    DMatrix::from_fn(nrows / 2, ncols / 2, |i, j| {
+        let (a, b) = if diagonal {
+            (img[(2 * i, 2 * j)], img[(2 * i + 1, 2 * j + 1)])
+        } else {
+            (img[(2 * i, 2 * j + 1)], img[(2 * i + 1, 2 * j)])
+        };
+        T::from_as((T::Big::from(a) + T::Big::from(b)) / T::Big::from(2u8))
+    })
+}
+
+/// Transformation of the coordinates of a half resolution plane into the ones of the mosaic,
+/// for a plane whose first pixel is at `offset` in the mosaic.
+fn plane_to_mosaic(offset: (f32, f32)) -> Matrix3<f32> {
+    projection_mat(&Vector6::new(1.0, 0.0, 0.0, 1.0, offset.0, offset.1))
+}
+
+/// Motion of a mosaic, from the motion of its green plane.
+pub fn mosaic_motion(green_motion: &Vector6<f32>) -> Vector6<f32> {
+    let to_mosaic = plane_to_mosaic((0.5, 0.5));
+    let to_plane = to_mosaic.try_inverse().expect("Scaling is invertible");
+    projection_params(&(to_mosaic * projection_mat(green_motion) * to_plane))
+}
+
+/// Motion of the green plane of a mosaic, from the motion of the mosaic.
+pub fn green_motion(mosaic_motion: &Vector6<f32>) -> Vector6<f32> {
+    let to_mosaic = plane_to_mosaic((0.5, 0.5));
+    let to_plane = to_mosaic.try_inverse().expect("Scaling is invertible");
+    projection_params(&(to_plane * projection_mat(mosaic_motion) * to_mosaic))
+}
+
+/// Warp a mosaic with the given motion, plane by plane,
+/// so that colors are never interpolated from pixels of other colors.
+#[allow(clippy::cast_precision_loss)]
+pub fn warp_mosaic<T, V>(img: &DMatrix<T>, motion_params: &Vector6<f32>) -> DMatrix<T>
+where
+    V: Add<Output = V>,
+    f32: Mul<V, Output = V>,
+    T: Scalar + Copy + CanLinearInterpolate<V, T>,
+{
+    let (nrows, ncols) = img.shape();
+    let motion = projection_mat(motion_params);
+    let mut warped = img.clone();
+    for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+        let (plane_rows, plane_cols) = ((nrows - dy + 1) / 2, (ncols - dx + 1) / 2);
+        if plane_rows < 3 || plane_cols < 3 {
+            continue;
+        }
+        let plane =
+            DMatrix::from_fn(plane_rows, plane_cols, |i, j| img[(2 * i + dy, 2 * j + dx)]);
+        let to_mosaic = plane_to_mosaic((dx as f32, dy as f32));
+        let to_plane = to_mosaic.try_inverse().expect("Scaling is invertible");
+        let plane_motion = projection_params(&(to_plane * motion * to_mosaic));
+        let warped_plane = crate::img::registration::warp::<T, V, T>(&plane, &plane_motion);
+        for j in 0..plane_cols {
+            for i in 0..plane_rows {
+                warped[(2 * i + dy, 2 * j + dx)] = warped_plane[(i, j)];
+            }
+        }
+    }
+    warped
+}
@@ -0,0 +1,93 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Output frame of registered images, which may be larger than the images,
+//! so that no content is lost at the borders of moving images.
+
+use nalgebra::{Vector3, Vector6};
+
+use crate::affine2d::projection_mat;
+use crate::img::crop::Crop;
+
+/// Frame of the output images, in the pixel coordinates of the reference image.
+/// It may extend beyond the reference image on any side.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Canvas {
+    /// Horizontal position of the left column of the canvas, negative on the left of the image.
+    pub left: f32,
+    /// Vertical position of the top row of the canvas, negative above the image.
+    pub top: f32,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Canvas {
+    /// Canvas of the given size, anchored at the top-left corner of the reference image.
+    pub fn new(width: usize, height: usize) -> Self {
+        Canvas {
+            left: 0.0,
+            top: 0.0,
+            width,
+            height,
+        }
+    }
+
+    /// Smallest canvas containing all the pixels of images of the given (height, width)
+    /// once registered with their motions, and the reference image itself.
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_precision_loss)]
+    #[allow(clippy::cast_sign_loss)]
+    pub fn bounding(shape: (usize, usize), motion_vec: &[Vector6<f32>]) -> Self {
+        let (height, width) = shape;
+        let (right, bottom) = (width as f32 - 1.0, height as f32 - 1.0);
+        let corners = [(0.0, 0.0), (right, 0.0), (0.0, bottom), (right, bottom)];
+        let (mut min_x, mut min_y, mut max_x, mut max_y) = (0.0_f32, 0.0_f32, right, bottom);
+        // An image registered with a motion M shows at position p its pixel at M p,
+        // so its corners are at the inverse motion of its own corners.
+        let inverses = motion_vec
+            .iter()
+            .filter_map(|motion| projection_mat(motion).try_inverse());
+        for inverse in inverses {
+            for &(x, y) in corners.iter() {
+                let p = inverse * Vector3::new(x, y, 1.0);
+                min_x = min_x.min(p.x);
+                min_y = min_y.min(p.y);
+                max_x = max_x.max(p.x);
+                max_y = max_y.max(p.y);
+            }
+        }
+        let (left, top) = (min_x.floor(), min_y.floor());
+        Canvas {
+            left,
+            top,
+            width: (max_x.ceil() - left) as usize + 1,
+            height: (max_y.ceil() - top) as usize + 1,
+        }
+    }
+
+    /// Same canvas, moved by a global offset in the reference image.
+    pub fn offset(self, dx: f32, dy: f32) -> Self {
+        Canvas {
+            left: self.left + dx,
+            top: self.top + dy,
+            ..self
+        }
+    }
+
+    /// Motion to warp an image registered with the given motion directly into this canvas.
+    pub fn motion(&self, motion: &Vector6<f32>) -> Vector6<f32> {
+        let translation = Vector6::new(0.0, 0.0, 0.0, 0.0, self.left, self.top);
+        crate::affine2d::projection_params(&(projection_mat(motion) * projection_mat(&translation)))
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+impl From<Crop> for Canvas {
+    fn from(crop: Crop) -> Self {
+        Canvas {
+            left: crop.left as f32,
+            top: crop.top as f32,
+            width: crop.right.saturating_sub(crop.left),
+            height: crop.bottom.saturating_sub(crop.top),
+        }
+    }
+}
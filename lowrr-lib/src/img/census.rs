@@ -0,0 +1,32 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Census transform, robust to changes of lighting between images.
+
+use nalgebra::DMatrix;
+
+use crate::img::normalization::CanNormalize;
+
+/// Census transform on a 3x3 neighborhood, summarized as an intensity.
+///
+/// The census signature of a pixel tells which of its 8 neighbors are darker.
+/// Since the registration needs intensities that can be interpolated,
+/// each pixel is replaced by the number of darker neighbors,
+/// scaled to the full range of intensities.
+/// This is invariant to any monotonic change of intensities.
+/// Neighbors out of the image are ignored.
+pub fn census<T: CanNormalize>(img: &DMatrix<T>) -> DMatrix<T> {
+    let (nb_rows, nb_cols) = img.shape();
+    let max_level = T::LEVELS - 1;
+    DMatrix::from_fn(nb_rows, nb_cols, |i, j| {
+        let center = img[(i, j)].to_level();
+        let mut darker = 0;
+        for ni in i.saturating_sub(1)..=(i + 1).min(nb_rows - 1) {
+            for nj in j.saturating_sub(1)..=(j + 1).min(nb_cols - 1) {
+                if img[(ni, nj)].to_level() < center {
+                    darker += 1;
+                }
+            }
+        }
+        T::from_level(darker * max_level / 8)
+    })
+}
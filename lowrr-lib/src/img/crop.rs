@@ -10,8 +10,9 @@ use wasm_bindgen::prelude::*;
 #[cfg(feature = "serde")]
 use serde::Deserialize;
 
+/// Crop frame in pixels, the right and bottom bounds being excluded.
 #[cfg_attr(feature = "wasm-bindgen", wasm_bindgen)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[cfg_attr(feature = "serde", derive(Deserialize))]
 pub struct Crop {
     pub left: usize,
@@ -30,6 +31,172 @@ pub enum CropError {
     TooManyArgs,
     #[error("Error parsing crop frame coordinates")]
     Parse(#[from] std::num::ParseIntError),
+    #[error("Error parsing relative crop frame coordinates")]
+    ParseRelative(#[from] std::num::ParseFloatError),
+}
+
+/// Coordinate or size of a crop frame, along the width or the height of the images.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Length {
+    Pixels(usize),
+    /// Fraction of the image dimension, written as a percentage such as "10%".
+    Relative(f32),
+}
+
+impl Length {
+    /// Number of pixels for an image dimension of `total` pixels.
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    pub fn pixels(self, total: usize) -> usize {
+        match self {
+            Length::Pixels(pixels) => pixels,
+            Length::Relative(fraction) => (fraction * total as f32).round().max(0.0) as usize,
+        }
+    }
+}
+
+impl std::str::FromStr for Length {
+    type Err = CropError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s.strip_suffix('%') {
+            Some(percent) => Ok(Length::Relative(percent.trim().parse::<f32>()? / 100.0)),
+            None => Ok(Length::Pixels(s.parse()?)),
+        }
+    }
+}
+
+/// Crop frame as given by the user, resolved into a [Crop] once the size of the images is known,
+/// so that relative frames apply to datasets of different resolutions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CropSpec {
+    /// Bounds of the frame: "left,top,right,bottom", such as "10%,10%,90%,90%".
+    Bounds {
+        left: Length,
+        top: Length,
+        right: Length,
+        bottom: Length,
+    },
+    /// Center and size of the frame: "center:x,y,width,height", such as "center:50%,50%,400,300".
+    Centered {
+        x: Length,
+        y: Length,
+        width: Length,
+        height: Length,
+    },
+}
+
+impl From<Crop> for CropSpec {
+    fn from(crop: Crop) -> Self {
+        CropSpec::Bounds {
+            left: Length::Pixels(crop.left),
+            top: Length::Pixels(crop.top),
+            right: Length::Pixels(crop.right),
+            bottom: Length::Pixels(crop.bottom),
+        }
+    }
+}
+
+impl TryFrom<&str> for CropSpec {
+    type Error = CropError;
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        let (centered, coords) = match s.trim().strip_prefix("center:") {
+            Some(coords) => (true, coords),
+            None => (false, s),
+        };
+        let mut vs = coords.split(',');
+        let [a, b, c, d] = match (vs.next(), vs.next(), vs.next(), vs.next(), vs.next()) {
+            (None, _, _, _, _) => return Err(CropError::NotEnoughArgs(0)),
+            (_, None, _, _, _) => return Err(CropError::NotEnoughArgs(1)),
+            (_, _, None, _, _) => return Err(CropError::NotEnoughArgs(2)),
+            (_, _, _, None, _) => return Err(CropError::NotEnoughArgs(3)),
+            (_, _, _, _, Some(_)) => return Err(CropError::TooManyArgs),
+            (Some(a), Some(b), Some(c), Some(d), None) => {
+                [a.parse()?, b.parse()?, c.parse()?, d.parse()?]
+            }
+        };
+        if centered {
+            Ok(CropSpec::Centered {
+                x: a,
+                y: b,
+                width: c,
+                height: d,
+            })
+        } else {
+            // Frames in pixels can already be checked before knowing the size of the images.
+            if let [Length::Pixels(left), Length::Pixels(top), Length::Pixels(right), Length::Pixels(bottom)] =
+                [a, b, c, d]
+            {
+                Crop {
+                    left,
+                    top,
+                    right,
+                    bottom,
+                }
+                .check_order()?;
+            }
+            Ok(CropSpec::Bounds {
+                left: a,
+                top: b,
+                right: c,
+                bottom: d,
+            })
+        }
+    }
+}
+
+impl CropSpec {
+    /// Frame in pixels for images of the given (height, width).
+    /// It is checked to be non-empty, but may still exceed the images.
+    pub fn resolve(&self, (height, width): (usize, usize)) -> Result<Crop, CropError> {
+        match *self {
+            CropSpec::Bounds {
+                left,
+                top,
+                right,
+                bottom,
+            } => {
+                let frame = Crop {
+                    left: left.pixels(width),
+                    top: top.pixels(height),
+                    right: right.pixels(width),
+                    bottom: bottom.pixels(height),
+                };
+                frame.check_order()?;
+                Ok(frame)
+            }
+            CropSpec::Centered {
+                x,
+                y,
+                width: crop_width,
+                height: crop_height,
+            } => {
+                let (x, y) = (x.pixels(width), y.pixels(height));
+                let (crop_width, crop_height) =
+                    (crop_width.pixels(width), crop_height.pixels(height));
+                let (left, top) = (
+                    x.checked_sub(crop_width / 2),
+                    y.checked_sub(crop_height / 2),
+                );
+                match (left, top) {
+                    (Some(left), Some(top)) => {
+                        let frame = Crop {
+                            left,
+                            top,
+                            right: left + crop_width,
+                            bottom: top + crop_height,
+                        };
+                        frame.check_order()?;
+                        Ok(frame)
+                    }
+                    _ => Err(CropError::InvalidFrame(format!(
+                        "frame of size {}x{} centered on ({}, {}) starts before the image",
+                        crop_width, crop_height, x, y
+                    ))),
+                }
+            }
+        }
+    }
 }
 
 impl TryFrom<Vec<&str>> for Crop {
@@ -42,68 +209,118 @@ impl TryFrom<Vec<&str>> for Crop {
             (_, _, None, _, _) => Err(CropError::NotEnoughArgs(2)),
             (_, _, _, None, _) => Err(CropError::NotEnoughArgs(3)),
             (_, _, _, _, Some(_)) => Err(CropError::TooManyArgs),
-            (Some(left), Some(top), Some(right), Some(bottom), None) => Ok(Crop {
-                left: left.parse()?,
-                top: top.parse()?,
-                right: right.parse()?,
-                bottom: bottom.parse()?,
-            }),
+            (Some(left), Some(top), Some(right), Some(bottom), None) => {
+                let frame = Crop {
+                    left: left.parse()?,
+                    top: top.parse()?,
+                    right: right.parse()?,
+                    bottom: bottom.parse()?,
+                };
+                frame.check_order()?;
+                Ok(frame)
+            }
         }
     }
 }
 
+impl std::fmt::Display for Crop {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{},{},{},{}",
+            self.left, self.top, self.right, self.bottom
+        )
+    }
+}
+
+impl Crop {
+    /// Check that the frame is not empty, with left < right and top < bottom.
+    pub fn check_order(&self) -> Result<(), CropError> {
+        if self.left >= self.right {
+            return Err(CropError::InvalidFrame(format!(
+                "{} is inverted or empty: left >= right ({} >= {})",
+                self, self.left, self.right
+            )));
+        }
+        if self.top >= self.bottom {
+            return Err(CropError::InvalidFrame(format!(
+                "{} is inverted or empty: top >= bottom ({} >= {})",
+                self, self.top, self.bottom
+            )));
+        }
+        Ok(())
+    }
+
+    /// Check that the frame is not empty and inside images of the given (height, width).
+    pub fn check_inside(&self, (height, width): (usize, usize)) -> Result<(), CropError> {
+        self.check_order()?;
+        if self.right > width {
+            return Err(CropError::InvalidFrame(format!(
+                "{} exceeds the {}x{} image: right > width ({} > {})",
+                self, width, height, self.right, width
+            )));
+        }
+        if self.bottom > height {
+            return Err(CropError::InvalidFrame(format!(
+                "{} exceeds the {}x{} image: bottom > height ({} > {})",
+                self, width, height, self.bottom, height
+            )));
+        }
+        Ok(())
+    }
+
+    /// Frame restricted to images of the given (height, width).
+    /// Fails if the frame is empty or entirely outside the images.
+    pub fn clamp_to(&self, (height, width): (usize, usize)) -> Result<Crop, CropError> {
+        self.check_order()?;
+        let clamped = Crop {
+            left: self.left,
+            top: self.top,
+            right: self.right.min(width),
+            bottom: self.bottom.min(height),
+        };
+        if clamped.left >= clamped.right || clamped.top >= clamped.bottom {
+            return Err(CropError::InvalidFrame(format!(
+                "{} is entirely outside the {}x{} image",
+                self, width, height
+            )));
+        }
+        Ok(clamped)
+    }
+}
+
+/// Extract the frame of an image, checking that it is inside the image.
 pub fn crop<T: Scalar>(frame: Crop, img: &DMatrix<T>) -> Result<DMatrix<T>, CropError> {
+    frame.check_inside(img.shape())?;
     let Crop {
         left,
         top,
         right,
         bottom,
     } = frame;
-    let (height, width) = img.shape();
-
-    // Check that the frame coordinates make sense.
-    if left >= width {
-        return Err(CropError::InvalidFrame(format!(
-            "left >= width ({} >= {})",
-            left, width
-        )));
-    }
-    if right > width {
-        return Err(CropError::InvalidFrame(format!(
-            "right > width ({} > {})",
-            right, width
-        )));
-    }
-    if top >= height {
-        return Err(CropError::InvalidFrame(format!(
-            "top >= height ({} >= {})",
-            top, height
-        )));
-    }
-    if bottom > height {
-        return Err(CropError::InvalidFrame(format!(
-            "bottom > height ({} > {})",
-            bottom, height
-        )));
-    }
-    if left >= right {
-        return Err(CropError::InvalidFrame(format!(
-            "left >= right ({} >= {})",
-            left, right
-        )));
-    }
-    if top >= bottom {
-        return Err(CropError::InvalidFrame(format!(
-            "top >= bottom ({} >= {})",
-            top, bottom
-        )));
-    }
-    // Extract the cropped slice.
-    let nrows = bottom - top;
-    let ncols = right - left;
-    Ok(img.slice((top, left), (nrows, ncols)).into_owned())
+    Ok(img
+        .slice((top, left), (bottom - top, right - left))
+        .into_owned())
+}
+
+/// Motions in the frame of the crop, from the ones in the frame of the full images
+/// (the inverse of [recover_original_motion]).
+pub fn crop_motion(crop: Crop, motion_vec: &[Vector6<f32>]) -> Vec<Vector6<f32>> {
+    let Crop { left, top, .. } = crop;
+    let translation =
+        crate::affine2d::projection_mat(&Vector6::new(0.0, 0.0, 0.0, 0.0, left as f32, top as f32));
+    let translation_inv = translation.try_inverse().unwrap();
+    motion_vec
+        .iter()
+        .map(|m| {
+            let motion = crate::affine2d::projection_mat(m);
+            crate::affine2d::projection_params(&(translation_inv * motion * translation))
+        })
+        .collect()
 }
 
+/// Motions in the frame of the full images, from the ones in the frame of the crop.
+/// Relative crops must be resolved first, with the size of the full images.
 pub fn recover_original_motion(crop: Crop, motion_vec_crop: &[Vector6<f32>]) -> Vec<Vector6<f32>> {
     let Crop { left, top, .. } = crop;
     let translation =
@@ -0,0 +1,153 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Defective pixels of the sensor, such as hot or dead pixels.
+//!
+//! Defective pixels do not move with the scene, so they act as sharp features
+//! pinned to the sensor coordinates, which pull motions toward zero.
+//! They are replaced by the mean of their valid neighbors before the registration,
+//! and in the registered images.
+
+use nalgebra::{DMatrix, Scalar};
+use std::ops::{Add, Mul};
+use thiserror::Error;
+
+use crate::img::interpolation::CanLinearInterpolate;
+use crate::img::normalization::CanNormalize;
+
+#[derive(Error, Debug)]
+pub enum DefectsError {
+    #[error("Defect map of size {map:?} does not match the size {image:?} of the images")]
+    Size {
+        map: (usize, usize),
+        image: (usize, usize),
+    },
+}
+
+/// Number of robust standard deviations above its neighbors
+/// for a pixel of a dark frame to be a hot pixel.
+pub const DEFAULT_HOT_PIXEL_SIGMAS: f32 = 6.0;
+
+/// Defect map from an image where defective pixels are non-zero.
+pub fn from_mask<T: CanNormalize>(img: &DMatrix<T>) -> DMatrix<bool> {
+    img.map(|x| x.to_level() > 0)
+}
+
+/// Hot pixels of a dark frame (a capture with the lens capped),
+/// brighter than the median of their neighbors by more than `sigmas`
+/// robust standard deviations of the noise of the frame.
+///
+/// Neighbors are `step` pixels apart, 2 for Bayer mosaics
+/// to only compare pixels of the same color.
+#[allow(clippy::cast_precision_loss)]
+pub fn hot_pixels<T: CanNormalize>(
+    dark: &DMatrix<T>,
+    sigmas: f32,
+    step: usize,
+) -> DMatrix<bool> {
+    let (height, width) = dark.shape();
+    let excess = DMatrix::from_fn(height, width, |y, x| {
+        let mut neighbors = neighbor_coordinates(height, width, y, x, step)
+            .map(|(ny, nx)| dark[(ny, nx)].to_level())
+            .collect::<Vec<_>>();
+        if neighbors.is_empty() {
+            return 0.0;
+        }
+        let middle = neighbors.len() / 2;
+        let (_, median, _) = neighbors.select_nth_unstable(middle);
+        dark[(y, x)].to_level() as f32 - *median as f32
+    });
+
+    // Median absolute deviation, with at least one level of noise.
+    let mut deviations: Vec<f32> = excess.iter().map(|e| e.abs()).collect();
+    let middle = deviations.len() / 2;
+    let mad = if deviations.is_empty() {
+        0.0
+    } else {
+        let (_, mad, _) =
+            deviations.select_nth_unstable_by(middle, |a, b| a.partial_cmp(b).expect("No NaN"));
+        *mad
+    };
+    let std = (1.4826 * mad).max(1.0);
+    excess.map(|e| e > sigmas * std)
+}
+
+/// Replace the defective pixels of an image by the mean of their valid neighbors,
+/// `step` pixels apart (see [hot_pixels]).
+/// Pixels without valid neighbors are kept.
+#[allow(clippy::cast_precision_loss)]
+pub fn inpaint<T, V>(
+    defects: &DMatrix<bool>,
+    step: usize,
+    img: &DMatrix<T>,
+) -> Result<DMatrix<T>, DefectsError>
+where
+    V: Add<Output = V>,
+    f32: Mul<V, Output = V>,
+    T: Scalar + Copy + CanLinearInterpolate<V, T>,
+{
+    if defects.shape() != img.shape() {
+        return Err(DefectsError::Size {
+            map: defects.shape(),
+            image: img.shape(),
+        });
+    }
+    let (height, width) = img.shape();
+    let mut inpainted = img.clone();
+    for x in 0..width {
+        for y in 0..height {
+            if !defects[(y, x)] {
+                continue;
+            }
+            let valid: Vec<V> = neighbor_coordinates(height, width, y, x, step)
+                .filter(|&coords| !defects[coords])
+                .map(|coords| img[coords].into_vector())
+                .collect();
+            let count = valid.len() as f32;
+            if let Some(sum) = valid.into_iter().reduce(|a, b| a + b) {
+                inpainted[(y, x)] = T::from_vector((1.0 / count) * sum);
+            }
+        }
+    }
+    Ok(inpainted)
+}
+
+/// Same as [inpaint] for all the images of a stack, in place.
+pub fn inpaint_all<T, V>(
+    defects: &DMatrix<bool>,
+    step: usize,
+    imgs: &mut [DMatrix<T>],
+) -> Result<(), DefectsError>
+where
+    V: Add<Output = V>,
+    f32: Mul<V, Output = V>,
+    T: Scalar + Copy + CanLinearInterpolate<V, T>,
+{
+    for img in imgs.iter_mut() {
+        *img = inpaint::<T, V>(defects, step, img)?;
+    }
+    Ok(())
+}
+
+/// Coordinates (y, x) of the 8 neighbors `step` pixels apart inside the image.
+#[allow(clippy::cast_possible_wrap)]
+#[allow(clippy::cast_sign_loss)]
+fn neighbor_coordinates(
+    height: usize,
+    width: usize,
+    y: usize,
+    x: usize,
+    step: usize,
+) -> impl Iterator<Item = (usize, usize)> {
+    (-1_isize..=1)
+        .flat_map(|dy| (-1_isize..=1).map(move |dx| (dy, dx)))
+        .filter(|&offset| offset != (0, 0))
+        .filter_map(move |(dy, dx)| {
+            let ny = y as isize + dy * step as isize;
+            let nx = x as isize + dx * step as isize;
+            if ny < 0 || nx < 0 || ny >= height as isize || nx >= width as isize {
+                None
+            } else {
+                Some((ny as usize, nx as usize))
+            }
+        })
+}
@@ -0,0 +1,338 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Non-rigid refinement of an affine registration with free-form deformations.
+//!
+//! Slightly deformable subjects, such as skin or fabric, are not fully registered
+//! by affine motions.
+//! This second stage adds to the motion of each image a smooth displacement field,
+//! the cubic B-spline interpolation of a coarse grid of control points.
+//! A pixel `x` of the registered frame is read at `motion(x + d(x))` in the image.
+//!
+//! Control points are estimated with the same low-rank + sparse data term
+//! as the affine registration, with a Gauss-Newton step of the displacements
+//! of each image replacing the step of its motion.
+//! Differences between neighbor control points are penalized,
+//! to keep deformations smooth where the images have no texture.
+
+use nalgebra::{DMatrix, DVector, Scalar, Vector2, Vector3, Vector6};
+use std::ops::{Add, Mul};
+
+use crate::affine2d::projection_mat;
+use crate::img::gradients;
+use crate::img::interpolation::{linear, CanLinearInterpolate};
+use crate::img::registration::{shrink, CanRegister, Config};
+use crate::svd::SvdBackend;
+
+/// Default spacing in pixels between control points.
+pub const DEFAULT_SPACING: usize = 32;
+
+/// Default weight of the differences between neighbor control points.
+pub const DEFAULT_SMOOTHNESS: f32 = 1.0;
+
+/// Small weight on the displacements themselves, so that control points
+/// supported by no pixel still have a unique solution.
+const RIDGE: f32 = 1e-6;
+
+/// Smooth displacement field interpolating a grid of control points with cubic B-splines.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Deformation {
+    /// Position (x, y) of the first control point.
+    pub origin: (f32, f32),
+    /// Distance in pixels between two neighbor control points.
+    pub spacing: f32,
+    /// Displacement (x, y) of each control point, in pixels.
+    pub control_points: DMatrix<Vector2<f32>>,
+}
+
+impl Deformation {
+    /// Null deformation of the frame of an image of the given (height, width).
+    /// The grid extends one control point beyond the image on each side.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn zero(shape: (usize, usize), spacing: usize) -> Self {
+        let (height, width) = shape;
+        let spacing = spacing.max(1);
+        let points = |length: usize| length.saturating_sub(1) / spacing + 4;
+        Deformation {
+            origin: (-(spacing as f32), -(spacing as f32)),
+            spacing: spacing as f32,
+            control_points: DMatrix::from_element(points(height), points(width), Vector2::zeros()),
+        }
+    }
+
+    /// Displacement at a point (x, y).
+    /// Outside of the grid, it is extended with the displacement of its border.
+    pub fn displacement(&self, x: f32, y: f32) -> Vector2<f32> {
+        let (rows, cols) = self.control_points.shape();
+        let (row, wy) = basis((y - self.origin.1) / self.spacing, rows);
+        let (col, wx) = basis((x - self.origin.0) / self.spacing, cols);
+        let mut d = Vector2::zeros();
+        for (a, wa) in wy.iter().enumerate() {
+            for (b, wb) in wx.iter().enumerate() {
+                d += self.control_points[(row + a, col + b)] * (wa * wb);
+            }
+        }
+        d
+    }
+
+    /// Same deformation in a frame where coordinates are scaled, then translated.
+    /// This brings deformations estimated on a downscaled crop into the frame of the full images.
+    pub fn transformed(&self, scale: f32, translation: (f32, f32)) -> Self {
+        Deformation {
+            origin: (
+                scale * self.origin.0 + translation.0,
+                scale * self.origin.1 + translation.1,
+            ),
+            spacing: scale * self.spacing,
+            control_points: self.control_points.map(|d| d * scale),
+        }
+    }
+}
+
+/// First control point and weights of the 4 control points
+/// around a coordinate in units of the grid, for a grid of `n` points.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_sign_loss)]
+#[allow(clippy::cast_precision_loss)]
+fn basis(u: f32, n: usize) -> (usize, [f32; 4]) {
+    // Cells with 4 control points around them start at 1 and end at n - 2.
+    let u = u.max(1.0).min(n as f32 - 2.0);
+    let i = (u as usize).min(n - 3);
+    let t = u - i as f32;
+    let (t2, t3) = (t * t, t * t * t);
+    let weights = [
+        (1.0 - t).powi(3) / 6.0,
+        (3.0 * t3 - 6.0 * t2 + 4.0) / 6.0,
+        (-3.0 * t3 + 3.0 * t2 + 3.0 * t + 1.0) / 6.0,
+        t3 / 6.0,
+    ];
+    (i - 1, weights)
+}
+
+/// Warp an image with the given motion and deformation.
+#[allow(clippy::cast_precision_loss)]
+pub fn warp<T, V, O>(
+    img: &DMatrix<T>,
+    motion: &Vector6<f32>,
+    deformation: &Deformation,
+) -> DMatrix<O>
+where
+    O: Scalar,
+    V: Add<Output = V>,
+    f32: Mul<V, Output = V>,
+    T: Scalar + Copy + CanLinearInterpolate<V, O>,
+{
+    let motion_mat = projection_mat(motion);
+    DMatrix::from_fn(img.nrows(), img.ncols(), |i, j| {
+        let (x, y) = (j as f32, i as f32);
+        let p = Vector2::new(x, y) + deformation.displacement(x, y);
+        let pos = motion_mat * Vector3::new(p.x, p.y, 1.0);
+        linear(pos.x, pos.y, img)
+    })
+}
+
+/// Refine the registration of images with the given motions,
+/// estimating a deformation of each image, with control points `spacing` pixels apart.
+///
+/// The first image is the reference and keeps a null deformation.
+/// The `lambda`, `rho`, `max_iterations`, `threshold` and `svd_library` parameters
+/// of the configuration are the ones of the affine registration,
+/// so the SVD library must be available.
+pub fn refine<T: CanRegister>(
+    config: &Config,
+    imgs: &[DMatrix<T>],
+    motion_vec: &[Vector6<f32>],
+    spacing: usize,
+    smoothness: f32,
+) -> Vec<Deformation> {
+    let shape = match imgs.first() {
+        None => return Vec::new(),
+        Some(img) => img.shape(),
+    };
+    let mut deformations = vec![Deformation::zero(shape, spacing); imgs.len()];
+    if imgs.len() < 2 {
+        return deformations;
+    }
+    let regularization = membrane(&deformations[0], smoothness);
+
+    #[allow(clippy::cast_precision_loss)]
+    let lambda = config.lambda / (shape.0 as f32 * shape.1 as f32).sqrt();
+    let rho = config.rho;
+    let mut registered = registered_imgs(imgs, motion_vec, &deformations);
+    let (pixels_count, imgs_count) = registered.shape();
+    let mut old_imgs_a = DMatrix::zeros(pixels_count, imgs_count);
+    let mut errors = DMatrix::zeros(pixels_count, imgs_count);
+    let mut lagrange_mult_rho = DMatrix::zeros(pixels_count, imgs_count);
+    let as_img = |col: usize, mat: &DMatrix<f32>| {
+        DMatrix::from_iterator(shape.0, shape.1, mat.column(col).iter().cloned())
+    };
+    for nb_iter in 0..config.max_iterations {
+        // A-update: low-rank approximation.
+        let (mut u, singular_values, v_t) = config
+            .svd_library
+            .svd(&registered + &errors + &lagrange_mult_rho);
+        for (mut u_col, &s) in u.column_iter_mut().zip(singular_values.iter()) {
+            u_col *= shrink(1.0 / rho, s);
+        }
+        let imgs_a = u * v_t;
+
+        // e-update: L1-regularized least-squares.
+        let errors_temp = &imgs_a - &registered - &lagrange_mult_rho;
+        errors = errors_temp.map(|x| shrink(lambda / rho, x));
+
+        // Deformations update: Gauss-Newton step of the control points.
+        let residuals = errors_temp - &errors;
+        for i in 1..imgs_count {
+            let (registered_i, residuals_i) = (as_img(i, &registered), as_img(i, &residuals));
+            let inside = inside_mask(&imgs[i], &motion_vec[i], &deformations[i]);
+            if let Some(step) = control_points_step(
+                &registered_i,
+                &residuals_i,
+                &inside,
+                &deformations[i],
+                &regularization,
+            ) {
+                for (k, d) in deformations[i].control_points.iter_mut().enumerate() {
+                    *d += Vector2::new(step[2 * k], step[2 * k + 1]);
+                }
+            } else {
+                log::debug!("Singular deformation step of image {}, skipped", i);
+            }
+        }
+        registered = registered_imgs(imgs, motion_vec, &deformations);
+
+        // y-update: dual ascent.
+        lagrange_mult_rho += &registered - &imgs_a + &errors;
+
+        // Check convergence.
+        let residual = (&imgs_a - &old_imgs_a).norm() / old_imgs_a.norm().max(1e-12);
+        log::debug!("Deformation iteration {}, residual: {}", nb_iter, residual);
+        old_imgs_a = imgs_a;
+        if residual < config.threshold {
+            break;
+        }
+    }
+    deformations
+}
+
+/// Images warped in the registered frame, one per column, with intensities in [0,1].
+fn registered_imgs<T: CanRegister>(
+    imgs: &[DMatrix<T>],
+    motion_vec: &[Vector6<f32>],
+    deformations: &[Deformation],
+) -> DMatrix<f32> {
+    let columns: Vec<DVector<f32>> = imgs
+        .iter()
+        .zip(motion_vec)
+        .zip(deformations)
+        .map(|((img, motion), deformation)| {
+            let warped = warp::<T, f32, f32>(img, motion, deformation);
+            DVector::from_column_slice(warped.as_slice())
+        })
+        .collect();
+    DMatrix::from_columns(&columns)
+}
+
+/// Pixels of the registered frame whose deformed position is inside the image.
+#[allow(clippy::cast_precision_loss)]
+fn inside_mask<T: Scalar>(
+    img: &DMatrix<T>,
+    motion: &Vector6<f32>,
+    deformation: &Deformation,
+) -> DMatrix<bool> {
+    let (height, width) = img.shape();
+    let motion_mat = projection_mat(motion);
+    let (max_x, max_y) = ((width - 1) as f32, (height - 1) as f32);
+    DMatrix::from_fn(height, width, |i, j| {
+        let (x, y) = (j as f32, i as f32);
+        let p = Vector2::new(x, y) + deformation.displacement(x, y);
+        let pos = motion_mat * Vector3::new(p.x, p.y, 1.0);
+        pos.x >= 0.0 && pos.y >= 0.0 && pos.x <= max_x && pos.y <= max_y
+    })
+}
+
+/// Regularization matrix penalizing the squared differences of neighbor control points,
+/// with parameters ordered as (x, y) displacements of the control points in column major order.
+fn membrane(deformation: &Deformation, smoothness: f32) -> DMatrix<f32> {
+    let (rows, cols) = deformation.control_points.shape();
+    let n = 2 * rows * cols;
+    let mut regularization = DMatrix::identity(n, n) * RIDGE;
+    let index = |row: usize, col: usize| row + col * rows;
+    let mut link = |k: usize, l: usize| {
+        for axis in 0..2 {
+            let (a, b) = (2 * k + axis, 2 * l + axis);
+            regularization[(a, a)] += smoothness;
+            regularization[(b, b)] += smoothness;
+            regularization[(a, b)] -= smoothness;
+            regularization[(b, a)] -= smoothness;
+        }
+    };
+    for col in 0..cols {
+        for row in 0..rows {
+            if row + 1 < rows {
+                link(index(row, col), index(row + 1, col));
+            }
+            if col + 1 < cols {
+                link(index(row, col), index(row, col + 1));
+            }
+        }
+    }
+    regularization
+}
+
+/// Gauss-Newton step of the control points of an image,
+/// None if the normal equations are singular.
+#[allow(clippy::cast_precision_loss)]
+fn control_points_step(
+    registered: &DMatrix<f32>,
+    residuals: &DMatrix<f32>,
+    inside: &DMatrix<bool>,
+    deformation: &Deformation,
+    regularization: &DMatrix<f32>,
+) -> Option<DVector<f32>> {
+    let (rows, cols) = deformation.control_points.shape();
+    let control_points = deformation.control_points.as_slice();
+    let current = DVector::from_fn(2 * rows * cols, |p, _| {
+        let d = control_points[p / 2];
+        if p % 2 == 0 {
+            d.x
+        } else {
+            d.y
+        }
+    });
+    let mut hessian = regularization.clone();
+    let mut descent = -(regularization * current);
+    let grads = gradients::centered_f32(registered);
+    let mut jacobian = Vec::with_capacity(32);
+    for ((i, j), &res) in (0..registered.ncols())
+        .flat_map(|j| (0..registered.nrows()).map(move |i| (i, j)))
+        .zip(residuals.iter())
+    {
+        if !inside[(i, j)] {
+            continue;
+        }
+        let (gx, gy) = grads[(i, j)];
+        let (row, wy) = basis(
+            (i as f32 - deformation.origin.1) / deformation.spacing,
+            rows,
+        );
+        let (col, wx) = basis(
+            (j as f32 - deformation.origin.0) / deformation.spacing,
+            cols,
+        );
+        jacobian.clear();
+        for (a, wa) in wy.iter().enumerate() {
+            for (b, wb) in wx.iter().enumerate() {
+                let k = (row + a) + (col + b) * rows;
+                jacobian.push((2 * k, gx * wa * wb));
+                jacobian.push((2 * k + 1, gy * wa * wb));
+            }
+        }
+        for &(p, jp) in jacobian.iter() {
+            descent[p] += jp * res;
+            for &(q, jq) in jacobian.iter() {
+                hessian[(p, q)] += jp * jq;
+            }
+        }
+    }
+    Some(hessian.cholesky()?.solve(&descent))
+}
@@ -0,0 +1,222 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Radial lens distortion, which affine motions cannot absorb.
+//!
+//! Wide-angle lenses bend straight lines, increasingly with the distance to the center.
+//! When the camera moves, the same point of the scene is bent differently in each image,
+//! leaving systematic residuals after an affine registration.
+//! The distortion is modeled by a polynomial of the radius:
+//! a point `p` of the undistorted image is seen at `c + (p - c) (1 + k1 r^2 + k2 r^4)`
+//! in the captured image, where `c` is the center of distortion and `r`
+//! the distance to the center, normalized by the half diagonal of the image.
+//!
+//! Images are undistorted before their registration,
+//! and registered images are read at the distorted position of their motion.
+
+use nalgebra::{DMatrix, DVector, Matrix2, Scalar, Vector2, Vector3, Vector6};
+use std::ops::{Add, Mul};
+
+use crate::affine2d::projection_mat;
+use crate::img::interpolation::{linear, CanLinearInterpolate};
+use crate::img::metrics::inside_mask;
+use crate::img::registration::CanRegister;
+
+/// Number of fixed point iterations to invert the distortion.
+const UNDISTORT_ITERATIONS: usize = 10;
+
+/// Maximum number of Gauss-Newton iterations of the estimation.
+const MAX_ITERATIONS: usize = 20;
+
+/// Step of the finite differences of the estimation.
+const FINITE_DIFFERENCE_STEP: f32 = 1e-3;
+
+/// Radial distortion of a lens.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Distortion {
+    pub k1: f32,
+    pub k2: f32,
+    /// Center (x, y) of the distortion, in pixels.
+    pub center: (f32, f32),
+    /// Distance to the center, in pixels, of a normalized radius of 1.
+    pub radius: f32,
+}
+
+impl Distortion {
+    /// Distortion centered on images of the given (height, width),
+    /// with radii normalized by their half diagonal.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn centered(k1: f32, k2: f32, shape: (usize, usize)) -> Self {
+        let (cy, cx) = ((shape.0 as f32 - 1.0) / 2.0, (shape.1 as f32 - 1.0) / 2.0);
+        Distortion {
+            k1,
+            k2,
+            center: (cx, cy),
+            radius: (cx * cx + cy * cy).sqrt().max(1.0),
+        }
+    }
+
+    /// Position in the captured image of a point of the undistorted image.
+    pub fn distort(&self, x: f32, y: f32) -> (f32, f32) {
+        let (dx, dy) = (x - self.center.0, y - self.center.1);
+        let factor = self.factor(dx, dy);
+        (self.center.0 + factor * dx, self.center.1 + factor * dy)
+    }
+
+    /// Position in the undistorted image of a point of the captured image.
+    pub fn undistort(&self, x: f32, y: f32) -> (f32, f32) {
+        let (dx, dy) = (x - self.center.0, y - self.center.1);
+        let (mut ux, mut uy) = (dx, dy);
+        for _ in 0..UNDISTORT_ITERATIONS {
+            let factor = self.factor(ux, uy);
+            ux = dx / factor;
+            uy = dy / factor;
+        }
+        (self.center.0 + ux, self.center.1 + uy)
+    }
+
+    /// Same distortion in a frame where coordinates are scaled, then translated.
+    /// This brings a distortion of the full images into the frame of a downscaled crop.
+    pub fn transformed(&self, scale: f32, translation: (f32, f32)) -> Self {
+        Distortion {
+            center: (
+                scale * self.center.0 + translation.0,
+                scale * self.center.1 + translation.1,
+            ),
+            radius: scale * self.radius,
+            ..*self
+        }
+    }
+
+    /// Scaling of the offset (dx, dy) to the center.
+    fn factor(&self, dx: f32, dy: f32) -> f32 {
+        let r2 = (dx * dx + dy * dy) / (self.radius * self.radius);
+        1.0 + self.k1 * r2 + self.k2 * r2 * r2
+    }
+}
+
+/// Undistort an image.
+#[allow(clippy::cast_precision_loss)]
+pub fn undistort_img<T, V, O>(img: &DMatrix<T>, distortion: &Distortion) -> DMatrix<O>
+where
+    O: Scalar,
+    V: Add<Output = V>,
+    f32: Mul<V, Output = V>,
+    T: Scalar + Copy + CanLinearInterpolate<V, O>,
+{
+    DMatrix::from_fn(img.nrows(), img.ncols(), |i, j| {
+        let (x, y) = distortion.distort(j as f32, i as f32);
+        linear(x, y, img)
+    })
+}
+
+/// Warp a captured image with the given motion of its undistorted image.
+#[allow(clippy::cast_precision_loss)]
+pub fn warp<T, V, O>(img: &DMatrix<T>, motion: &Vector6<f32>, distortion: &Distortion) -> DMatrix<O>
+where
+    O: Scalar,
+    V: Add<Output = V>,
+    f32: Mul<V, Output = V>,
+    T: Scalar + Copy + CanLinearInterpolate<V, O>,
+{
+    let motion_mat = projection_mat(motion);
+    DMatrix::from_fn(img.nrows(), img.ncols(), |i, j| {
+        let p = motion_mat * Vector3::new(j as f32, i as f32, 1.0);
+        let (x, y) = distortion.distort(p.x, p.y);
+        linear(x, y, img)
+    })
+}
+
+/// Estimate the coefficients of a distortion shared by captured images,
+/// registered with the given motions, starting from the `initial` distortion,
+/// whose center and radius are kept.
+///
+/// The coefficients minimize the differences between the registered images and their mean,
+/// with Gauss-Newton iterations and finite differences.
+/// They are only observable when the images move significantly relative to each other,
+/// such as in panning sequences.
+pub fn estimate<T: CanRegister>(
+    imgs: &[DMatrix<T>],
+    motion_vec: &[Vector6<f32>],
+    initial: Distortion,
+) -> Distortion {
+    let shape = match imgs.first() {
+        None => return initial,
+        Some(img) => img.shape(),
+    };
+    if imgs.len() < 2 {
+        return initial;
+    }
+    // Pixels seen by all images.
+    let mask = motion_vec.iter().fold(
+        DMatrix::from_element(shape.0, shape.1, true),
+        |mask, motion| mask.zip_map(&inside_mask(motion, shape), |a, b| a && b),
+    );
+    let mut distortion = initial;
+    let with = |d: &Distortion, k: Vector2<f32>| Distortion {
+        k1: k.x,
+        k2: k.y,
+        ..*d
+    };
+    for iteration in 0..MAX_ITERATIONS {
+        let k = Vector2::new(distortion.k1, distortion.k2);
+        let residuals = dispersion(imgs, motion_vec, &distortion, &mask);
+        let h = FINITE_DIFFERENCE_STEP;
+        let jacobian: Vec<DVector<f32>> = (0..2)
+            .map(|p| {
+                let offset = Vector2::from_fn(|q, _| if p == q { h } else { 0.0 });
+                let plus = dispersion(imgs, motion_vec, &with(&distortion, k + offset), &mask);
+                let minus = dispersion(imgs, motion_vec, &with(&distortion, k - offset), &mask);
+                (plus - minus) / (2.0 * h)
+            })
+            .collect();
+        let hessian = Matrix2::from_fn(|p, q| jacobian[p].dot(&jacobian[q]));
+        let gradient = Vector2::from_fn(|p, _| jacobian[p].dot(&residuals));
+        let step = match hessian.cholesky() {
+            None => break,
+            Some(chol) => -chol.solve(&gradient),
+        };
+        distortion = with(&distortion, k + step);
+        log::debug!(
+            "Distortion iteration {}: k1 = {}, k2 = {}",
+            iteration,
+            distortion.k1,
+            distortion.k2
+        );
+        if step.norm() < 1e-5 {
+            break;
+        }
+    }
+    distortion
+}
+
+/// Differences between the registered images and their mean, on the pixels of the mask.
+fn dispersion<T: CanRegister>(
+    imgs: &[DMatrix<T>],
+    motion_vec: &[Vector6<f32>],
+    distortion: &Distortion,
+    mask: &DMatrix<bool>,
+) -> DVector<f32> {
+    let registered: Vec<DMatrix<f32>> = imgs
+        .iter()
+        .zip(motion_vec)
+        .map(|(img, motion)| warp::<T, f32, f32>(img, motion, distortion))
+        .collect();
+    let mut mean = DMatrix::zeros(mask.nrows(), mask.ncols());
+    for img in registered.iter() {
+        mean += img;
+    }
+    #[allow(clippy::cast_precision_loss)]
+    let count = registered.len() as f32;
+    mean /= count;
+    let residuals: Vec<f32> = registered
+        .iter()
+        .flat_map(|img| {
+            img.iter()
+                .zip(mean.iter())
+                .zip(mask.iter())
+                .filter(|(_, inside)| **inside)
+                .map(|((&x, &m), _)| x - m)
+        })
+        .collect();
+    DVector::from_vec(residuals)
+}
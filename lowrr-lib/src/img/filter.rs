@@ -4,11 +4,15 @@
 
 use nalgebra::DMatrix;
 
+use crate::img::normalization::CanNormalize;
+
 /// Direct convolution with the following 3x3 kernel:
 ///
+/// ```text
 ///  1   | 0 1 0 |
 ///  - * | 1 2 1 |
 ///  6   | 0 1 0 |
+/// ```
 pub fn smooth(img: &DMatrix<u8>) -> DMatrix<u8> {
     let (nrows, ncols) = img.shape();
     let mut smoothed = DMatrix::zeros(nrows, ncols);
@@ -58,7 +62,7 @@ pub fn conv_2d_direct_same(img: &DMatrix<u8>, kernel: &DMatrix<f32>) -> DMatrix<
             }
         }
     }
-    result_f32.map(|x| x.round().max(0.0).min(255.0) as u8)
+    result_f32.map(|x| x.round().clamp(0.0, 255.0) as u8)
 }
 
 /// Compute 4th order centered gradients:
@@ -97,7 +101,7 @@ pub fn conv_2d_direct_same_f32(img: &DMatrix<f32>, kernel: &DMatrix<f32>) -> DMa
                     let img_j = (j as isize) + (kj as isize) - shift_cols;
                     let img_j = img_j.min(ncols as isize - 1).max(0) as usize;
 
-                    result[(i, j)] += img[(img_i, img_j)] as f32 * kernel[(ki, kj)];
+                    result[(i, j)] += img[(img_i, img_j)] * kernel[(ki, kj)];
                 }
             }
         }
@@ -122,3 +126,75 @@ pub fn gaussian_kernel(sigma: f32, size: usize) -> DMatrix<f32> {
     let kernel = DMatrix::from_fn(size, size, gauss_2d);
     kernel / sum_kernel
 }
+
+/// Gaussian blur of standard deviation `sigma`, by repeating the border elements.
+/// It is applied as two 1D convolutions since the kernel is separable.
+pub fn gaussian_blur<T: CanNormalize>(img: &DMatrix<T>, sigma: f32) -> DMatrix<T> {
+    let img_f32 = img.map(|x| x.to_level() as f32);
+    let blurred = gaussian_blur_f32(&img_f32, sigma);
+    blurred.map(|x| T::from_level(x.round().max(0.0) as usize))
+}
+
+/// Gaussian blur of a floating point image, by repeating the border elements.
+pub fn gaussian_blur_f32(img: &DMatrix<f32>, sigma: f32) -> DMatrix<f32> {
+    assert!(sigma > 0.0);
+    let radius = (3.0 * sigma).ceil() as usize;
+    let exp_coef = -1.0 / (2.0 * sigma * sigma);
+    let kernel_x = DMatrix::from_fn(1, 2 * radius + 1, |_, j| {
+        let x = j as f32 - radius as f32;
+        (exp_coef * x * x).exp()
+    });
+    let kernel_x = &kernel_x / kernel_x.sum();
+    let kernel_y = kernel_x.transpose();
+    conv_2d_direct_same_f32(&conv_2d_direct_same_f32(img, &kernel_x), &kernel_y)
+}
+
+/// Median of each 3x3 neighborhood, by repeating the border elements.
+pub fn median_3x3<T: CanNormalize>(img: &DMatrix<T>) -> DMatrix<T> {
+    let (nrows, ncols) = img.shape();
+    DMatrix::from_fn(nrows, ncols, |i, j| {
+        let mut neighbors = [0; 9];
+        for (k, neighbor) in neighbors.iter_mut().enumerate() {
+            let ni = (i + k / 3).saturating_sub(1).min(nrows - 1);
+            let nj = (j + k % 3).saturating_sub(1).min(ncols - 1);
+            *neighbor = img[(ni, nj)].to_level();
+        }
+        neighbors.sort_unstable();
+        T::from_level(neighbors[4])
+    })
+}
+
+/// Bilateral filter, averaging the neighbors with a gaussian weight
+/// of standard deviation `sigma_space` for their distance,
+/// and `sigma_range` for their intensity difference, in [0,1].
+/// This smooths noise while preserving edges.
+pub fn bilateral<T: CanNormalize>(
+    img: &DMatrix<T>,
+    sigma_space: f32,
+    sigma_range: f32,
+) -> DMatrix<T> {
+    assert!(sigma_space > 0.0 && sigma_range > 0.0);
+    let (nrows, ncols) = img.shape();
+    let radius = (2.0 * sigma_space).ceil() as isize;
+    let space_coef = -1.0 / (2.0 * sigma_space * sigma_space);
+    let sigma_range = sigma_range * (T::LEVELS - 1) as f32;
+    let range_coef = -1.0 / (2.0 * sigma_range * sigma_range);
+    DMatrix::from_fn(nrows, ncols, |i, j| {
+        let center = img[(i, j)].to_level() as f32;
+        let mut sum = 0.0;
+        let mut sum_weights = 0.0;
+        for di in -radius..=radius {
+            for dj in -radius..=radius {
+                let ni = (i as isize + di).clamp(0, nrows as isize - 1) as usize;
+                let nj = (j as isize + dj).clamp(0, ncols as isize - 1) as usize;
+                let value = img[(ni, nj)].to_level() as f32;
+                let dist_sqr = (di * di + dj * dj) as f32;
+                let diff = value - center;
+                let weight = (space_coef * dist_sqr + range_coef * diff * diff).exp();
+                sum += weight * value;
+                sum_weights += weight;
+            }
+        }
+        T::from_level((sum / sum_weights).round() as usize)
+    })
+}
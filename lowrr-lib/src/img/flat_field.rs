@@ -0,0 +1,124 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Flat-field correction, removing the vignetting of images before their registration.
+//!
+//! Vignetting is the same for all images, so it does not move with them,
+//! and ends up in the sparse errors of the registration.
+
+use nalgebra::{DMatrix, Matrix4, Vector4};
+use thiserror::Error;
+
+use crate::img::normalization::CanNormalize;
+
+#[derive(Error, Debug)]
+pub enum FlatFieldError {
+    #[error("Flat-field of size {flat:?} does not match the size {image:?} of the images")]
+    Size {
+        flat: (usize, usize),
+        image: (usize, usize),
+    },
+    #[error("The flat-field is black")]
+    Black,
+}
+
+/// Lowest value of a normalized flat-field, to avoid amplifying dark corners too much.
+const FLAT_MIN: f32 = 0.05;
+
+/// Flat-field from an image of a uniformly lit target, normalized to a mean of 1.
+#[allow(clippy::cast_precision_loss)]
+pub fn from_image<T: CanNormalize>(img: &DMatrix<T>) -> Result<DMatrix<f32>, FlatFieldError> {
+    let flat = img.map(|x| x.to_level() as f32);
+    let mean = flat.mean();
+    if mean <= 0.0 {
+        return Err(FlatFieldError::Black);
+    }
+    Ok(flat.map(|x| (x / mean).max(FLAT_MIN)))
+}
+
+/// Flat-field fitted on the mean image of a stack,
+/// as a radial polynomial k0 + k1 r^2 + k2 r^4 + k3 r^6 centered on the image.
+///
+/// The content of the images averages out over the stack,
+/// leaving the vignetting shared by all images.
+/// The flat-field is normalized to 1 at the center of the image.
+#[allow(clippy::cast_precision_loss)]
+pub fn fit_radial<T: CanNormalize>(imgs: &[DMatrix<T>]) -> Result<DMatrix<f32>, FlatFieldError> {
+    let (height, width) = match imgs.first() {
+        Some(img) => img.shape(),
+        None => return Err(FlatFieldError::Black),
+    };
+    if let Some(img) = imgs.iter().find(|img| img.shape() != (height, width)) {
+        return Err(FlatFieldError::Size {
+            flat: (height, width),
+            image: img.shape(),
+        });
+    }
+
+    // Mean image of the stack.
+    let mut mean = DMatrix::<f64>::zeros(height, width);
+    for img in imgs.iter() {
+        mean.zip_apply(img, |m, x| m + x.to_level() as f64);
+    }
+    mean /= imgs.len() as f64;
+
+    // Least squares fit of the polynomial, with normal equations.
+    let r2 = radius_squared(height, width);
+    let mut ata = Matrix4::<f64>::zeros();
+    let mut atb = Vector4::<f64>::zeros();
+    for ((y, x), &m) in index_iter(height, width).zip(mean.iter()) {
+        let a = powers(r2(y, x));
+        ata += a * a.transpose();
+        atb += a * m;
+    }
+    let k = ata.cholesky().ok_or(FlatFieldError::Black)?.solve(&atb);
+    if k[0] <= 0.0 {
+        return Err(FlatFieldError::Black);
+    }
+    Ok(DMatrix::from_fn(height, width, |y, x| {
+        ((powers(r2(y, x)).dot(&k) / k[0]) as f32).max(FLAT_MIN)
+    }))
+}
+
+/// Divide images by a flat-field, saturating intensities out of range.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_precision_loss)]
+#[allow(clippy::cast_sign_loss)]
+pub fn correct<T: CanNormalize>(
+    flat: &DMatrix<f32>,
+    imgs: &mut [DMatrix<T>],
+) -> Result<(), FlatFieldError> {
+    if let Some(img) = imgs.iter().find(|img| img.shape() != flat.shape()) {
+        return Err(FlatFieldError::Size {
+            flat: flat.shape(),
+            image: img.shape(),
+        });
+    }
+    for img in imgs.iter_mut() {
+        img.zip_apply(flat, |x, f| {
+            T::from_level((x.to_level() as f32 / f).round() as usize)
+        });
+    }
+    Ok(())
+}
+
+/// Squared distance to the center of the image,
+/// normalized to 1 at the corners.
+#[allow(clippy::cast_precision_loss)]
+fn radius_squared(height: usize, width: usize) -> impl Fn(usize, usize) -> f64 {
+    let (cy, cx) = ((height as f64 - 1.0) / 2.0, (width as f64 - 1.0) / 2.0);
+    let max_r2 = (cy * cy + cx * cx).max(1.0);
+    move |y, x| {
+        let (dy, dx) = (y as f64 - cy, x as f64 - cx);
+        (dy * dy + dx * dx) / max_r2
+    }
+}
+
+/// (1, r^2, r^4, r^6)
+fn powers(r2: f64) -> Vector4<f64> {
+    Vector4::new(1.0, r2, r2 * r2, r2 * r2 * r2)
+}
+
+/// Coordinates (y, x) in the column-major order of nalgebra matrices.
+fn index_iter(height: usize, width: usize) -> impl Iterator<Item = (usize, usize)> {
+    (0..width).flat_map(move |x| (0..height).map(move |y| (y, x)))
+}
@@ -0,0 +1,215 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Exposure fusion of a registered stack of bracketed images (Mertens et al.).
+//!
+//! Each pixel of each image is weighted by its contrast, its saturation
+//! and how well exposed it is, and the images are blended with these weights
+//! in a laplacian pyramid, to avoid seams where the weights change abruptly.
+//! The result is a displayable image, without going through an HDR radiance map.
+
+use nalgebra::DMatrix;
+
+use crate::img::filter::{conv_2d_direct_same_f32, gaussian_blur_f32};
+use crate::img::normalization::CanNormalize;
+
+/// Standard deviation of the well-exposedness gaussian, around the middle intensity 0.5.
+const EXPOSEDNESS_SIGMA: f32 = 0.2;
+
+/// Small weight added everywhere, so that pixels badly exposed in all images
+/// still get an average of the images.
+const MIN_WEIGHT: f32 = 1e-12;
+
+/// Size of the smallest side of the coarsest level of the pyramids.
+const MIN_LEVEL_SIZE: usize = 8;
+
+/// Exposure fusion of registered gray images.
+/// Return an empty matrix if there is no image.
+pub fn fuse_gray<T: CanNormalize>(imgs: &[DMatrix<T>]) -> DMatrix<T> {
+    let channels: Vec<Vec<DMatrix<f32>>> = imgs.iter().map(|im| vec![to_unit(im)]).collect();
+    match fuse(&channels).first() {
+        None => DMatrix::from_element(0, 0, T::from_level(0)),
+        Some(fused) => from_unit(fused),
+    }
+}
+
+/// Exposure fusion of registered RGB images.
+/// Return an empty matrix if there is no image.
+pub fn fuse_rgb<T: CanNormalize>(imgs: &[DMatrix<(T, T, T)>]) -> DMatrix<(T, T, T)> {
+    let channels: Vec<Vec<DMatrix<f32>>> = imgs
+        .iter()
+        .map(|im| {
+            vec![
+                to_unit(&im.map(|p| p.0)),
+                to_unit(&im.map(|p| p.1)),
+                to_unit(&im.map(|p| p.2)),
+            ]
+        })
+        .collect();
+    match fuse(&channels).as_slice() {
+        [red, green, blue] => {
+            let (red, green, blue) = (from_unit(red), from_unit(green), from_unit(blue));
+            red.zip_zip_map(&green, &blue, |r, g, b| (r, g, b))
+        }
+        _ => DMatrix::from_element(0, 0, (T::from_level(0), T::from_level(0), T::from_level(0))),
+    }
+}
+
+/// Exposure fusion of images given as their channels, with intensities in [0, 1].
+/// All images must have the same number of channels and the same size.
+/// Return the channels of the fused image, none if there is no image.
+pub fn fuse(imgs: &[Vec<DMatrix<f32>>]) -> Vec<DMatrix<f32>> {
+    let (nrows, ncols) = match imgs.first().and_then(|channels| channels.first()) {
+        None => return Vec::new(),
+        Some(channel) => channel.shape(),
+    };
+    let levels = pyramid_levels(nrows.min(ncols));
+
+    // Weights of each image, normalized to sum to 1 at each pixel.
+    let mut weights: Vec<DMatrix<f32>> = imgs.iter().map(|channels| weights(channels)).collect();
+    let mut total = DMatrix::zeros(nrows, ncols);
+    weights.iter().for_each(|w| total += w);
+    weights
+        .iter_mut()
+        .for_each(|w| w.component_div_assign(&total));
+
+    // Blend the laplacian pyramids of the images with the gaussian pyramids of their weights.
+    let channels_count = imgs[0].len();
+    let mut fused: Vec<Vec<DMatrix<f32>>> = (0..channels_count)
+        .map(|_| Vec::with_capacity(levels))
+        .collect();
+    for (channels, weight) in imgs.iter().zip(&weights) {
+        let weight_pyramid = gaussian_pyramid(weight.clone(), levels);
+        for (channel, fused_channel) in channels.iter().zip(fused.iter_mut()) {
+            let blended = laplacian_pyramid(channel.clone(), levels)
+                .into_iter()
+                .zip(&weight_pyramid)
+                .map(|(lap, w)| lap.component_mul(w));
+            if fused_channel.is_empty() {
+                fused_channel.extend(blended);
+            } else {
+                fused_channel
+                    .iter_mut()
+                    .zip(blended)
+                    .for_each(|(acc, b)| *acc += b);
+            }
+        }
+    }
+    fused.into_iter().map(collapse).collect()
+}
+
+/// Mertens weight of each pixel of an image given as its channels, with intensities in [0, 1]:
+/// the product of its contrast, its saturation (for color images) and its well-exposedness.
+pub fn weights(channels: &[DMatrix<f32>]) -> DMatrix<f32> {
+    let (nrows, ncols) = channels[0].shape();
+    let count = channels.len() as f32;
+    let mut mean = DMatrix::zeros(nrows, ncols);
+    channels.iter().for_each(|c| mean += c);
+    mean /= count;
+
+    // Contrast: absolute value of the laplacian of the gray image.
+    let laplacian_kernel =
+        DMatrix::from_row_slice(3, 3, &[0.0, 1.0, 0.0, 1.0, -4.0, 1.0, 0.0, 1.0, 0.0]);
+    let mut weights = conv_2d_direct_same_f32(&mean, &laplacian_kernel).map(f32::abs);
+
+    // Saturation: standard deviation of the channels.
+    if channels.len() > 1 {
+        let mut variance: DMatrix<f32> = DMatrix::zeros(nrows, ncols);
+        for c in channels {
+            variance.zip_zip_apply(c, &mean, |v, x, m| v + (x - m) * (x - m));
+        }
+        weights.zip_apply(&variance, |w, v| w * (v / count).sqrt());
+    }
+
+    // Well-exposedness: closeness of each channel to the middle intensity.
+    let coef = -1.0 / (2.0 * EXPOSEDNESS_SIGMA * EXPOSEDNESS_SIGMA);
+    for c in channels {
+        weights.zip_apply(c, |w, x| w * (coef * (x - 0.5) * (x - 0.5)).exp());
+    }
+    weights.map(|w| w + MIN_WEIGHT)
+}
+
+/// Number of pyramid levels for an image with the given smallest side.
+fn pyramid_levels(min_side: usize) -> usize {
+    let mut levels = 1;
+    let mut side = min_side;
+    while side / 2 >= MIN_LEVEL_SIZE {
+        side = side.div_ceil(2);
+        levels += 1;
+    }
+    levels
+}
+
+/// Gaussian pyramid of an image, with decreasing resolutions.
+fn gaussian_pyramid(img: DMatrix<f32>, levels: usize) -> Vec<DMatrix<f32>> {
+    let mut pyramid = Vec::with_capacity(levels);
+    pyramid.push(img);
+    while pyramid.len() < levels {
+        let next = downsample(pyramid.last().unwrap());
+        pyramid.push(next);
+    }
+    pyramid
+}
+
+/// Laplacian pyramid of an image, with decreasing resolutions.
+/// The last level is the coarsest level of the gaussian pyramid.
+fn laplacian_pyramid(img: DMatrix<f32>, levels: usize) -> Vec<DMatrix<f32>> {
+    let mut pyramid = gaussian_pyramid(img, levels);
+    for i in 0..levels - 1 {
+        let upsampled = upsample(&pyramid[i + 1], pyramid[i].shape());
+        pyramid[i] -= upsampled;
+    }
+    pyramid
+}
+
+/// Image reconstructed from its laplacian pyramid, clamped to [0, 1].
+fn collapse(pyramid: Vec<DMatrix<f32>>) -> DMatrix<f32> {
+    let mut levels = pyramid.into_iter().rev();
+    let coarsest = levels.next().expect("The pyramid has at least one level");
+    let img = levels.fold(coarsest, |acc, lap| upsample(&acc, lap.shape()) + lap);
+    img.map(|x| x.clamp(0.0, 1.0))
+}
+
+/// Blur and keep one pixel out of two in each direction, rounding the size up.
+fn downsample(img: &DMatrix<f32>) -> DMatrix<f32> {
+    let blurred = gaussian_blur_f32(img, 1.0);
+    let (nrows, ncols) = img.shape();
+    DMatrix::from_fn(nrows.div_ceil(2), ncols.div_ceil(2), |i, j| {
+        blurred[(2 * i, 2 * j)]
+    })
+}
+
+/// Bilinear upsampling of a downsampled image back to the given (rows, columns) shape.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_precision_loss)]
+#[allow(clippy::cast_sign_loss)]
+fn upsample(img: &DMatrix<f32>, shape: (usize, usize)) -> DMatrix<f32> {
+    let (nrows, ncols) = img.shape();
+    DMatrix::from_fn(shape.0, shape.1, |i, j| {
+        // Pixel k of the downsampled image is at pixel 2k of the full image.
+        let y = (i as f32 / 2.0).min((nrows - 1) as f32);
+        let x = (j as f32 / 2.0).min((ncols - 1) as f32);
+        let (v, u) = (y as usize, x as usize);
+        let (v1, u1) = ((v + 1).min(nrows - 1), (u + 1).min(ncols - 1));
+        let (a, b) = (x - u as f32, y - v as f32);
+        (1.0 - a) * (1.0 - b) * img[(v, u)]
+            + a * (1.0 - b) * img[(v, u1)]
+            + (1.0 - a) * b * img[(v1, u)]
+            + a * b * img[(v1, u1)]
+    })
+}
+
+/// Intensities of an image in [0, 1].
+#[allow(clippy::cast_precision_loss)]
+fn to_unit<T: CanNormalize>(img: &DMatrix<T>) -> DMatrix<f32> {
+    let max = (T::LEVELS - 1) as f32;
+    img.map(|x| x.to_level() as f32 / max)
+}
+
+/// Image from intensities in [0, 1].
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_precision_loss)]
+#[allow(clippy::cast_sign_loss)]
+fn from_unit<T: CanNormalize>(img: &DMatrix<f32>) -> DMatrix<T> {
+    let max = (T::LEVELS - 1) as f32;
+    img.map(|x| T::from_level((x * max).round().max(0.0) as usize))
+}
@@ -5,6 +5,8 @@
 use nalgebra::{DMatrix, Scalar};
 use std::ops::{Add, Div, Mul, Sub};
 
+use crate::img::normalization::CanNormalize;
+
 /// Compute a centered gradient.
 ///
 /// 1/2 * ( img(i+1,j) - img(i-1,j), img(i,j+1) - img(i,j-1) )
@@ -291,3 +293,33 @@ pub fn bloc_squared_norm(a: u8, b: u8, c: u8, d: u8) -> u16 {
     // I have checked that the max value is in u16.
     ((dx * dx + dy * dy) / 4) as u16
 }
+
+/// Norm of the centered gradient, with the same pixel type as the image.
+///
+/// Norms are stretched such that the highest one is the maximum intensity,
+/// since gradients of natural images are usually much lower than intensities.
+/// Gradients of pixels at the border of the image are set to 0.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_precision_loss)]
+#[allow(clippy::cast_sign_loss)]
+pub fn magnitude<T: CanNormalize>(img: &DMatrix<T>) -> DMatrix<T> {
+    let (nb_rows, nb_cols) = img.shape();
+    let mut norms = DMatrix::zeros(nb_rows, nb_cols);
+    if nb_rows >= 3 && nb_cols >= 3 {
+        let level = |i, j| img[(i, j)].to_level() as f32;
+        for j in 1..nb_cols - 1 {
+            for i in 1..nb_rows - 1 {
+                let gx = 0.5 * (level(i, j + 1) - level(i, j - 1));
+                let gy = 0.5 * (level(i + 1, j) - level(i - 1, j));
+                norms[(i, j)] = (gx * gx + gy * gy).sqrt();
+            }
+        }
+    }
+    let max_norm = norms.max();
+    let scale = if max_norm > 0.0 {
+        (T::LEVELS - 1) as f32 / max_norm
+    } else {
+        0.0
+    };
+    norms.map(|norm: f32| T::from_level((scale * norm).round() as usize))
+}
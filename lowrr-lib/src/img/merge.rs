@@ -0,0 +1,138 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Merge of a registered stack into a single frame with less noise,
+//! such as a burst of photos of the same scene.
+//!
+//! Both methods are robust to pixels that do not match in some images,
+//! because of moving objects or of residual misalignments.
+
+use nalgebra::DMatrix;
+
+use crate::img::normalization::CanNormalize;
+
+/// Fraction of the lowest and of the highest values of each pixel
+/// ignored by the trimmed mean.
+pub const TRIM_FRACTION: f32 = 0.25;
+
+/// Width of the neighborhood in which the Wiener merge compares an image to the reference.
+const WIENER_WINDOW: usize = 3;
+
+/// Strength of the Wiener merge: the higher, the more different pixels are still merged.
+const WIENER_STRENGTH: f32 = 4.0;
+
+/// Robust per-pixel estimator merging registered images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Merge {
+    /// Mean of the values of each pixel, without the lowest and highest ones.
+    #[default]
+    TrimmedMean,
+    /// Average of the images and the reference (first) image,
+    /// weighted by how well they match around each pixel compared to the noise level.
+    Wiener,
+}
+
+impl std::str::FromStr for Merge {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "trimmed-mean" => Ok(Merge::TrimmedMean),
+            "wiener" => Ok(Merge::Wiener),
+            _ => Err(format!(
+                "Unknown merge method \"{}\", expecting trimmed-mean or wiener",
+                s
+            )),
+        }
+    }
+}
+
+/// Merge registered images into a single frame.
+/// Return an empty matrix if there is no image.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_sign_loss)]
+pub fn merge<T: CanNormalize>(method: Merge, imgs: &[DMatrix<T>]) -> DMatrix<T> {
+    let merged = match method {
+        Merge::TrimmedMean => trimmed_mean(TRIM_FRACTION, imgs),
+        Merge::Wiener => wiener(imgs),
+    };
+    merged.map(|x| T::from_level(x.round().max(0.0) as usize))
+}
+
+/// Mean of each pixel, ignoring the given fraction of its lowest values
+/// and the same fraction of its highest values, in intensity levels of `T`.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_precision_loss)]
+#[allow(clippy::cast_sign_loss)]
+pub fn trimmed_mean<T: CanNormalize>(fraction: f32, imgs: &[DMatrix<T>]) -> DMatrix<f32> {
+    let (nrows, ncols) = match imgs.first() {
+        None => return DMatrix::zeros(0, 0),
+        Some(img) => img.shape(),
+    };
+    let trimmed = ((imgs.len() as f32 * fraction) as usize).min((imgs.len() - 1) / 2);
+    let kept = imgs.len() - 2 * trimmed;
+    let mut values = Vec::with_capacity(imgs.len());
+    DMatrix::from_fn(nrows, ncols, |i, j| {
+        values.clear();
+        values.extend(imgs.iter().map(|img| img[(i, j)].to_level()));
+        values.sort_unstable();
+        let sum: usize = values[trimmed..trimmed + kept].iter().sum();
+        sum as f32 / kept as f32
+    })
+}
+
+/// Pairwise Wiener merge with the reference image, in intensity levels of `T`.
+///
+/// Each image moves the reference toward it, less where they differ in the neighborhood
+/// of a pixel: the weight of an image is `D / (D + c * noise)`,
+/// where `D` is the mean squared difference with the reference in a small window,
+/// and `noise` the variance of the noise estimated on the whole stack.
+#[allow(clippy::cast_precision_loss)]
+pub fn wiener<T: CanNormalize>(imgs: &[DMatrix<T>]) -> DMatrix<f32> {
+    let reference = match imgs.first() {
+        None => return DMatrix::zeros(0, 0),
+        Some(img) => img.map(|x| x.to_level() as f32),
+    };
+    let differences: Vec<DMatrix<f32>> = imgs[1..]
+        .iter()
+        .map(|img| img.zip_map(&reference, |x, r| x.to_level() as f32 - r))
+        .collect();
+    let distances: Vec<DMatrix<f32>> = differences
+        .iter()
+        .map(|diff| window_mean(&diff.map(|d| d * d)))
+        .collect();
+    let noise = noise_variance(&distances);
+    // Mean of the reference and of the images moved toward it,
+    // each image contributing reference + (1 - weight) * difference.
+    let count = imgs.len() as f32;
+    let mut merged = reference;
+    for (diff, distance) in differences.iter().zip(&distances) {
+        merged.zip_zip_apply(diff, distance, |m, d, dist| {
+            let weight = dist / (dist + WIENER_STRENGTH * noise).max(f32::EPSILON);
+            m + (1.0 - weight) * d / count
+        });
+    }
+    merged
+}
+
+/// Variance of the noise of each image, from the median of the windowed squared
+/// differences between images and the reference, which mostly match once registered.
+/// The difference of two images has twice the variance of their noise.
+fn noise_variance(distances: &[DMatrix<f32>]) -> f32 {
+    let mut values: Vec<f32> = distances.iter().flat_map(|d| d.iter().cloned()).collect();
+    if values.is_empty() {
+        return 0.0;
+    }
+    let middle = values.len() / 2;
+    let (_, median, _) = values.select_nth_unstable_by(middle, |a, b| a.total_cmp(b));
+    *median / 2.0
+}
+
+/// Mean of each `WIENER_WINDOW` x `WIENER_WINDOW` neighborhood, by repeating the border elements.
+#[allow(clippy::cast_precision_loss)]
+fn window_mean(img: &DMatrix<f32>) -> DMatrix<f32> {
+    let kernel = DMatrix::repeat(
+        WIENER_WINDOW,
+        WIENER_WINDOW,
+        1.0 / (WIENER_WINDOW * WIENER_WINDOW) as f32,
+    );
+    crate::img::filter::conv_2d_direct_same_f32(img, &kernel)
+}
@@ -0,0 +1,168 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Similarity metrics between the images of a stack and the first one,
+//! before and after registration, to check the quality of a registration.
+//!
+//! Intensities are normalized in [0,1], and after registration,
+//! only the pixels seen by both images are compared.
+
+use nalgebra::{DMatrix, Vector3, Vector6};
+
+use crate::affine2d::projection_mat;
+use crate::img::interpolation::CanLinearInterpolate;
+use crate::img::registration::{warp, CanRegister};
+
+/// Side of the blocks of the structural similarity.
+const SSIM_BLOCK_SIZE: usize = 8;
+
+/// Similarity of two images.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Similarity {
+    /// Normalized cross-correlation, in [-1,1].
+    pub ncc: f32,
+    /// Mean structural similarity of 8x8 blocks, in [-1,1].
+    pub ssim: f32,
+    /// Peak signal to noise ratio, in dB.
+    pub psnr: f32,
+}
+
+/// Similarity of an image to the first image of the stack, before and after registration.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Metrics {
+    pub before: Similarity,
+    pub after: Similarity,
+}
+
+/// Metrics of each image of a stack registered with the given motions.
+pub fn metrics<T: CanRegister>(imgs: &[DMatrix<T>], motion_vec: &[Vector6<f32>]) -> Vec<Metrics> {
+    let normalized = |img: &DMatrix<T>| {
+        img.map(|x| {
+            let raw = <T as CanLinearInterpolate<f32, f32>>::into_vector(x);
+            <T as CanLinearInterpolate<f32, f32>>::from_vector(raw)
+        })
+    };
+    let (first_img, first_motion) = match imgs.first().zip(motion_vec.first()) {
+        None => return Vec::new(),
+        Some(first) => first,
+    };
+    let reference = normalized(first_img);
+    let registered_reference = warp::<T, f32, f32>(first_img, first_motion);
+    let reference_mask = inside_mask(first_motion, first_img.shape());
+    imgs.iter()
+        .zip(motion_vec)
+        .map(|(img, motion)| {
+            let all = DMatrix::from_element(img.nrows(), img.ncols(), true);
+            let mask = inside_mask(motion, img.shape()).zip_map(&reference_mask, |a, b| a && b);
+            let registered = warp::<T, f32, f32>(img, motion);
+            Metrics {
+                before: similarity(&reference, &normalized(img), &all),
+                after: similarity(&registered_reference, &registered, &mask),
+            }
+        })
+        .collect()
+}
+
+/// Similarity of two images with intensities in [0,1], on the pixels of the mask.
+pub fn similarity(a: &DMatrix<f32>, b: &DMatrix<f32>, mask: &DMatrix<bool>) -> Similarity {
+    let pixels = a
+        .iter()
+        .zip(b.iter())
+        .zip(mask.iter())
+        .filter(|(_, inside)| **inside)
+        .map(|((&x, &y), _)| (x, y));
+    let (ncc, mse) = match Moments::of(pixels) {
+        None => (0.0, 0.0),
+        Some(m) => (m.correlation(), m.mse),
+    };
+    Similarity {
+        ncc,
+        ssim: mean_ssim(a, b, mask),
+        psnr: -10.0 * mse.max(f32::EPSILON).log10(),
+    }
+}
+
+/// Mean structural similarity of the blocks fully inside the mask.
+#[allow(clippy::cast_precision_loss)]
+fn mean_ssim(a: &DMatrix<f32>, b: &DMatrix<f32>, mask: &DMatrix<bool>) -> f32 {
+    // Stabilization constants of the original paper, for intensities in [0,1].
+    let (c1, c2) = (0.01_f32.powi(2), 0.03_f32.powi(2));
+    let (height, width) = a.shape();
+    let mut ssim_sum = 0.0;
+    let mut blocks = 0;
+    for top in (0..height.saturating_sub(SSIM_BLOCK_SIZE - 1)).step_by(SSIM_BLOCK_SIZE) {
+        for left in (0..width.saturating_sub(SSIM_BLOCK_SIZE - 1)).step_by(SSIM_BLOCK_SIZE) {
+            let (corner, size) = ((top, left), (SSIM_BLOCK_SIZE, SSIM_BLOCK_SIZE));
+            if mask.slice(corner, size).iter().all(|&inside| inside) {
+                let (block_a, block_b) = (a.slice(corner, size), b.slice(corner, size));
+                let pairs = block_a.iter().cloned().zip(block_b.iter().cloned());
+                let m = Moments::of(pairs).expect("Blocks are not empty");
+                ssim_sum += ((2.0 * m.mean.0 * m.mean.1 + c1) * (2.0 * m.covariance + c2))
+                    / ((m.mean.0.powi(2) + m.mean.1.powi(2) + c1)
+                        * (m.variance.0 + m.variance.1 + c2));
+                blocks += 1;
+            }
+        }
+    }
+    if blocks == 0 {
+        0.0
+    } else {
+        ssim_sum / blocks as f32
+    }
+}
+
+/// Pixels of the registered frame whose position in the image is inside it.
+#[allow(clippy::cast_precision_loss)]
+pub(crate) fn inside_mask(motion: &Vector6<f32>, shape: (usize, usize)) -> DMatrix<bool> {
+    let (height, width) = shape;
+    let mat = projection_mat(motion);
+    let (max_x, max_y) = ((width - 1) as f32, (height - 1) as f32);
+    DMatrix::from_fn(height, width, |i, j| {
+        let p = mat * Vector3::new(j as f32, i as f32, 1.0);
+        p.x >= 0.0 && p.y >= 0.0 && p.x <= max_x && p.y <= max_y
+    })
+}
+
+/// First and second order moments of pairs of intensities.
+struct Moments {
+    mean: (f32, f32),
+    variance: (f32, f32),
+    covariance: f32,
+    /// Mean squared difference.
+    mse: f32,
+}
+
+impl Moments {
+    #[allow(clippy::cast_possible_truncation)]
+    fn of<I: Iterator<Item = (f32, f32)>>(pairs: I) -> Option<Self> {
+        let (mut n, mut sa, mut sb, mut saa, mut sbb, mut sab) = (0_u32, 0.0, 0.0, 0.0, 0.0, 0.0);
+        for (a, b) in pairs {
+            let (a, b) = (f64::from(a), f64::from(b));
+            n += 1;
+            sa += a;
+            sb += b;
+            saa += a * a;
+            sbb += b * b;
+            sab += a * b;
+        }
+        if n == 0 {
+            return None;
+        }
+        let n = f64::from(n);
+        let (ma, mb) = (sa / n, sb / n);
+        Some(Moments {
+            mean: (ma as f32, mb as f32),
+            variance: ((saa / n - ma * ma) as f32, (sbb / n - mb * mb) as f32),
+            covariance: (sab / n - ma * mb) as f32,
+            mse: ((saa - 2.0 * sab + sbb) / n) as f32,
+        })
+    }
+
+    fn correlation(&self) -> f32 {
+        let norm = (self.variance.0 * self.variance.1).sqrt();
+        if norm > 0.0 {
+            self.covariance / norm
+        } else {
+            0.0
+        }
+    }
+}
@@ -5,11 +5,32 @@
 //! This module is a namespace for submodules dealing with image manipulation.
 //! The underlying data is almost always considered to be a 2D nalgebra matrix.
 
+pub mod bayer;
+pub mod calibration;
+pub mod canvas;
+pub mod census;
 pub mod crop;
+pub mod deformable;
+pub mod defects;
+pub mod distortion;
 pub mod filter;
+pub mod flat_field;
+pub mod fusion;
 pub mod gradients;
 pub mod interpolation;
+pub mod merge;
+pub mod metrics;
 pub mod multires;
+pub mod normalization;
+pub mod pixel;
 pub mod registration;
+pub mod residual;
+pub mod roi;
+pub mod size;
 pub mod sparse;
+pub mod superres;
+pub mod synthetic;
+pub mod transfer;
+pub mod view;
 pub mod viz;
+pub mod white_balance;
@@ -0,0 +1,217 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Intensity normalization of images before their registration,
+//! helping with unevenly lit images such as photometric stereo inputs.
+
+use nalgebra::{DMatrix, Scalar};
+
+/// Trait for pixel types with a finite number of intensity levels.
+pub trait CanNormalize: Scalar + Copy {
+    /// Number of intensity levels.
+    const LEVELS: usize;
+    /// Intensity level of a pixel, in `0..LEVELS`.
+    fn to_level(self) -> usize;
+    /// Pixel corresponding to an intensity level, in `0..LEVELS`.
+    fn from_level(level: usize) -> Self;
+}
+
+impl CanNormalize for u8 {
+    const LEVELS: usize = 1 << 8;
+    fn to_level(self) -> usize {
+        self as usize
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    fn from_level(level: usize) -> Self {
+        level.min(u8::MAX as usize) as u8
+    }
+}
+
+impl CanNormalize for u16 {
+    const LEVELS: usize = 1 << 16;
+    fn to_level(self) -> usize {
+        self as usize
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    fn from_level(level: usize) -> Self {
+        level.min(u16::MAX as usize) as u16
+    }
+}
+
+// Dynamic range ###############################################################
+
+/// Bit depths of camera sensors whose data is commonly stored in 16 bits integers.
+const SENSOR_BITS: [usize; 4] = [10, 12, 14, 16];
+
+/// Maximum intensity level of the data stored in images,
+/// such as 4095 for 12 bits data stored in 16 bits integers.
+///
+/// For 16 bits pixels, it is the maximum of the smallest sensor bit depth
+/// (10, 12, 14 or 16 bits) holding all the pixels.
+/// Other pixel types are assumed to use their whole range.
+pub fn detect_max_level<T: CanNormalize>(imgs: &[DMatrix<T>]) -> usize {
+    let type_max = T::LEVELS - 1;
+    if T::LEVELS != 1 << 16 {
+        return type_max;
+    }
+    let max = imgs
+        .iter()
+        .flat_map(|img| img.iter())
+        .map(|x| x.to_level())
+        .max()
+        .unwrap_or(0);
+    SENSOR_BITS
+        .iter()
+        .map(|bits| (1 << bits) - 1)
+        .find(|&bits_max| max <= bits_max)
+        .unwrap_or(type_max)
+}
+
+/// Stretch intensities in `[0, max_level]` to the whole range of the pixel type,
+/// clamping the ones above.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_precision_loss)]
+#[allow(clippy::cast_sign_loss)]
+pub fn stretch<T: CanNormalize>(max_level: f32, imgs: &mut [DMatrix<T>]) {
+    let scale = (T::LEVELS - 1) as f32 / max_level;
+    let mapping: Vec<T> = (0..T::LEVELS)
+        .map(|level| T::from_level((level as f32 * scale).round() as usize))
+        .collect();
+    for img in imgs.iter_mut() {
+        img.apply(|x| mapping[x.to_level()]);
+    }
+}
+
+// Histogram matching ##########################################################
+
+/// Match the histogram of each image to the one of the reference image.
+pub fn match_histograms<T: CanNormalize>(reference: &DMatrix<T>, imgs: &mut [DMatrix<T>]) {
+    let reference_cdf = cumulative_histogram(reference);
+    for img in imgs.iter_mut() {
+        let cdf = cumulative_histogram(img);
+        // For each level, the first reference level with a cumulative count at least as high.
+        let mut mapping = vec![0; T::LEVELS];
+        let mut ref_level = 0;
+        for (level, &count) in cdf.iter().enumerate() {
+            while ref_level < T::LEVELS - 1 && reference_cdf[ref_level] < count {
+                ref_level += 1;
+            }
+            mapping[level] = ref_level;
+        }
+        img.apply(|x| T::from_level(mapping[x.to_level()]));
+    }
+}
+
+/// Cumulative histogram, normalized in [0,1].
+#[allow(clippy::cast_precision_loss)]
+fn cumulative_histogram<T: CanNormalize>(img: &DMatrix<T>) -> Vec<f64> {
+    let mut histogram = vec![0_usize; T::LEVELS];
+    img.iter().for_each(|x| histogram[x.to_level()] += 1);
+    let total = img.len().max(1) as f64;
+    let mut cumulated = 0;
+    histogram
+        .iter()
+        .map(|count| {
+            cumulated += count;
+            cumulated as f64 / total
+        })
+        .collect()
+}
+
+// CLAHE #######################################################################
+
+/// Number of tiles in each dimension for the CLAHE.
+pub const CLAHE_TILES: usize = 8;
+
+/// Clip limit of the CLAHE histograms, relative to the mean count of a bin.
+pub const CLAHE_CLIP_LIMIT: f32 = 2.0;
+
+/// Number of bins of the tile histograms, independently of the pixel type.
+const CLAHE_BINS: usize = 256;
+
+/// Contrast limited adaptive histogram equalization.
+///
+/// The image is divided in `tiles x tiles` tiles, each equalized with its own histogram,
+/// clipped at `clip_limit` times the mean bin count to limit the noise amplification.
+/// The mappings of the 4 closest tiles are bilinearly interpolated to avoid seams.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_precision_loss)]
+#[allow(clippy::cast_sign_loss)]
+pub fn clahe<T: CanNormalize>(img: &DMatrix<T>, tiles: usize, clip_limit: f32) -> DMatrix<T> {
+    let (height, width) = img.shape();
+    if height == 0 || width == 0 {
+        return img.clone();
+    }
+    let tile_height = height.div_ceil(tiles.clamp(1, height));
+    let tile_width = width.div_ceil(tiles.clamp(1, width));
+    let tiles_y = height.div_ceil(tile_height);
+    let tiles_x = width.div_ceil(tile_width);
+
+    // Compute the clipped cumulative histogram of each tile.
+    let mut cdfs = Vec::with_capacity(tiles_x * tiles_y);
+    for tx in 0..tiles_x {
+        for ty in 0..tiles_y {
+            let (y0, x0) = (ty * tile_height, tx * tile_width);
+            let tile_h = tile_height.min(height - y0);
+            let tile_w = tile_width.min(width - x0);
+            let tile = img.slice((y0, x0), (tile_h, tile_w));
+            cdfs.push(clipped_cdf(tile.iter().cloned(), clip_limit));
+        }
+    }
+    let cdf = |tx: usize, ty: usize| &cdfs[tx * tiles_y + ty];
+
+    // Position of a pixel in the grid of tile centers, with the 2 closest tiles and weight.
+    let grid_position = |pos: usize, tile_size: usize, count: usize| {
+        let t = ((pos as f32 + 0.5) / tile_size as f32 - 0.5).clamp(0.0, (count - 1) as f32);
+        let t0 = t.floor() as usize;
+        let t1 = (t0 + 1).min(count - 1);
+        (t0, t1, t - t0 as f32)
+    };
+
+    let max_level = (T::LEVELS - 1) as f32;
+    DMatrix::from_fn(height, width, |y, x| {
+        let (ty0, ty1, wy) = grid_position(y, tile_height, tiles_y);
+        let (tx0, tx1, wx) = grid_position(x, tile_width, tiles_x);
+        let level = img[(y, x)].to_level();
+        let map = |tx, ty| map_level(cdf(tx, ty), level, T::LEVELS);
+        let top = (1.0 - wx) * map(tx0, ty0) + wx * map(tx1, ty0);
+        let bottom = (1.0 - wx) * map(tx0, ty1) + wx * map(tx1, ty1);
+        let value = (1.0 - wy) * top + wy * bottom;
+        T::from_level((value * max_level).round() as usize)
+    })
+}
+
+/// Cumulative histogram of the pixels on `CLAHE_BINS` bins,
+/// after clipping and uniform redistribution of the excess.
+/// It starts with 0 and has `CLAHE_BINS + 1` values, normalized in [0,1].
+#[allow(clippy::cast_precision_loss)]
+fn clipped_cdf<T: CanNormalize>(pixels: impl Iterator<Item = T>, clip_limit: f32) -> Vec<f32> {
+    let mut histogram = vec![0.0_f32; CLAHE_BINS];
+    let mut count = 0;
+    for x in pixels {
+        histogram[x.to_level() * CLAHE_BINS / T::LEVELS] += 1.0;
+        count += 1;
+    }
+    let limit = (clip_limit * count as f32 / CLAHE_BINS as f32).max(1.0);
+    let excess: f32 = histogram.iter().map(|&h| (h - limit).max(0.0)).sum();
+    let redistributed = excess / CLAHE_BINS as f32;
+    let total = (count as f32).max(1.0);
+    let mut cdf = Vec::with_capacity(CLAHE_BINS + 1);
+    let mut cumulated = 0.0;
+    cdf.push(0.0);
+    for h in histogram.iter() {
+        cumulated += h.min(limit) + redistributed;
+        cdf.push(cumulated / total);
+    }
+    cdf
+}
+
+/// Equalized intensity in [0,1] of a level, including the pixels at that level.
+/// The cumulative histogram is linearly interpolated inside bins
+/// to keep the precision of 16 bits images.
+#[allow(clippy::cast_precision_loss)]
+fn map_level(cdf: &[f32], level: usize, levels: usize) -> f32 {
+    let position = ((level + 1) * CLAHE_BINS) as f32 / levels as f32;
+    let bin = ((level + 1) * CLAHE_BINS / levels).min(CLAHE_BINS - 1);
+    let frac = position - bin as f32;
+    cdf[bin] + frac * (cdf[bin + 1] - cdf[bin])
+}
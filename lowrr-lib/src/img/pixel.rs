@@ -0,0 +1,181 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Pixel types that can be registered, and how to add new ones.
+//!
+//! The registration is generic over the pixel type of the images,
+//! which only needs to implement the set of traits gathered here.
+//! They are implemented for `u8` and `u16` gray images,
+//! and can be implemented for other sensor data, such as 12 bits packed pixels,
+//! without converting whole stacks to 16 bits integers.
+//!
+//! A pixel type `P` must implement:
+//!
+//! - [CanRegister], the umbrella trait of the registration, with the type
+//!   of the squared gradients norm compared to the sparse diff threshold.
+//! - [CanLinearInterpolate] into `f32`, normalizing intensities in [0,1]
+//!   (this is how the algorithm reads pixels), and into `P` itself to warp images.
+//! - [CanNormalize], with the number of intensity levels of the sensor,
+//!   for the histogram based normalizations and the denoising filters.
+//! - [multires::Bigger](crate::img::multires::Bigger), a type holding the sum of 4 pixels
+//!   to build the multi-resolution pyramid.
+//! - [gradients::Bigger](crate::img::gradients::Bigger), a signed type holding
+//!   the squared differences of pixels to select the sparse pixels.
+//! - [IntoRgb8], to visualize the sparse pixels.
+//!
+//! [CanEqualize] is also needed to equalize the mean intensity of images
+//! with the [pipeline](crate::pipeline).
+//! Nothing requires the pixels to be a primitive integer type,
+//! only to be `Copy`, comparable and printable, like all nalgebra scalars.
+//!
+//! [Packed12] is a complete example, registering 12 bits images
+//! with the same sparse diff threshold as 8 bits images, scaled to 12 bits:
+//!
+//! ```
+//! use lowrr::img::pixel::Packed12;
+//! use lowrr::img::registration::{gray_affine, Config};
+//!
+//! let (imgs, motions) = lowrr::img::synthetic::translated_stack(64, 64, 3, 0);
+//! let imgs = imgs
+//!     .iter()
+//!     .map(|img| img.map(|x| Packed12::new(u16::from(x) << 4)))
+//!     .collect();
+//! let config = Config { levels: 2, ..Config::default() };
+//! let (motion_vec, _) = gray_affine(config, imgs, Packed12::SPARSE_DIFF_THRESHOLD)?;
+//! for (motion, expected) in motion_vec.iter().zip(&motions) {
+//!     assert!((motion[4] - expected[4]).abs() < 1.0);
+//!     assert!((motion[5] - expected[5]).abs() < 1.0);
+//! }
+//! # Ok::<(), lowrr::img::registration::RegistrationError>(())
+//! ```
+
+pub use crate::img::interpolation::CanLinearInterpolate;
+pub use crate::img::normalization::CanNormalize;
+pub use crate::img::registration::CanRegister;
+pub use crate::img::viz::IntoRgb8;
+pub use crate::utils::CanEqualize;
+
+/// Pixel of a 12 bits sensor, with values in `0..=Packed12::MAX`,
+/// stored unpacked in the lowest bits of a `u16`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Packed12(u16);
+
+impl Packed12 {
+    /// Highest value of a 12 bits pixel.
+    pub const MAX: u16 = (1 << 12) - 1;
+
+    /// Sparse diff threshold of 12 bits images, the one of 8 bits images scaled to 12 bits.
+    pub const SPARSE_DIFF_THRESHOLD: u32 = 40 * 16 * 16;
+
+    /// Pixel with the given value, saturated to 12 bits.
+    pub fn new(value: u16) -> Self {
+        Self(value.min(Self::MAX))
+    }
+
+    pub fn value(self) -> u16 {
+        self.0
+    }
+
+    /// Unpack two 12 bits pixels from the 3 bytes of the usual packed layout
+    /// of camera sensors (MIPI RAW12): the 8 highest bits of each pixel,
+    /// then their 4 lowest bits in a shared byte, starting with the first pixel.
+    pub fn unpack_pair(bytes: [u8; 3]) -> (Self, Self) {
+        let first = (u16::from(bytes[0]) << 4) | u16::from(bytes[2] & 0x0f);
+        let second = (u16::from(bytes[1]) << 4) | u16::from(bytes[2] >> 4);
+        (Self(first), Self(second))
+    }
+}
+
+impl From<Packed12> for u32 {
+    fn from(p: Packed12) -> Self {
+        u32::from(p.0)
+    }
+}
+
+impl From<Packed12> for i64 {
+    fn from(p: Packed12) -> Self {
+        i64::from(p.0)
+    }
+}
+
+impl From<Packed12> for f32 {
+    fn from(p: Packed12) -> Self {
+        f32::from(p.0)
+    }
+}
+
+/// Squared gradients of 12 bits pixels are at most 2 * 4095^2 / 4, which fits in a u32.
+impl CanRegister for Packed12 {
+    type Bigger = u32;
+}
+
+/// WARNING: like other integer pixels, interpolating with a f32 output
+/// normalizes values from [0-4095] to [0.0, 1.0].
+impl CanLinearInterpolate<f32, f32> for Packed12 {
+    fn into_vector(self) -> f32 {
+        self.0 as f32
+    }
+    fn from_vector(v: f32) -> f32 {
+        (v / Self::MAX as f32).clamp(0.0, 1.0)
+    }
+}
+
+impl CanLinearInterpolate<f32, Packed12> for Packed12 {
+    fn into_vector(self) -> f32 {
+        self.0 as f32
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    fn from_vector(v: f32) -> Packed12 {
+        Packed12(v.max(0.0).min(Self::MAX as f32).round() as u16)
+    }
+}
+
+impl CanNormalize for Packed12 {
+    const LEVELS: usize = 1 << 12;
+    fn to_level(self) -> usize {
+        self.0 as usize
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    fn from_level(level: usize) -> Self {
+        Packed12(level.min(Self::MAX as usize) as u16)
+    }
+}
+
+impl crate::img::multires::Bigger for Packed12 {
+    type Big = u32;
+    #[allow(clippy::cast_possible_truncation)]
+    fn from_as(b: Self::Big) -> Self {
+        Packed12(b as u16)
+    }
+}
+
+impl crate::img::gradients::Bigger<u32> for Packed12 {
+    type BigSigned = i64;
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    fn from_as(b: Self::BigSigned) -> u32 {
+        b as u32
+    }
+    fn zero() -> u32 {
+        0
+    }
+}
+
+impl IntoRgb8 for Packed12 {
+    #[allow(clippy::cast_possible_truncation)]
+    fn into_rgb8(self) -> (u8, u8, u8) {
+        let gray = (self.0 >> 4) as u8;
+        (gray, gray, gray)
+    }
+}
+
+impl CanEqualize for Packed12 {
+    fn target_mean(target: f32) -> f32 {
+        Self::MAX as f32 * target
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    fn from_as(f: f32) -> Self {
+        Packed12(f.clamp(0.0, Self::MAX as f32).round() as u16)
+    }
+}
@@ -2,35 +2,586 @@
 
 //! Registration algorithm for a sequence of slightly misaligned images.
 
-use image::Primitive;
-use nalgebra::{DMatrix, Matrix3, Matrix6, RealField, Scalar, Vector3, Vector6};
+use nalgebra::{
+    DMatrix, DVector, Matrix2x6, Matrix3, Matrix6, RealField, Scalar, Vector2, Vector3, Vector6,
+};
 use std::future::Future;
-use std::ops::{Add, Mul};
-use std::rc::Rc;
+use std::ops::{Add, Mul, Range};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
 use crate::affine2d::{projection_mat, projection_params};
-use crate::img::interpolation::CanLinearInterpolate;
-use crate::interop::ToImage;
+use crate::img::canvas::Canvas;
+use crate::img::interpolation::{linear_lanes, linear_view, CanLinearInterpolate, LANES};
+use crate::img::normalization::{CanNormalize, CLAHE_CLIP_LIMIT, CLAHE_TILES};
+use crate::img::view::ImageView;
+use crate::svd::{IncrementalSvd, Nalgebra, SvdBackend};
+
+#[cfg(feature = "faer")]
+use crate::svd::Faer;
+#[cfg(feature = "lapack")]
+use crate::svd::Lapack;
 
 #[cfg(feature = "wasm-bindgen")]
 use wasm_bindgen::prelude::*;
 
 #[cfg(feature = "serde")]
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 /// Configuration (parameters) of the registration algorithm.
+///
+/// This is the single configuration shared by the library, the command line program,
+/// and the Python, C and WebAssembly bindings.
+/// Missing fields of a deserialized configuration take their default value.
 #[cfg_attr(feature = "wasm-bindgen", wasm_bindgen)]
 #[derive(Debug, Clone, Copy)]
-#[cfg_attr(feature = "serde", derive(Deserialize))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct Config {
     pub lambda: f32,
     pub rho: f32,
     pub max_iterations: usize,
     pub threshold: f32,
+    /// Also stop the iterations of a level when no image corner moved more than this
+    /// during the last iteration, in pixels at the resolution of the level, 0 to disable it.
+    pub motion_threshold: f32,
+    /// Levenberg-Marquardt damping of the Gauss-Newton steps of the motions, 0 to disable it.
+    /// The diagonal of the Hessian is scaled by 1 + damping, shortening the steps
+    /// along poorly constrained directions, which oscillate on low-texture crops at fine levels.
+    pub damping: f32,
+    /// Backtracking line search on the Gauss-Newton step of each motion, in the low-rank mode.
+    /// The step is halved until the residuals of the image to its low-rank approximation
+    /// decrease, together with its [temporal_smoothness](Config::temporal_smoothness) prior,
+    /// and the motion is left unchanged for the iteration if they never do.
+    pub line_search: bool,
+    /// Forwards or inverse compositional Gauss-Newton steps of the motions, in the low-rank mode.
+    pub compositional: Compositional,
+    /// Maximum displacement of the center of each image, in pixels of the original resolution,
+    /// 0 for no limit.
+    /// Motions are brought back within this bound and [max_deformation](Config::max_deformation)
+    /// after each iteration, so that a bad estimate at a coarse level cannot warp an image
+    /// off the canvas and spoil the registration of the others.
+    /// This holds in all modes, for chunked registrations once their chunks are stitched,
+    /// and in the online [Registration], also when its reference image is removed.
+    pub max_translation: f32,
+    /// Maximum deviation from the identity of each coefficient of the linear part
+    /// of the motions (scale, rotation and shear), 0 for no limit.
+    /// For example 0.1 allows scale changes of 10%.
+    /// Coefficients are bounded separately, so a scale change combined with a shear
+    /// moves points by up to about 14% of their distance along the diagonal.
+    pub max_deformation: f32,
     pub sparse_ratio_threshold: f32,
     pub levels: usize,
+    /// Low-rank registration of all images together, or simpler registrations of pairs of images.
+    pub mode: Mode,
+    /// Verbosity of the diagnostics, 3 and above computing the costs of each iteration.
+    /// All messages go through the `log` crate, so the logger of the application
+    /// decides what is actually displayed.
     pub verbosity: u32,
+    /// Floating point precision of the optimization.
+    pub precision: Precision,
+    /// Computation of the singular value decomposition of the low-rank approximation.
+    pub svd_backend: Svd,
+    /// Linear algebra library computing the dense SVDs.
+    pub svd_library: SvdLibrary,
+    /// Store the registered images of the low-rank model as 16 bits integers,
+    /// halving the memory of this matrix in single precision (a quarter in double precision).
+    /// Their values are unpacked on the fly when needed.
+    pub pack_observations: bool,
+    /// Strategy selecting the pixels used at levels with sparse resolution.
+    pub sparse_strategy: SparseStrategy,
+    /// Fraction of pixels kept at each level by the percentile and grid strategies.
+    pub sparse_fraction: f32,
+    /// Maximum number of pixels used at each level, 0 for no limit.
+    /// When set, it replaces the sparse ratio threshold and strategy:
+    /// levels within the budget use the dense resolution,
+    /// and the others keep the pixels with the highest gradients.
+    pub pixel_budget: usize,
+    /// Maximum intensity of the input images, in the units of their pixel type,
+    /// or 0 to detect it (see [detect_max_level](crate::img::normalization::detect_max_level)).
+    /// The pipeline stretches intensities up to this maximum to the whole range of the type
+    /// before the equalization, so that lambda keeps its meaning with 12 bits data
+    /// stored in 16 bits images for example.
+    pub input_max: f32,
+    /// Intensity normalization of the images before their registration.
+    pub normalization: Normalization,
+    /// Images on which the data term is computed, at each level of the pyramid.
+    pub data_term: DataTerm,
+    /// Estimate a gain and bias for each image jointly with the registration,
+    /// so that global exposure differences are not absorbed by the sparse errors.
+    pub gain_bias: bool,
+    /// Estimate the sparse errors of the images (e-update), which absorb shadows,
+    /// highlights and occlusions that the low-rank model cannot represent.
+    /// Without it, the registered images are fitted by a purely low-rank model,
+    /// which converges faster on clean datasets but is pulled by any such artifact.
+    /// Outliers are then only detected on the magnitude of their motion.
+    /// Only the low-rank mode has sparse errors, the other modes ignore this.
+    pub do_image_correction: bool,
+    /// Denoising of the images used by the registration,
+    /// leaving the images themselves untouched.
+    pub denoise: Denoise,
+    /// Number of levels denoised, starting from the original resolution.
+    /// Lower resolutions are already smoothed by the multi-resolution pyramid.
+    pub denoise_levels: usize,
+    /// Weight of a prior penalizing differences between the motions of consecutive images,
+    /// for time-lapses or video frames, 0 to disable it.
+    /// The difference of two motions is their mean squared displacement of the pixels,
+    /// weighted against squared intensity differences, with intensities in [0, 1].
+    pub temporal_smoothness: f32,
+    /// Robust z-score above which an image is flagged as an outlier after the coarsest level,
+    /// 0 to disable the detection, which needs at least 8 images.
+    /// Scores are computed on the energy of the sparse errors of each image,
+    /// and on the magnitude of its motion.
+    /// Outliers are excluded from the low-rank model at the next levels,
+    /// and keep their motion estimated at the coarsest level.
+    pub outlier_threshold: f32,
+    /// Number of images registered together, 0 to register all images at once.
+    /// Longer sequences are registered by overlapping chunks of this many images
+    /// (at least 2), and the motions of each chunk are brought into the frame
+    /// of the first image through the images shared with the previous chunk.
+    pub chunk_size: usize,
+    /// Aspect ratio (width divided by height) of the pixels of the images,
+    /// 1 for square pixels.
+    /// Motions stay in the pixel coordinates of the images, since affine motions
+    /// of non-square pixels are affine motions of square pixels,
+    /// but displacements are measured in units of the pixel height, for the motion threshold,
+    /// the maximum translation, the temporal smoothness and the detection of outliers.
+    pub pixel_aspect_ratio: f32,
+    /// Measure the time spent in each part of the algorithm, at each level.
+    /// Not available in WebAssembly, which has no clock.
+    pub profile: bool,
+}
+
+/// Default value of `Config::sparse_fraction`.
+pub const DEFAULT_SPARSE_FRACTION: f32 = 0.1;
+
+/// Default value of `Config::denoise_levels`.
+pub const DEFAULT_DENOISE_LEVELS: usize = 1;
+
+/// Default configuration, the same as the command line program.
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            lambda: 1.5,
+            rho: 0.1,
+            max_iterations: 40,
+            threshold: 1e-3,
+            motion_threshold: 0.0,
+            damping: 0.0,
+            line_search: false,
+            compositional: Compositional::default(),
+            max_translation: 0.0,
+            max_deformation: 0.0,
+            sparse_ratio_threshold: 0.5,
+            levels: 4,
+            mode: Mode::default(),
+            verbosity: 0,
+            precision: Precision::default(),
+            svd_backend: Svd::default(),
+            svd_library: SvdLibrary::default(),
+            pack_observations: false,
+            sparse_strategy: SparseStrategy::default(),
+            sparse_fraction: DEFAULT_SPARSE_FRACTION,
+            pixel_budget: 0,
+            input_max: 0.0,
+            normalization: Normalization::default(),
+            data_term: DataTerm::default(),
+            gain_bias: false,
+            do_image_correction: true,
+            denoise: Denoise::default(),
+            denoise_levels: DEFAULT_DENOISE_LEVELS,
+            temporal_smoothness: 0.0,
+            outlier_threshold: 0.0,
+            chunk_size: 0,
+            pixel_aspect_ratio: 1.0,
+            profile: false,
+        }
+    }
+}
+
+/// Strategy to select the pixels used at levels with sparse resolution.
+#[cfg_attr(feature = "wasm-bindgen", wasm_bindgen)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum SparseStrategy {
+    /// Coarse to fine selection of the highest gradients in each 2x2 bloc,
+    /// using the sparse diff threshold.
+    #[default]
+    Threshold,
+    /// Highest gradients, keeping `sparse_fraction` of the pixels at each level.
+    Percentile,
+    /// Uniform grid, keeping about `sparse_fraction` of the pixels at each level.
+    Grid,
+    /// FAST corners.
+    Fast,
+}
+
+impl std::str::FromStr for SparseStrategy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "threshold" => Ok(SparseStrategy::Threshold),
+            "percentile" => Ok(SparseStrategy::Percentile),
+            "grid" => Ok(SparseStrategy::Grid),
+            "fast" => Ok(SparseStrategy::Fast),
+            _ => Err(format!(
+                "Unknown sparse strategy \"{}\", expecting threshold, percentile, grid or fast",
+                s
+            )),
+        }
+    }
+}
+
+/// Intensity normalization applied to the images before their registration.
+#[cfg_attr(feature = "wasm-bindgen", wasm_bindgen)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Normalization {
+    /// Images are registered as they are.
+    #[default]
+    None,
+    /// Match the histogram of each image to the one of the first image.
+    Histogram,
+    /// Contrast limited adaptive histogram equalization of each image,
+    /// for images with uneven lighting.
+    Clahe,
+}
+
+impl std::str::FromStr for Normalization {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Normalization::None),
+            "histogram" => Ok(Normalization::Histogram),
+            "clahe" => Ok(Normalization::Clahe),
+            _ => Err(format!(
+                "Unknown normalization \"{}\", expecting none, histogram or clahe",
+                s
+            )),
+        }
+    }
+}
+
+/// Normalize the intensities of images, the first one being the reference.
+fn normalize<T: CanNormalize>(normalization: Normalization, imgs: &mut [DMatrix<T>]) {
+    match normalization {
+        Normalization::None => {}
+        Normalization::Histogram => {
+            if let Some((reference, others)) = imgs.split_first_mut() {
+                crate::img::normalization::match_histograms(reference, others);
+            }
+        }
+        Normalization::Clahe => {
+            for img in imgs.iter_mut() {
+                *img = crate::img::normalization::clahe(img, CLAHE_TILES, CLAHE_CLIP_LIMIT);
+            }
+        }
+    }
+}
+
+/// Images on which the data term of the registration is computed.
+///
+/// Brightness constancy is violated when the lighting changes between images,
+/// such as for photometric stereo.
+/// Gradient magnitude and census transform are less sensitive to it.
+#[cfg_attr(feature = "wasm-bindgen", wasm_bindgen)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum DataTerm {
+    /// Raw intensities.
+    #[default]
+    Intensity,
+    /// Norm of the intensity gradients.
+    Gradient,
+    /// Census transform, counting the darker neighbors of each pixel.
+    Census,
+}
+
+impl std::str::FromStr for DataTerm {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "intensity" => Ok(DataTerm::Intensity),
+            "gradient" => Ok(DataTerm::Gradient),
+            "census" => Ok(DataTerm::Census),
+            _ => Err(format!(
+                "Unknown data term \"{}\", expecting intensity, gradient or census",
+                s
+            )),
+        }
+    }
+}
+
+/// Denoising filter applied to the images used by the registration.
+#[cfg_attr(feature = "wasm-bindgen", wasm_bindgen)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Denoise {
+    /// No denoising.
+    #[default]
+    None,
+    /// Gaussian blur.
+    Gaussian,
+    /// Median of 3x3 neighborhoods, for salt and pepper noise.
+    Median,
+    /// Bilateral filter, preserving edges.
+    Bilateral,
+}
+
+impl std::str::FromStr for Denoise {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Denoise::None),
+            "gaussian" => Ok(Denoise::Gaussian),
+            "median" => Ok(Denoise::Median),
+            "bilateral" => Ok(Denoise::Bilateral),
+            _ => Err(format!(
+                "Unknown denoise filter \"{}\", expecting none, gaussian, median or bilateral",
+                s
+            )),
+        }
+    }
+}
+
+/// Standard deviation, in pixels, of the `Denoise::Gaussian` filter.
+const DENOISE_GAUSSIAN_SIGMA: f32 = 1.0;
+
+/// Standard deviations of the `Denoise::Bilateral` filter,
+/// in pixels and for intensities in [0,1].
+const DENOISE_BILATERAL_SIGMAS: (f32, f32) = (1.5, 0.1);
+
+/// Whether the registration uses working copies of the images,
+/// instead of the images themselves.
+fn uses_working_copies(config: &Config) -> bool {
+    config.data_term != DataTerm::Intensity
+        || (config.denoise != Denoise::None && config.denoise_levels > 0)
+}
+
+/// Working copy of an image at a given pyramid level, on which the data term is computed.
+fn working_img<T: CanNormalize>(config: &Config, level: usize, img: &DMatrix<T>) -> DMatrix<T> {
+    let denoised;
+    let img = if level < config.denoise_levels {
+        denoised = match config.denoise {
+            Denoise::None => None,
+            Denoise::Gaussian => Some(crate::img::filter::gaussian_blur(
+                img,
+                DENOISE_GAUSSIAN_SIGMA,
+            )),
+            Denoise::Median => Some(crate::img::filter::median_3x3(img)),
+            Denoise::Bilateral => {
+                let (sigma_space, sigma_range) = DENOISE_BILATERAL_SIGMAS;
+                Some(crate::img::filter::bilateral(img, sigma_space, sigma_range))
+            }
+        };
+        denoised.as_ref().unwrap_or(img)
+    } else {
+        img
+    };
+    match config.data_term {
+        DataTerm::Intensity => img.clone(),
+        DataTerm::Gradient => crate::img::gradients::magnitude(img),
+        DataTerm::Census => crate::img::census::census(img),
+    }
+}
+
+/// Floating point precision of the ADMM state, SVD and Gauss-Newton steps.
+///
+/// Double precision is slower and uses twice the memory,
+/// but may help convergence on low-contrast 16 bits images.
+#[cfg_attr(feature = "wasm-bindgen", wasm_bindgen)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Precision {
+    /// f32
+    #[default]
+    Single,
+    /// f64
+    Double,
+}
+
+impl std::str::FromStr for Precision {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "single" | "f32" => Ok(Precision::Single),
+            "double" | "f64" => Ok(Precision::Double),
+            _ => Err(format!(
+                "Unknown precision \"{}\", expecting single or double",
+                s
+            )),
+        }
+    }
+}
+
+/// Computation of the SVD of the A-update, at each iteration of the low-rank model.
+#[cfg_attr(feature = "wasm-bindgen", wasm_bindgen)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Svd {
+    /// Full SVD recomputed at each iteration.
+    #[default]
+    Full,
+    /// Thin SVD of the previous iteration, updated with the images that changed
+    /// (see [crate::svd::IncrementalSvd]).
+    /// It is faster for stacks with many images once most of them barely move,
+    /// at the cost of an approximation of the smallest changes.
+    Incremental,
+}
+
+impl std::str::FromStr for Svd {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(Svd::Full),
+            "incremental" => Ok(Svd::Incremental),
+            _ => Err(format!(
+                "Unknown SVD backend \"{}\", expecting full or incremental",
+                s
+            )),
+        }
+    }
+}
+
+/// Linear algebra library computing the dense SVDs of the low-rank approximation.
+///
+/// Faer and LAPACK require the cargo features of the same name,
+/// otherwise the registration fails with [RegistrationError::SvdLibrary].
+#[cfg_attr(feature = "wasm-bindgen", wasm_bindgen)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum SvdLibrary {
+    /// SVD of nalgebra, always available.
+    #[default]
+    Nalgebra,
+    /// SVD of faer, usually faster on large stacks.
+    Faer,
+    /// SVD of the LAPACK the program is linked to.
+    Lapack,
+}
+
+impl SvdLibrary {
+    /// Whether the library was compiled in.
+    pub fn is_available(self) -> bool {
+        match self {
+            SvdLibrary::Nalgebra => true,
+            SvdLibrary::Faer => cfg!(feature = "faer"),
+            SvdLibrary::Lapack => cfg!(feature = "lapack"),
+        }
+    }
+}
+
+impl std::str::FromStr for SvdLibrary {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nalgebra" => Ok(SvdLibrary::Nalgebra),
+            "faer" => Ok(SvdLibrary::Faer),
+            "lapack" => Ok(SvdLibrary::Lapack),
+            _ => Err(format!(
+                "Unknown SVD library \"{}\", expecting nalgebra, faer or lapack",
+                s
+            )),
+        }
+    }
+}
+
+impl<F: RealField> SvdBackend<F> for SvdLibrary {
+    fn svd(&self, mat: DMatrix<F>) -> (DMatrix<F>, DVector<F>, DMatrix<F>) {
+        match self {
+            SvdLibrary::Nalgebra => Nalgebra.svd(mat),
+            #[cfg(feature = "faer")]
+            SvdLibrary::Faer => Faer.svd(mat),
+            #[cfg(feature = "lapack")]
+            SvdLibrary::Lapack => Lapack.svd(mat),
+            #[allow(unreachable_patterns)]
+            library => panic!("The {:?} SVD library was not compiled in", library),
+        }
+    }
+}
+
+/// Linearization of the Gauss-Newton steps of the motions, in the low-rank mode.
+#[cfg_attr(feature = "wasm-bindgen", wasm_bindgen)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Compositional {
+    /// Gradients and Hessians of the registered images recomputed at each iteration.
+    #[default]
+    Forwards,
+    /// Gradients and Hessians computed once per level, on the low-rank approximations
+    /// of the images at its first iteration.
+    /// Levels with sparse resolution use the registered reference image instead,
+    /// since the gradients of the approximations are not known there.
+    /// Both are approximations of the forwards step, which they match on images
+    /// that only differ by their motions, such as `img::synthetic::translated_stack`.
+    /// Iterations are substantially cheaper, especially with sparse resolution,
+    /// at the cost of keeping the gradients in memory, and of a few more iterations
+    /// for images moving a lot within a level.
+    Inverse,
+}
+
+impl std::str::FromStr for Compositional {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "forwards" => Ok(Compositional::Forwards),
+            "inverse" => Ok(Compositional::Inverse),
+            _ => Err(format!(
+                "Unknown compositional step \"{}\", expecting forwards or inverse",
+                s
+            )),
+        }
+    }
+}
+
+/// Tolerance of the incremental SVD, relative to the singular value threshold 1 / rho.
+/// Singular values under it are dropped, and images changing less than it are not updated.
+const INCREMENTAL_SVD_TOLERANCE: f32 = 0.1;
+
+/// Registration model of the images.
+#[cfg_attr(feature = "wasm-bindgen", wasm_bindgen)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Mode {
+    /// All images are registered together by minimizing the rank of the registered images.
+    #[default]
+    LowRank,
+    /// Each image is registered to the reference (first) image,
+    /// with a robust Gauss-Newton on the intensity differences.
+    /// It is a baseline, and it never builds the pixels x images matrix,
+    /// so it fits larger stacks in memory.
+    /// Gain and bias estimation, outliers detection and double precision
+    /// are only available with the low-rank model.
+    Pairwise,
+    /// Same as pairwise, but each image is registered to the previous one,
+    /// for sequences slowly drifting away from the reference.
+    Sequential,
+}
+
+impl std::str::FromStr for Mode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lowrank" => Ok(Mode::LowRank),
+            "pairwise" => Ok(Mode::Pairwise),
+            "sequential" => Ok(Mode::Sequential),
+            _ => Err(format!(
+                "Unknown mode \"{}\", expecting lowrank, pairwise or sequential",
+                s
+            )),
+        }
+    }
 }
 
 /// Type alias just to semantically differenciate Vec<Levels<_>> and Levels<Vec<_>>.
@@ -38,19 +589,19 @@ type Levels<T> = Vec<T>;
 
 /// Trait for types that implement all the necessary stuff in order
 /// to do registration on matrices of that type.
-/// Basically u8 and u16 (only gray images supported for now).
+/// Implemented for u8 and u16 gray images,
+/// see the [pixel](crate::img::pixel) module to implement it for other pixel types.
 pub trait CanRegister:
     Copy
     + Scalar
-    + Primitive
     + crate::img::viz::IntoRgb8
     + crate::img::multires::Bigger
     + crate::img::gradients::Bigger<<Self as CanRegister>::Bigger>
     + CanLinearInterpolate<f32, f32>
     + CanLinearInterpolate<f32, Self>
-where
-    DMatrix<Self>: ToImage,
+    + CanNormalize
 {
+    /// Type of the squared norm of the gradients, compared to the sparse diff threshold.
     type Bigger: Scalar + Copy + PartialOrd + Add<Output = Self::Bigger>;
 }
 
@@ -65,18 +616,87 @@ impl CanRegister for u16 {
 pub enum RegistrationError {
     #[error("The algorithm was stopped by the caller")]
     StoppedByCaller,
+    #[error("There is no image to register")]
+    NoImages,
     #[error("Error while trying to inverse the motion of the reference image: {0}")]
     InverseRefMotion(Vector6<f32>),
     #[error("Not enough pixels to perform a direct image alignment estimation: {0}")]
     NotEnoughPoints(u32),
     #[error("The Hessian matrix computed for the direct alignment is not definite positive so its Choleski decomposition failed: {0}")]
-    NonDefinitePositiveHessian(Matrix6<f32>),
+    NonDefinitePositiveHessian(Box<Matrix6<f32>>),
+    #[error("Image of size {actual:?} does not match the size {expected:?} of the other images")]
+    ImageSize {
+        expected: (usize, usize),
+        actual: (usize, usize),
+    },
+    #[error("{images} channel images cannot be split into images of {channels} channels")]
+    ChannelsCount { images: usize, channels: usize },
+    #[error(
+        "The checkpoint of {images} images and {levels} levels does not match this registration"
+    )]
+    Checkpoint { levels: usize, images: usize },
+    #[error("The {0:?} SVD library requires the lowrr feature of the same name")]
+    SvdLibrary(SvdLibrary),
 }
 
 macro_rules! gray_affine_may_stop {
-    ($config: expr, $imgs: expr, $sparse_diff_threshold: expr, $($should_stop: expr),*) => {{
+    ($config: expr, $channels: expr, $imgs: expr, $sparse_diff_threshold: expr, $on_progress: expr, $on_preview: expr, $is_cancelled: expr, $resume: expr, $on_checkpoint: expr, $($should_stop: expr),*) => {{
+        // Normalize the intensities of the images.
+        let mut imgs: Vec<DMatrix<T>> = $imgs;
+        if imgs.is_empty() {
+            return Err(RegistrationError::NoImages);
+        }
+        if !$config.svd_library.is_available() {
+            return Err(RegistrationError::SvdLibrary($config.svd_library));
+        }
+        if let Err(crate::img::size::SizeError::Mismatch { expected, actual, .. }) = crate::img::size::check(&imgs) {
+            return Err(RegistrationError::ImageSize { expected, actual });
+        }
+        let channels: usize = $channels;
+        if channels == 0 || imgs.len() % channels != 0 {
+            return Err(RegistrationError::ChannelsCount { images: imgs.len(), channels });
+        }
+        normalize($config.normalization, &mut imgs);
+
+        // Register long sequences by overlapping chunks of images.
+        let windows = chunk_windows($config.chunk_size, imgs.len() / channels);
+        let resume: Option<&Checkpoint> = $resume;
+        if windows.len() <= 1 {
+            register_levels!($config, channels, imgs, $sparse_diff_threshold, $on_progress, $on_preview, $is_cancelled, resume, $on_checkpoint, $($should_stop),*)
+        } else {
+            if resume.is_some() {
+                log::warn!("Checkpoints are not supported for chunked registrations, starting from scratch");
+            }
+            let mut stitching = Stitching::new(imgs.len() / channels);
+            for window in windows {
+                log::info!("=============  Chunk of images {} to {}  =============", window.start, window.end - 1);
+                let chunk_imgs = imgs[window.start * channels..window.end * channels].to_vec();
+                let chunk = register_levels!($config, channels, chunk_imgs, $sparse_diff_threshold, $on_progress, $on_preview, $is_cancelled, None, |_: &Checkpoint| {}, $($should_stop),*)?;
+                if stitching.push(window, chunk) {
+                    break;
+                }
+            }
+            let mut registered = stitching.into_registered(imgs);
+            // Motions composed across chunks may leave the bounds of each chunk.
+            let step_config = StepConfig::from(&$config);
+            let image_size = (registered.imgs[0].ncols(), registered.imgs[0].nrows());
+            for motion in registered.motion_vec.iter_mut() {
+                clamp_motion(motion, &step_config, image_size);
+            }
+            Ok(registered)
+        }
+    }};
+}
+
+/// Multi-resolution registration of already normalized images,
+/// the `channels` consecutive images being the channels of one image.
+macro_rules! register_levels {
+    ($config: expr, $channels: expr, $imgs: expr, $sparse_diff_threshold: expr, $on_progress: expr, $on_preview: expr, $is_cancelled: expr, $resume: expr, $on_checkpoint: expr, $($should_stop: expr),*) => {{
+        let imgs: Vec<DMatrix<T>> = $imgs;
+        let channels: usize = $channels;
+
         // Get the number of images to align.
-        let imgs_count = $imgs.len();
+        let imgs_count = imgs.len();
 
         // Precompute a hierarchy of multi-resolution images and gradients norm.
         $(if $should_stop("Precompute multiresolution pyramid", None).await {
@@ -86,36 +706,30 @@ macro_rules! gray_affine_may_stop {
         log::debug!("Precompute sparse pixels");
         let mut multires_imgs: Vec<Levels<_>> = Vec::with_capacity(imgs_count);
         let mut multires_sparse_pixels: Vec<Levels<_>> = Vec::with_capacity(imgs_count);
-        for im in $imgs.into_iter() {
-            let pyramid: Levels<DMatrix<T>> = crate::img::multires::mean_pyramid($config.levels, im);
-            let gradients: Levels<DMatrix<T::Bigger>> = pyramid
-                .iter()
-                .map(crate::img::gradients::squared_norm_direct)
-                .collect();
-            let sparse_pixels = crate::img::sparse::select($sparse_diff_threshold, gradients.as_slice());
-            multires_sparse_pixels.push(sparse_pixels);
+        // Original images, kept only if the registration uses working copies.
+        let mut original_imgs = Vec::new();
+        // Images at the lowest resolution, for the previews.
+        let mut thumbnails = Vec::with_capacity(imgs_count);
+        for im in imgs.into_iter() {
+            let mut pyramid: Levels<DMatrix<T>> = crate::img::multires::mean_pyramid($config.levels, im);
+            thumbnails.push(pyramid[pyramid.len() - 1].clone());
+            if uses_working_copies(&$config) {
+                let transformed = pyramid.iter().enumerate().map(|(level, img)| working_img(&$config, level, img)).collect();
+                original_imgs.extend(std::mem::replace(&mut pyramid, transformed).into_iter().next());
+            }
+            // Only the sparse pixels of the channels of the first image are used.
+            if multires_sparse_pixels.len() < channels {
+                multires_sparse_pixels.push(sparse_masks(&$config, &pyramid, $sparse_diff_threshold));
+            }
             multires_imgs.push(pyramid);
         }
 
         // Save multires imgs.
         // crate::utils::save_imgs("out/multires_imgs", &multires_imgs[0]);
 
-        // Save sparse pixels of first image.
-        let mut multires_sparse_viz: Levels<DMatrix<(u8, u8, u8)>> = Vec::with_capacity($config.levels);
-        for (sparse_mask, img_mat) in multires_sparse_pixels[0]
-            .iter()
-            .zip(multires_imgs[0].iter().rev())
-        {
-            multires_sparse_viz.push(crate::img::viz::mask_overlay(sparse_mask, img_mat));
-        }
-        // crate::utils::save_rgb_imgs::<&str, u8>(
-        //     "out/multires_sparse_img0",
-        //     multires_sparse_viz.as_slice(),
-        // );
-
         // Transpose the `Vec<Levels<_>>` structure of multires images
         // into a `Levels<Vec<_>>` to have each level regrouped.
-        let multires_imgs: Levels<Vec<_>> = crate::utils::transpose(multires_imgs);
+        let mut multires_imgs: Levels<Vec<_>> = crate::utils::transpose(multires_imgs);
         // let multires_sparse_pixels: Levels<Vec<_>> = crate::utils::transpose(multires_sparse_pixels);
 
         // // Merge sparse pixels by level.
@@ -123,7 +737,13 @@ macro_rules! gray_affine_may_stop {
         //     .iter()
         //     .map(|v| merge_sparse(v))
         //     .collect();
-        let multires_sparse_pixels = multires_sparse_pixels[0].clone();
+        let mut channels_sparse_pixels = multires_sparse_pixels.into_iter();
+        let mut multires_sparse_pixels = channels_sparse_pixels.next().expect("There is at least one image");
+        for channel_sparse_pixels in channels_sparse_pixels {
+            for (mask, channel_mask) in multires_sparse_pixels.iter_mut().zip(channel_sparse_pixels) {
+                mask.zip_apply(&channel_mask, |a, b| a || b);
+            }
+        }
 
         // // Save merged sparse pixels of all images.
         // let mut multires_sparse_merged_viz = Vec::with_capacity(config.levels);
@@ -136,18 +756,65 @@ macro_rules! gray_affine_may_stop {
         // crate::utils::save_rgb_imgs("out/multires_sparse_merged", &multires_sparse_merged_viz);
 
         // Initialize the motion vector.
-        let mut motion_vec = vec![Vector6::zeros(); imgs_count];
+        let mut motion_vec = vec![Vector6::zeros(); imgs_count / channels];
+
+        // Sparse masks of the levels where sparse resolution is used.
+        let mut used_sparse_masks: Levels<Option<DMatrix<bool>>> = vec![None; multires_imgs.len()];
+
+        // Level at which the registration was cancelled, if it was.
+        let mut cancelled_level = None;
+
+        // Time spent at each level, if profiled.
+        let mut profile = Vec::new();
+
+        // Motions at the end of each level, at the original resolution.
+        let mut level_motions = Vec::with_capacity(multires_imgs.len());
+
+        // Uncertainty of the motions of the images registered at the original resolution.
+        let mut uncertainties = vec![None; motion_vec.len()];
+
+        // Levels that reached the maximum number of iterations.
+        let mut unconverged_levels = Vec::new();
+
+        // Images still in the low-rank model, and those excluded as outliers,
+        // with their channels at the original resolution.
+        let mut inliers: Vec<usize> = (0..motion_vec.len()).collect();
+        let mut outliers: Vec<(usize, Vec<DMatrix<T>>)> = Vec::new();
+
+        // Resume after the last level completed by a previous registration.
+        let levels_count = multires_imgs.len();
+        let mut first_level = levels_count;
+        let resume: Option<&Checkpoint> = $resume;
+        if let Some(checkpoint) = resume {
+            if !checkpoint.fits(levels_count, motion_vec.len()) {
+                return Err(RegistrationError::Checkpoint {
+                    levels: checkpoint.levels,
+                    images: checkpoint.motion_vec.len(),
+                });
+            }
+            if checkpoint.level == levels_count {
+                log::info!("Start the registration from the given motions");
+            } else {
+                log::info!("Resume the registration after level {}", checkpoint.level);
+            }
+            first_level = checkpoint.level;
+            motion_vec = checkpoint.motion_vec.clone();
+            let mut checkpoint_outliers = checkpoint.outliers.clone();
+            checkpoint_outliers.sort_unstable();
+            checkpoint_outliers.dedup();
+            // Positions in the inliers are the indices of the images while none is removed.
+            for &i in checkpoint_outliers.iter().rev() {
+                outliers.push(exclude_outlier(i, channels, &mut inliers, &mut multires_imgs));
+            }
+        }
 
         // Multi-resolution algorithm.
         // Does the same thing at each level for the corresponding images and gradients.
         // The iterator is reversed to start at last level (lowest resolution).
         // Level 0 are the initial images.
-        for (level, (lvl_imgs, lvl_sparse_pixels)) in multires_imgs
-            .iter()
-            .zip(multires_sparse_pixels.iter().rev())
-            .enumerate()
-            .rev()
-        {
+        for level in (0..first_level).rev() {
+            let lvl_imgs = &multires_imgs[level];
+            let lvl_sparse_pixels = &multires_sparse_pixels[levels_count - 1 - level];
             log::info!("=============  Start level {}  =============", level);
             $(if $should_stop("level", Some(level as u32)).await {
                     return Err(RegistrationError::StoppedByCaller);
@@ -155,108 +822,568 @@ macro_rules! gray_affine_may_stop {
 
             // Algorithm parameters.
             let (height, width) = lvl_imgs[0].shape();
-            let step_config = StepConfig {
-                lambda: $config.lambda,
-                rho: $config.rho,
-                max_iterations: $config.max_iterations,
-                threshold: $config.threshold,
-                verbosity: $config.verbosity,
-            };
+            let step_config = StepConfig::from(&$config).at_level(level);
 
             // motion_vec is adapted when changing level.
             for motion in motion_vec.iter_mut() {
                 motion[4] *= 2.0;
                 motion[5] *= 2.0;
             }
-
-            // Sparse filter.
-            let pixels_count = height * width;
-            let sparse_count: usize = lvl_sparse_pixels
-                .iter()
-                .map(|x| if *x { 1 } else { 0 })
-                .sum();
-            let sparse_ratio = sparse_count as f32 / pixels_count as f32;
+            if $is_cancelled() {
+                cancelled_level = Some(level);
+                break;
+            }
 
             // Choose sparsity.
-            let sparsity: Sparsity;
-            let actual_pixel_count: usize;
-            let pixel_coordinates: Rc<Vec<(usize, usize)>>;
-            if sparse_ratio > $config.sparse_ratio_threshold {
-                log::info!(
-                    "Sparse ratio: {} / {} = {:.2} >= {:.2}    using DENSE resolution",
-                    sparse_count,
-                    pixels_count,
-                    sparse_ratio,
-                    $config.sparse_ratio_threshold
-                );
-                sparsity = Sparsity::Full;
-                actual_pixel_count = pixels_count;
-                pixel_coordinates = Rc::new(crate::utils::coords_col_major((height, width)).collect());
-            } else {
-                log::info!(
-                    "Sparse ratio: {} / {} = {:.2} <= {:.2}    using SPARSE resolution",
-                    sparse_count,
-                    pixels_count,
-                    sparse_ratio,
-                    $config.sparse_ratio_threshold
-                );
-                sparsity = Sparsity::Sparse;
-                actual_pixel_count = sparse_count;
-                pixel_coordinates = Rc::new(crate::utils::coordinates_from_mask(lvl_sparse_pixels));
+            let (sparsity, pixel_coordinates) = choose_sparsity(&$config, lvl_sparse_pixels)?;
+            if let Sparsity::Sparse = sparsity {
+                used_sparse_masks[level] = Some(lvl_sparse_pixels.clone());
             }
 
             // Declare mutable loop state.
-            let mut loop_state;
-            let mut imgs_registered;
             let obs = Obs {
                 image_size: (width, height),
                 images: lvl_imgs.as_slice(),
+                channels,
                 sparsity,
                 coordinates: pixel_coordinates.as_slice(),
             };
-
-            // We also recompute the registered images before starting the algorithm loop.
-            imgs_registered = DMatrix::zeros(actual_pixel_count, imgs_count);
-            project_f32(
-                pixel_coordinates.iter().cloned(),
-                &mut imgs_registered,
-                &lvl_imgs,
-                &motion_vec,
-            );
-
-            // Updated state variables for the loops.
-            loop_state = State {
-                nb_iter: 0,
-                imgs_registered,
-                old_imgs_a: DMatrix::zeros(actual_pixel_count, imgs_count),
-                errors: DMatrix::zeros(actual_pixel_count, imgs_count),
-                lagrange_mult_rho: DMatrix::zeros(actual_pixel_count, imgs_count),
-                motion_vec: motion_vec.clone(),
-            };
+            let inliers_motion_vec: Vec<_> = inliers.iter().map(|&i| motion_vec[i]).collect();
+            let mut loop_state = LevelState::new(&$config, &obs, &inliers_motion_vec);
+            let level_start = if $config.profile { Some(Instant::now()) } else { None };
 
             // Main loop.
             let mut continuation = Continue::Forward;
             while continuation == Continue::Forward {
-                $(if $should_stop("iteration", Some(loop_state.nb_iter as u32)).await {
+                $(if $should_stop("iteration", Some(loop_state.nb_iter() as u32)).await {
                         return Err(RegistrationError::StoppedByCaller);
                 })*
+                if $is_cancelled() {
+                    cancelled_level = Some(level);
+                    break;
+                }
                 continuation = loop_state.step(&step_config, &obs)?;
+                $on_progress(Progress {
+                    level,
+                    iteration: loop_state.nb_iter(),
+                    residual: loop_state.residual(),
+                });
+            }
+            // The last iteration is counted before stopping.
+            if cancelled_level.is_none() && loop_state.nb_iter() > $config.max_iterations {
+                log::info!("Level {} stopped before converging", level);
+                unconverged_levels.push(level);
+            }
+
+            if let Some(start) = level_start {
+                profile.push(LevelProfile {
+                    level,
+                    iterations: loop_state.nb_iter(),
+                    total: start.elapsed(),
+                    ..loop_state.profile()
+                });
             }
 
+            // Flag outliers after the coarsest level, from the images of that level.
+            let new_outliers = if level + 1 == levels_count
+                && $config.outlier_threshold > 0.0
+                && $config.mode == Mode::LowRank
+            {
+                find_outliers(
+                    $config.outlier_threshold,
+                    &loop_state,
+                    channels,
+                    (width, height),
+                    $config.pixel_aspect_ratio,
+                )
+            } else {
+                Vec::new()
+            };
+
             // Update the motion vec before next level
-            motion_vec = loop_state.motion_vec;
+            let step_covariances = loop_state.step_covariances().to_vec();
+            for (&i, motion) in inliers.iter().zip(loop_state.into_motion_vec()) {
+                motion_vec[i] = motion;
+            }
+            if level == 0 {
+                for (&i, step_covariance) in inliers.iter().zip(&step_covariances) {
+                    uncertainties[i] =
+                        Some(MotionUncertainty::from_step(&motion_vec[i], step_covariance, (width, height)));
+                }
+            }
             motion_vec
                 .iter()
                 .for_each(|v| log::debug!("   {:?}", v.data));
+            let level_scale = 2_f32.powi(level as i32);
+            level_motions.push((
+                level,
+                motion_vec
+                    .iter()
+                    .map(|motion| {
+                        let mut motion = *motion;
+                        motion[4] *= level_scale;
+                        motion[5] *= level_scale;
+                        motion
+                    })
+                    .collect(),
+            ));
+
+            // Preview of the registration at the lowest resolution.
+            let scale = 2_f32.powi((levels_count - 1 - level) as i32);
+            $on_preview(Preview {
+                level,
+                imgs: thumbnails
+                    .iter()
+                    .enumerate()
+                    .map(|(j, img)| {
+                        let mut motion = motion_vec[j / channels];
+                        motion[4] /= scale;
+                        motion[5] /= scale;
+                        warp::<T, f32, T>(img, &motion)
+                    })
+                    .collect(),
+            });
+            if cancelled_level.is_some() {
+                break;
+            }
+
+            // Exclude outliers from the next levels, from the last one to keep indices valid.
+            for &k in new_outliers.iter().rev() {
+                let outlier = exclude_outlier(k, channels, &mut inliers, &mut multires_imgs);
+                log::info!("Image {} is an outlier, excluded from the next levels", outlier.0);
+                outliers.push(outlier);
+            }
+
+            // State needed to resume the registration after this level.
+            $on_checkpoint(&Checkpoint {
+                levels: levels_count,
+                level,
+                motion_vec: motion_vec.clone(),
+                outliers: outliers.iter().map(|(i, _)| *i).collect(),
+            });
         } // End of levels
 
+        // Bring back partial motions estimated at a lower resolution to the original one.
+        if let Some(level) = cancelled_level {
+            log::warn!("Registration cancelled at level {}, motions are partial", level);
+            let scale = 2_f32.powi(level as i32);
+            for motion in motion_vec.iter_mut() {
+                motion[4] *= scale;
+                motion[5] *= scale;
+            }
+        }
+
         // Return the final motion vector.
         // And give back the images at original resolution.
-        let imgs = multires_imgs.into_iter().next().unwrap();
-        Ok((motion_vec, imgs))
+        outliers.sort_by_key(|(i, _)| *i);
+        let imgs = if uses_working_copies(&$config) {
+            original_imgs
+        } else {
+            // Put back the outliers at their place, in increasing order.
+            let mut imgs = multires_imgs.into_iter().next().unwrap();
+            for (i, channel_imgs) in outliers.iter_mut() {
+                let position = *i * channels;
+                imgs.splice(position..position, channel_imgs.drain(..));
+            }
+            imgs
+        };
+        let outliers = outliers.into_iter().map(|(i, _)| i).collect();
+        unconverged_levels.reverse();
+        Ok(Registered {
+            motion_vec,
+            imgs,
+            sparse_masks: used_sparse_masks,
+            cancelled: cancelled_level.is_some(),
+            profile,
+            outliers,
+            level_motions,
+            uncertainties,
+            unconverged_levels,
+        })
     }};
 }
 
+/// Remove the image at position `k` of the inliers from the multi-resolution images,
+/// returning its index and its channels at the original resolution.
+fn exclude_outlier<T: Scalar>(
+    k: usize,
+    channels: usize,
+    inliers: &mut Vec<usize>,
+    multires_imgs: &mut Levels<Vec<DMatrix<T>>>,
+) -> (usize, Vec<DMatrix<T>>) {
+    let i = inliers.remove(k);
+    let columns = k * channels..(k + 1) * channels;
+    let removed: Levels<Vec<_>> = multires_imgs
+        .iter_mut()
+        .map(|imgs| imgs.drain(columns.clone()).collect())
+        .collect();
+    (
+        i,
+        removed
+            .into_iter()
+            .next()
+            .expect("There is at least one level"),
+    )
+}
+
+/// Minimum number of images shared by consecutive chunks,
+/// so that a single badly registered image does not break their stitching.
+const MIN_CHUNK_OVERLAP: usize = 3;
+
+/// Ranges of images registered together, in increasing order.
+///
+/// A single range covers all images if `chunk_size` is 0 or not smaller than the count.
+/// Otherwise, consecutive ranges share a quarter of their images
+/// (at least `MIN_CHUNK_OVERLAP` if they are large enough),
+/// and the last range is moved back to keep its full size.
+fn chunk_windows(chunk_size: usize, count: usize) -> Vec<Range<usize>> {
+    if chunk_size == 0 || count <= chunk_size {
+        return std::iter::once(0..count).collect();
+    }
+    let size = chunk_size.max(2);
+    let step = size - (size / 4).max(MIN_CHUNK_OVERLAP).min(size - 1);
+    let mut windows = Vec::new();
+    let mut start = 0;
+    loop {
+        let start_clamped = start.min(count - size);
+        windows.push(start_clamped..start_clamped + size);
+        if start_clamped + size == count {
+            return windows;
+        }
+        start += step;
+    }
+}
+
+/// Registration results of consecutive chunks, brought into the frame of the first image.
+struct Stitching {
+    motion_vec: Vec<Vector6<f32>>,
+    /// Number of images with a motion in the frame of the first image.
+    done: usize,
+    sparse_masks: Option<Levels<Option<DMatrix<bool>>>>,
+    cancelled: bool,
+    profile: Vec<LevelProfile>,
+    outliers: Vec<usize>,
+    level_motions: Vec<(usize, Vec<Vector6<f32>>)>,
+    uncertainties: Vec<Option<MotionUncertainty>>,
+    unconverged_levels: Vec<usize>,
+}
+
+impl Stitching {
+    fn new(count: usize) -> Self {
+        Self {
+            motion_vec: vec![Vector6::zeros(); count],
+            done: 0,
+            sparse_masks: None,
+            cancelled: false,
+            profile: Vec::new(),
+            outliers: Vec::new(),
+            level_motions: Vec::new(),
+            uncertainties: vec![None; count],
+            unconverged_levels: Vec::new(),
+        }
+    }
+
+    /// Add the registration of the images of a chunk, starting within the images already done.
+    /// Return true if the registration was cancelled.
+    ///
+    /// The motions of a chunk are relative to its first image.
+    /// They are composed with the median transformation from the first image of the sequence
+    /// to the first image of the chunk, estimated on each shared image.
+    /// Shared images keep their motion from the previous chunk.
+    /// The motions of each level are composed with the same transformation as the final ones.
+    fn push<T: Scalar>(&mut self, window: Range<usize>, chunk: Registered<T>) -> bool {
+        let chunk_outliers: Vec<usize> = chunk.outliers.iter().map(|k| window.start + k).collect();
+        let shared = window.start..self.done;
+        let is_anchor = |i: &usize| !self.outliers.contains(i) && !chunk_outliers.contains(i);
+        let anchors: Vec<usize> = match shared.clone().filter(is_anchor).collect::<Vec<_>>() {
+            anchors if anchors.is_empty() => shared.collect(),
+            anchors => anchors,
+        };
+        let transforms: Vec<Vector6<f32>> = anchors
+            .iter()
+            .filter_map(|&i| {
+                let local_inverse =
+                    projection_mat(&chunk.motion_vec[i - window.start]).try_inverse()?;
+                Some(projection_params(
+                    &(local_inverse * projection_mat(&self.motion_vec[i])),
+                ))
+            })
+            .collect();
+        let anchor = if transforms.is_empty() {
+            if window.start > 0 {
+                log::warn!(
+                    "No shared image to stitch the chunk starting at image {}",
+                    window.start
+                );
+            }
+            Vector6::zeros()
+        } else {
+            // Median of each parameter, robust to a shared image badly registered in one chunk.
+            Vector6::from_fn(|k, _| {
+                let mut values: Vec<f32> = transforms.iter().map(|t| t[k]).collect();
+                let middle = values.len() / 2;
+                *values
+                    .select_nth_unstable_by(middle, |a, b| a.total_cmp(b))
+                    .1
+            })
+        };
+        let anchor_mat = projection_mat(&anchor);
+        for i in self.done..window.end {
+            let local = projection_mat(&chunk.motion_vec[i - window.start]);
+            self.motion_vec[i] = projection_params(&(local * anchor_mat));
+        }
+        if let Some(image_size) = chunk.imgs.first().map(|img| (img.ncols(), img.nrows())) {
+            for i in self.done..window.end {
+                self.uncertainties[i] = chunk.uncertainties[i - window.start]
+                    .map(|uncertainty| uncertainty.compose(&anchor_mat, image_size));
+            }
+        }
+        let count = self.motion_vec.len();
+        for (k, (level, chunk_motions)) in chunk.level_motions.iter().enumerate() {
+            if k == self.level_motions.len() {
+                self.level_motions
+                    .push((*level, vec![Vector6::zeros(); count]));
+            }
+            for i in self.done..window.end {
+                let local = projection_mat(&chunk_motions[i - window.start]);
+                self.level_motions[k].1[i] = projection_params(&(local * anchor_mat));
+            }
+        }
+        self.done = window.end;
+        self.sparse_masks.get_or_insert(chunk.sparse_masks);
+        self.cancelled = chunk.cancelled;
+        self.profile.extend(chunk.profile);
+        self.outliers.extend(chunk_outliers);
+        self.unconverged_levels.extend(chunk.unconverged_levels);
+        self.cancelled
+    }
+
+    /// Registration of the whole sequence.
+    /// Images after a cancellation keep a zero motion.
+    fn into_registered<T: Scalar>(mut self, imgs: Vec<DMatrix<T>>) -> Registered<T> {
+        self.outliers.sort_unstable();
+        self.outliers.dedup();
+        self.unconverged_levels.sort_unstable();
+        self.unconverged_levels.dedup();
+        Registered {
+            motion_vec: self.motion_vec,
+            imgs,
+            sparse_masks: self.sparse_masks.unwrap_or_default(),
+            cancelled: self.cancelled,
+            profile: self.profile,
+            outliers: self.outliers,
+            level_motions: self.level_motions,
+            uncertainties: self.uncertainties,
+            unconverged_levels: self.unconverged_levels,
+        }
+    }
+}
+
+/// FAST threshold of the `SparseStrategy::Fast` strategy, for intensities in [0,1].
+const FAST_THRESHOLD: f32 = 0.05;
+
+/// Sparse pixels selected at each level of a multi-resolution pyramid,
+/// with the strategy of the config.
+///
+/// The pyramid starts with the highest resolution,
+/// while the masks are returned starting with the lowest resolution.
+/// This is useful to visualize the pixels used by the registration
+/// of images with this one as the reference.
+pub fn sparse_masks<T: CanRegister>(
+    config: &Config,
+    pyramid: &[DMatrix<T>],
+    sparse_diff_threshold: T::Bigger,
+) -> Levels<DMatrix<bool>> {
+    let gradients = || -> Levels<DMatrix<T::Bigger>> {
+        pyramid
+            .iter()
+            .map(crate::img::gradients::squared_norm_direct)
+            .collect()
+    };
+    if config.pixel_budget > 0 {
+        return crate::img::sparse::select_budget(config.pixel_budget, gradients().as_slice());
+    }
+    match config.sparse_strategy {
+        SparseStrategy::Threshold => {
+            crate::img::sparse::select(sparse_diff_threshold, gradients().as_slice())
+        }
+        SparseStrategy::Percentile => {
+            crate::img::sparse::select_top_fraction(config.sparse_fraction, gradients().as_slice())
+        }
+        SparseStrategy::Grid => crate::img::sparse::select_grid(config.sparse_fraction, pyramid),
+        SparseStrategy::Fast => {
+            let normalized: Levels<DMatrix<f32>> = pyramid
+                .iter()
+                .map(|img| {
+                    img.map(|x| {
+                        let raw = <T as CanLinearInterpolate<f32, f32>>::into_vector(x);
+                        <T as CanLinearInterpolate<f32, f32>>::from_vector(raw)
+                    })
+                })
+                .collect();
+            crate::img::sparse::select_fast(FAST_THRESHOLD, &normalized)
+        }
+    }
+}
+
+/// Result of a registration, with the details useful to inspect it.
+pub struct Registered<T: Scalar> {
+    /// Motion of each image.
+    pub motion_vec: Vec<Vector6<f32>>,
+    /// Images given back at original resolution.
+    pub imgs: Vec<DMatrix<T>>,
+    /// Sparse pixels used at each level, starting with the original resolution.
+    /// `None` for levels where the dense resolution was used.
+    pub sparse_masks: Levels<Option<DMatrix<bool>>>,
+    /// Whether the registration was cancelled before convergence,
+    /// in which case the motions are the ones estimated so far.
+    pub cancelled: bool,
+    /// Time spent at each level, in the order they were registered,
+    /// starting with the lowest resolution.
+    /// Empty unless `Config::profile` is set.
+    pub profile: Vec<LevelProfile>,
+    /// Indices of the images flagged as outliers, in increasing order.
+    /// Empty unless `Config::outlier_threshold` is set.
+    pub outliers: Vec<usize>,
+    /// Motions estimated at the end of each level, with that level,
+    /// in the order they were registered, starting with the lowest resolution.
+    /// Translations are scaled to the original resolution, like the final motions.
+    pub level_motions: Vec<(usize, Vec<Vector6<f32>>)>,
+    /// Uncertainty of each motion, estimated at the last iteration of the original resolution.
+    /// `None` for outliers, and for all images if the registration was cancelled before.
+    pub uncertainties: Vec<Option<MotionUncertainty>>,
+    /// Levels stopped by `Config::max_iterations` before meeting a convergence threshold,
+    /// in increasing order. Motions are less reliable if it contains the level 0.
+    pub unconverged_levels: Vec<usize>,
+}
+
+/// Time spent in the main parts of the registration of one level.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LevelProfile {
+    /// Level of the pyramid, 0 being the original resolution.
+    pub level: usize,
+    pub iterations: usize,
+    /// Low-rank approximation of the registered images.
+    pub svd: Duration,
+    /// Gradients of the registered images.
+    pub gradients: Duration,
+    /// Gauss-Newton step of the motions.
+    pub gauss_newton: Duration,
+    /// Projection of the images with their updated motions.
+    pub projection: Duration,
+    /// Whole level, including the other updates of the algorithm.
+    pub total: Duration,
+}
+
+/// Uncertainty of a motion, from the Gauss-Newton approximation of its last step:
+/// the inverse of the Hessian scaled by the variance of the residuals of the image.
+///
+/// Like the motion, it is relative to the first image, whose own uncertainty is ignored,
+/// and expressed in the frame of the registered images.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MotionUncertainty {
+    /// Covariance of the affine parameters, in the order of the motion vector.
+    pub covariance: Matrix6<f32>,
+    /// Standard deviation of the displacement of the worst constrained image corner,
+    /// along its worst constrained direction, in pixels.
+    pub pixel_std: f32,
+}
+
+impl MotionUncertainty {
+    /// Uncertainty of the motion `motion * (I + step)` in an image of size (width, height),
+    /// given the covariance of the step.
+    fn from_step(
+        motion: &Vector6<f32>,
+        step_covariance: &Matrix6<f32>,
+        image_size: (usize, usize),
+    ) -> Self {
+        let motion = projection_mat(motion);
+        let jacobian = params_jacobian(|step| motion * step);
+        Self::new(
+            jacobian * step_covariance * jacobian.transpose(),
+            image_size,
+        )
+    }
+
+    /// Uncertainty of the motion composed on the right with another one,
+    /// considered exact.
+    fn compose(&self, other: &Matrix3<f32>, image_size: (usize, usize)) -> Self {
+        let jacobian = params_jacobian(|delta| delta * other);
+        Self::new(
+            jacobian * self.covariance * jacobian.transpose(),
+            image_size,
+        )
+    }
+
+    fn new(covariance: Matrix6<f32>, (width, height): (usize, usize)) -> Self {
+        let (w, h) = (width as f32, height as f32);
+        let pixel_std = [(0.0, 0.0), (w, 0.0), (0.0, h), (w, h)]
+            .iter()
+            .map(|&(x, y)| {
+                // Displacement of the corner is J * d for a change d of the parameters.
+                #[rustfmt::skip]
+                let jacobian = Matrix2x6::new(
+                    x, 0.0, y, 0.0, 1.0, 0.0,
+                    0.0, x, 0.0, y, 0.0, 1.0,
+                );
+                let corner_covariance = jacobian * covariance * jacobian.transpose();
+                corner_covariance
+                    .symmetric_eigenvalues()
+                    .max()
+                    .max(0.0)
+                    .sqrt()
+            })
+            .fold(0.0, f32::max);
+        Self {
+            covariance,
+            pixel_std,
+        }
+    }
+}
+
+/// Jacobian of the affine parameters of `f(D)`, for a linear function `f`
+/// of the matrices `D` of affine parameters changes (with a zero last row).
+fn params_jacobian(f: impl Fn(Matrix3<f32>) -> Matrix3<f32>) -> Matrix6<f32> {
+    let mut jacobian = Matrix6::zeros();
+    for k in 0..6 {
+        let delta = projection_mat(&Vector6::from_fn(|i, _| if i == k { 1.0 } else { 0.0 }))
+            - Matrix3::identity();
+        let image = f(delta);
+        jacobian.set_column(
+            k,
+            &Vector6::new(
+                image.m11, image.m21, image.m12, image.m22, image.m13, image.m23,
+            ),
+        );
+    }
+    jacobian
+}
+
+impl<T: CanRegister> Registered<T> {
+    /// Sparse pixels used at the given level, `None` if it used the dense resolution.
+    /// Level 0 is the original resolution.
+    pub fn sparse_mask(&self, level: usize) -> Option<&DMatrix<bool>> {
+        self.sparse_masks.get(level).and_then(|mask| mask.as_ref())
+    }
+
+    /// Overlay in red of the sparse pixels on the first image,
+    /// for each level where the sparse resolution was used.
+    #[allow(clippy::type_complexity)]
+    pub fn sparse_mask_overlays(&self) -> Vec<(usize, DMatrix<(u8, u8, u8)>)> {
+        let pyramid =
+            crate::img::multires::mean_pyramid(self.sparse_masks.len(), self.imgs[0].clone());
+        self.sparse_masks
+            .iter()
+            .zip(pyramid.iter())
+            .enumerate()
+            .filter_map(|(level, (mask, img))| {
+                let mask = mask.as_ref()?;
+                Some((level, crate::img::viz::mask_overlay(mask, img)))
+            })
+            .collect()
+    }
+}
+
 /// Affine registration of single channel images.
 ///
 /// Internally, this uses a multi-resolution approach,
@@ -270,45 +1397,684 @@ pub fn gray_affine<T: CanRegister>(
     config: Config,
     imgs: Vec<DMatrix<T>>,
     sparse_diff_threshold: T::Bigger, // 50
-) -> Result<(Vec<Vector6<f32>>, Vec<DMatrix<T>>), RegistrationError>
-where
-    DMatrix<T>: ToImage,
-{
-    gray_affine_may_stop!(config, imgs, sparse_diff_threshold,)
+) -> Result<(Vec<Vector6<f32>>, Vec<DMatrix<T>>), RegistrationError> {
+    gray_affine_detailed(config, imgs, sparse_diff_threshold).map(|r| (r.motion_vec, r.imgs))
 }
 
-/// Async version of [gray_affine].
-#[allow(clippy::type_complexity)]
-pub async fn async_gray_affine<T: CanRegister, FB: Future<Output = bool>>(
+/// Same as [gray_affine], also returning the sparse pixels used.
+pub fn gray_affine_detailed<T: CanRegister>(
     config: Config,
     imgs: Vec<DMatrix<T>>,
     sparse_diff_threshold: T::Bigger, // 50
-    should_stop: fn(&'static str, Option<u32>) -> FB,
-) -> Result<(Vec<Vector6<f32>>, Vec<DMatrix<T>>), RegistrationError>
-where
-    DMatrix<T>: ToImage,
-{
-    gray_affine_may_stop!(config, imgs, sparse_diff_threshold, should_stop)
+) -> Result<Registered<T>, RegistrationError> {
+    gray_affine_may_stop!(
+        config,
+        1,
+        imgs,
+        sparse_diff_threshold,
+        |_| {},
+        |_| {},
+        || false,
+        None,
+        |_: &Checkpoint| {},
+    )
 }
 
-/// Configuration parameters for the core loop of the algorithm.
-struct StepConfig {
-    lambda: f32,
+/// Joint registration of multi-channel images, given as their separate channels,
+/// the `channels` consecutive images of `imgs` being the channels of one image.
+///
+/// All the channels of an image share the same motion, estimated from
+/// the residuals of all of them, and are columns of the low-rank matrix.
+/// This is more robust than registering a single channel
+/// when the texture of the scene is mostly in one of them.
+/// The returned images are the channel images, with one motion per multi-channel image.
+pub fn multichannel_affine_detailed<T: CanRegister>(
+    config: Config,
+    channels: usize,
+    imgs: Vec<DMatrix<T>>,
+    sparse_diff_threshold: T::Bigger,
+) -> Result<Registered<T>, RegistrationError> {
+    multichannel_affine_previews(config, channels, imgs, sparse_diff_threshold, |_| {})
+}
+
+/// Same as [multichannel_affine_detailed], also calling `on_preview` at the end of each level.
+pub fn multichannel_affine_previews<T: CanRegister>(
+    config: Config,
+    channels: usize,
+    imgs: Vec<DMatrix<T>>,
+    sparse_diff_threshold: T::Bigger,
+    on_preview: impl FnMut(Preview<T>),
+) -> Result<Registered<T>, RegistrationError> {
+    multichannel_affine_checkpoints(
+        config,
+        channels,
+        imgs,
+        sparse_diff_threshold,
+        |_| {},
+        on_preview,
+        None,
+        |_| {},
+    )
+}
+
+/// Same as [multichannel_affine_previews], also calling `on_progress` after each iteration
+/// and `on_checkpoint` at the end of each level,
+/// and resuming after the level of the `resume` checkpoint if there is one.
+///
+/// Checkpoints are ignored by chunked registrations.
+#[allow(clippy::too_many_arguments)]
+pub fn multichannel_affine_checkpoints<T: CanRegister>(
+    config: Config,
+    channels: usize,
+    imgs: Vec<DMatrix<T>>,
+    sparse_diff_threshold: T::Bigger,
+    mut on_progress: impl FnMut(Progress),
+    mut on_preview: impl FnMut(Preview<T>),
+    resume: Option<&Checkpoint>,
+    mut on_checkpoint: impl FnMut(&Checkpoint),
+) -> Result<Registered<T>, RegistrationError> {
+    gray_affine_may_stop!(
+        config,
+        channels,
+        imgs,
+        sparse_diff_threshold,
+        on_progress,
+        on_preview,
+        || false,
+        resume,
+        on_checkpoint,
+    )
+}
+
+/// Async version of [gray_affine].
+#[allow(clippy::type_complexity)]
+pub async fn async_gray_affine<T: CanRegister, FB: Future<Output = bool>>(
+    config: Config,
+    imgs: Vec<DMatrix<T>>,
+    sparse_diff_threshold: T::Bigger, // 50
+    should_stop: fn(&'static str, Option<u32>) -> FB,
+) -> Result<(Vec<Vector6<f32>>, Vec<DMatrix<T>>), RegistrationError> {
+    async_gray_affine_detailed(config, imgs, sparse_diff_threshold, should_stop)
+        .await
+        .map(|r| (r.motion_vec, r.imgs))
+}
+
+/// Async version of [gray_affine_detailed].
+pub async fn async_gray_affine_detailed<T: CanRegister, FB: Future<Output = bool>>(
+    config: Config,
+    imgs: Vec<DMatrix<T>>,
+    sparse_diff_threshold: T::Bigger, // 50
+    should_stop: fn(&'static str, Option<u32>) -> FB,
+) -> Result<Registered<T>, RegistrationError> {
+    async_gray_affine_cancellable(
+        config,
+        imgs,
+        sparse_diff_threshold,
+        should_stop,
+        |_| {},
+        &CancelToken::new(),
+    )
+    .await
+}
+
+/// Same as [async_gray_affine_detailed], also calling `on_progress` after each iteration.
+pub async fn async_gray_affine_progress<T: CanRegister, FB: Future<Output = bool>>(
+    config: Config,
+    imgs: Vec<DMatrix<T>>,
+    sparse_diff_threshold: T::Bigger, // 50
+    should_stop: fn(&'static str, Option<u32>) -> FB,
+    on_progress: impl FnMut(Progress),
+) -> Result<Registered<T>, RegistrationError> {
+    async_gray_affine_cancellable(
+        config,
+        imgs,
+        sparse_diff_threshold,
+        should_stop,
+        on_progress,
+        &CancelToken::new(),
+    )
+    .await
+}
+
+/// Same as [async_gray_affine_progress], also stopping early if `cancel` is triggered.
+///
+/// Contrary to `should_stop`, which makes the registration fail,
+/// a cancellation returns the motions estimated so far,
+/// with the `cancelled` field of the result set.
+pub async fn async_gray_affine_cancellable<T: CanRegister, FB: Future<Output = bool>>(
+    config: Config,
+    imgs: Vec<DMatrix<T>>,
+    sparse_diff_threshold: T::Bigger, // 50
+    should_stop: fn(&'static str, Option<u32>) -> FB,
+    on_progress: impl FnMut(Progress),
+    cancel: &CancelToken,
+) -> Result<Registered<T>, RegistrationError> {
+    async_gray_affine_previews(
+        config,
+        imgs,
+        sparse_diff_threshold,
+        should_stop,
+        on_progress,
+        |_| {},
+        cancel,
+    )
+    .await
+}
+
+/// Same as [async_gray_affine_cancellable], also calling `on_preview` at the end of each level.
+pub async fn async_gray_affine_previews<T: CanRegister, FB: Future<Output = bool>>(
+    config: Config,
+    imgs: Vec<DMatrix<T>>,
+    sparse_diff_threshold: T::Bigger, // 50
+    should_stop: fn(&'static str, Option<u32>) -> FB,
+    mut on_progress: impl FnMut(Progress),
+    mut on_preview: impl FnMut(Preview<T>),
+    cancel: &CancelToken,
+) -> Result<Registered<T>, RegistrationError> {
+    gray_affine_may_stop!(
+        config,
+        1,
+        imgs,
+        sparse_diff_threshold,
+        on_progress,
+        on_preview,
+        || cancel.is_cancelled(),
+        None,
+        |_: &Checkpoint| {},
+        should_stop
+    )
+}
+
+/// Shared flag to cancel a registration from another task or thread.
+///
+/// Clones share the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask the registrations using this token to stop as soon as possible.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Clear the cancellation, to reuse the token for another registration.
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Progress of a registration, reported after each iteration.
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    /// Current level, 0 being the original resolution.
+    pub level: usize,
+    /// Number of iterations done at the current level.
+    pub iteration: usize,
+    /// Relative change of the low-rank approximation during the last iteration,
+    /// compared to the convergence threshold of the config.
+    pub residual: f32,
+}
+
+/// State of a registration at the end of a level, to resume it after an interruption.
+///
+/// The low-rank approximation and the Lagrange multipliers are not kept:
+/// they are initialized again from the motions at the next level,
+/// like at the start of any level.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checkpoint {
+    /// Number of levels of the registration.
+    pub levels: usize,
+    /// Level that just ended, 0 being the original resolution,
+    /// or the number of levels to start a registration from given motions
+    /// (see [Pipeline::initial_checkpoint](crate::pipeline::Pipeline::initial_checkpoint)).
+    pub level: usize,
+    /// Motions of all images, at the resolution of that level.
+    pub motion_vec: Vec<Vector6<f32>>,
+    /// Images excluded as outliers.
+    pub outliers: Vec<usize>,
+}
+
+impl Checkpoint {
+    /// Whether the checkpoint can resume a registration of `images` images with `levels` levels.
+    pub fn fits(&self, levels: usize, images: usize) -> bool {
+        self.levels == levels
+            && self.level <= levels
+            && self.motion_vec.len() == images
+            && self.outliers.iter().all(|&i| i < images)
+    }
+}
+
+/// Registered thumbnails, reported at the end of each level,
+/// to check early whether the registration is heading the right way.
+///
+/// With chunked registration, the previews of each chunk are reported in turn.
+pub struct Preview<T: Scalar> {
+    /// Level that just ended, 0 being the original resolution.
+    pub level: usize,
+    /// Images at the lowest resolution of the pyramid,
+    /// warped with the motions estimated so far.
+    /// Like the registered images, they are the channels of each image.
+    pub imgs: Vec<DMatrix<T>>,
+}
+
+/// Incremental registration of a set of images that can grow or shrink,
+/// such as in an interactive capture session where images arrive one at a time.
+///
+/// The first call to [Registration::refine] performs the complete multi-resolution registration.
+/// Afterwards, the state of the algorithm at the original resolution
+/// (motions and low-rank approximation) is kept, and later calls to `refine`
+/// only iterate at that resolution.
+/// Pushed images are warm-started with the motion of the previous image,
+/// without resetting the state of the other images.
+///
+/// ```ignore
+/// let mut registration = Registration::new(config, 40);
+/// for img in capture_session {
+///     registration.push_image(img)?;
+///     let motion_vec = registration.refine()?;
+/// }
+/// ```
+pub struct Registration<T: CanRegister> {
+    config: Config,
+    sparse_diff_threshold: T::Bigger,
+    imgs: Vec<DMatrix<T>>,
+    /// Working copies of the images for the data term, empty if not needed.
+    data_imgs: Vec<DMatrix<T>>,
+    motion_vec: Vec<Vector6<f32>>,
+    /// Whether the multi-resolution registration must run at the next refinement.
+    needs_multires: bool,
+    state: Option<IncrementalState>,
+}
+
+/// Loop state kept at the original resolution between refinements.
+struct IncrementalState {
+    sparsity: Sparsity,
+    coordinates: Vec<(usize, usize)>,
+    loop_state: LevelState,
+}
+
+impl<T: CanRegister> Registration<T> {
+    /// Start a registration without any image.
+    pub fn new(config: Config, sparse_diff_threshold: T::Bigger) -> Self {
+        Self {
+            config,
+            sparse_diff_threshold,
+            imgs: Vec::new(),
+            data_imgs: Vec::new(),
+            motion_vec: Vec::new(),
+            needs_multires: true,
+            state: None,
+        }
+    }
+
+    /// Images currently registered, after their intensity normalization.
+    pub fn images(&self) -> &[DMatrix<T>] {
+        &self.imgs
+    }
+
+    /// Motion of each image, as of the last refinement.
+    /// Images pushed since then have the motion used to warm-start them.
+    pub fn motion_vec(&self) -> &[Vector6<f32>] {
+        &self.motion_vec
+    }
+
+    /// Add an image, warm-started with the motion of the previous one.
+    /// All images must have the same size.
+    ///
+    /// The image is normalized when pushed,
+    /// histograms being matched to the first image at that time.
+    pub fn push_image(&mut self, mut img: DMatrix<T>) -> Result<(), RegistrationError> {
+        if let Some(first) = self.imgs.first() {
+            if first.shape() != img.shape() {
+                return Err(RegistrationError::ImageSize {
+                    expected: first.shape(),
+                    actual: img.shape(),
+                });
+            }
+        }
+        match (self.config.normalization, self.imgs.first()) {
+            (Normalization::Histogram, Some(reference)) => {
+                crate::img::normalization::match_histograms(
+                    reference,
+                    std::slice::from_mut(&mut img),
+                )
+            }
+            (Normalization::Clahe, _) => {
+                img = crate::img::normalization::clahe(&img, CLAHE_TILES, CLAHE_CLIP_LIMIT)
+            }
+            _ => {}
+        }
+        let motion = self
+            .motion_vec
+            .last()
+            .cloned()
+            .unwrap_or_else(Vector6::zeros);
+        if uses_working_copies(&self.config) {
+            self.data_imgs.push(working_img(&self.config, 0, &img));
+        }
+        self.imgs.push(img);
+        self.motion_vec.push(motion);
+        if let Some(IncrementalState {
+            sparsity,
+            coordinates,
+            loop_state,
+        }) = self.state.as_mut()
+        {
+            let obs = incremental_obs(
+                data_imgs(&self.imgs, &self.data_imgs),
+                *sparsity,
+                coordinates,
+            );
+            loop_state.push_column(&obs, &motion);
+        }
+        Ok(())
+    }
+
+    /// Remove the image at the given index and return it.
+    ///
+    /// The first image is the reference of all motions.
+    /// Removing it makes the next one the new reference,
+    /// and the state of the algorithm is then rebuilt at the next refinement.
+    ///
+    /// Panics if the index is out of bounds.
+    pub fn remove_image(&mut self, index: usize) -> DMatrix<T> {
+        let img = self.imgs.remove(index);
+        if !self.data_imgs.is_empty() {
+            self.data_imgs.remove(index);
+        }
+        self.motion_vec.remove(index);
+        if index == 0 {
+            // Express all motions relative to the new reference.
+            if let Some(inverse_ref) = self
+                .motion_vec
+                .first()
+                .and_then(|m| projection_mat(m).try_inverse())
+            {
+                let step_config = StepConfig::from(&self.config);
+                let image_size = (img.ncols(), img.nrows());
+                for motion in self.motion_vec.iter_mut() {
+                    *motion = projection_params(&(inverse_ref * projection_mat(motion)));
+                    clamp_motion(motion, &step_config, image_size);
+                }
+            }
+            self.state = None;
+        } else if let Some(state) = self.state.as_mut() {
+            state.loop_state.remove_column(index);
+        }
+        if self.imgs.is_empty() {
+            self.needs_multires = true;
+        }
+        img
+    }
+
+    /// Register the current images, starting from the current motions,
+    /// and return the motion of each image.
+    pub fn refine(&mut self) -> Result<&[Vector6<f32>], RegistrationError> {
+        if !self.config.svd_library.is_available() {
+            return Err(RegistrationError::SvdLibrary(self.config.svd_library));
+        }
+        if self.imgs.len() < 2 {
+            self.motion_vec
+                .iter_mut()
+                .for_each(|m| *m = Vector6::zeros());
+            return Ok(&self.motion_vec);
+        }
+        if self.needs_multires {
+            // Images are already normalized when pushed.
+            let config = Config {
+                normalization: Normalization::None,
+                ..self.config
+            };
+            let registered =
+                gray_affine_detailed(config, self.imgs.clone(), self.sparse_diff_threshold)?;
+            self.motion_vec = registered.motion_vec;
+            self.needs_multires = false;
+            self.state = None;
+        }
+        if self.state.is_none() {
+            let reference = data_imgs(&self.imgs, &self.data_imgs)[0].clone();
+            let pyramid = crate::img::multires::mean_pyramid(self.config.levels, reference);
+            let masks = sparse_masks(&self.config, &pyramid, self.sparse_diff_threshold);
+            let mask = masks.last().expect("There is at least one level");
+            let (sparsity, coordinates) = choose_sparsity(&self.config, mask)?;
+            let obs = incremental_obs(
+                data_imgs(&self.imgs, &self.data_imgs),
+                sparsity,
+                &coordinates,
+            );
+            let loop_state = LevelState::new(&self.config, &obs, &self.motion_vec);
+            self.state = Some(IncrementalState {
+                sparsity,
+                coordinates,
+                loop_state,
+            });
+        }
+        self.iterate(self.config.max_iterations)?;
+        Ok(&self.motion_vec)
+    }
+
+    /// Online registration of a newly captured image against the current model,
+    /// designed to give feedback at capture time.
+    ///
+    /// Once the current images are registered, only a few iterations
+    /// at the original resolution are performed, instead of a complete registration.
+    /// The returned residual indicates whether the image fits the low-rank model,
+    /// a high value suggests that the shot should be retaken.
+    /// The image can then be removed with [Registration::remove_image].
+    pub fn register_online(
+        &mut self,
+        img: DMatrix<T>,
+        iterations: usize,
+    ) -> Result<OnlineFrame, RegistrationError> {
+        self.push_image(img)?;
+        if self.needs_multires || self.state.is_none() {
+            self.refine()?;
+        } else {
+            self.iterate(iterations)?;
+        }
+        let index = self.imgs.len() - 1;
+        let residual = match &self.state {
+            Some(state) => state.loop_state.column_residual(index),
+            None => 0.0,
+        };
+        Ok(OnlineFrame {
+            motion: self.motion_vec[index],
+            residual,
+        })
+    }
+
+    /// Iterate at the original resolution, with an initialized state.
+    fn iterate(&mut self, max_iterations: usize) -> Result<(), RegistrationError> {
+        let IncrementalState {
+            sparsity,
+            coordinates,
+            loop_state,
+        } = self
+            .state
+            .as_mut()
+            .expect("State initialized before iterating");
+        let obs = incremental_obs(
+            data_imgs(&self.imgs, &self.data_imgs),
+            *sparsity,
+            coordinates,
+        );
+        let step_config = StepConfig {
+            max_iterations,
+            ..StepConfig::from(&self.config)
+        };
+        loop_state.reset_iterations();
+        let mut continuation = Continue::Forward;
+        while continuation == Continue::Forward {
+            continuation = loop_state.step(&step_config, &obs)?;
+        }
+        self.motion_vec = loop_state.motion_vec();
+        Ok(())
+    }
+}
+
+/// Result of the online registration of a newly captured image.
+#[derive(Debug, Clone, Copy)]
+pub struct OnlineFrame {
+    /// Motion of the image.
+    pub motion: Vector6<f32>,
+    /// Root mean square difference, with intensities in [0,1],
+    /// between the registered image and its low-rank approximation.
+    pub residual: f32,
+}
+
+/// Images used by the data term of the incremental registration.
+fn data_imgs<'a, T: Scalar>(
+    imgs: &'a [DMatrix<T>],
+    data_imgs: &'a [DMatrix<T>],
+) -> &'a [DMatrix<T>] {
+    if data_imgs.is_empty() {
+        imgs
+    } else {
+        data_imgs
+    }
+}
+
+/// Observations at the original resolution for the incremental registration.
+fn incremental_obs<'a, T: Scalar + Copy>(
+    imgs: &'a [DMatrix<T>],
+    sparsity: Sparsity,
+    coordinates: &'a [(usize, usize)],
+) -> Obs<'a, T> {
+    let (height, width) = imgs[0].shape();
+    Obs {
+        image_size: (width, height),
+        images: imgs,
+        channels: 1,
+        sparsity,
+        coordinates,
+    }
+}
+
+/// Configuration parameters for the core loop of the algorithm.
+struct StepConfig {
+    lambda: f32,
     rho: f32,
     max_iterations: usize,
     threshold: f32,
+    motion_threshold: f32,
+    damping: f32,
+    line_search: bool,
+    compositional: Compositional,
+    /// Maximum displacement of the image centers, in pixels of the current level.
+    max_translation: f32,
+    max_deformation: f32,
     verbosity: u32,
+    temporal_smoothness: f32,
+    pixel_aspect_ratio: f32,
+    do_image_correction: bool,
+    svd_backend: Svd,
+    svd_library: SvdLibrary,
+    profile: bool,
+}
+
+impl From<&Config> for StepConfig {
+    fn from(config: &Config) -> Self {
+        StepConfig {
+            lambda: config.lambda,
+            rho: config.rho,
+            max_iterations: config.max_iterations,
+            threshold: config.threshold,
+            motion_threshold: config.motion_threshold,
+            damping: config.damping,
+            line_search: config.line_search,
+            compositional: config.compositional,
+            max_translation: config.max_translation,
+            max_deformation: config.max_deformation,
+            verbosity: config.verbosity,
+            temporal_smoothness: config.temporal_smoothness,
+            pixel_aspect_ratio: config.pixel_aspect_ratio,
+            do_image_correction: config.do_image_correction,
+            svd_backend: config.svd_backend,
+            svd_library: config.svd_library,
+            profile: config.profile,
+        }
+    }
+}
+
+impl StepConfig {
+    /// Configuration of the given level, with images downscaled by 2^level.
+    fn at_level(self, level: usize) -> Self {
+        StepConfig {
+            max_translation: self.max_translation / 2_f32.powi(level as i32),
+            ..self
+        }
+    }
+}
+
+/// Choose between dense and sparse resolution for a level,
+/// given the mask of sparse pixels selected at that level.
+/// Return the chosen sparsity and the coordinates of the pixels to use.
+#[allow(clippy::cast_precision_loss)]
+fn choose_sparsity(
+    config: &Config,
+    sparse_mask: &DMatrix<bool>,
+) -> Result<(Sparsity, Vec<(usize, usize)>), RegistrationError> {
+    let (height, width) = sparse_mask.shape();
+    let pixels_count = height * width;
+    let sparse_count = sparse_mask.iter().filter(|x| **x).count();
+    let sparse_ratio = sparse_count as f32 / pixels_count as f32;
+    let use_dense = if config.pixel_budget > 0 {
+        pixels_count <= config.pixel_budget
+    } else {
+        sparse_ratio > config.sparse_ratio_threshold
+    };
+    let resolution = if use_dense { "DENSE" } else { "SPARSE" };
+    if config.pixel_budget > 0 {
+        log::info!(
+            "Pixel budget: {} / {} pixels    using {} resolution",
+            sparse_count,
+            pixels_count,
+            resolution
+        );
+    } else {
+        log::info!(
+            "Sparse ratio: {} / {} = {:.2} {} {:.2}    using {} resolution",
+            sparse_count,
+            pixels_count,
+            sparse_ratio,
+            if use_dense { ">=" } else { "<=" },
+            config.sparse_ratio_threshold,
+            resolution
+        );
+    }
+    if use_dense {
+        let coordinates = crate::utils::coords_col_major((height, width)).collect();
+        Ok((Sparsity::Full, coordinates))
+    } else if sparse_count < 6 {
+        // Some strategies, such as FAST corners on smooth images, may select nothing.
+        Err(RegistrationError::NotEnoughPoints(sparse_count as u32))
+    } else {
+        let coordinates = crate::utils::coordinates_from_mask(sparse_mask);
+        Ok((Sparsity::Sparse, coordinates))
+    }
 }
 
 /// "Observations" contains the data provided outside the core of the algorithm.
 /// These are immutable references since we are not supposed to mutate them.
 struct Obs<'a, T: Scalar + Copy> {
     image_size: (usize, usize),
+    /// Channels of all images, the channels of an image being consecutive.
     images: &'a [DMatrix<T>],
+    /// Number of channels of each image, all sharing the same motion.
+    channels: usize,
     sparsity: Sparsity,
     coordinates: &'a [(usize, usize)],
 }
 
+#[derive(Clone, Copy)]
 enum Sparsity {
     Full,
     Sparse,
@@ -322,17 +2088,710 @@ enum Continue {
     Stop,
 }
 
-/// State variables of the loop.
-struct State {
-    nb_iter: usize,
-    imgs_registered: DMatrix<f32>,   // W(u; theta) in paper
-    old_imgs_a: DMatrix<f32>,        // A in paper
-    errors: DMatrix<f32>,            // e in paper
-    lagrange_mult_rho: DMatrix<f32>, // y / rho in paper
-    motion_vec: Vec<Vector6<f32>>,   // theta in paper
-}
+/// Floating point types in which the optimization can run.
+trait Float: RealField + Copy + Default {
+    fn from_single(x: f32) -> Self;
+    fn to_single(self) -> f32;
+    fn to_double(self) -> f64;
+}
+
+impl Float for f32 {
+    fn from_single(x: f32) -> Self {
+        x
+    }
+    fn to_single(self) -> f32 {
+        self
+    }
+    fn to_double(self) -> f64 {
+        self as f64
+    }
+}
+
+impl Float for f64 {
+    fn from_single(x: f32) -> Self {
+        x as f64
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    fn to_single(self) -> f32 {
+        self as f32
+    }
+    fn to_double(self) -> f64 {
+        self
+    }
+}
+
+/// Loop state of one level, in the precision chosen in the config,
+/// or of the pairwise registration.
+enum LevelState {
+    Single(State<f32>),
+    Double(State<f64>),
+    Pairwise(PairwiseState),
+}
+
+impl LevelState {
+    /// Initialize the loop state of a level in the precision of the config.
+    fn new<T: Scalar + Copy + CanLinearInterpolate<f32, f32>>(
+        config: &Config,
+        obs: &Obs<T>,
+        motion_vec: &[Vector6<f32>],
+    ) -> Self {
+        let (gain_bias, packed) = (config.gain_bias, config.pack_observations);
+        match (config.mode, config.precision) {
+            (Mode::Pairwise, _) => LevelState::Pairwise(PairwiseState::new(obs, motion_vec, false)),
+            (Mode::Sequential, _) => {
+                LevelState::Pairwise(PairwiseState::new(obs, motion_vec, true))
+            }
+            (Mode::LowRank, Precision::Single) => {
+                LevelState::Single(State::new(obs, motion_vec, gain_bias, packed))
+            }
+            (Mode::LowRank, Precision::Double) => {
+                LevelState::Double(State::new(obs, motion_vec, gain_bias, packed))
+            }
+        }
+    }
+
+    fn nb_iter(&self) -> usize {
+        match self {
+            LevelState::Single(state) => state.nb_iter,
+            LevelState::Double(state) => state.nb_iter,
+            LevelState::Pairwise(state) => state.nb_iter,
+        }
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn residual(&self) -> f32 {
+        match self {
+            LevelState::Single(state) => state.residual as f32,
+            LevelState::Double(state) => state.residual as f32,
+            LevelState::Pairwise(state) => state.residual as f32,
+        }
+    }
+
+    fn step<T: Scalar + Copy + CanLinearInterpolate<f32, f32>>(
+        &mut self,
+        config: &StepConfig,
+        obs: &Obs<T>,
+    ) -> Result<Continue, RegistrationError> {
+        match self {
+            LevelState::Single(state) => state.step(config, obs),
+            LevelState::Double(state) => state.step(config, obs),
+            LevelState::Pairwise(state) => state.step(config, obs),
+        }
+    }
+
+    fn profile(&self) -> LevelProfile {
+        match self {
+            LevelState::Single(state) => state.profile,
+            LevelState::Double(state) => state.profile,
+            LevelState::Pairwise(state) => state.profile,
+        }
+    }
+
+    fn into_motion_vec(self) -> Vec<Vector6<f32>> {
+        match self {
+            LevelState::Single(state) => state.motion_vec,
+            LevelState::Double(state) => state.motion_vec.iter().map(to_single_vec).collect(),
+            LevelState::Pairwise(state) => state.motion_vec,
+        }
+    }
+
+    fn motion_vec(&self) -> Vec<Vector6<f32>> {
+        match self {
+            LevelState::Single(state) => state.motion_vec.clone(),
+            LevelState::Double(state) => state.motion_vec.iter().map(to_single_vec).collect(),
+            LevelState::Pairwise(state) => state.motion_vec.clone(),
+        }
+    }
+
+    fn step_covariances(&self) -> &[Matrix6<f32>] {
+        match self {
+            LevelState::Single(state) => &state.step_covariances,
+            LevelState::Double(state) => &state.step_covariances,
+            LevelState::Pairwise(state) => &state.step_covariances,
+        }
+    }
+
+    fn column_residual(&self, i: usize) -> f32 {
+        match self {
+            LevelState::Single(state) => state.column_residual(i),
+            LevelState::Double(state) => state.column_residual(i),
+            LevelState::Pairwise(state) => state.column_residual(i),
+        }
+    }
+
+    fn column_error(&self, i: usize) -> f32 {
+        match self {
+            LevelState::Single(state) => state.column_error(i),
+            LevelState::Double(state) => state.column_error(i),
+            LevelState::Pairwise(state) => state.column_residual(i),
+        }
+    }
+
+    fn reset_iterations(&mut self) {
+        match self {
+            LevelState::Single(state) => state.nb_iter = 0,
+            LevelState::Double(state) => state.nb_iter = 0,
+            LevelState::Pairwise(state) => state.nb_iter = 0,
+        }
+    }
+
+    fn push_column<T: Scalar + Copy + CanLinearInterpolate<f32, f32>>(
+        &mut self,
+        obs: &Obs<T>,
+        motion: &Vector6<f32>,
+    ) {
+        match self {
+            LevelState::Single(state) => state.push_column(obs, motion),
+            LevelState::Double(state) => state.push_column(obs, motion),
+            LevelState::Pairwise(state) => state.push_column(motion),
+        }
+    }
+
+    fn remove_column(&mut self, i: usize) {
+        match self {
+            LevelState::Single(state) => state.remove_column(i),
+            LevelState::Double(state) => state.remove_column(i),
+            LevelState::Pairwise(state) => state.remove_column(i),
+        }
+    }
+}
+
+/// State variables of the loop.
+struct State<F: Float> {
+    nb_iter: usize,
+    residual: f64,
+    imgs_registered: RegisteredImgs<F>, // W(u; theta) in paper
+    old_imgs_a: DMatrix<F>,             // A in paper
+    errors: DMatrix<F>,                 // e in paper
+    lagrange_mult_rho: DMatrix<F>,      // y / rho in paper
+    motion_vec: Vec<Vector6<F>>,        // theta in paper
+    /// Covariance of the last Gauss-Newton step of each image.
+    step_covariances: Vec<Matrix6<f32>>,
+    /// Gain and bias of each image, if estimated.
+    gain_bias: Option<GainBias<F>>,
+    /// SVD of the previous A-update, kept with the incremental SVD backend.
+    svd: Option<IncrementalSvd<F>>,
+    /// Time accumulated in each part of the steps, if profiled.
+    profile: LevelProfile,
+    workspace: Workspace<F>,
+}
+
+/// Per-image gain and bias, such that the constraint of the algorithm becomes
+/// gain * W(u; theta) + bias + e = A.
+///
+/// There is one gain and bias per column, the channels of an image being consecutive columns.
+/// They are updated in closed form at each iteration, by matching the mean
+/// and standard deviation of each registered channel to the ones of the same channel
+/// of the reference image.
+/// Fitting them to the low-rank approximation instead is unstable:
+/// the approximation is poor while images are misaligned,
+/// and a lower gain makes an image even less represented in the next approximation.
+struct GainBias<F: Float> {
+    gains: Vec<F>,
+    biases: Vec<F>,
+    channels: usize,
+}
+
+impl<F: Float> GainBias<F> {
+    fn new(imgs_count: usize, channels: usize) -> Self {
+        Self {
+            gains: vec![F::one(); imgs_count],
+            biases: vec![F::zero(); imgs_count],
+            channels,
+        }
+    }
+
+    /// Replace each column of the registered images by gain * column + bias.
+    fn apply(&self, imgs: &mut DMatrix<F>) {
+        for ((mut col, &gain), &bias) in imgs
+            .column_iter_mut()
+            .zip(self.gains.iter())
+            .zip(self.biases.iter())
+        {
+            col.apply(|x| gain * x + bias);
+        }
+    }
+
+    /// Update gains and biases for the current registered images.
+    fn update(&mut self, imgs_registered: &RegisteredImgs<F>) {
+        let stats: Vec<(F, F)> = (0..imgs_registered.ncols())
+            .map(|j| mean_std(imgs_registered.column(j).iter().cloned()))
+            .collect();
+        for (j, &(mean, std)) in stats.iter().enumerate() {
+            let (mean_ref, std_ref) = stats[j % self.channels];
+            // Keep the previous gain for flat images.
+            if std > F::from_single(GAIN_MIN_STD) {
+                self.gains[j] = std_ref / std;
+            }
+            self.biases[j] = mean_ref - self.gains[j] * mean;
+        }
+    }
+
+    fn push(&mut self) {
+        self.gains.push(F::one());
+        self.biases.push(F::zero());
+    }
+
+    fn remove(&mut self, i: usize) {
+        self.gains.remove(i);
+        self.biases.remove(i);
+    }
+}
+
+/// Mean and standard deviation of values.
+#[allow(clippy::cast_precision_loss)]
+fn mean_std<F: Float>(values: impl ExactSizeIterator<Item = F>) -> (F, F) {
+    let count = F::from_single(values.len().max(1) as f32);
+    let (sum, sum_sqr) = values.fold((F::zero(), F::zero()), |(s, s2), x| (s + x, s2 + x * x));
+    let mean = sum / count;
+    let variance = (sum_sqr / count - mean * mean).max(F::zero());
+    (mean, variance.sqrt())
+}
+
+/// Standard deviation of intensities, in [0,1], under which an image is considered flat
+/// and its gain is not updated.
+const GAIN_MIN_STD: f32 = 1e-4;
+
+/// Buffers preallocated once per level and reused at every iteration,
+/// to avoid allocating full pixels x images matrices in each step.
+struct Workspace<F: Float> {
+    /// New A, swapped with the old one at the end of each iteration.
+    imgs_a: DMatrix<F>,
+    /// Temporary matrix, successively used as the input of the SVD,
+    /// for A - W - Y / rho in the e-update, and for the residuals of the theta-update.
+    /// The SVD consumes its input, so it is replaced by the U matrix of the decomposition,
+    /// which has the same shape.
+    temp: DMatrix<F>,
+    /// Gradients of the registered images, only used with sparse resolution.
+    gradients: Vec<GradientsCache>,
+    /// Gradients of all the channels of the image in the current Gauss-Newton step.
+    step_gradients: Vec<(F, F)>,
+    /// Channel of an image projected with a candidate motion of the line search.
+    projected: Vec<F>,
+    /// Template of the inverse compositional step of each image,
+    /// computed at the first iteration of the level.
+    templates: Vec<Option<Template<F>>>,
+}
+
+impl<F: Float> Workspace<F> {
+    fn new(nrows: usize, ncols: usize) -> Self {
+        Self {
+            imgs_a: DMatrix::zeros(nrows, ncols),
+            temp: DMatrix::zeros(nrows, ncols),
+            gradients: (0..ncols).map(|_| GradientsCache::default()).collect(),
+            step_gradients: Vec::new(),
+            projected: Vec::new(),
+            templates: Vec::new(),
+        }
+    }
+}
+
+/// Registered images W(u; theta), with one column per image channel.
+///
+/// They can be packed as 16 bits integers, precise enough for intensities
+/// interpolated from 8 or 16 bits images, and unpacked on the fly.
+enum RegisteredImgs<F: Float> {
+    Float(DMatrix<F>),
+    Packed(DMatrix<u16>),
+}
+
+/// Scale of the intensities in [0,1] of packed registered images.
+const PACKED_SCALE: f32 = u16::MAX as f32;
+
+impl<F: Float> RegisteredImgs<F> {
+    fn new(nrows: usize, ncols: usize, packed: bool) -> Self {
+        if packed {
+            RegisteredImgs::Packed(DMatrix::zeros(nrows, ncols))
+        } else {
+            RegisteredImgs::Float(DMatrix::zeros(nrows, ncols))
+        }
+    }
+
+    fn nrows(&self) -> usize {
+        match self {
+            RegisteredImgs::Float(mat) => mat.nrows(),
+            RegisteredImgs::Packed(mat) => mat.nrows(),
+        }
+    }
+
+    fn ncols(&self) -> usize {
+        match self {
+            RegisteredImgs::Float(mat) => mat.ncols(),
+            RegisteredImgs::Packed(mat) => mat.ncols(),
+        }
+    }
+
+    /// Values of a column, unpacked if needed.
+    fn column(&self, j: usize) -> std::borrow::Cow<'_, [F]> {
+        let nrows = self.nrows();
+        match self {
+            RegisteredImgs::Float(mat) => {
+                std::borrow::Cow::Borrowed(&mat.as_slice()[j * nrows..(j + 1) * nrows])
+            }
+            RegisteredImgs::Packed(mat) => std::borrow::Cow::Owned(
+                mat.as_slice()[j * nrows..(j + 1) * nrows]
+                    .iter()
+                    .map(|&x| unpack(x))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Copy the registered images into a matrix of the same shape.
+    fn copy_into(&self, out: &mut DMatrix<F>) {
+        match self {
+            RegisteredImgs::Float(mat) => out.copy_from(mat),
+            RegisteredImgs::Packed(mat) => out.zip_apply(mat, |_, x| unpack(x)),
+        }
+    }
+
+    /// Subtract the registered images from a matrix of the same shape.
+    fn sub_from(&self, out: &mut DMatrix<F>) {
+        match self {
+            RegisteredImgs::Float(mat) => *out -= mat,
+            RegisteredImgs::Packed(mat) => out.zip_apply(mat, |y, x| y - unpack(x)),
+        }
+    }
+
+    /// Project the images with their motions.
+    fn project<T: Scalar + Copy + CanLinearInterpolate<f32, f32>>(
+        &mut self,
+        obs: &Obs<T>,
+        motion_vec: &[Vector6<F>],
+    ) {
+        match self {
+            RegisteredImgs::Float(mat) => {
+                project(obs.coordinates, mat, obs.images, obs.channels, motion_vec)
+            }
+            RegisteredImgs::Packed(mat) => {
+                let mut registered_col = vec![F::zero(); mat.nrows()];
+                for (j, img) in obs.images.iter().enumerate() {
+                    let motion = &motion_vec[j / obs.channels];
+                    project_column(obs.coordinates, &mut registered_col, img, motion);
+                    for (packed, &x) in mat.column_mut(j).iter_mut().zip(&registered_col) {
+                        *packed = pack(x);
+                    }
+                }
+            }
+        }
+    }
+
+    fn push_column(&mut self, col: &[F]) {
+        match self {
+            RegisteredImgs::Float(mat) => push_zero_column(mat).copy_from_slice(col),
+            RegisteredImgs::Packed(mat) => {
+                for (packed, &x) in push_zero_column(mat).iter_mut().zip(col) {
+                    *packed = pack(x);
+                }
+            }
+        }
+    }
+
+    fn remove_column(&mut self, i: usize) {
+        match self {
+            RegisteredImgs::Float(mat) => remove_column(mat, i),
+            RegisteredImgs::Packed(mat) => remove_column(mat, i),
+        }
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_sign_loss)]
+fn pack<F: Float>(x: F) -> u16 {
+    (x.to_single() * PACKED_SCALE)
+        .round()
+        .clamp(0.0, PACKED_SCALE) as u16
+}
+
+fn unpack<F: Float>(x: u16) -> F {
+    F::from_single(f32::from(x) / PACKED_SCALE)
+}
+
+/// Motion change, in pixels, under which cached gradients are reused.
+const GRADIENTS_CACHE_THRESHOLD: f32 = 1e-3;
+
+/// Gradients of a registered image, with the motion used to compute them.
+///
+/// With sparse resolution, gradients are interpolated from the original image
+/// at 4 points per pixel, which is the most expensive part of the theta-update.
+/// Once an image is almost registered, its motion barely changes between two iterations
+/// and recomputing them is wasted work.
+#[derive(Default)]
+struct GradientsCache {
+    motion: Option<Vector6<f32>>,
+    gradients: Vec<(f32, f32)>,
+}
+
+impl GradientsCache {
+    /// Gradients of the image registered with the given motion.
+    /// They are only recomputed if the motion moved a pixel of the image
+    /// by more than `GRADIENTS_CACHE_THRESHOLD` since the last computation.
+    fn get<T: Scalar + Copy + CanLinearInterpolate<f32, f32>>(
+        &mut self,
+        img: &DMatrix<T>,
+        motion: Vector6<f32>,
+        coordinates: &[(usize, usize)],
+    ) -> &[(f32, f32)] {
+        let (height, width) = img.shape();
+        let up_to_date = self.motion.map_or(false, |cached| {
+            max_displacement(&(motion - cached), (width, height), 1.0) < GRADIENTS_CACHE_THRESHOLD
+        });
+        if !up_to_date {
+            let motion_mat = projection_mat(&motion);
+            self.gradients.clear();
+            self.gradients.extend(compute_registered_gradients_sparse(
+                img,
+                &motion_mat,
+                coordinates.iter().cloned(),
+            ));
+            self.motion = Some(motion);
+        }
+        &self.gradients
+    }
+}
+
+/// Minimum number of images to look for outliers,
+/// the median absolute deviation of fewer scores being unreliable.
+const MIN_IMAGES_FOR_OUTLIERS: usize = 8;
+
+/// Minimum deviation of the sparse errors of the images, for intensities in [0,1].
+const MIN_ERROR_SIGMA: f32 = 0.002;
+
+/// Minimum deviation of the motion magnitudes of the images, in pixels of the coarsest level.
+const MIN_DISPLACEMENT_SIGMA: f32 = 0.5;
+
+/// Indices, in increasing order, of the images of a level state that are outliers,
+/// by their sparse errors or the magnitude of their motion.
+/// The reference image (the first one) is never an outlier.
+fn find_outliers(
+    threshold: f32,
+    state: &LevelState,
+    channels: usize,
+    image_size: (usize, usize),
+    aspect_ratio: f32,
+) -> Vec<usize> {
+    let motion_vec = state.motion_vec();
+    if motion_vec.len() < MIN_IMAGES_FOR_OUTLIERS {
+        log::warn!(
+            "Outliers are only detected with at least {} images",
+            MIN_IMAGES_FOR_OUTLIERS
+        );
+        return Vec::new();
+    }
+    let errors: Vec<f32> = (0..motion_vec.len())
+        .map(|i| {
+            (0..channels)
+                .map(|c| state.column_error(i * channels + c))
+                .sum()
+        })
+        .collect();
+    let displacements: Vec<f32> = motion_vec
+        .iter()
+        .map(|motion| max_displacement(motion, image_size, aspect_ratio))
+        .collect();
+    let errors_z = robust_z_scores(&errors, MIN_ERROR_SIGMA);
+    let displacements_z = robust_z_scores(&displacements, MIN_DISPLACEMENT_SIGMA);
+    (1..motion_vec.len())
+        .filter(|&i| {
+            log::debug!(
+                "Image {} outlier scores: errors {}, motion {}",
+                i,
+                errors_z[i],
+                displacements_z[i]
+            );
+            errors_z[i] > threshold || displacements_z[i] > threshold
+        })
+        .collect()
+}
+
+/// Distance of each value above the median, in robust standard deviations
+/// estimated with the median absolute deviation.
+/// The standard deviation is at least `min_sigma`, so that tiny variations
+/// of values that are almost all equal are not outliers.
+fn robust_z_scores(values: &[f32], min_sigma: f32) -> Vec<f32> {
+    let median = |mut v: Vec<f32>| {
+        let middle = v.len() / 2;
+        *v.select_nth_unstable_by(middle, |a, b| a.total_cmp(b)).1
+    };
+    let m = median(values.to_vec());
+    let mad = median(values.iter().map(|x| (x - m).abs()).collect());
+    // Scaled to match the standard deviation of normally distributed values.
+    let sigma = (1.4826 * mad).max(min_sigma);
+    values.iter().map(|x| (x - m) / sigma).collect()
+}
+
+/// Maximum displacement of a pixel of the image by the difference of two motions,
+/// in units of the pixel height for pixels of the given aspect ratio.
+/// Since the motion is affine, the maximum is reached at one of the corners.
+fn max_displacement(
+    params_diff: &Vector6<f32>,
+    (width, height): (usize, usize),
+    aspect_ratio: f32,
+) -> f32 {
+    let (w, h) = (width as f32, height as f32);
+    [(0.0, 0.0), (w, 0.0), (0.0, h), (w, h)]
+        .iter()
+        .map(|&(x, y)| {
+            let d = params_diff;
+            let dx = aspect_ratio * (d[0] * x + d[2] * y + d[4]);
+            let dy = d[1] * x + d[3] * y + d[5];
+            (dx * dx + dy * dy).sqrt()
+        })
+        .fold(0.0, f32::max)
+}
+
+/// Bring a motion back within the bounds of the configuration,
+/// in an image of size (width, height) at the current level.
+/// The linear coefficients are clamped, then the translation is shortened
+/// so that the image center does not move further than the maximum translation.
+/// Return whether the motion was out of its bounds.
+fn clamp_motion<F: Float>(
+    motion: &mut Vector6<F>,
+    config: &StepConfig,
+    (width, height): (usize, usize),
+) -> bool {
+    let mut bounded = to_single_vec(motion);
+    if config.max_deformation > 0.0 {
+        for coef in bounded.iter_mut().take(4) {
+            *coef = coef.clamp(-config.max_deformation, config.max_deformation);
+        }
+    }
+    if config.max_translation > 0.0 {
+        let (cx, cy) = (0.5 * width as f32, 0.5 * height as f32);
+        let dx = bounded[0] * cx + bounded[2] * cy + bounded[4];
+        let dy = bounded[1] * cx + bounded[3] * cy + bounded[5];
+        let displacement = (config.pixel_aspect_ratio * dx).hypot(dy);
+        if displacement > config.max_translation {
+            let excess = 1.0 - config.max_translation / displacement;
+            bounded[4] -= excess * dx;
+            bounded[5] -= excess * dy;
+        }
+    }
+    if bounded == to_single_vec(motion) {
+        return false;
+    }
+    *motion = bounded.map(F::from_single);
+    true
+}
+
+impl<F: Float> State<F> {
+    /// Initialize the state of a level, starting with the given motion.
+    fn new<T: Scalar + Copy + CanLinearInterpolate<f32, f32>>(
+        obs: &Obs<T>,
+        motion_vec: &[Vector6<f32>],
+        gain_bias: bool,
+        packed: bool,
+    ) -> Self {
+        // Each channel of an image is a column, all its channels share the same motion.
+        let (pixels_count, imgs_count) = (obs.coordinates.len(), obs.images.len());
+        let motion_vec: Vec<Vector6<F>> =
+            motion_vec.iter().map(|m| m.map(F::from_single)).collect();
+
+        // We also recompute the registered images before starting the algorithm loop.
+        let mut imgs_registered = RegisteredImgs::new(pixels_count, imgs_count, packed);
+        imgs_registered.project(obs, &motion_vec);
+        let gain_bias = if gain_bias {
+            let mut gain_bias = GainBias::new(imgs_count, obs.channels);
+            gain_bias.update(&imgs_registered);
+            Some(gain_bias)
+        } else {
+            None
+        };
+        Self {
+            nb_iter: 0,
+            residual: f64::INFINITY,
+            imgs_registered,
+            old_imgs_a: DMatrix::zeros(pixels_count, imgs_count),
+            errors: DMatrix::zeros(pixels_count, imgs_count),
+            lagrange_mult_rho: DMatrix::zeros(pixels_count, imgs_count),
+            step_covariances: vec![Matrix6::zeros(); motion_vec.len()],
+            motion_vec,
+            gain_bias,
+            svd: None,
+            profile: LevelProfile::default(),
+            workspace: Workspace::new(pixels_count, imgs_count),
+        }
+    }
+
+    /// Add the last image of the observations, warm-started with the given motion.
+    /// Only its column is initialized, the state of other images is kept.
+    fn push_column<T: Scalar + Copy + CanLinearInterpolate<f32, f32>>(
+        &mut self,
+        obs: &Obs<T>,
+        motion: &Vector6<f32>,
+    ) {
+        let (pixels_count, imgs_count) =
+            (self.imgs_registered.nrows(), self.imgs_registered.ncols());
+        let motion = motion.map(F::from_single);
+        let mut registered_col = vec![F::zero(); pixels_count];
+        project_column(
+            obs.coordinates,
+            &mut registered_col,
+            &obs.images[imgs_count],
+            &motion,
+        );
+        // The low-rank approximation of the new image starts with the image itself.
+        self.imgs_registered.push_column(&registered_col);
+        push_zero_column(&mut self.old_imgs_a).copy_from_slice(&registered_col);
+        push_zero_column(&mut self.errors);
+        push_zero_column(&mut self.lagrange_mult_rho);
+        push_zero_column(&mut self.workspace.imgs_a);
+        push_zero_column(&mut self.workspace.temp);
+        self.workspace.gradients.push(GradientsCache::default());
+        self.motion_vec.push(motion);
+        self.step_covariances.push(Matrix6::zeros());
+        self.svd = None;
+        if let Some(gain_bias) = self.gain_bias.as_mut() {
+            gain_bias.push();
+            gain_bias.update(&self.imgs_registered);
+        }
+    }
+
+    /// Root mean square difference between a registered image and its low-rank approximation.
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_precision_loss)]
+    fn column_residual(&self, i: usize) -> f32 {
+        let (gain, bias) = match &self.gain_bias {
+            Some(gb) => (gb.gains[i].to_double(), gb.biases[i].to_double()),
+            None => (1.0, 0.0),
+        };
+        let registered = self.imgs_registered.column(i);
+        let low_rank = self.old_imgs_a.column(i);
+        let registered = registered.as_ref();
+        let sum_sqr: f64 = registered
+            .iter()
+            .zip(low_rank.iter())
+            .map(|(&x, &a)| (gain * x.to_double() + bias - a.to_double()).powi(2))
+            .sum();
+        (sum_sqr / registered.len().max(1) as f64).sqrt() as f32
+    }
+
+    /// Root mean square of the sparse errors of a registered image.
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_precision_loss)]
+    fn column_error(&self, i: usize) -> f32 {
+        let errors = self.errors.column(i);
+        let sum_sqr: f64 = errors.iter().map(|e| e.to_double().powi(2)).sum();
+        (sum_sqr / errors.len().max(1) as f64).sqrt() as f32
+    }
+
+    /// Remove the image at the given index, keeping the state of other images.
+    fn remove_column(&mut self, i: usize) {
+        self.imgs_registered.remove_column(i);
+        remove_column(&mut self.old_imgs_a, i);
+        remove_column(&mut self.errors, i);
+        remove_column(&mut self.lagrange_mult_rho, i);
+        remove_column(&mut self.workspace.imgs_a, i);
+        remove_column(&mut self.workspace.temp, i);
+        self.workspace.gradients.remove(i);
+        self.motion_vec.remove(i);
+        self.step_covariances.remove(i);
+        self.svd = None;
+        if let Some(gain_bias) = self.gain_bias.as_mut() {
+            gain_bias.remove(i);
+        }
+    }
 
-impl State {
     /// Core iteration step of the algorithm.
     fn step<T: Scalar + Copy + CanLinearInterpolate<f32, f32>>(
         &mut self,
@@ -343,96 +2802,317 @@ impl State {
         let (width, height) = obs.image_size;
         let State {
             nb_iter,
+            residual: state_residual,
             old_imgs_a,
             imgs_registered,
             errors,
             lagrange_mult_rho,
             motion_vec,
+            step_covariances,
+            gain_bias,
+            svd: incremental_svd,
+            profile,
+            workspace,
         } = self;
+        let Workspace {
+            imgs_a,
+            temp,
+            gradients,
+            step_gradients,
+            projected,
+            templates,
+        } = workspace;
+        let mut clock = Clock::start(config.profile);
+        let rho = F::from_single(config.rho);
         // Pre-scale lambda.
-        let lambda = config.lambda / (imgs_registered.nrows() as f32).sqrt();
+        let lambda =
+            F::from_single(config.lambda) / F::from_single(imgs_registered.nrows() as f32).sqrt();
 
         // A-update: low-rank approximation.
         log::trace!("A-update: low-rank approximation");
-        let imgs_a_temp = &*imgs_registered + &*errors + &*lagrange_mult_rho;
-        let mut svd = imgs_a_temp.svd(true, true);
-        log::trace!("   singular values before shrink: {}", svd.singular_values);
-        for x in svd.singular_values.iter_mut() {
-            *x = shrink(1.0 / config.rho, *x);
+        imgs_registered.copy_into(temp);
+        if let Some(gain_bias) = gain_bias.as_ref() {
+            gain_bias.apply(temp);
+        }
+        *temp += &*errors;
+        *temp += &*lagrange_mult_rho;
+        if config.svd_backend == Svd::Incremental {
+            let tolerance = F::from_single(INCREMENTAL_SVD_TOLERANCE / config.rho);
+            match incremental_svd.as_mut() {
+                None => {
+                    *incremental_svd = Some(IncrementalSvd::new(
+                        &config.svd_library,
+                        temp.clone(),
+                        tolerance,
+                    ))
+                }
+                Some(svd) => {
+                    let updated = svd.update(&config.svd_library, temp);
+                    log::trace!("   {} images updated in the SVD", updated);
+                }
+            }
         }
-        log::trace!("   singular values after shrink: {}", svd.singular_values);
-        let singular_values = svd.singular_values.clone();
-        let imgs_a = svd.recompose().unwrap();
+        let singular_values = match incremental_svd.as_ref() {
+            Some(svd) => {
+                log::trace!(
+                    "   singular values before shrink: {}",
+                    svd.singular_values()
+                );
+                svd.recompose_into(|s| shrink(F::one() / rho, s), imgs_a);
+                let singular_values = svd.singular_values().map(|s| shrink(F::one() / rho, s));
+                log::trace!("   singular values after shrink: {}", singular_values);
+                singular_values
+            }
+            None => full_svd_update(&config.svd_library, rho, temp, imgs_a),
+        };
+        clock.lap(&mut profile.svd);
 
         // e-update: L1-regularized least-squares
         log::trace!("e-update: L1-regularized least-squares");
-        let errors_temp = &imgs_a - &*imgs_registered - &*lagrange_mult_rho;
-        *errors = errors_temp.map(|x| shrink(lambda / config.rho, x));
+        let errors_temp = temp;
+        errors_temp.copy_from(imgs_a);
+        imgs_registered.sub_from(errors_temp);
+        *errors_temp -= &*lagrange_mult_rho;
+        if let Some(gain_bias) = gain_bias.as_ref() {
+            // A - gain * W - bias - Y / rho, from A - W - Y / rho.
+            for (j, (mut col, (&gain, &bias))) in errors_temp
+                .column_iter_mut()
+                .zip(gain_bias.gains.iter().zip(gain_bias.biases.iter()))
+                .enumerate()
+            {
+                let registered = imgs_registered.column(j);
+                for (x, &w) in col.iter_mut().zip(registered.iter()) {
+                    *x = *x + w - gain * w - bias;
+                }
+            }
+        }
+        // Without image correction, the errors stay at zero.
+        if config.do_image_correction {
+            errors.zip_apply(errors_temp, |_, x| shrink(lambda / rho, x));
+        }
 
-        // theta-update: forwards compositional step of a Gauss-Newton approximation.
-        log::trace!("theta-update: forwards compositional step of GN approximation");
-        let residuals = &errors_temp - &*errors;
+        // theta-update: compositional step of a Gauss-Newton approximation.
+        log::trace!("theta-update: compositional step of GN approximation");
+        clock.restart();
+        let residuals = errors_temp;
+        *residuals -= &*errors;
+        // Residuals are scaled by the gains, like the gradients of the registered images.
+        if let Some(gain_bias) = gain_bias.as_ref() {
+            for (mut col, &gain) in residuals.column_iter_mut().zip(gain_bias.gains.iter()) {
+                col /= gain;
+            }
+        }
+        let (channels, nrows) = (obs.channels, residuals.nrows());
+        let residuals_all = residuals.as_slice();
+        let old_motion_vec: Vec<Vector6<f32>> = motion_vec.iter().map(to_single_vec).collect();
+        // Templates are computed again at the first iteration of each level or refinement.
+        if *nb_iter == 0 {
+            templates.clear();
+        }
+        templates.resize_with(motion_vec.len(), || None);
+        let inverse = config.compositional == Compositional::Inverse;
         #[allow(clippy::needless_range_loop)]
-        for i in 0..obs.images.len() {
-            // Compute gradients for the registered image.
-            let gradients = match &obs.sparsity {
-                Sparsity::Full => compute_registered_gradients_full(
+        for i in 0..motion_vec.len() {
+            // Compute residuals and motion step,
+            // with gradients of the registered image.
+            // The residuals of all the channels of an image are stacked.
+            let columns = i * channels..(i + 1) * channels;
+            let coordinates = (0..channels).flat_map(|_| obs.coordinates.iter().cloned());
+            let residuals_i = columns
+                .clone()
+                .flat_map(|j| residuals_all[j * nrows..(j + 1) * nrows].iter().cloned());
+            if inverse && templates[i].is_none() {
+                // The template of the inverse compositional step is what the image is
+                // registered to: its low-rank approximation, whose gradients are only known
+                // with full resolution, or else the registered reference image.
+                // Like the residuals, both are brought to the intensities of the image.
+                step_gradients.clear();
+                // Gains are per column, the channels of an image being consecutive columns.
+                let gain = |j: usize| gain_bias.as_ref().map_or(F::one(), |gb| gb.gains[j]);
+                match &obs.sparsity {
+                    Sparsity::Full => {
+                        for j in columns.clone() {
+                            let scale = F::one() / gain(j);
+                            let approx = &imgs_a.as_slice()[j * nrows..(j + 1) * nrows];
+                            step_gradients.extend(
+                                registered_gradients_full((height, width), approx)
+                                    .map(|(gx, gy)| (gx * scale, gy * scale)),
+                            );
+                        }
+                    }
+                    Sparsity::Sparse => {
+                        let motion_ref = to_single_vec(&motion_vec[0]);
+                        for c in 0..channels {
+                            let scale = gain(c) / gain(i * channels + c);
+                            let cache = &mut gradients[c];
+                            step_gradients.extend(
+                                cache
+                                    .get(&obs.images[c], motion_ref, obs.coordinates)
+                                    .iter()
+                                    .map(|&(gx, gy)| {
+                                        (F::from_single(gx) * scale, F::from_single(gy) * scale)
+                                    }),
+                            );
+                        }
+                    }
+                }
+                templates[i] = Some(Template::new(
+                    (height, width),
+                    coordinates.clone(),
+                    step_gradients.clone(),
+                ));
+            } else if !inverse {
+                step_gradients.clear();
+                match &obs.sparsity {
+                    Sparsity::Full => {
+                        for j in columns {
+                            let registered = imgs_registered.column(j);
+                            step_gradients
+                                .extend(registered_gradients_full((height, width), &registered));
+                        }
+                    }
+                    Sparsity::Sparse => {
+                        let motion_i = to_single_vec(&motion_vec[i]);
+                        step_gradients.extend(
+                            gradients[columns.clone()]
+                                .iter_mut()
+                                .zip(&obs.images[columns])
+                                .flat_map(|(cache, img)| cache.get(img, motion_i, obs.coordinates))
+                                .map(|&(gx, gy)| (F::from_single(gx), F::from_single(gy))),
+                        )
+                    }
+                }
+            }
+            clock.lap(&mut profile.gradients);
+            let smoothness = Smoothness::new(
+                config.temporal_smoothness,
+                config.pixel_aspect_ratio,
+                motion_vec,
+                i,
+            );
+            let damping = F::from_single(config.damping);
+            let (step_params, inverse_hessian) = match templates[i].as_ref() {
+                Some(template) => inverse_compositional_step(
                     (height, width),
-                    imgs_registered.column(i).as_slice(),
-                ),
-                Sparsity::Sparse => compute_registered_gradients_sparse(
-                    &obs.images[i],
-                    &(projection_mat(&motion_vec[i])),
-                    obs.coordinates.iter().cloned(),
+                    template,
+                    coordinates,
+                    residuals_i,
+                    smoothness,
+                    damping,
+                )?,
+                None => forwards_compositional_step(
+                    (height, width),
+                    coordinates,
+                    residuals_i,
+                    step_gradients.iter().cloned(),
+                    smoothness,
+                    damping,
+                )?,
+            };
+            let step_params = if config.line_search {
+                line_search(
+                    obs,
+                    i,
+                    &motion_vec[i],
+                    step_params,
+                    residuals_all,
+                    imgs_registered,
+                    smoothness,
+                    projected,
                 )
-                .collect(),
+            } else {
+                step_params
             };
-
-            // Compute residuals and motion step.
-            let step_params = forwards_compositional_step(
-                (height, width),
-                obs.coordinates.iter().cloned(),
-                residuals.column(i).iter().cloned(),
-                gradients.into_iter(),
-            )?;
+            // Variance of the residuals of the model, without the dual variable
+            // (scaled by the gain of each channel like the residuals).
+            let model_residuals_sqr: f64 = (i * channels..(i + 1) * channels)
+                .flat_map(|j| {
+                    let gain = gain_bias.as_ref().map_or(F::one(), |gb| gb.gains[j]);
+                    let range = j * nrows..(j + 1) * nrows;
+                    residuals_all[range.clone()]
+                        .iter()
+                        .zip(&lagrange_mult_rho.as_slice()[range])
+                        .map(move |(&r, &y)| (r + y / gain).to_double().powi(2))
+                })
+                .sum();
+            let dof = (channels * nrows).saturating_sub(6).max(1);
+            let variance = model_residuals_sqr / dof as f64;
+            step_covariances[i] = inverse_hessian.map(|x| (x.to_double() * variance) as f32);
 
             // Save motion for this image.
             motion_vec[i] =
                 projection_params(&(projection_mat(&motion_vec[i]) * projection_mat(&step_params)));
+            clock.lap(&mut profile.gauss_newton);
         }
 
         // Transform all motion parameters such that image 0 is the reference.
         let inverse_motion_ref = projection_mat(&motion_vec[0])
             .try_inverse()
-            .ok_or_else(|| RegistrationError::InverseRefMotion(motion_vec[0]))?;
+            .ok_or_else(|| RegistrationError::InverseRefMotion(to_single_vec(&motion_vec[0])))?;
         for motion_params in motion_vec.iter_mut() {
             *motion_params =
-                projection_params(&(inverse_motion_ref * projection_mat(&motion_params)));
+                projection_params(&(inverse_motion_ref * projection_mat(motion_params)));
+        }
+        for (i, motion_params) in motion_vec.iter_mut().enumerate() {
+            if clamp_motion(motion_params, config, obs.image_size) {
+                log::trace!("   motion of image {} brought back within its bounds", i);
+            }
         }
+        // The reference motion is exact by definition.
+        step_covariances[0] = Matrix6::zeros();
+        let motion_change = old_motion_vec
+            .iter()
+            .zip(motion_vec.iter())
+            .map(|(old, new)| {
+                max_displacement(
+                    &(to_single_vec(new) - old),
+                    obs.image_size,
+                    config.pixel_aspect_ratio,
+                )
+            })
+            .fold(0.0, f32::max);
 
         // Update imgs_registered.
-        project_f32(
-            obs.coordinates.iter().cloned(),
-            imgs_registered,
-            &obs.images,
-            &motion_vec,
-        );
+        clock.restart();
+        imgs_registered.project(obs, motion_vec);
+        clock.lap(&mut profile.projection);
+
+        // gain and bias update, for the new registered images.
+        if let Some(gain_bias) = gain_bias.as_mut() {
+            log::trace!("gain and bias update");
+            gain_bias.update(imgs_registered);
+        }
 
         // y-update: dual ascent
         log::trace!("y-update: dual ascent");
-        *lagrange_mult_rho += &*imgs_registered - &imgs_a + &*errors;
+        let imgs_corrected = match (gain_bias.as_ref(), &*imgs_registered) {
+            (None, RegisteredImgs::Float(imgs_registered)) => imgs_registered,
+            _ => {
+                // The residuals are not needed anymore.
+                imgs_registered.copy_into(residuals);
+                if let Some(gain_bias) = gain_bias.as_ref() {
+                    gain_bias.apply(residuals);
+                }
+                &*residuals
+            }
+        };
+        *lagrange_mult_rho += imgs_corrected;
+        *lagrange_mult_rho -= &*imgs_a;
+        *lagrange_mult_rho += &*errors;
 
         // Check convergence
         log::trace!("Checking convergence");
-        let residual = norm(&(&imgs_a - &*old_imgs_a)) / 1e-12.max(norm(old_imgs_a));
-        if config.verbosity >= 3 {
-            let nuclear_norm = singular_values.sum();
-            let l1_norm = lambda * errors.map(|x| x.abs()).sum();
-            let r = &*imgs_registered - &imgs_a + &*errors;
+        let residual = norm_diff(imgs_a, old_imgs_a) / 1e-12.max(norm(old_imgs_a));
+        // These costs are only computed for a logger accepting debug messages.
+        if config.verbosity >= 3 && log::log_enabled!(log::Level::Debug) {
+            let nuclear_norm = singular_values.sum().to_double();
+            let l1_norm =
+                lambda.to_double() * errors.iter().map(|x| x.abs().to_double()).sum::<f64>();
+            let r = imgs_corrected - &*imgs_a + &*errors;
             let augmented_lagrangian = nuclear_norm
                 + l1_norm
-                + config.rho * (lagrange_mult_rho.component_mul(&r)).sum()
-                + 0.5 * config.rho * (norm_sqr(&r) as f32);
+                + rho.to_double() * (lagrange_mult_rho.component_mul(&r)).sum().to_double()
+                + 0.5 * rho.to_double() * norm_sqr(&r);
             log::debug!(
                 "
             Iteration {}:
@@ -451,25 +3131,286 @@ impl State {
             );
         }
         let mut continuation = Continue::Forward;
-        if *nb_iter >= config.max_iterations || residual < config.threshold {
+        if *nb_iter >= config.max_iterations
+            || residual < config.threshold as f64
+            || motion_change < config.motion_threshold
+        {
             continuation = Continue::Stop;
         }
 
         // Update state.
         *nb_iter += 1;
-        *old_imgs_a = imgs_a;
+        *state_residual = residual;
+        std::mem::swap(old_imgs_a, imgs_a);
 
         // Returned value.
         Ok(continuation)
     }
 }
 
-fn compute_registered_gradients_full(shape: (usize, usize), registered: &[f32]) -> Vec<(f32, f32)> {
+/// Multiple of the robust standard deviation of the residuals
+/// above which the Huber weights of the pairwise registration decrease.
+const HUBER_SCALE: f32 = 1.345;
+
+/// State of the pairwise registration of one level, without low-rank term:
+/// each image is registered to the reference image, or to the previous image,
+/// with a Gauss-Newton step reweighted by the Huber loss of its residuals.
+struct PairwiseState {
+    nb_iter: usize,
+    /// Largest relative change of the residuals of an image at the last iteration.
+    residual: f64,
+    /// Register each image to the previous one instead of the reference.
+    sequential: bool,
+    motion_vec: Vec<Vector6<f32>>,
+    /// Covariance of the last Gauss-Newton step of each image, zero for the reference.
+    step_covariances: Vec<Matrix6<f32>>,
+    /// Root mean square residual of each image after its last step.
+    residuals: Vec<f32>,
+    /// Gradients of each column registered with sparse resolution.
+    gradients: Vec<GradientsCache>,
+    /// Time accumulated in each part of the steps, if profiled.
+    profile: LevelProfile,
+    /// Buffers of the channels of the target and the registered image,
+    /// and of the weighted residuals and gradients.
+    target: Vec<f32>,
+    registered: Vec<f32>,
+    weighted_residuals: Vec<f32>,
+    weighted_gradients: Vec<(f32, f32)>,
+}
+
+impl PairwiseState {
+    fn new<T: Scalar + Copy>(obs: &Obs<T>, motion_vec: &[Vector6<f32>], sequential: bool) -> Self {
+        Self {
+            nb_iter: 0,
+            residual: f64::INFINITY,
+            sequential,
+            motion_vec: motion_vec.to_vec(),
+            step_covariances: vec![Matrix6::zeros(); motion_vec.len()],
+            residuals: vec![f32::INFINITY; motion_vec.len()],
+            gradients: (0..obs.images.len())
+                .map(|_| GradientsCache::default())
+                .collect(),
+            profile: LevelProfile::default(),
+            target: Vec::new(),
+            registered: Vec::new(),
+            weighted_residuals: Vec::new(),
+            weighted_gradients: Vec::new(),
+        }
+    }
+
+    /// Add an image, warm-started with the given motion.
+    fn push_column(&mut self, motion: &Vector6<f32>) {
+        self.motion_vec.push(*motion);
+        self.step_covariances.push(Matrix6::zeros());
+        self.residuals.push(f32::INFINITY);
+        self.gradients.push(GradientsCache::default());
+    }
+
+    /// Root mean square residual of an image after its last step, 0 for the reference.
+    fn column_residual(&self, i: usize) -> f32 {
+        if i == 0 {
+            0.0
+        } else {
+            self.residuals[i]
+        }
+    }
+
+    fn remove_column(&mut self, i: usize) {
+        self.motion_vec.remove(i);
+        self.step_covariances.remove(i);
+        self.residuals.remove(i);
+        self.gradients.remove(i);
+    }
+
+    /// One robust Gauss-Newton step of each image, in order,
+    /// so that in sequential mode an image is registered to its updated predecessor.
+    #[allow(clippy::cast_precision_loss)]
+    fn step<T: Scalar + Copy + CanLinearInterpolate<f32, f32>>(
+        &mut self,
+        config: &StepConfig,
+        obs: &Obs<T>,
+    ) -> Result<Continue, RegistrationError> {
+        let (width, height) = obs.image_size;
+        let (channels, coordinates) = (obs.channels, obs.coordinates);
+        let pixels_count = coordinates.len();
+        let mut clock = Clock::start(config.profile);
+        let mut motion_change: f32 = 0.0;
+        self.target.resize(pixels_count * channels, 0.0);
+        self.registered.resize(pixels_count * channels, 0.0);
+        let mut max_change: f64 = 0.0;
+        for i in 1..self.motion_vec.len() {
+            // Project the target and the image with their current motions.
+            clock.restart();
+            let reference = if self.sequential { i - 1 } else { 0 };
+            for c in 0..channels {
+                let columns = c * pixels_count..(c + 1) * pixels_count;
+                let (target, registered) = (
+                    &mut self.target[columns.clone()],
+                    &mut self.registered[columns],
+                );
+                let reference_img = &obs.images[reference * channels + c];
+                project_column(
+                    coordinates,
+                    target,
+                    reference_img,
+                    &self.motion_vec[reference],
+                );
+                let img = &obs.images[i * channels + c];
+                project_column(coordinates, registered, img, &self.motion_vec[i]);
+            }
+            clock.lap(&mut self.profile.projection);
+
+            // Huber weights of the residuals, with a robust estimation of their deviation.
+            self.weighted_residuals.clear();
+            self.weighted_residuals
+                .extend(self.target.iter().zip(&self.registered).map(|(t, r)| t - r));
+            let mut abs_residuals: Vec<f32> =
+                self.weighted_residuals.iter().map(|r| r.abs()).collect();
+            let middle = abs_residuals.len() / 2;
+            let (_, median, _) =
+                abs_residuals.select_nth_unstable_by(middle, |a, b| a.total_cmp(b));
+            let huber_threshold = (HUBER_SCALE * 1.4826 * *median).max(f32::EPSILON);
+            let sqrt_weights: Vec<f32> = self
+                .weighted_residuals
+                .iter()
+                .map(|r| (huber_threshold / r.abs().max(huber_threshold)).sqrt())
+                .collect();
+            let sum_sqr: f32 = self.weighted_residuals.iter().map(|r| r * r).sum();
+            let rms = (sum_sqr / self.weighted_residuals.len().max(1) as f32).sqrt();
+
+            // Gradients of the registered image, weighted like the residuals.
+            self.weighted_gradients.clear();
+            for c in 0..channels {
+                let columns = c * pixels_count..(c + 1) * pixels_count;
+                match &obs.sparsity {
+                    Sparsity::Full => self.weighted_gradients.extend(registered_gradients_full(
+                        (height, width),
+                        &self.registered[columns],
+                    )),
+                    Sparsity::Sparse => {
+                        let j = i * channels + c;
+                        let cache = &mut self.gradients[j];
+                        let gradients = cache.get(&obs.images[j], self.motion_vec[i], coordinates);
+                        self.weighted_gradients.extend_from_slice(gradients);
+                    }
+                }
+            }
+            for ((r, g), w) in self
+                .weighted_residuals
+                .iter_mut()
+                .zip(self.weighted_gradients.iter_mut())
+                .zip(&sqrt_weights)
+            {
+                *r *= w;
+                *g = (g.0 * w, g.1 * w);
+            }
+            clock.lap(&mut self.profile.gradients);
+
+            let smoothness = Smoothness::new(
+                config.temporal_smoothness,
+                config.pixel_aspect_ratio,
+                &self.motion_vec,
+                i,
+            );
+            let coordinates_channels = (0..channels).flat_map(|_| coordinates.iter().cloned());
+            let (step_params, inverse_hessian) = forwards_compositional_step(
+                (height, width),
+                coordinates_channels,
+                self.weighted_residuals.iter().cloned(),
+                self.weighted_gradients.iter().cloned(),
+                smoothness,
+                config.damping,
+            )?;
+            let weighted_sqr: f32 = self.weighted_residuals.iter().map(|r| r * r).sum();
+            let dof = self.weighted_residuals.len().saturating_sub(6).max(1);
+            self.step_covariances[i] = inverse_hessian * (weighted_sqr / dof as f32);
+            let old_motion = self.motion_vec[i];
+            self.motion_vec[i] = projection_params(
+                &(projection_mat(&self.motion_vec[i]) * projection_mat(&step_params)),
+            );
+            if clamp_motion(&mut self.motion_vec[i], config, obs.image_size) {
+                log::trace!("   motion of image {} brought back within its bounds", i);
+            }
+            motion_change = motion_change.max(max_displacement(
+                &(self.motion_vec[i] - old_motion),
+                obs.image_size,
+                config.pixel_aspect_ratio,
+            ));
+            clock.lap(&mut self.profile.gauss_newton);
+
+            // Relative change of the residuals of this image.
+            let previous = self.residuals[i];
+            if previous.is_finite() {
+                let change = ((rms - previous).abs() / previous.max(1e-12)) as f64;
+                max_change = max_change.max(change);
+            } else {
+                max_change = f64::INFINITY;
+            }
+            self.residuals[i] = rms;
+        }
+        log::debug!(
+            "Iteration {}: residuals change {}",
+            self.nb_iter,
+            max_change
+        );
+
+        let mut continuation = Continue::Forward;
+        if self.nb_iter >= config.max_iterations
+            || max_change < config.threshold as f64
+            || motion_change < config.motion_threshold
+        {
+            continuation = Continue::Stop;
+        }
+        self.nb_iter += 1;
+        self.residual = max_change;
+        Ok(continuation)
+    }
+}
+
+/// Append a column of zeros to a matrix, and return it as a mutable slice.
+fn push_zero_column<T: Scalar + Copy + Default>(mat: &mut DMatrix<T>) -> &mut [T] {
+    let ncols = mat.ncols();
+    let taken = std::mem::replace(mat, DMatrix::from_vec(0, 0, Vec::new()));
+    *mat = taken.insert_column(ncols, T::default());
+    let nrows = mat.nrows();
+    &mut mat.as_mut_slice()[ncols * nrows..]
+}
+
+/// Remove a column of a matrix in place.
+fn remove_column<T: Scalar + Copy>(mat: &mut DMatrix<T>, i: usize) {
+    let taken = std::mem::replace(mat, DMatrix::from_vec(0, 0, Vec::new()));
+    *mat = taken.remove_column(i);
+}
+
+fn to_single_vec<F: Float>(v: &Vector6<F>) -> Vector6<f32> {
+    v.map(F::to_single)
+}
+
+/// Centered gradients of a registered image stored as a column-major slice,
+/// in the same order than the pixels. Gradients are 0 on the borders.
+/// This is equivalent to `gradients::centered_f32` without allocating.
+fn registered_gradients_full<F: Float>(
+    shape: (usize, usize),
+    registered: &[F],
+) -> impl Iterator<Item = (F, F)> + '_ {
     let (nrows, ncols) = shape;
-    let img_registered_shaped = DMatrix::from_iterator(nrows, ncols, registered.iter().cloned());
-    crate::img::gradients::centered_f32(&img_registered_shaped)
-        .data
-        .into()
+    assert!(
+        nrows > 2 && ncols > 2,
+        "Impossible to compute centered gradients of a {}x{} image",
+        nrows,
+        ncols
+    );
+    crate::utils::coords_col_major((nrows, ncols)).map(move |(x, y)| {
+        if x == 0 || y == 0 || x == ncols - 1 || y == nrows - 1 {
+            (F::zero(), F::zero())
+        } else {
+            let half = F::from_single(0.5);
+            let idx = x * nrows + y;
+            let gx = half * (registered[idx + nrows] - registered[idx - nrows]);
+            let gy = half * (registered[idx + 1] - registered[idx - 1]);
+            (gx, gy)
+        }
+    })
 }
 
 /// Compute the gradients of warped image.
@@ -510,58 +3451,389 @@ where
     })
 }
 
-fn forwards_compositional_step(
+/// Clock measuring the time between successive laps, only if profiling.
+/// It is never read otherwise, since WebAssembly has no clock.
+struct Clock(Option<Instant>);
+
+impl Clock {
+    fn start(enabled: bool) -> Self {
+        Clock(if enabled { Some(Instant::now()) } else { None })
+    }
+
+    /// Restart the clock, ignoring the time since the last lap.
+    fn restart(&mut self) {
+        if let Some(last) = self.0.as_mut() {
+            *last = Instant::now();
+        }
+    }
+
+    /// Add the time since the last lap to the given duration.
+    fn lap(&mut self, duration: &mut Duration) {
+        if let Some(last) = self.0.as_mut() {
+            let now = Instant::now();
+            *duration += now - *last;
+            *last = now;
+        }
+    }
+}
+
+/// Temporal smoothness prior of the motion of one image,
+/// pulling it toward the motions of the previous and next images.
+#[derive(Clone, Copy)]
+struct Smoothness<F: Float> {
+    /// Weight of the prior.
+    weight: F,
+    /// Aspect ratio of the pixels, scaling horizontal displacements.
+    aspect_ratio: F,
+    /// Number of neighbor images, 1 or 2.
+    neighbors: usize,
+    /// Sum of the differences between the motions of the neighbors and of the image.
+    pull: Vector6<F>,
+}
+
+impl<F: Float> Smoothness<F> {
+    /// Prior of image `i`, none if disabled or if the image has no neighbor.
+    fn new(weight: f32, aspect_ratio: f32, motion_vec: &[Vector6<F>], i: usize) -> Option<Self> {
+        if weight <= 0.0 {
+            return None;
+        }
+        let next = Some(i + 1).filter(|&j| j < motion_vec.len());
+        let mut neighbors = 0;
+        let mut pull = Vector6::zeros();
+        for j in i.checked_sub(1).into_iter().chain(next) {
+            pull += motion_vec[j] - motion_vec[i];
+            neighbors += 1;
+        }
+        if neighbors == 0 {
+            return None;
+        }
+        Some(Smoothness {
+            weight: F::from_single(weight),
+            aspect_ratio: F::from_single(aspect_ratio),
+            neighbors,
+            pull,
+        })
+    }
+
+    /// Weighted metric G of the motions, given the moments of the pixels (see [StepSums]).
+    /// The squared displacement of the pixels between two motions differing by d
+    /// is d^T G d, summed over the pixels, with the jacobian of the displacement
+    /// of pixel (x, y): dx = d1 x + d3 y + d5, and dy = d2 x + d4 y + d6.
+    /// Horizontal displacements are scaled by the aspect ratio of the pixels.
+    fn metric(&self, moments: &[F; 6]) -> Matrix6<F> {
+        let [n, sx, sy, sxx, sxy, syy] = *moments;
+        let mut g = Matrix6::zeros();
+        let aspect_sqr = self.aspect_ratio * self.aspect_ratio;
+        for (offset, scale) in [(0, aspect_sqr), (1, F::one())] {
+            let (a, b, c) = (offset, offset + 2, offset + 4);
+            g[(a, a)] = scale * sxx;
+            g[(a, b)] = scale * sxy;
+            g[(a, c)] = scale * sx;
+            g[(b, a)] = scale * sxy;
+            g[(b, b)] = scale * syy;
+            g[(b, c)] = scale * sy;
+            g[(c, a)] = scale * sx;
+            g[(c, b)] = scale * sy;
+            g[(c, c)] = scale * n;
+        }
+        g * self.weight
+    }
+
+    /// Change of the prior when the motion moves by `step`, with the metric G.
+    /// Summed over the neighbors, (d - step)^T G (d - step) - d^T G d
+    /// is step^T G (neighbors * step - 2 * pull).
+    fn cost_change(&self, metric: &Matrix6<F>, step: &Vector6<F>) -> f64 {
+        let neighbors = F::from_single(self.neighbors as f32);
+        let two = F::from_single(2.0);
+        step.dot(&(metric * (step * neighbors - self.pull * two)))
+            .to_double()
+    }
+}
+
+/// Sums over the pixels inside the margin of an image, for the Gauss-Newton step of its motion.
+struct StepSums<F: Float> {
+    /// Gauss-Newton approximation of the Hessian of the data term.
+    hessian: Matrix6<F>,
+    /// Sums of 1, x, y, x^2, xy and y^2 over the pixels, for the smoothness prior.
+    moments: [F; 6],
+    pixels_count_inside: usize,
+}
+
+impl<F: Float> StepSums<F> {
+    fn zeros() -> Self {
+        StepSums {
+            hessian: Matrix6::zeros(),
+            moments: [F::zero(); 6],
+            pixels_count_inside: 0,
+        }
+    }
+
+    /// Add pixel (x, y), given the jacobian of its intensity.
+    fn add(&mut self, pixel: (usize, usize), jac_t: &Vector6<F>) {
+        self.hessian += jac_t * jac_t.transpose();
+        add_moments(&mut self.moments, pixel);
+        self.pixels_count_inside += 1;
+    }
+}
+
+/// Add the moments 1, x, y, x^2, xy and y^2 of pixel (x, y).
+fn add_moments<F: Float>(moments: &mut [F; 6], (x, y): (usize, usize)) {
+    let x_ = F::from_single(x as f32);
+    let y_ = F::from_single(y as f32);
+    let pixel_moments = [F::one(), x_, y_, x_ * x_, x_ * y_, y_ * y_];
+    for (m, pm) in moments.iter_mut().zip(pixel_moments.iter()) {
+        *m += *pm;
+    }
+}
+
+/// Whether a pixel is within the margin of the pixels used by the Gauss-Newton steps,
+/// in an image of the given shape.
+fn inside_step_margin((height, width): (usize, usize), (x, y): (usize, usize)) -> bool {
+    let border = (0.04 * height.min(width) as f32) as usize;
+    x > border && x + border < width && y > border && y + border < height
+}
+
+/// Jacobian of the intensity of pixel (x, y) of a registered image
+/// with respect to the step of its motion, given its gradient.
+fn step_jacobian<F: Float>((x, y): (usize, usize), (gx, gy): (F, F)) -> Vector6<F> {
+    let x_ = F::from_single(x as f32);
+    let y_ = F::from_single(y as f32);
+    Vector6::new(x_ * gx, x_ * gy, y_ * gx, y_ * gy, gx, gy)
+}
+
+/// Gauss-Newton step of the motion of an image, composed on the right of its current motion.
+/// Also return the inverse of the Hessian, which scaled by the variance of the residuals
+/// is the covariance of the step.
+/// A positive damping scales the diagonal of the Hessian of the step (Levenberg-Marquardt),
+/// but not of the covariance.
+fn forwards_compositional_step<F: Float>(
     shape: (usize, usize),
     coordinates: impl Iterator<Item = (usize, usize)>,
-    residuals: impl Iterator<Item = f32>,
-    gradients: impl Iterator<Item = (f32, f32)>,
-) -> Result<Vector6<f32>, RegistrationError> {
-    let (height, width) = shape;
+    residuals: impl Iterator<Item = F>,
+    gradients: impl Iterator<Item = (F, F)>,
+    smoothness: Option<Smoothness<F>>,
+    damping: F,
+) -> Result<(Vector6<F>, Matrix6<F>), RegistrationError> {
     let mut descent_params = Vector6::zeros();
-    let mut hessian = Matrix6::zeros();
-    let border = (0.04 * height.min(width) as f32) as usize;
-    let mut pixels_count_inside = 0;
-    for (((x, y), res), (gx, gy)) in coordinates.zip(residuals).zip(gradients) {
+    let mut sums = StepSums::zeros();
+    for ((pixel, res), gradient) in coordinates.zip(residuals).zip(gradients) {
         // Only use points within a given margin.
-        if x > border && x + border < width && y > border && y + border < height {
-            let x_ = x as f32;
-            let y_ = y as f32;
-            let jac_t = Vector6::new(x_ * gx, x_ * gy, y_ * gx, y_ * gy, gx, gy);
-            hessian += jac_t * jac_t.transpose();
-            descent_params += res * jac_t;
-            pixels_count_inside += 1;
+        if inside_step_margin(shape, pixel) {
+            let jac_t = step_jacobian(pixel, gradient);
+            sums.add(pixel, &jac_t);
+            descent_params += jac_t * res;
+        }
+    }
+    solve_step(&sums, descent_params, smoothness, damping)
+}
+
+/// Template of the inverse compositional step of an image:
+/// gradients of what it is registered to at the first iteration of a level,
+/// and the sums of its Gauss-Newton steps.
+struct Template<F: Float> {
+    gradients: Vec<(F, F)>,
+    sums: StepSums<F>,
+}
+
+impl<F: Float> Template<F> {
+    fn new(
+        shape: (usize, usize),
+        coordinates: impl Iterator<Item = (usize, usize)>,
+        gradients: Vec<(F, F)>,
+    ) -> Self {
+        let mut sums = StepSums::zeros();
+        for (pixel, &gradient) in coordinates.zip(&gradients) {
+            if inside_step_margin(shape, pixel) {
+                sums.add(pixel, &step_jacobian(pixel, gradient));
+            }
+        }
+        Template { gradients, sums }
+    }
+}
+
+/// Inverse compositional Gauss-Newton step of the motion of an image,
+/// with the gradients and the Hessian of its template.
+/// The step of the template toward the image is inverted into a step
+/// composed on the right of the current motion, like the forwards compositional one.
+fn inverse_compositional_step<F: Float>(
+    shape: (usize, usize),
+    template: &Template<F>,
+    coordinates: impl Iterator<Item = (usize, usize)>,
+    residuals: impl Iterator<Item = F>,
+    smoothness: Option<Smoothness<F>>,
+    damping: F,
+) -> Result<(Vector6<F>, Matrix6<F>), RegistrationError> {
+    let mut descent_params = Vector6::zeros();
+    for ((pixel, res), &gradient) in coordinates.zip(residuals).zip(&template.gradients) {
+        if inside_step_margin(shape, pixel) {
+            descent_params += step_jacobian(pixel, gradient) * res;
         }
     }
-    if pixels_count_inside < 6 {
-        return Err(RegistrationError::NotEnoughPoints(pixels_count_inside));
+    let (step, inverse_hessian) = solve_step(&template.sums, descent_params, smoothness, damping)?;
+    // The template moves by -step, so the image moves by its inverse,
+    // approximated at the first order in the unlikely case where it does not exist.
+    let step = projection_mat(&(-step))
+        .try_inverse()
+        .map_or(step, |inverse| projection_params(&inverse));
+    Ok((step, inverse_hessian))
+}
+
+/// Solve the normal equations of a Gauss-Newton step, with the smoothness prior and damping.
+/// Return the step and the inverse of the undamped Hessian.
+fn solve_step<F: Float>(
+    sums: &StepSums<F>,
+    mut descent_params: Vector6<F>,
+    smoothness: Option<Smoothness<F>>,
+    damping: F,
+) -> Result<(Vector6<F>, Matrix6<F>), RegistrationError> {
+    if sums.pixels_count_inside < 6 {
+        return Err(RegistrationError::NotEnoughPoints(sums.pixels_count_inside));
+    }
+    let mut hessian = sums.hessian;
+    if let Some(smoothness) = smoothness {
+        let g = smoothness.metric(&sums.moments);
+        hessian += g * F::from_single(smoothness.neighbors as f32);
+        descent_params += g * smoothness.pull;
+    }
+    let hessian_chol = hessian.cholesky().ok_or_else(|| {
+        RegistrationError::NonDefinitePositiveHessian(Box::new(hessian.map(F::to_single)))
+    })?;
+    let inverse_hessian = hessian_chol.inverse();
+    if damping <= F::zero() {
+        return Ok((hessian_chol.solve(&descent_params), inverse_hessian));
+    }
+    let mut damped = hessian;
+    for k in 0..6 {
+        damped[(k, k)] *= F::one() + damping;
+    }
+    // The damped Hessian stays definite positive.
+    let damped_chol = damped.cholesky().ok_or_else(|| {
+        RegistrationError::NonDefinitePositiveHessian(Box::new(damped.map(F::to_single)))
+    })?;
+    Ok((damped_chol.solve(&descent_params), inverse_hessian))
+}
+
+/// Maximum number of halvings of a Gauss-Newton step in the line search.
+const LINE_SEARCH_HALVINGS: usize = 5;
+
+/// Backtracking line search of the Gauss-Newton step of image `i`,
+/// halving the step until the cost of the image decreases:
+/// the sum of its squared residuals, plus its smoothness prior if any.
+/// The residuals at the current motion are A - e - Y / rho - W(theta),
+/// divided by the gain of the image when estimating gains and biases,
+/// so W(theta) + residuals is the target of the candidate registered images.
+/// Return a null step if no halving decreases the cost.
+#[allow(clippy::too_many_arguments)]
+fn line_search<T: Scalar + Copy + CanLinearInterpolate<f32, f32>, F: Float>(
+    obs: &Obs<T>,
+    i: usize,
+    motion: &Vector6<F>,
+    step: Vector6<F>,
+    residuals: &[F],
+    imgs_registered: &RegisteredImgs<F>,
+    smoothness: Option<Smoothness<F>>,
+    projected: &mut Vec<F>,
+) -> Vector6<F> {
+    let nrows = obs.coordinates.len();
+    let columns = i * obs.channels..(i + 1) * obs.channels;
+    let current_cost: f64 = residuals[columns.start * nrows..columns.end * nrows]
+        .iter()
+        .map(|r| r.to_double().powi(2))
+        .sum();
+    // Metric of the prior, with the pixels of the Gauss-Newton steps of all the channels.
+    let prior = smoothness.map(|smoothness| {
+        let (width, height) = obs.image_size;
+        let mut moments = [F::zero(); 6];
+        for &pixel in obs.coordinates.iter() {
+            if inside_step_margin((height, width), pixel) {
+                add_moments(&mut moments, pixel);
+            }
+        }
+        let channels = F::from_single(obs.channels as f32);
+        moments.iter_mut().for_each(|m| *m *= channels);
+        (smoothness, smoothness.metric(&moments))
+    });
+    let registered: Vec<_> = columns.clone().map(|j| imgs_registered.column(j)).collect();
+    projected.resize(nrows, F::zero());
+    let mut scale = F::one();
+    for _ in 0..=LINE_SEARCH_HALVINGS {
+        let candidate_step = step * scale;
+        let candidate =
+            projection_params(&(projection_mat(motion) * projection_mat(&candidate_step)));
+        // The prior is counted relative to the current motion, where its change is 0.
+        let mut cost = prior.as_ref().map_or(0.0, |(smoothness, metric)| {
+            smoothness.cost_change(metric, &candidate_step)
+        });
+        for (registered_j, j) in registered.iter().zip(columns.clone()) {
+            project_column(obs.coordinates, projected, &obs.images[j], &candidate);
+            cost += residuals[j * nrows..(j + 1) * nrows]
+                .iter()
+                .zip(registered_j.iter())
+                .zip(projected.iter())
+                .map(|((&r, &w), &w_candidate)| (r + w - w_candidate).to_double().powi(2))
+                .sum::<f64>();
+        }
+        if cost < current_cost {
+            return candidate_step;
+        }
+        scale *= F::from_single(0.5);
     }
-    let hessian_chol = hessian
-        .cholesky()
-        .ok_or(RegistrationError::NonDefinitePositiveHessian(hessian))?;
-    Ok(hessian_chol.solve(&descent_params))
+    log::trace!("   line search: no decreasing step for image {}", i);
+    Vector6::zeros()
 }
 
 /// Compute the projection of each pixel of the image (modify in place).
-/// CAREFUL: coordinates must have the same amount of items that
+/// Coordinates must have the same amount of items that
 /// the number of rows in registered.
-/// Otherwise it may silently compute a wrong projection.
-/// I don't know how to assert the number of items in the coordinates iterator.
-fn project_f32<T: Scalar + Copy + CanLinearInterpolate<f32, f32>>(
-    coordinates: impl Iterator<Item = (usize, usize)> + Clone,
-    registered: &mut DMatrix<f32>,
+/// Each image has `channels` consecutive columns, projected with the same motion.
+fn project<T: Scalar + Copy + CanLinearInterpolate<f32, f32>, F: Float>(
+    coordinates: &[(usize, usize)],
+    registered: &mut DMatrix<F>,
     imgs: &[DMatrix<T>],
-    motion_vec: &[Vector6<f32>],
+    channels: usize,
+    motion_vec: &[Vector6<F>],
+) {
+    assert_eq!(coordinates.len(), registered.nrows());
+    for (j, img) in imgs.iter().enumerate() {
+        let mut registered_col = registered.column_mut(j);
+        let motion = &motion_vec[j / channels];
+        project_column(coordinates, registered_col.as_mut_slice(), img, motion);
+    }
+}
+
+/// Compute the projection of each pixel of one image (modify in place).
+fn project_column<T: Scalar + Copy + CanLinearInterpolate<f32, f32>, F: Float>(
+    coordinates: &[(usize, usize)],
+    registered_col: &mut [F],
+    img: &DMatrix<T>,
+    motion: &Vector6<F>,
 ) {
-    for (i, motion) in motion_vec.iter().enumerate() {
-        let motion_mat = projection_mat(motion);
-        let mut registered_col = registered.column_mut(i);
-        for ((x, y), pixel) in coordinates.clone().zip(registered_col.iter_mut()) {
-            let new_pos = motion_mat * Vector3::new(x as f32, y as f32, 1.0);
-            // WARNING: beware that interpolating with a f32 output normalize values in [0,1].
-            let interp: f32 = crate::img::interpolation::linear(new_pos.x, new_pos.y, &imgs[i]);
-            *pixel = interp;
+    // Interpolation is done in f32, precision of the pixels is already limited.
+    let motion_mat = projection_mat(&to_single_vec(motion));
+    let img = ImageView::from_matrix(img);
+    let (mut xs, mut ys) = ([0.0; LANES], [0.0; LANES]);
+    // Interpolate LANES pixels at a time, the remainder one by one.
+    let coords_chunks = coordinates.chunks_exact(LANES);
+    let coords_remainder = coords_chunks.remainder();
+    let mut pixels_chunks = registered_col.chunks_exact_mut(LANES);
+    for (coords, pixels) in coords_chunks.zip(&mut pixels_chunks) {
+        for (k, &(x, y)) in coords.iter().enumerate() {
+            let (x, y) = (x as f32, y as f32);
+            xs[k] = motion_mat.m11 * x + motion_mat.m12 * y + motion_mat.m13;
+            ys[k] = motion_mat.m21 * x + motion_mat.m22 * y + motion_mat.m23;
+        }
+        // WARNING: beware that interpolating with a f32 output normalize values in [0,1].
+        let interp: [f32; LANES] = linear_lanes(&xs, &ys, img);
+        for (pixel, v) in pixels.iter_mut().zip(interp.iter()) {
+            *pixel = F::from_single(*v);
         }
     }
+    let pixels_remainder = pixels_chunks.into_remainder();
+    for (&(x, y), pixel) in coords_remainder.iter().zip(pixels_remainder) {
+        let new_pos = motion_mat * Vector3::new(x as f32, y as f32, 1.0);
+        let interp: f32 = linear_view(new_pos.x, new_pos.y, img);
+        *pixel = F::from_single(interp);
+    }
 }
 
 /// Compute the projection of each pixel of the image.
@@ -576,6 +3848,23 @@ where
     imgs.iter().zip(motion_vec).map(warp_pair).collect()
 }
 
+/// Compute the projection of each image into the same canvas,
+/// which may be larger than the images.
+pub fn reproject_canvas<T, V, O>(
+    imgs: &[DMatrix<T>],
+    motion_vec: &[Vector6<f32>],
+    canvas: Canvas,
+) -> Vec<DMatrix<O>>
+where
+    O: Scalar,
+    V: Add<Output = V>,
+    f32: Mul<V, Output = V>,
+    T: Scalar + Copy + CanLinearInterpolate<V, O>,
+{
+    let warp_pair = |(im, motion)| warp_canvas(im, motion, canvas);
+    imgs.iter().zip(motion_vec).map(warp_pair).collect()
+}
+
 /// Async version of reproject.
 pub async fn reproject_may_stop<T, V, O, FB: Future<Output = bool>>(
     imgs: &[DMatrix<T>],
@@ -598,6 +3887,7 @@ where
     Ok(reprojected)
 }
 
+/// Warp an image with the given motion.
 pub fn warp<T, V, O>(img: &DMatrix<T>, motion_params: &Vector6<f32>) -> DMatrix<O>
 where
     O: Scalar,
@@ -605,27 +3895,138 @@ where
     f32: Mul<V, Output = V>,
     T: Scalar + Copy + CanLinearInterpolate<V, O>,
 {
-    let (nrows, ncols) = img.shape();
+    warp_view(ImageView::from_matrix(img), motion_params)
+}
+
+/// Warp an image with the given motion into a canvas.
+/// Parts of the canvas outside of the image are extrapolated from its borders.
+pub fn warp_canvas<T, V, O>(
+    img: &DMatrix<T>,
+    motion_params: &Vector6<f32>,
+    canvas: Canvas,
+) -> DMatrix<O>
+where
+    O: Scalar,
+    V: Add<Output = V>,
+    f32: Mul<V, Output = V>,
+    T: Scalar + Copy + CanLinearInterpolate<V, O>,
+{
+    warp_sized(
+        ImageView::from_matrix(img),
+        &canvas.motion(motion_params),
+        (canvas.height, canvas.width),
+    )
+}
+
+/// Warp an image with the given motion, reading pixels through a strided view.
+pub fn warp_view<T, V, O>(img: ImageView<T>, motion_params: &Vector6<f32>) -> DMatrix<O>
+where
+    O: Scalar,
+    V: Add<Output = V>,
+    f32: Mul<V, Output = V>,
+    T: Scalar + Copy + CanLinearInterpolate<V, O>,
+{
+    let shape = (img.height(), img.width());
+    warp_sized(img, motion_params, shape)
+}
+
+/// Warp an image with the given motion into an output of the given (height, width).
+///
+/// Output pixels are computed in the column major order of the result matrix,
+/// and the warped position is updated incrementally along each column.
+#[allow(clippy::cast_precision_loss)]
+fn warp_sized<T, V, O>(
+    img: ImageView<T>,
+    motion_params: &Vector6<f32>,
+    shape: (usize, usize),
+) -> DMatrix<O>
+where
+    O: Scalar,
+    V: Add<Output = V>,
+    f32: Mul<V, Output = V>,
+    T: Scalar + Copy + CanLinearInterpolate<V, O>,
+{
+    let (nrows, ncols) = shape;
     let motion_mat = projection_mat(motion_params);
-    DMatrix::from_fn(nrows, ncols, |i, j| {
-        let new_pos = motion_mat * Vector3::new(j as f32, i as f32, 1.0);
-        crate::img::interpolation::linear(new_pos.x, new_pos.y, img)
-    })
+    // Moving one pixel down in the output moves by this step in the input.
+    let step = Vector2::new(motion_mat[(0, 1)], motion_mat[(1, 1)]);
+    let mut data = Vec::with_capacity(nrows * ncols);
+    let (mut xs, mut ys) = ([0.0; LANES], [0.0; LANES]);
+    for j in 0..ncols {
+        let top = (motion_mat * Vector3::new(j as f32, 0.0, 1.0)).xy();
+        // Interpolate LANES pixels at a time, the remainder one by one.
+        let mut i = 0;
+        while i + LANES <= nrows {
+            for (k, (x, y)) in xs.iter_mut().zip(ys.iter_mut()).enumerate() {
+                let t = (i + k) as f32;
+                *x = top.x + Mul::<f32>::mul(step.x, t);
+                *y = top.y + Mul::<f32>::mul(step.y, t);
+            }
+            data.extend(linear_lanes(&xs, &ys, img));
+            i += LANES;
+        }
+        data.extend((i..nrows).map(|i| {
+            let new_pos = top + step * i as f32;
+            linear_view(new_pos.x, new_pos.y, img)
+        }));
+    }
+    DMatrix::from_vec(nrows, ncols, data)
+}
+
+/// A-update with a full SVD: A = U * shrink(S) * V^T, for the SVD of `temp`.
+/// Return the shrinked singular values.
+/// The SVD consumes `temp`, which is replaced by a buffer of the same shape.
+fn full_svd_update<F: Float>(
+    backend: &impl SvdBackend<F>,
+    rho: F,
+    temp: &mut DMatrix<F>,
+    imgs_a: &mut DMatrix<F>,
+) -> DVector<F> {
+    // The SVD consumes its input, we temporarily leave an empty matrix in place.
+    let imgs_a_temp = std::mem::replace(temp, DMatrix::zeros(0, 0));
+    let (mut u, mut singular_values, v_t) = backend.svd(imgs_a_temp);
+    log::trace!("   singular values before shrink: {}", singular_values);
+    for x in singular_values.iter_mut() {
+        *x = shrink(F::one() / rho, *x);
+    }
+    log::trace!("   singular values after shrink: {}", singular_values);
+    // Recompose A = U * S * V^T, scaling U columns in place.
+    for (mut u_col, s) in u.column_iter_mut().zip(singular_values.iter()) {
+        u_col *= *s;
+    }
+    imgs_a.gemm(F::one(), &u, &v_t, F::zero());
+    if u.shape() == imgs_a.shape() {
+        *temp = u;
+    } else {
+        // U is smaller than A when there are less pixels than images.
+        *temp = DMatrix::zeros(imgs_a.nrows(), imgs_a.ncols());
+    }
+    singular_values
 }
 
 /// Computes the sqrt of the sum of squared values.
 /// This is the L2 norm of the vectorized version of the matrix.
-fn norm(matrix: &DMatrix<f32>) -> f32 {
-    norm_sqr(matrix).sqrt() as f32
+fn norm<F: Float>(matrix: &DMatrix<F>) -> f64 {
+    norm_sqr(matrix).sqrt()
+}
+
+/// Computes the L2 norm of the difference of two matrices, without allocating.
+fn norm_diff<F: Float>(a: &DMatrix<F>, b: &DMatrix<F>) -> f64 {
+    let sum_sqr: f64 = a
+        .iter()
+        .zip(b.iter())
+        .map(|(&x, &y)| (x.to_double() - y.to_double()).powi(2))
+        .sum();
+    sum_sqr.sqrt()
 }
 
 /// Computes the sum of squared values of a matrix.
-fn norm_sqr(matrix: &DMatrix<f32>) -> f64 {
-    matrix.iter().map(|&x| (x as f64).powi(2)).sum()
+fn norm_sqr<F: Float>(matrix: &DMatrix<F>) -> f64 {
+    matrix.iter().map(|&x| x.to_double().powi(2)).sum()
 }
 
 /// Shrink values toward 0.
-fn shrink<T: RealField>(alpha: T, x: T) -> T {
+pub(crate) fn shrink<T: RealField>(alpha: T, x: T) -> T {
     let alpha = alpha.abs();
     if x.is_sign_positive() {
         (x - alpha).max(T::zero())
@@ -633,3 +4034,171 @@ fn shrink<T: RealField>(alpha: T, x: T) -> T {
         (x + alpha).min(T::zero())
     }
 }
+
+/// Entry points of the private hot loops of the registration, for the benchmarks.
+#[doc(hidden)]
+pub mod bench {
+    use super::*;
+
+    /// Projection of single precision images, as done at each iteration.
+    pub fn project_f32<T: Scalar + Copy + CanLinearInterpolate<f32, f32>>(
+        coordinates: &[(usize, usize)],
+        registered: &mut DMatrix<f32>,
+        imgs: &[DMatrix<T>],
+        motion_vec: &[Vector6<f32>],
+    ) {
+        project(coordinates, registered, imgs, 1, motion_vec)
+    }
+
+    /// A-update of the low-rank approximation with a full SVD, as done at each iteration.
+    pub fn svd_update(
+        backend: &impl SvdBackend<f32>,
+        rho: f32,
+        temp: &mut DMatrix<f32>,
+        imgs_a: &mut DMatrix<f32>,
+    ) -> DVector<f32> {
+        full_svd_update(backend, rho, temp, imgs_a)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::img::synthetic::translated_stack;
+
+    /// Size of the synthetic images, small enough for debug builds.
+    const SIZE: usize = 128;
+
+    /// Default configuration, with levels down to 32x32 pixels.
+    fn config() -> Config {
+        Config {
+            levels: 3,
+            ..Config::default()
+        }
+    }
+
+    /// Largest difference between the parameters of two lists of motions.
+    fn max_difference(motion_vec: &[Vector6<f32>], expected: &[Vector6<f32>]) -> f32 {
+        assert_eq!(motion_vec.len(), expected.len());
+        motion_vec
+            .iter()
+            .zip(expected)
+            .map(|(m, e)| (m - e).amax())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn inverse_compositional_matches_forwards() {
+        let (imgs, motions) = translated_stack(SIZE, SIZE, 4, 42);
+        let register = |compositional| {
+            let config = Config {
+                compositional,
+                ..config()
+            };
+            gray_affine(config, imgs.clone(), 40).unwrap().0
+        };
+        let forwards = register(Compositional::Forwards);
+        let inverse = register(Compositional::Inverse);
+        assert!(max_difference(&forwards, &motions) < 0.25);
+        assert!(max_difference(&inverse, &forwards) < 0.1);
+    }
+
+    /// Stack of `count` images of 3 channels, image after image,
+    /// each channel cut from another texture with the shifts of [translated_stack].
+    /// Channel c of image k is scaled by `gain(k, c)`.
+    fn rgb_stack(count: usize, gain: impl Fn(usize, usize) -> f32) -> Vec<DMatrix<u8>> {
+        let planes: Vec<Vec<DMatrix<u8>>> = (0..3)
+            .map(|c| translated_stack(SIZE, SIZE, count, 42 + c as u64).0)
+            .collect();
+        (0..count)
+            .flat_map(|k| (0..3).map(move |c| (k, c)))
+            .map(|(k, c)| {
+                let g = gain(k, c);
+                planes[c][k].map(|x| (g * x as f32).round().min(255.0) as u8)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn gain_bias_matches_each_channel_of_the_reference() {
+        // The channels of the reference image have different contrasts,
+        // and those of the second image are twice darker.
+        let contrast = [1.0, 0.5, 0.25];
+        let imgs = rgb_stack(2, |k, c| contrast[c] / (1 + k) as f32);
+        let columns: Vec<f32> = imgs
+            .iter()
+            .flat_map(|img| img.iter().map(|&x| x as f32 / 255.0))
+            .collect();
+        let registered = RegisteredImgs::Float(DMatrix::from_vec(SIZE * SIZE, 6, columns));
+        let mut gain_bias = GainBias::new(6, 3);
+        gain_bias.update(&registered);
+        for c in 0..3 {
+            // Channels of the reference are kept as they are.
+            assert!((gain_bias.gains[c] - 1.0).abs() < 1e-4);
+            assert!(gain_bias.biases[c].abs() < 1e-4);
+            // Channels of the second image are matched to the same channel of the reference.
+            let gain = gain_bias.gains[3 + c];
+            assert!((gain - 2.0).abs() < 0.2, "gain of channel {}: {}", c, gain);
+        }
+    }
+
+    #[test]
+    fn multichannel_inverse_compositional_matches_forwards() {
+        // Each channel of each image has its own exposure.
+        let imgs = rgb_stack(3, |k, c| 1.0 - 0.1 * (k + c) as f32);
+        let (_, motions) = translated_stack(SIZE, SIZE, 3, 42);
+        let register = |compositional| {
+            let config = Config {
+                compositional,
+                gain_bias: true,
+                ..config()
+            };
+            let registered = multichannel_affine_detailed(config, 3, imgs.clone(), 40);
+            registered.unwrap().motion_vec
+        };
+        let forwards = register(Compositional::Forwards);
+        let inverse = register(Compositional::Inverse);
+        assert!(max_difference(&forwards, &motions) < 0.25);
+        assert!(max_difference(&inverse, &forwards) < 0.1);
+    }
+
+    /// Largest uncertainty of the motions of a registration, in pixels,
+    /// checking that the reference motion is exact.
+    fn max_pixel_std(imgs: Vec<DMatrix<u8>>) -> f32 {
+        let registered = gray_affine_detailed(config(), imgs, 40).unwrap();
+        let uncertainties: Vec<MotionUncertainty> = registered
+            .uncertainties
+            .into_iter()
+            .map(|u| u.expect("Uncertainty of an inlier"))
+            .collect();
+        assert_eq!(uncertainties[0].pixel_std, 0.0);
+        let pixel_stds = uncertainties.iter().map(|u| u.pixel_std);
+        assert!(pixel_stds.clone().all(f32::is_finite));
+        pixel_stds.fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn uncertainty_grows_with_noise() {
+        let (imgs, _) = translated_stack(SIZE, SIZE, 4, 42);
+        // Deterministic noise of up to 20 intensity levels, different for each image.
+        let noisy: Vec<DMatrix<u8>> = imgs
+            .iter()
+            .enumerate()
+            .map(|(k, img)| {
+                DMatrix::from_fn(SIZE, SIZE, |i, j| {
+                    let noise = ((i * 7919 + j * 104_729 + k * 1_299_709) % 41) as i16 - 20;
+                    (img[(i, j)] as i16 + noise).clamp(0, 255) as u8
+                })
+            })
+            .collect();
+        let clean_std = max_pixel_std(imgs);
+        let noisy_std = max_pixel_std(noisy);
+        assert!(clean_std < 0.1, "clean: {}", clean_std);
+        assert!(
+            noisy_std > clean_std,
+            "noisy: {}, clean: {}",
+            noisy_std,
+            clean_std
+        );
+    }
+}
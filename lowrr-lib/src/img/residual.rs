@@ -0,0 +1,155 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Residual motion left by the affine registration, to detect non-rigid scenes.
+//!
+//! Registered images are split in blocks, and the translation aligning each block
+//! with the same block of the first registered image is estimated with a few
+//! Lucas-Kanade iterations.
+//! When the affine model fits the scene, these translations stay well below a pixel.
+//! Large ones indicate parallax, deformations or rolling shutter,
+//! which need a non-rigid model.
+
+use nalgebra::{DMatrix, Matrix2, Vector2, Vector6};
+
+use crate::img::gradients;
+use crate::img::interpolation;
+use crate::img::metrics::inside_mask;
+use crate::img::registration::{warp, CanRegister};
+
+/// Side of the blocks of the residual translation field.
+pub const DEFAULT_BLOCK_SIZE: usize = 64;
+
+/// Residual translation, in pixels, above which the scene is likely non-rigid.
+pub const DEFAULT_THRESHOLD: f32 = 0.5;
+
+/// Maximum number of Lucas-Kanade iterations for each block.
+const MAX_ITERATIONS: usize = 10;
+
+/// Mean squared gradient along the weakest direction of a block,
+/// for intensities in [0,1], below which it has too little texture
+/// for its translation to be estimated.
+const MIN_TEXTURE: f32 = 1e-5;
+
+/// Residual translation of each block of an image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResidualField {
+    pub block_size: usize,
+    /// Translation (x, y) in pixels of each block, None for blocks
+    /// partially outside of the images, without texture, or that did not converge.
+    pub shifts: DMatrix<Option<Vector2<f32>>>,
+}
+
+impl ResidualField {
+    /// Norm of the largest residual translation, 0 if there is none.
+    pub fn max_norm(&self) -> f32 {
+        self.shifts
+            .iter()
+            .flatten()
+            .map(|shift| shift.norm())
+            .fold(0.0, f32::max)
+    }
+}
+
+/// Residual translation fields of each image of a stack registered with the given motions,
+/// relative to the first image.
+pub fn residual_fields<T: CanRegister>(
+    imgs: &[DMatrix<T>],
+    motion_vec: &[Vector6<f32>],
+    block_size: usize,
+) -> Vec<ResidualField> {
+    let (first_img, first_motion) = match imgs.first().zip(motion_vec.first()) {
+        None => return Vec::new(),
+        Some(first) => first,
+    };
+    let reference = warp::<T, f32, f32>(first_img, first_motion);
+    let reference_mask = inside_mask(first_motion, first_img.shape());
+    imgs.iter()
+        .zip(motion_vec)
+        .map(|(img, motion)| {
+            let mask = inside_mask(motion, img.shape()).zip_map(&reference_mask, |a, b| a && b);
+            let registered = warp::<T, f32, f32>(img, motion);
+            residual_field(&reference, &registered, &mask, block_size)
+        })
+        .collect()
+}
+
+/// Residual translation field between two images with intensities in [0,1],
+/// for blocks fully inside the mask.
+///
+/// The translation of a block is such that `img(x + shift)` matches `reference(x)`.
+#[allow(clippy::cast_precision_loss)]
+pub fn residual_field(
+    reference: &DMatrix<f32>,
+    img: &DMatrix<f32>,
+    mask: &DMatrix<bool>,
+    block_size: usize,
+) -> ResidualField {
+    let (height, width) = reference.shape();
+    let (rows, cols) = (height / block_size, width / block_size);
+    if rows == 0 || cols == 0 || height < 3 || width < 3 {
+        return ResidualField {
+            block_size,
+            shifts: DMatrix::from_element(rows, cols, None),
+        };
+    }
+    let gradients = gradients::centered_f32(reference);
+    let shifts = DMatrix::from_fn(rows, cols, |row, col| {
+        let corner = (row * block_size, col * block_size);
+        let size = (block_size, block_size);
+        if !mask.slice(corner, size).iter().all(|&inside| inside) {
+            return None;
+        }
+        block_shift(
+            &reference.slice(corner, size).into_owned(),
+            &gradients.slice(corner, size).into_owned(),
+            img,
+            corner,
+        )
+    });
+    ResidualField { block_size, shifts }
+}
+
+/// Inverse compositional Lucas-Kanade estimation of the translation of a block,
+/// with the gradients of the reference block.
+#[allow(clippy::cast_precision_loss)]
+fn block_shift(
+    reference: &DMatrix<f32>,
+    gradients: &DMatrix<(f32, f32)>,
+    img: &DMatrix<f32>,
+    corner: (usize, usize),
+) -> Option<Vector2<f32>> {
+    let hessian = gradients.iter().fold(Matrix2::zeros(), |h, &(gx, gy)| {
+        h + Matrix2::new(gx * gx, gx * gy, gx * gy, gy * gy)
+    });
+    let eigenvalues = hessian.symmetric_eigenvalues();
+    if eigenvalues.min() < MIN_TEXTURE * reference.len() as f32 {
+        return None;
+    }
+    let hessian_inv = hessian.try_inverse()?;
+    let (top, left) = (corner.0 as f32, corner.1 as f32);
+    let mut shift = Vector2::zeros();
+    for _ in 0..MAX_ITERATIONS {
+        let mut descent = Vector2::zeros();
+        for j in 0..reference.ncols() {
+            for i in 0..reference.nrows() {
+                let x = left + j as f32 + shift.x;
+                let y = top + i as f32 + shift.y;
+                let error = interpolation::linear::<f32, f32, f32>(x, y, img) - reference[(i, j)];
+                let (gx, gy) = gradients[(i, j)];
+                descent += Vector2::new(gx, gy) * error;
+            }
+        }
+        // Compose with the inverse of the increment of the reference block.
+        let step = hessian_inv * descent;
+        shift -= step;
+        if step.norm() < 1e-3 {
+            break;
+        }
+    }
+    // Shifts larger than half a block did not converge to the same content.
+    if shift.norm() < reference.nrows() as f32 / 2.0 {
+        Some(shift)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,91 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Tracking of a region of interest across a registered stack.
+//!
+//! The region is a rectangle of the registered frame, the frame of the first image.
+//! Its corners are mapped into each image with the motion of that image,
+//! giving a quadrilateral, and its bounding box to crop a moving specimen consistently.
+
+use nalgebra::{Vector3, Vector6};
+
+use crate::affine2d::projection_mat;
+
+/// Rectangular region of interest of the registered frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Roi {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl std::str::FromStr for Roi {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid region of interest \"{}\", expecting x,y,w,h", s);
+        let values: Vec<f32> = s
+            .split(',')
+            .map(|v| v.trim().parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| invalid())?;
+        match *values.as_slice() {
+            [x, y, width, height] if width >= 0.0 && height >= 0.0 => Ok(Roi {
+                x,
+                y,
+                width,
+                height,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Roi {
+    /// Corners (x, y), clockwise from the top left one.
+    pub fn corners(&self) -> [(f32, f32); 4] {
+        let (right, bottom) = (self.x + self.width, self.y + self.height);
+        [
+            (self.x, self.y),
+            (right, self.y),
+            (right, bottom),
+            (self.x, bottom),
+        ]
+    }
+}
+
+/// Region of interest transformed into one image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quad {
+    /// Corners (x, y) in the image, in the order of [Roi::corners].
+    pub corners: [(f32, f32); 4],
+}
+
+impl Quad {
+    /// Bounding box (left, top, right, bottom) of the corners.
+    pub fn bounding_box(&self) -> (f32, f32, f32, f32) {
+        let xs = self.corners.iter().map(|c| c.0);
+        let ys = self.corners.iter().map(|c| c.1);
+        (
+            xs.clone().fold(f32::INFINITY, f32::min),
+            ys.clone().fold(f32::INFINITY, f32::min),
+            xs.fold(f32::NEG_INFINITY, f32::max),
+            ys.fold(f32::NEG_INFINITY, f32::max),
+        )
+    }
+}
+
+/// Region of interest of the registered frame in each image, with the motions of the images.
+pub fn track(roi: &Roi, motion_vec: &[Vector6<f32>]) -> Vec<Quad> {
+    motion_vec
+        .iter()
+        .map(|motion| {
+            let mat = projection_mat(motion);
+            let mut corners = roi.corners();
+            for corner in corners.iter_mut() {
+                let p = mat * Vector3::new(corner.0, corner.1, 1.0);
+                *corner = (p.x, p.y);
+            }
+            Quad { corners }
+        })
+        .collect()
+}
@@ -0,0 +1,105 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Handling of image stacks where images do not all have the same size,
+//! such as images cropped differently by the camera.
+
+use nalgebra::{DMatrix, Scalar};
+use thiserror::Error;
+
+/// What to do with images whose size differs from the others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SizePolicy {
+    /// Refuse the stack with an error.
+    #[default]
+    Error,
+    /// Center-crop all images to the size common to all of them.
+    Crop,
+    /// Pad all images to the size of the largest, replicating their borders.
+    Pad,
+}
+
+impl std::str::FromStr for SizePolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(SizePolicy::Error),
+            "crop" => Ok(SizePolicy::Crop),
+            "pad" => Ok(SizePolicy::Pad),
+            _ => Err(format!(
+                "Unknown size policy \"{}\", expecting error, crop or pad",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum SizeError {
+    #[error("Image {index} has size {actual:?} (height, width) but the first image has size {expected:?}")]
+    Mismatch {
+        index: usize,
+        expected: (usize, usize),
+        actual: (usize, usize),
+    },
+    #[error("Images have no area in common: {0:?} (height, width)")]
+    Empty((usize, usize)),
+}
+
+/// Check that all images have the same size as the first one.
+pub fn check<T: Scalar>(imgs: &[DMatrix<T>]) -> Result<(), SizeError> {
+    let expected = match imgs.first() {
+        None => return Ok(()),
+        Some(img) => img.shape(),
+    };
+    match imgs.iter().position(|img| img.shape() != expected) {
+        None => Ok(()),
+        Some(index) => Err(SizeError::Mismatch {
+            index,
+            expected,
+            actual: imgs[index].shape(),
+        }),
+    }
+}
+
+/// Give all images the same size, according to the chosen policy.
+/// Images already of the common size are left untouched.
+pub fn unify<T: Scalar + Copy>(
+    policy: SizePolicy,
+    imgs: Vec<DMatrix<T>>,
+) -> Result<Vec<DMatrix<T>>, SizeError> {
+    let shapes = imgs.iter().map(|img| img.shape());
+    let shape = match policy {
+        SizePolicy::Error => return check(&imgs).map(|_| imgs),
+        SizePolicy::Crop => shapes.fold((usize::MAX, usize::MAX), |(h, w), (hi, wi)| {
+            (h.min(hi), w.min(wi))
+        }),
+        SizePolicy::Pad => shapes.fold((0, 0), |(h, w), (hi, wi)| (h.max(hi), w.max(wi))),
+    };
+    if imgs.is_empty() {
+        return Ok(imgs);
+    } else if shape.0 == 0 || shape.1 == 0 {
+        return Err(SizeError::Empty(shape));
+    }
+    Ok(imgs.into_iter().map(|img| fit(shape, img)).collect())
+}
+
+/// Center an image in a frame of the given (height, width),
+/// cropping it where it is larger and replicating its borders where it is smaller.
+#[allow(clippy::cast_possible_wrap)]
+#[allow(clippy::cast_sign_loss)]
+pub fn fit<T: Scalar + Copy>(shape: (usize, usize), img: DMatrix<T>) -> DMatrix<T> {
+    let (height, width) = img.shape();
+    if (height, width) == shape || height == 0 || width == 0 {
+        return img;
+    }
+    // Offsets of the frame in the image, negative when padding.
+    let top = (height as isize - shape.0 as isize) / 2;
+    let left = (width as isize - shape.1 as isize) / 2;
+    let clamp = |v: isize, max: usize| v.clamp(0, max as isize - 1) as usize;
+    DMatrix::from_fn(shape.0, shape.1, |y, x| {
+        img[(
+            clamp(y as isize + top, height),
+            clamp(x as isize + left, width),
+        )]
+    })
+}
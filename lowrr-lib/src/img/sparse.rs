@@ -85,6 +85,173 @@ where
     result
 }
 
+// Alternative selection strategies ###########################################
+
+/// Keep the given fraction of points with the highest gradients at each level.
+///
+/// Levels are independent, contrary to `select`.
+/// As in `select`, all points of the lowest resolution are kept,
+/// and the masks are returned starting with the lowest resolution.
+pub fn select_top_fraction<T>(fraction: f32, gradients: &[DMatrix<T>]) -> Vec<DMatrix<bool>>
+where
+    T: Copy + Scalar + std::cmp::PartialOrd,
+{
+    select_per_level(gradients, |grad_mat| {
+        top_k(kept_count(fraction, grad_mat.len()), grad_mat)
+    })
+}
+
+/// Keep at most `budget` points at each level, with the highest gradients.
+///
+/// This amounts to adapting the gradient threshold at each level.
+/// Levels with less points than the budget keep all of them,
+/// and the masks are returned starting with the lowest resolution.
+pub fn select_budget<T>(budget: usize, gradients: &[DMatrix<T>]) -> Vec<DMatrix<bool>>
+where
+    T: Copy + Scalar + std::cmp::PartialOrd,
+{
+    gradients
+        .iter()
+        .rev()
+        .map(|grad_mat| top_k(budget.min(grad_mat.len()), grad_mat))
+        .collect()
+}
+
+/// Mask of the `count` highest values of a matrix.
+fn top_k<T>(count: usize, mat: &DMatrix<T>) -> DMatrix<bool>
+where
+    T: Copy + Scalar + std::cmp::PartialOrd,
+{
+    let (nrows, ncols) = mat.shape();
+    if count >= mat.len() {
+        return DMatrix::repeat(nrows, ncols, true);
+    }
+    let mut mask = DMatrix::repeat(nrows, ncols, false);
+    if count > 0 {
+        let mut indices: Vec<usize> = (0..mat.len()).collect();
+        // Partition the indices around the k-th highest value.
+        indices.select_nth_unstable_by(count - 1, |&i, &j| mat[j].partial_cmp(&mat[i]).unwrap());
+        for &idx in &indices[..count] {
+            mask[idx] = true;
+        }
+    }
+    mask
+}
+
+/// Select points on a regular grid, with a spacing such that
+/// approximately the given fraction of points is kept at each level.
+///
+/// All points of the lowest resolution are kept,
+/// and the masks are returned starting with the lowest resolution.
+pub fn select_grid<T: Scalar>(fraction: f32, levels: &[DMatrix<T>]) -> Vec<DMatrix<bool>> {
+    let spacing = (1.0 / fraction.max(f32::EPSILON).sqrt()).round().max(1.0) as usize;
+    let offset = spacing / 2;
+    select_per_level(levels, |mat| {
+        let (nrows, ncols) = mat.shape();
+        DMatrix::from_fn(nrows, ncols, |i, j| {
+            i % spacing == offset && j % spacing == offset
+        })
+    })
+}
+
+/// Select corners detected with the FAST-9 segment test,
+/// on images with intensities normalized in [0,1].
+///
+/// A point is a corner if 9 contiguous points of the circle of radius 3 around it
+/// are all brighter than its intensity + threshold, or all darker than its intensity - threshold.
+/// All points of the lowest resolution are kept,
+/// and the masks are returned starting with the lowest resolution.
+pub fn select_fast(threshold: f32, imgs: &[DMatrix<f32>]) -> Vec<DMatrix<bool>> {
+    select_per_level(imgs, |img| fast_corners(threshold, img))
+}
+
+/// Offsets (row, column) of the 16 points of the Bresenham circle of radius 3.
+const FAST_CIRCLE: [(isize, isize); 16] = [
+    (-3, 0),
+    (-3, 1),
+    (-2, 2),
+    (-1, 3),
+    (0, 3),
+    (1, 3),
+    (2, 2),
+    (3, 1),
+    (3, 0),
+    (3, -1),
+    (2, -2),
+    (1, -3),
+    (0, -3),
+    (-1, -3),
+    (-2, -2),
+    (-3, -1),
+];
+
+/// Minimum number of contiguous points of the circle for a FAST corner.
+const FAST_ARC_LENGTH: usize = 9;
+
+fn fast_corners(threshold: f32, img: &DMatrix<f32>) -> DMatrix<bool> {
+    let (nrows, ncols) = img.shape();
+    let mut mask = DMatrix::repeat(nrows, ncols, false);
+    if nrows < 7 || ncols < 7 {
+        return mask;
+    }
+    for j in 3..ncols - 3 {
+        for i in 3..nrows - 3 {
+            let center = img[(i, j)];
+            // +1 for brighter points, -1 for darker points, 0 otherwise.
+            let mut signs = [0_i8; 16];
+            for (sign, (di, dj)) in signs.iter_mut().zip(FAST_CIRCLE.iter()) {
+                let value = img[((i as isize + di) as usize, (j as isize + dj) as usize)];
+                if value > center + threshold {
+                    *sign = 1;
+                } else if value < center - threshold {
+                    *sign = -1;
+                }
+            }
+            mask[(i, j)] = has_arc(&signs, 1) || has_arc(&signs, -1);
+        }
+    }
+    mask
+}
+
+/// Check if the circle contains FAST_ARC_LENGTH contiguous points of the given sign.
+fn has_arc(signs: &[i8; 16], sign: i8) -> bool {
+    let mut run = 0;
+    // Go around the circle twice to account for arcs wrapping around the start.
+    for &s in signs.iter().chain(signs.iter()) {
+        if s == sign {
+            run += 1;
+            if run >= FAST_ARC_LENGTH {
+                return true;
+            }
+        } else {
+            run = 0;
+        }
+    }
+    false
+}
+
+/// Apply a selection independently at each level except the lowest resolution,
+/// where all points are kept. Levels are given starting with the highest resolution,
+/// and masks are returned starting with the lowest resolution.
+fn select_per_level<T, F>(levels: &[DMatrix<T>], f: F) -> Vec<DMatrix<bool>>
+where
+    T: Scalar,
+    F: Fn(&DMatrix<T>) -> DMatrix<bool>,
+{
+    let (nrows, ncols) = levels.last().unwrap().shape();
+    std::iter::once(DMatrix::repeat(nrows, ncols, true))
+        .chain(levels.iter().rev().skip(1).map(f))
+        .collect()
+}
+
+/// Number of points kept for a given fraction of the total.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_sign_loss)]
+#[allow(clippy::cast_precision_loss)]
+fn kept_count(fraction: f32, total: usize) -> usize {
+    ((fraction.max(0.0) * total as f32).round() as usize).min(total)
+}
+
 // Utilitary functions on sparse matrices ######################################
 
 /// Merge multiple sparse matrices into one combining all sparsely selected pixels.
@@ -0,0 +1,151 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Super-resolution reconstruction of the reference image of a registered stack.
+//!
+//! The sub-pixel motions estimated by the registration tell where each pixel
+//! of each image samples the scene, more densely than the pixels of a single image.
+//! Samples are accumulated on a finer grid (shift-and-add),
+//! which is then sharpened by a Richardson-Lucy deconvolution.
+
+use nalgebra::{DMatrix, Vector3, Vector6};
+
+use crate::affine2d::projection_mat;
+use crate::img::filter::gaussian_blur_f32;
+use crate::img::normalization::CanNormalize;
+
+/// Accumulated weight under which a pixel of the fine grid has no sample,
+/// and is interpolated from the reference image instead.
+const MIN_WEIGHT: f32 = 1e-3;
+
+/// Super-resolution of the reference image, upscaled by an integer factor,
+/// from the images and the motions given by their registration.
+///
+/// # Panics
+///
+/// If the factor is 0.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_sign_loss)]
+pub fn super_resolve<T: CanNormalize>(
+    imgs: &[DMatrix<T>],
+    motion_vec: &[Vector6<f32>],
+    factor: usize,
+    deconvolution_iterations: usize,
+) -> DMatrix<T> {
+    let stacked = shift_and_add(imgs, motion_vec, factor);
+    let sharpened = deconvolve(&stacked, psf_sigma(factor), deconvolution_iterations);
+    sharpened.map(|x| T::from_level(x.round().max(0.0) as usize))
+}
+
+/// Standard deviation of the gaussian approximating the blur of a shift-and-add
+/// image upscaled by the given factor, mostly due to the size of the original pixels:
+/// the one of a box as wide as an original pixel.
+#[allow(clippy::cast_precision_loss)]
+pub fn psf_sigma(factor: usize) -> f32 {
+    factor as f32 / 12_f32.sqrt()
+}
+
+/// Average of the samples of all images on a grid finer by the given factor,
+/// in the frame of the reference image, with intensities in levels of `T`.
+///
+/// Each sample is spread on its 4 nearest pixels of the fine grid with bilinear weights.
+/// Pixels without samples, at the borders or with too few images,
+/// are interpolated from the reference image (the first one).
+///
+/// # Panics
+///
+/// If the factor is 0.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_precision_loss)]
+#[allow(clippy::cast_sign_loss)]
+pub fn shift_and_add<T: CanNormalize>(
+    imgs: &[DMatrix<T>],
+    motion_vec: &[Vector6<f32>],
+    factor: usize,
+) -> DMatrix<f32> {
+    assert!(factor > 0, "The upscaling factor must be at least 1");
+    let (height, width) = match imgs.first() {
+        None => return DMatrix::zeros(0, 0),
+        Some(img) => img.shape(),
+    };
+    let (fine_height, fine_width) = (height * factor, width * factor);
+    let mut sum: DMatrix<f32> = DMatrix::zeros(fine_height, fine_width);
+    let mut weights: DMatrix<f32> = DMatrix::zeros(fine_height, fine_width);
+    let scale = factor as f32;
+    for (img, motion) in imgs.iter().zip(motion_vec) {
+        // The pixel q of an image registered with the motion M is at M^-1 q in the reference.
+        let inverse = match projection_mat(motion).try_inverse() {
+            None => continue,
+            Some(inverse) => inverse,
+        };
+        for ((x, y), value) in crate::utils::coords_col_major((height, width)).zip(img.iter()) {
+            let p = inverse * Vector3::new(x as f32, y as f32, 1.0);
+            // Pixel centers at k in the reference are at (k + 0.5) * factor - 0.5 in the fine grid.
+            let fx = (p.x + 0.5) * scale - 0.5;
+            let fy = (p.y + 0.5) * scale - 0.5;
+            if fx < 0.0 || fy < 0.0 {
+                continue;
+            }
+            let (u, v) = (fx as usize, fy as usize);
+            if u + 1 >= fine_width || v + 1 >= fine_height {
+                continue;
+            }
+            let (a, b) = (fx - u as f32, fy - v as f32);
+            let level = value.to_level() as f32;
+            for &(i, j, w) in &[
+                (v, u, (1.0 - a) * (1.0 - b)),
+                (v, u + 1, a * (1.0 - b)),
+                (v + 1, u, (1.0 - a) * b),
+                (v + 1, u + 1, a * b),
+            ] {
+                sum[(i, j)] += w * level;
+                weights[(i, j)] += w;
+            }
+        }
+    }
+    let reference = &imgs[0];
+    DMatrix::from_fn(fine_height, fine_width, |i, j| {
+        let w = weights[(i, j)];
+        if w > MIN_WEIGHT {
+            sum[(i, j)] / w
+        } else {
+            let x = (j as f32 + 0.5) / scale - 0.5;
+            let y = (i as f32 + 0.5) / scale - 0.5;
+            bilinear_level(reference, x, y)
+        }
+    })
+}
+
+/// Richardson-Lucy deconvolution with a gaussian point spread function.
+/// Intensities are expected to be non-negative.
+pub fn deconvolve(img: &DMatrix<f32>, sigma: f32, iterations: usize) -> DMatrix<f32> {
+    let mut estimate = img.clone();
+    for _ in 0..iterations {
+        // The gaussian is symmetric, so it is its own adjoint.
+        let mut ratio = gaussian_blur_f32(&estimate, sigma);
+        ratio.zip_apply(img, |blurred, observed| {
+            observed / blurred.max(f32::EPSILON)
+        });
+        let correction = gaussian_blur_f32(&ratio, sigma);
+        estimate.component_mul_assign(&correction);
+    }
+    estimate
+}
+
+/// Bilinear interpolation of the intensity level of an image,
+/// with positions clamped inside of the image.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_precision_loss)]
+#[allow(clippy::cast_sign_loss)]
+fn bilinear_level<T: CanNormalize>(img: &DMatrix<T>, x: f32, y: f32) -> f32 {
+    let (height, width) = img.shape();
+    let x = x.clamp(0.0, (width - 1) as f32);
+    let y = y.clamp(0.0, (height - 1) as f32);
+    let (u, v) = (x as usize, y as usize);
+    let (u1, v1) = ((u + 1).min(width - 1), (v + 1).min(height - 1));
+    let (a, b) = (x - u as f32, y - v as f32);
+    let level = |i, j| img[(i, j)].to_level() as f32;
+    (1.0 - a) * (1.0 - b) * level(v, u)
+        + a * (1.0 - b) * level(v, u1)
+        + (1.0 - a) * b * level(v1, u)
+        + a * b * level(v1, u1)
+}
@@ -0,0 +1,76 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Synthetic image stacks with known motions,
+//! to benchmark and check the registration without image files.
+
+use nalgebra::{DMatrix, Vector6};
+
+/// Deterministic pseudo-random numbers in [0,1), from a linear congruential generator.
+struct Lcg(u64);
+
+impl Lcg {
+    #[allow(clippy::cast_precision_loss)]
+    fn next(&mut self) -> f32 {
+        self.0 = self
+            .0
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        (self.0 >> 40) as f32 / (1_u64 << 24) as f32
+    }
+}
+
+/// Smooth random texture of bright and dark gaussian blobs of various sizes,
+/// the same for a given seed.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_precision_loss)]
+#[allow(clippy::cast_sign_loss)]
+pub fn texture(height: usize, width: usize, seed: u64) -> DMatrix<u8> {
+    let mut rnd = Lcg(seed);
+    let mut intensities = DMatrix::repeat(height, width, 128.0_f32);
+    let blobs_count = height * width / 300 + 1;
+    for _ in 0..blobs_count {
+        let (y, x) = (rnd.next() * height as f32, rnd.next() * width as f32);
+        let radius = 2.0 + 10.0 * rnd.next();
+        let amplitude = 80.0 * (2.0 * rnd.next() - 1.0);
+        // Blobs are negligible further than 3 times their radius.
+        let reach = 3.0 * radius;
+        let rows = (y - reach).max(0.0) as usize..((y + reach) as usize + 1).min(height);
+        let cols = (x - reach).max(0.0) as usize..((x + reach) as usize + 1).min(width);
+        for j in cols {
+            for i in rows.clone() {
+                let d2 = (i as f32 - y).powi(2) + (j as f32 - x).powi(2);
+                intensities[(i, j)] += amplitude * (-d2 / (2.0 * radius * radius)).exp();
+            }
+        }
+    }
+    intensities.map(|v| v.round().clamp(0.0, 255.0) as u8)
+}
+
+/// Stack of `count` images of the given size, cut from the same texture
+/// with a shift of 2 pixels horizontally and 1 pixel vertically between successive images.
+///
+/// Also return the motions registering each image to the first one.
+#[allow(clippy::cast_precision_loss)]
+pub fn translated_stack(
+    height: usize,
+    width: usize,
+    count: usize,
+    seed: u64,
+) -> (Vec<DMatrix<u8>>, Vec<Vector6<f32>>) {
+    let shift = |k: usize| (k, 2 * k);
+    let (max_dy, max_dx) = shift(count);
+    let base = texture(height + max_dy, width + max_dx, seed);
+    let imgs = (0..count)
+        .map(|k| {
+            let (dy, dx) = shift(k);
+            base.slice((dy, dx), (height, width)).into_owned()
+        })
+        .collect();
+    let motions = (0..count)
+        .map(|k| {
+            let (dy, dx) = shift(k);
+            Vector6::new(0.0, 0.0, 0.0, 0.0, -(dx as f32), -(dy as f32))
+        })
+        .collect();
+    (imgs, motions)
+}
@@ -0,0 +1,96 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Transfer functions of gamma-encoded images, such as sRGB.
+//!
+//! Most 8 bits images store intensities with a nonlinear encoding,
+//! which compresses bright values, and biases the intensity residuals of the registration
+//! towards dark parts of the scene.
+//! Registering linearized images gives the same importance to all intensities.
+
+use nalgebra::DMatrix;
+
+use crate::img::normalization::CanNormalize;
+
+/// Transfer function of the intensities of images, normalized in [0,1].
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Transfer {
+    /// Intensities already proportional to the light.
+    #[default]
+    Linear,
+    /// Piecewise transfer function of the sRGB standard.
+    Srgb,
+    /// Pure power law with the given exponent, such as 2.2.
+    Gamma(f32),
+}
+
+impl std::str::FromStr for Transfer {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let unknown = || {
+            format!(
+                "Unknown transfer function \"{}\", expecting linear, srgb or gamma:<exponent>",
+                s
+            )
+        };
+        match s {
+            "linear" => Ok(Transfer::Linear),
+            "srgb" => Ok(Transfer::Srgb),
+            _ => match s.strip_prefix("gamma:").map(str::parse) {
+                Some(Ok(gamma)) if gamma > 0.0 => Ok(Transfer::Gamma(gamma)),
+                _ => Err(unknown()),
+            },
+        }
+    }
+}
+
+impl Transfer {
+    /// Linear intensity of an encoded intensity.
+    pub fn decode(self, x: f32) -> f32 {
+        match self {
+            Transfer::Linear => x,
+            Transfer::Srgb if x <= 0.04045 => x / 12.92,
+            Transfer::Srgb => ((x + 0.055) / 1.055).powf(2.4),
+            Transfer::Gamma(gamma) => x.max(0.0).powf(gamma),
+        }
+    }
+
+    /// Encoded intensity of a linear intensity.
+    pub fn encode(self, x: f32) -> f32 {
+        match self {
+            Transfer::Linear => x,
+            Transfer::Srgb if x <= 0.003_130_8 => 12.92 * x,
+            Transfer::Srgb => 1.055 * x.powf(1.0 / 2.4) - 0.055,
+            Transfer::Gamma(gamma) => x.max(0.0).powf(1.0 / gamma),
+        }
+    }
+}
+
+/// Linearize encoded images.
+pub fn linearize<T: CanNormalize>(transfer: Transfer, imgs: &mut [DMatrix<T>]) {
+    if transfer != Transfer::Linear {
+        apply_lut::<T>(|x| transfer.decode(x), imgs);
+    }
+}
+
+/// Encode linear images.
+pub fn encode<T: CanNormalize>(transfer: Transfer, imgs: &mut [DMatrix<T>]) {
+    if transfer != Transfer::Linear {
+        apply_lut::<T>(|x| transfer.encode(x), imgs);
+    }
+}
+
+/// Apply a function of the normalized intensities to all pixels, with a lookup table.
+#[allow(clippy::cast_precision_loss)]
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn apply_lut<T: CanNormalize>(f: impl Fn(f32) -> f32, imgs: &mut [DMatrix<T>]) {
+    let max = (T::LEVELS - 1) as f32;
+    let lut: Vec<T> = (0..T::LEVELS)
+        .map(|level| {
+            let y = f(level as f32 / max).max(0.0).min(1.0);
+            T::from_level((y * max).round() as usize)
+        })
+        .collect();
+    for img in imgs.iter_mut() {
+        img.apply(|x| lut[x.to_level()]);
+    }
+}
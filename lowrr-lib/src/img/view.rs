@@ -0,0 +1,64 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Read-only view over the pixels of an image, with explicit strides.
+//!
+//! Images are stored column major in nalgebra matrices,
+//! but row major in decoded images and in most external buffers.
+//! A view abstracts over both layouts so that algorithms such as warping
+//! can read pixels without first transposing the whole image.
+
+use nalgebra::{DMatrix, Scalar};
+
+/// Strided view over the pixels of an image.
+#[derive(Debug, Clone, Copy)]
+pub struct ImageView<'a, T> {
+    data: &'a [T],
+    width: usize,
+    height: usize,
+    /// Distance in the buffer between two vertically consecutive pixels.
+    row_stride: usize,
+    /// Distance in the buffer between two horizontally consecutive pixels.
+    col_stride: usize,
+}
+
+impl<'a, T: Scalar + Copy> ImageView<'a, T> {
+    /// View of a column major matrix.
+    pub fn from_matrix(mat: &'a DMatrix<T>) -> Self {
+        let (height, width) = mat.shape();
+        Self {
+            data: mat.as_slice(),
+            width,
+            height,
+            row_stride: 1,
+            col_stride: height,
+        }
+    }
+
+    /// View of a row major buffer, such as the raw data of a decoded image.
+    ///
+    /// Panics if the buffer is smaller than `width * height`.
+    pub fn from_row_major(data: &'a [T], width: usize, height: usize) -> Self {
+        assert!(data.len() >= width * height, "Buffer too small");
+        Self {
+            data,
+            width,
+            height,
+            row_stride: width,
+            col_stride: 1,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Pixel at column `x` and row `y`.
+    #[inline]
+    pub fn get(&self, x: usize, y: usize) -> T {
+        self.data[y * self.row_stride + x * self.col_stride]
+    }
+}
@@ -2,7 +2,11 @@
 
 //! Helper module for visualizations.
 
-use nalgebra::{DMatrix, Scalar};
+use nalgebra::{DMatrix, Scalar, Vector6};
+
+use crate::img::interpolation::CanLinearInterpolate;
+use crate::img::multires::mean_pyramid;
+use crate::img::registration::{warp, CanRegister};
 
 /// Transform an RGB value into a single channel gray value.
 pub trait IntoGray {
@@ -71,3 +75,122 @@ pub fn mask_overlay<T: Scalar + IntoRgb8>(
         }
     })
 }
+
+/// Side of the square tiles of checkerboard composites.
+pub const CHECKERBOARD_TILE_SIZE: usize = 32;
+
+/// Checkerboard composite of two images of the same size,
+/// with alternating square tiles of each image, starting with `a` at the top left.
+/// Misaligned edges are broken at the borders of the tiles.
+pub fn checkerboard<T: Scalar + Copy>(
+    a: &DMatrix<T>,
+    b: &DMatrix<T>,
+    tile_size: usize,
+) -> DMatrix<T> {
+    assert_eq!(a.shape(), b.shape(), "Images must have the same size");
+    let tile_size = tile_size.max(1);
+    let (height, width) = a.shape();
+    DMatrix::from_fn(height, width, |i, j| {
+        if (i / tile_size + j / tile_size) % 2 == 0 {
+            a[(i, j)]
+        } else {
+            b[(i, j)]
+        }
+    })
+}
+
+/// Largest dimension of the thumbnails of a contact sheet.
+pub const CONTACT_SHEET_THUMBNAIL_SIZE: usize = 256;
+
+/// Width of the residual strip of a contact sheet, and of the gaps between thumbnails.
+const STRIP_WIDTH: usize = 16;
+const GAP: usize = 4;
+
+/// Contact sheet of a registration, with one row per image:
+/// the thumbnail of the image, its registered thumbnail,
+/// and a strip colored from blue to red with the residual of the image,
+/// relative to the highest residual of the stack, to spot bad frames quickly.
+///
+/// Thumbnails are halved until their largest dimension is at most `thumbnail_size`.
+/// The residual of an image is the mean absolute difference
+/// between its registered thumbnail and the mean of all registered thumbnails.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_precision_loss)]
+#[allow(clippy::cast_sign_loss)]
+pub fn contact_sheet<T: CanRegister>(
+    imgs: &[DMatrix<T>],
+    motion_vec: &[Vector6<f32>],
+    thumbnail_size: usize,
+) -> DMatrix<(u8, u8, u8)> {
+    let halvings = match imgs.first() {
+        None => return DMatrix::from_element(0, 0, (0, 0, 0)),
+        Some(img) => {
+            let (height, width) = img.shape();
+            let mut halvings = 0;
+            while (height.max(width) >> halvings) > thumbnail_size.max(1) {
+                halvings += 1;
+            }
+            halvings
+        }
+    };
+    let scale = (1 << halvings) as f32;
+    let thumbnails: Vec<DMatrix<T>> = imgs
+        .iter()
+        .map(|img| {
+            mean_pyramid(halvings + 1, img.clone())
+                .pop()
+                .expect("There is at least one level")
+        })
+        .collect();
+    let registered: Vec<DMatrix<T>> = thumbnails
+        .iter()
+        .zip(motion_vec)
+        .map(|(thumbnail, motion)| {
+            let mut motion = *motion;
+            motion[4] /= scale;
+            motion[5] /= scale;
+            warp::<T, f32, T>(thumbnail, &motion)
+        })
+        .collect();
+
+    // Residual of each image to the mean registered thumbnail.
+    let normalized = |x: T| {
+        let raw = <T as CanLinearInterpolate<f32, f32>>::into_vector(x);
+        <T as CanLinearInterpolate<f32, f32>>::from_vector(raw)
+    };
+    let (height, width) = registered[0].shape();
+    let mut mean = DMatrix::<f32>::zeros(height, width);
+    for img in registered.iter() {
+        mean.zip_apply(img, |m, x| m + normalized(x));
+    }
+    mean /= registered.len() as f32;
+    let residuals: Vec<f32> = registered
+        .iter()
+        .map(|img| img.zip_map(&mean, |x, m| (normalized(x) - m).abs()).mean())
+        .collect();
+    let max_residual = residuals.iter().cloned().fold(f32::EPSILON, f32::max);
+
+    // Rows of thumbnails, separated by gaps.
+    let row_height = height + GAP;
+    let sheet_width = 2 * width + STRIP_WIDTH + 2 * GAP;
+    let mut sheet = DMatrix::from_element(imgs.len() * row_height, sheet_width, (0, 0, 0));
+    for (i, ((thumbnail, registered), residual)) in
+        thumbnails.iter().zip(&registered).zip(&residuals).enumerate()
+    {
+        let top = i * row_height;
+        for x in 0..width {
+            for y in 0..height {
+                sheet[(top + y, x)] = thumbnail[(y, x)].into_rgb8();
+                sheet[(top + y, width + GAP + x)] = registered[(y, x)].into_rgb8();
+            }
+        }
+        let heat = (255.0 * residual / max_residual).round() as u8;
+        let strip_left = 2 * (width + GAP);
+        for x in strip_left..strip_left + STRIP_WIDTH {
+            for y in top..top + height {
+                sheet[(y, x)] = (heat, 0, 255 - heat);
+            }
+        }
+    }
+    sheet
+}
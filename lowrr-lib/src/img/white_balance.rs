@@ -0,0 +1,164 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! White balance normalization of RGB stacks.
+//!
+//! Cameras in auto white balance pick different channel gains for each capture,
+//! so the same scene point changes color across the stack.
+//! These changes bias the registration of gray conversions mixing channels,
+//! and leave color fringes when the registered images are stacked.
+//! Each image is corrected with per-channel gains, keeping its green channel,
+//! so that all images share the same white balance.
+
+use nalgebra::{DMatrix, Scalar, Vector3};
+use thiserror::Error;
+
+use crate::img::interpolation::CanLinearInterpolate;
+use crate::img::roi::Roi;
+
+#[derive(Error, Debug)]
+pub enum WhiteBalanceError {
+    #[error("The gray patch {patch:?} is outside of the images of size {image:?}")]
+    Patch { patch: Roi, image: (usize, usize) },
+    #[error("Channel {channel} of image {index} is black, its white balance cannot be estimated")]
+    Black { index: usize, channel: usize },
+}
+
+/// Estimation of the white balance of each image.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum WhiteBalance {
+    /// Keep the colors of the images.
+    #[default]
+    None,
+    /// Give all images the median color balance of the stack,
+    /// estimated with the median of each channel.
+    Median,
+    /// Make a gray patch (x,y,w,h), visible in all images, neutral in each image.
+    Patch(Roi),
+}
+
+impl std::str::FromStr for WhiteBalance {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(WhiteBalance::None),
+            "median" => Ok(WhiteBalance::Median),
+            _ => match s.strip_prefix("patch:") {
+                Some(patch) => patch.parse().map(WhiteBalance::Patch),
+                None => Err(format!(
+                    "Unknown white balance \"{}\", expecting none, median or patch:x,y,w,h",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// Maximum number of pixels used to compute the median of a channel.
+const MEDIAN_SAMPLES: usize = 100_000;
+
+/// Per-channel gains (red, green, blue) of each image, with a green gain of 1.
+#[allow(clippy::cast_precision_loss)]
+pub fn gains<P>(
+    method: WhiteBalance,
+    imgs: &[DMatrix<P>],
+) -> Result<Vec<Vector3<f32>>, WhiteBalanceError>
+where
+    P: Scalar + Copy + CanLinearInterpolate<Vector3<f32>, P>,
+{
+    // Color of each image, divided by its green channel.
+    let colors = imgs.iter().enumerate().map(|(index, img)| {
+        let color = match method {
+            WhiteBalance::None => return Ok(Vector3::repeat(1.0)),
+            WhiteBalance::Median => median_color(img),
+            WhiteBalance::Patch(patch) => patch_color(&patch, img)?,
+        };
+        match color.iter().position(|&c| c <= 0.0) {
+            Some(channel) => Err(WhiteBalanceError::Black { index, channel }),
+            None => Ok(color / color.y),
+        }
+    });
+    let colors: Vec<Vector3<f32>> = colors.collect::<Result<_, _>>()?;
+    let target = match method {
+        // The reference is the geometric mean of the colors of the stack.
+        WhiteBalance::Median => {
+            let log_sum = colors
+                .iter()
+                .fold(Vector3::zeros(), |acc, c| acc + c.map(f32::ln));
+            (log_sum / colors.len().max(1) as f32).map(f32::exp)
+        }
+        WhiteBalance::None | WhiteBalance::Patch(_) => Vector3::repeat(1.0),
+    };
+    Ok(colors.iter().map(|c| target.component_div(c)).collect())
+}
+
+/// Multiply the channels of images by their gains,
+/// clamping intensities like interpolated ones.
+pub fn apply<P>(gains: &[Vector3<f32>], imgs: &mut [DMatrix<P>])
+where
+    P: Scalar + Copy + CanLinearInterpolate<Vector3<f32>, P>,
+{
+    for (img, gain) in imgs.iter_mut().zip(gains) {
+        img.apply(|x| P::from_vector(x.into_vector().component_mul(gain)));
+    }
+}
+
+/// Estimate and apply the white balance of images, returning the gains of each image.
+pub fn balance<P>(
+    method: WhiteBalance,
+    imgs: &mut [DMatrix<P>],
+) -> Result<Vec<Vector3<f32>>, WhiteBalanceError>
+where
+    P: Scalar + Copy + CanLinearInterpolate<Vector3<f32>, P>,
+{
+    let gains = gains(method, imgs)?;
+    apply(&gains, imgs);
+    Ok(gains)
+}
+
+/// Median of each channel, on a regular subsample of large images.
+fn median_color<P>(img: &DMatrix<P>) -> Vector3<f32>
+where
+    P: Scalar + Copy + CanLinearInterpolate<Vector3<f32>, P>,
+{
+    let step = (img.len() / MEDIAN_SAMPLES).max(1);
+    let pixels: Vec<Vector3<f32>> = img.iter().step_by(step).map(|&x| x.into_vector()).collect();
+    Vector3::from_fn(|channel, _| {
+        let mut values: Vec<f32> = pixels.iter().map(|p| p[channel]).collect();
+        median(&mut values)
+    })
+}
+
+/// Mean color of the gray patch.
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+#[allow(clippy::cast_precision_loss)]
+fn patch_color<P>(patch: &Roi, img: &DMatrix<P>) -> Result<Vector3<f32>, WhiteBalanceError>
+where
+    P: Scalar + Copy + CanLinearInterpolate<Vector3<f32>, P>,
+{
+    let (height, width) = img.shape();
+    let left = patch.x.max(0.0) as usize;
+    let top = patch.y.max(0.0) as usize;
+    let right = ((patch.x + patch.width).max(0.0) as usize).min(width);
+    let bottom = ((patch.y + patch.height).max(0.0) as usize).min(height);
+    if left >= right || top >= bottom {
+        return Err(WhiteBalanceError::Patch {
+            patch: *patch,
+            image: (height, width),
+        });
+    }
+    let pixels = img.slice((top, left), (bottom - top, right - left));
+    let sum = pixels
+        .iter()
+        .fold(Vector3::zeros(), |acc, &x| acc + x.into_vector());
+    Ok(sum / pixels.len() as f32)
+}
+
+/// Median of values, reordering them.
+fn median(values: &mut [f32]) -> f32 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    let middle = values.len() / 2;
+    let (_, m, _) = values.select_nth_unstable_by(middle, |a, b| a.total_cmp(b));
+    *m
+}
@@ -16,11 +16,8 @@ use nalgebra::{DMatrix, Scalar};
 #[allow(clippy::cast_possible_truncation)]
 pub fn image_from_matrix<T: Scalar + Primitive>(mat: &DMatrix<T>) -> ImageBuffer<Luma<T>, Vec<T>> {
     let (nb_rows, nb_cols) = mat.shape();
-    let mut img_buf = ImageBuffer::new(nb_cols as u32, nb_rows as u32);
-    for (x, y, pixel) in img_buf.enumerate_pixels_mut() {
-        *pixel = Luma([mat[(y as usize, x as usize)]]);
-    }
-    img_buf
+    let raw = transpose_to_row_major(mat, 1, |x, out| out[0] = *x);
+    ImageBuffer::from_raw(nb_cols as u32, nb_rows as u32, raw).expect("Buffer of the right size")
 }
 
 /// Convert a `(T,T,T)` RGB matrix into an RGB image.
@@ -32,14 +29,9 @@ pub fn image_from_matrix<T: Scalar + Primitive>(mat: &DMatrix<T>) -> ImageBuffer
 pub fn rgb_from_matrix<T: Scalar + Primitive>(
     mat: &DMatrix<(T, T, T)>,
 ) -> ImageBuffer<Rgb<T>, Vec<T>> {
-    // TODO: improve the suboptimal allocation in addition to transposition.
     let (nb_rows, nb_cols) = mat.shape();
-    let mut img_buf = ImageBuffer::new(nb_cols as u32, nb_rows as u32);
-    for (x, y, pixel) in img_buf.enumerate_pixels_mut() {
-        let (r, g, b) = mat[(y as usize, x as usize)];
-        *pixel = Rgb([r, g, b]);
-    }
-    img_buf
+    let raw = transpose_to_row_major(mat, 3, |&(r, g, b), out| out.copy_from_slice(&[r, g, b]));
+    ImageBuffer::from_raw(nb_cols as u32, nb_rows as u32, raw).expect("Buffer of the right size")
 }
 
 pub trait ToImage {
@@ -70,6 +62,60 @@ impl ToImage for DMatrix<(u16, u16, u16)> {
     }
 }
 
+/// Floating point images are saved as 16 bits images.
+impl ToImage for DMatrix<f32> {
+    fn to_image(&self) -> DynamicImage {
+        DynamicImage::ImageLuma16(image_from_matrix(&coerce::<f32, u16>(self)))
+    }
+}
+
+/// Floating point images are saved as 16 bits images.
+impl ToImage for DMatrix<(f32, f32, f32)> {
+    fn to_image(&self) -> DynamicImage {
+        DynamicImage::ImageRgb16(rgb_from_matrix(&coerce::<_, (u16, u16, u16)>(self)))
+    }
+}
+
+// Supported image layouts ----------------------------------------------------
+// -----------------------------------------------------------------------------
+
+/// Convert an image of any layout into one of those supported by `IntoDMatrix`:
+/// Gray or RGB, with 8 or 16 bits channels.
+///
+/// Conversions apply in this order, like most image processing tools:
+///
+/// 1. The alpha channel is dropped, without any blending.
+/// 2. BGR images are converted to RGB.
+/// 3. RGB images with all three channels equal, such as grayscale JPEGs
+///    or paletted PNGs of gray levels, are converted to gray.
+///
+/// The bit depth is always kept.
+pub fn into_supported_layout(img: DynamicImage) -> DynamicImage {
+    let img = match img {
+        DynamicImage::ImageLumaA8(_) => DynamicImage::ImageLuma8(img.into_luma8()),
+        DynamicImage::ImageLumaA16(_) => DynamicImage::ImageLuma16(img.into_luma16()),
+        DynamicImage::ImageRgba8(_) | DynamicImage::ImageBgr8(_) | DynamicImage::ImageBgra8(_) => {
+            DynamicImage::ImageRgb8(img.into_rgb8())
+        }
+        DynamicImage::ImageRgba16(_) => DynamicImage::ImageRgb16(img.into_rgb16()),
+        _ => img,
+    };
+    match img {
+        DynamicImage::ImageRgb8(rgb) if is_gray(rgb.as_raw()) => {
+            DynamicImage::ImageLuma8(DynamicImage::ImageRgb8(rgb).into_luma8())
+        }
+        DynamicImage::ImageRgb16(rgb) if is_gray(rgb.as_raw()) => {
+            DynamicImage::ImageLuma16(DynamicImage::ImageRgb16(rgb).into_luma16())
+        }
+        _ => img,
+    }
+}
+
+/// Check if all pixels of a raw RGB buffer have equal channels.
+fn is_gray<T: PartialEq>(raw: &[T]) -> bool {
+    raw.chunks_exact(3).all(|p| p[0] == p[1] && p[1] == p[2])
+}
+
 // Convert a DMatrix into an Image ---------------------------------------------
 // -----------------------------------------------------------------------------
 
@@ -77,7 +123,7 @@ impl ToImage for DMatrix<(u16, u16, u16)> {
 /// Inverse operation of `image_from_matrix`.
 pub fn matrix_from_image<T: Scalar + Primitive>(img: ImageBuffer<Luma<T>, Vec<T>>) -> DMatrix<T> {
     let (width, height) = img.dimensions();
-    DMatrix::from_row_slice(height as usize, width as usize, &img.into_raw())
+    transpose_to_col_major(width as usize, height as usize, 1, img.as_raw(), |p| p[0])
 }
 
 /// Convert an RGB image into a `(T, T, T)` RGB matrix.
@@ -85,14 +131,71 @@ pub fn matrix_from_image<T: Scalar + Primitive>(img: ImageBuffer<Luma<T>, Vec<T>
 pub fn matrix_from_rgb_image<T: Scalar + Primitive>(
     img: ImageBuffer<Rgb<T>, Vec<T>>,
 ) -> DMatrix<(T, T, T)> {
-    // TODO: improve the suboptimal allocation in addition to transposition.
     let (width, height) = img.dimensions();
-    DMatrix::from_iterator(
-        width as usize,
-        height as usize,
-        img.as_raw().chunks_exact(3).map(|s| (s[0], s[1], s[2])),
-    )
-    .transpose()
+    transpose_to_col_major(width as usize, height as usize, 3, img.as_raw(), |p| {
+        (p[0], p[1], p[2])
+    })
+}
+
+// Transpositions --------------------------------------------------------------
+// -----------------------------------------------------------------------------
+
+/// Side of the square tiles used for transpositions.
+/// Processing tile by tile keeps both the read and written memory in cache.
+const TILE: usize = 64;
+
+/// Build a column major matrix from a row major buffer of pixels
+/// with `channels` values each, in a single pass and a single allocation.
+fn transpose_to_col_major<S: Copy, T: Scalar>(
+    width: usize,
+    height: usize,
+    channels: usize,
+    raw: &[S],
+    to_pixel: impl Fn(&[S]) -> T,
+) -> DMatrix<T> {
+    if width == 0 || height == 0 {
+        return DMatrix::from_iterator(height, width, std::iter::empty());
+    }
+    let mut data = vec![to_pixel(&raw[..channels]); width * height];
+    for y_start in (0..height).step_by(TILE) {
+        let y_end = height.min(y_start + TILE);
+        for x_start in (0..width).step_by(TILE) {
+            for x in x_start..width.min(x_start + TILE) {
+                let column = &mut data[x * height + y_start..x * height + y_end];
+                for (y, pixel) in (y_start..y_end).zip(column.iter_mut()) {
+                    let offset = (y * width + x) * channels;
+                    *pixel = to_pixel(&raw[offset..offset + channels]);
+                }
+            }
+        }
+    }
+    DMatrix::from_vec(height, width, data)
+}
+
+/// Build a row major buffer of pixels with `channels` values each from a column major matrix.
+fn transpose_to_row_major<S: Scalar + Primitive, T: Scalar>(
+    mat: &DMatrix<T>,
+    channels: usize,
+    write_pixel: impl Fn(&T, &mut [S]),
+) -> Vec<S> {
+    let (height, width) = mat.shape();
+    let data = mat.as_slice();
+    let mut raw = vec![nalgebra::zero(); width * height * channels];
+    for x_start in (0..width).step_by(TILE) {
+        let x_end = width.min(x_start + TILE);
+        for y_start in (0..height).step_by(TILE) {
+            for y in y_start..height.min(y_start + TILE) {
+                let row = &mut raw[y * width * channels..(y + 1) * width * channels];
+                for x in x_start..x_end {
+                    write_pixel(
+                        &data[x * height + y],
+                        &mut row[x * channels..(x + 1) * channels],
+                    );
+                }
+            }
+        }
+    }
+    raw
 }
 
 pub trait IntoDMatrix<P, T: Scalar> {
@@ -126,3 +229,104 @@ impl IntoDMatrix<Rgb<u16>, (u16, u16, u16)> for DynamicImage {
         matrix_from_rgb_image(self.into_rgb16())
     }
 }
+
+// Convert between pixel depths ------------------------------------------------
+// -----------------------------------------------------------------------------
+
+/// Pixel representation shared by all images of a stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Depth {
+    U8,
+    U16,
+    /// Floating point values in [0.0, 1.0].
+    F32,
+}
+
+impl std::str::FromStr for Depth {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "u8" => Ok(Depth::U8),
+            "u16" => Ok(Depth::U16),
+            "f32" => Ok(Depth::F32),
+            _ => Err(format!(
+                "Unknown pixel depth \"{}\", expecting u8, u16 or f32",
+                s
+            )),
+        }
+    }
+}
+
+/// Pixel values that can be converted to another depth,
+/// through their relative intensity in [0.0, 1.0].
+///
+/// Going from 8 to 16 bits and back is exact.
+pub trait Rescale: Scalar + Copy {
+    fn to_unit(self) -> f32;
+    fn from_unit(v: f32) -> Self;
+}
+
+impl Rescale for u8 {
+    fn to_unit(self) -> f32 {
+        f32::from(self) / 255.0
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    fn from_unit(v: f32) -> Self {
+        (v.clamp(0.0, 1.0) * 255.0).round() as u8
+    }
+}
+
+impl Rescale for u16 {
+    fn to_unit(self) -> f32 {
+        f32::from(self) / 65535.0
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    fn from_unit(v: f32) -> Self {
+        (v.clamp(0.0, 1.0) * 65535.0).round() as u16
+    }
+}
+
+impl Rescale for f32 {
+    fn to_unit(self) -> f32 {
+        self
+    }
+    fn from_unit(v: f32) -> Self {
+        v
+    }
+}
+
+/// Conversion of pixels to another depth, and from gray to RGB.
+pub trait CoerceInto<T> {
+    fn coerce_into(self) -> T;
+}
+
+impl<S: Rescale, T: Rescale> CoerceInto<T> for S {
+    fn coerce_into(self) -> T {
+        T::from_unit(self.to_unit())
+    }
+}
+
+/// Gray pixels are replicated in all channels.
+impl<S: Rescale, T: Rescale> CoerceInto<(T, T, T)> for S {
+    fn coerce_into(self) -> (T, T, T) {
+        let x = T::from_unit(self.to_unit());
+        (x, x, x)
+    }
+}
+
+impl<S: Rescale, T: Rescale> CoerceInto<(T, T, T)> for (S, S, S) {
+    fn coerce_into(self) -> (T, T, T) {
+        (
+            self.0.coerce_into(),
+            self.1.coerce_into(),
+            self.2.coerce_into(),
+        )
+    }
+}
+
+/// Convert an image to another pixel depth, or a gray image to RGB.
+pub fn coerce<S: Scalar + CoerceInto<T>, T: Scalar>(img: &DMatrix<S>) -> DMatrix<T> {
+    img.map(|x| x.coerce_into())
+}
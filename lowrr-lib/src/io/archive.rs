@@ -0,0 +1,192 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Reading of zip and tar archives of images.
+//!
+//! A whole dataset can be moved as a single file, such as to and from a browser.
+//! Archives are read in memory, and their files are returned ordered by name,
+//! without the directories and the hidden files added by some archivers.
+//! Zip entries may be stored or deflated, tar archives may use ustar, pax or GNU long names.
+
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::io::zip::{self, ZipError};
+
+#[derive(Error, Debug)]
+pub enum ArchiveError {
+    #[error("Failed to read {path} with the following error: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Invalid zip archive: {0}")]
+    Zip(#[from] ZipError),
+    #[error("Invalid tar archive: {0}")]
+    Tar(&'static str),
+    #[error("This is neither a zip nor a tar archive")]
+    UnknownFormat,
+}
+
+/// File of an archive.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// Path of the file in the archive, with slashes.
+    pub name: String,
+    pub content: Vec<u8>,
+}
+
+/// Whether a path has the extension of a supported archive, .zip or .tar.
+pub fn is_archive<P: AsRef<Path>>(path: P) -> bool {
+    let extension = path.as_ref().extension().and_then(|e| e.to_str());
+    matches!(
+        extension.map(str::to_lowercase).as_deref(),
+        Some("zip") | Some("tar")
+    )
+}
+
+/// Read the files of a zip or tar archive, ordered by name.
+pub fn read_entries<P: AsRef<Path>>(path: P) -> Result<Vec<Entry>, ArchiveError> {
+    let path = path.as_ref();
+    let bytes = std::fs::read(path).map_err(|source| ArchiveError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    entries(&bytes)
+}
+
+/// Files of a zip or tar archive in memory, ordered by name.
+/// The format is recognized from the content.
+pub fn entries(archive: &[u8]) -> Result<Vec<Entry>, ArchiveError> {
+    let mut entries = if archive.starts_with(b"PK") {
+        zip_entries(archive)?
+    } else if archive.len() >= TAR_BLOCK && tar_checksum_ok(&archive[..TAR_BLOCK]) {
+        tar_entries(archive)?
+    } else {
+        return Err(ArchiveError::UnknownFormat);
+    };
+    entries.retain(|entry| !is_hidden(&entry.name) && !entry.name.ends_with('/'));
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+/// Hidden files, and the resource forks of macOS archives.
+fn is_hidden(name: &str) -> bool {
+    name.split('/')
+        .any(|part| part.starts_with('.') || part == "__MACOSX")
+}
+
+fn zip_entries(archive: &[u8]) -> Result<Vec<Entry>, ArchiveError> {
+    zip::entries(archive)?
+        .iter()
+        .map(|entry| {
+            Ok(Entry {
+                name: entry.name.clone(),
+                content: entry.decompress()?,
+            })
+        })
+        .collect()
+}
+
+/// Size of the blocks of tar archives.
+const TAR_BLOCK: usize = 512;
+
+fn tar_entries(archive: &[u8]) -> Result<Vec<Entry>, ArchiveError> {
+    let mut entries = Vec::new();
+    // Name of the next file, from a GNU long name or a pax header.
+    let mut long_name: Option<String> = None;
+    let mut pos = 0;
+    while let Some(header) = archive.get(pos..pos + TAR_BLOCK) {
+        // The archive ends with empty blocks.
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        if !tar_checksum_ok(header) {
+            return Err(ArchiveError::Tar("invalid header checksum"));
+        }
+        let size = parse_octal(&header[124..136]).ok_or(ArchiveError::Tar("invalid size"))?;
+        let start = pos + TAR_BLOCK;
+        let content = archive
+            .get(start..start + size)
+            .ok_or(ArchiveError::Tar("unexpected end of data"))?;
+        pos = start + size.div_ceil(TAR_BLOCK) * TAR_BLOCK;
+        match header[156] {
+            // Regular files.
+            b'0' | b'\0' | b'7' => {
+                let name = match long_name.take() {
+                    Some(name) => name,
+                    None if &header[257..262] == b"ustar" && header[345] != 0 => {
+                        format!(
+                            "{}/{}",
+                            c_string(&header[345..500]),
+                            c_string(&header[..100])
+                        )
+                    }
+                    None => c_string(&header[..100]),
+                };
+                entries.push(Entry {
+                    name,
+                    content: content.to_vec(),
+                });
+            }
+            b'L' => long_name = Some(c_string(content)),
+            b'x' => long_name = pax_path(content).or(long_name),
+            // Directories, links and global headers.
+            _ => long_name = None,
+        }
+    }
+    Ok(entries)
+}
+
+/// Whether the checksum of a tar header matches its content.
+fn tar_checksum_ok(header: &[u8]) -> bool {
+    let expected = match parse_octal(&header[148..156]) {
+        Some(checksum) => checksum,
+        None => return false,
+    };
+    // The checksum is computed with its own field filled with spaces.
+    let sum: usize = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| {
+            if (148..156).contains(&i) {
+                32
+            } else {
+                usize::from(b)
+            }
+        })
+        .sum();
+    sum == expected
+}
+
+/// Octal number of a tar header field, padded with spaces or NUL bytes.
+fn parse_octal(field: &[u8]) -> Option<usize> {
+    let digits = c_string(field);
+    let digits = digits.trim();
+    if digits.is_empty() {
+        return Some(0);
+    }
+    usize::from_str_radix(digits, 8).ok()
+}
+
+/// Text of a field ending with a NUL byte.
+fn c_string(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// Path of a pax extended header, made of "length key=value\n" records.
+fn pax_path(records: &[u8]) -> Option<String> {
+    let mut rest = records;
+    let mut path = None;
+    while !rest.is_empty() {
+        let space = rest.iter().position(|&b| b == b' ')?;
+        let length: usize = std::str::from_utf8(&rest[..space]).ok()?.parse().ok()?;
+        let record = rest.get(space + 1..length)?;
+        let record = record.strip_suffix(b"\n").unwrap_or(record);
+        if let Some(value) = record.strip_prefix(b"path=") {
+            path = Some(String::from_utf8_lossy(value).into_owned());
+        }
+        rest = &rest[length..];
+    }
+    path
+}
@@ -0,0 +1,135 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Text files of registration checkpoints, to resume a registration after an interruption.
+//!
+//! A checkpoint holds the number of levels, the last completed level,
+//! the images excluded as outliers, and the motions of all images
+//! at the resolution of that level, one per line as in the motions files of lowrr:
+//!
+//! ```text
+//! # lowrr checkpoint
+//! levels 4
+//! level 2
+//! outliers 3 7
+//! 0.001, -0.002, 0.002, 0.001, 3.5, -1.25
+//! ...
+//! ```
+
+use nalgebra::Vector6;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::img::registration::Checkpoint;
+use crate::io::motions::Convention;
+
+#[derive(Error, Debug)]
+pub enum CheckpointError {
+    #[error("Failed to read {path} with the following error: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Failed to write {path} with the following error: {source}")]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Line {line} of {path} is not a valid checkpoint line")]
+    Parse { path: PathBuf, line: usize },
+    #[error("The checkpoint {0} does not contain the number of levels and the last level")]
+    Incomplete(PathBuf),
+}
+
+/// Save a checkpoint in a text file.
+///
+/// The file is written next to its destination first, then renamed,
+/// so that an interruption while saving keeps the previous checkpoint intact.
+pub fn save<P: AsRef<Path>>(path: P, checkpoint: &Checkpoint) -> Result<(), CheckpointError> {
+    let path = path.as_ref();
+    let mut lines = String::from("# lowrr checkpoint\n");
+    lines.push_str(&format!("levels {}\n", checkpoint.levels));
+    lines.push_str(&format!("level {}\n", checkpoint.level));
+    lines.push_str("outliers");
+    for i in checkpoint.outliers.iter() {
+        lines.push_str(&format!(" {}", i));
+    }
+    lines.push('\n');
+    for motion in checkpoint.motion_vec.iter() {
+        let line = Convention::Lowrr
+            .format(motion)
+            .expect("Always some in lowrr convention");
+        lines.push_str(&line);
+        lines.push('\n');
+    }
+    let write_error = |source| CheckpointError::Write {
+        path: path.to_path_buf(),
+        source,
+    };
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, lines).map_err(write_error)?;
+    std::fs::rename(&tmp_path, path).map_err(write_error)
+}
+
+/// Load a checkpoint saved with [save].
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Checkpoint, CheckpointError> {
+    let path = path.as_ref();
+    let content = std::fs::read_to_string(path).map_err(|source| CheckpointError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let (mut levels, mut level) = (None, None);
+    let mut outliers = Vec::new();
+    let mut motion_vec = Vec::new();
+    for (line_index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parse_error = || CheckpointError::Parse {
+            path: path.to_path_buf(),
+            line: line_index + 1,
+        };
+        let mut words = line.split(|c: char| c == ',' || c.is_whitespace());
+        let first = words.next().unwrap_or_default();
+        let mut numbers = words.filter(|s| !s.is_empty());
+        match first {
+            "levels" => levels = Some(parse_count(&mut numbers).ok_or_else(parse_error)?),
+            "level" => level = Some(parse_count(&mut numbers).ok_or_else(parse_error)?),
+            "outliers" => {
+                outliers = numbers
+                    .map(|s| s.parse())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| parse_error())?;
+            }
+            _ => {
+                let values: Vec<f32> = std::iter::once(first)
+                    .chain(numbers)
+                    .map(|s| s.parse())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| parse_error())?;
+                match *values.as_slice() {
+                    [a, b, c, d, e, f] => motion_vec.push(Vector6::new(a, b, c, d, e, f)),
+                    _ => return Err(parse_error()),
+                }
+            }
+        }
+    }
+    match (levels, level) {
+        (Some(levels), Some(level)) => Ok(Checkpoint {
+            levels,
+            level,
+            motion_vec,
+            outliers,
+        }),
+        _ => Err(CheckpointError::Incomplete(path.to_path_buf())),
+    }
+}
+
+/// Single unsigned integer of the rest of a line.
+fn parse_count<'a, I: Iterator<Item = &'a str>>(words: &mut I) -> Option<usize> {
+    let count = words.next()?.parse().ok()?;
+    match words.next() {
+        None => Some(count),
+        Some(_) => None,
+    }
+}
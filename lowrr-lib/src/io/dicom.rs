@@ -0,0 +1,370 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Minimal reader of DICOM files, to load series of gray slices.
+//!
+//! Only the subset of the standard needed for motion correction of a series is supported:
+//! uncompressed little endian transfer syntaxes (implicit and explicit VR),
+//! one sample per pixel, and 8 or 16 bits allocated per pixel.
+//! Multi-frame files are supported and expanded into one matrix per frame.
+
+use nalgebra::DMatrix;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum DicomError {
+    #[error("Failed to read {path} with the following error: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Failed to parse DICOM file {path}: {source}")]
+    Parse { path: PathBuf, source: ParseError },
+    #[error("Slices of the series do not all have the same size")]
+    SizeMismatch,
+    #[error("The series does not contain any frame")]
+    EmptySeries,
+}
+
+#[derive(Error, Debug)]
+pub enum ParseError {
+    #[error("Missing the \"DICM\" prefix, this is not a DICOM file")]
+    NotDicom,
+    #[error("Unexpected end of data")]
+    Truncated,
+    #[error("Unsupported transfer syntax {0} (only uncompressed little endian is supported)")]
+    UnsupportedTransferSyntax(String),
+    #[error("Missing required attribute {0}")]
+    MissingAttribute(&'static str),
+    #[error("Invalid value for attribute {0}")]
+    InvalidValue(&'static str),
+    #[error("Unsupported pixel format: {0}")]
+    UnsupportedPixels(String),
+    #[error("PixelData has {actual} bytes, but the frames need {expected}")]
+    ShortPixelData { expected: usize, actual: usize },
+}
+
+/// One DICOM file of a series, potentially containing multiple frames.
+#[derive(Debug, Clone)]
+pub struct Slice {
+    /// Instance number (0020,0013) used to order slices of a series.
+    pub instance_number: Option<i64>,
+    /// Frames with the rescale slope and intercept applied.
+    pub frames: Vec<DMatrix<u16>>,
+}
+
+impl Slice {
+    /// Parse a DICOM file already loaded in memory.
+    ///
+    /// Values are mapped with the rescale slope (0028,1053) and intercept (0028,1052)
+    /// and then clamped into the u16 range.
+    /// For example, CT Hounsfield units below 0 are clamped to 0.
+    pub fn from_bytes(data: &[u8]) -> Result<Slice, ParseError> {
+        let attributes = parse_attributes(data)?;
+        attributes.into_slice()
+    }
+}
+
+/// Read one DICOM file.
+pub fn read_slice<P: AsRef<Path>>(path: P) -> Result<Slice, DicomError> {
+    let path = path.as_ref();
+    let data = std::fs::read(path).map_err(|source| DicomError::Read {
+        path: PathBuf::from(path),
+        source,
+    })?;
+    Slice::from_bytes(&data).map_err(|source| DicomError::Parse {
+        path: PathBuf::from(path),
+        source,
+    })
+}
+
+/// Read a series of DICOM files, ordered by instance number.
+///
+/// Files without instance number keep their relative position in the given paths.
+/// Multi-frame files are expanded into consecutive frames.
+/// A series without any frame is an error.
+pub fn read_series<P: AsRef<Path>>(paths: &[P]) -> Result<Vec<DMatrix<u16>>, DicomError> {
+    let pb = crate::verbosity::progress_bar(paths.len() as u64);
+    let mut slices = Vec::with_capacity(paths.len());
+    for path in paths.iter() {
+        slices.push(read_slice(path)?);
+        pb.inc(1);
+    }
+    pb.finish();
+    slices.sort_by_key(|s| s.instance_number.unwrap_or(i64::MIN));
+    let frames: Vec<DMatrix<u16>> = slices.into_iter().flat_map(|s| s.frames).collect();
+    let first = frames.first().ok_or(DicomError::EmptySeries)?;
+    if frames.iter().any(|f| f.shape() != first.shape()) {
+        return Err(DicomError::SizeMismatch);
+    }
+    Ok(frames)
+}
+
+// Parsing of the data elements ################################################
+
+const IMPLICIT_VR_LE: &str = "1.2.840.10008.1.2";
+const EXPLICIT_VR_LE: &str = "1.2.840.10008.1.2.1";
+
+/// Tag of a data element: (group, element).
+type Tag = (u16, u16);
+
+const ITEM: Tag = (0xFFFE, 0xE000);
+const ITEM_DELIMITATION: Tag = (0xFFFE, 0xE00D);
+const SEQUENCE_DELIMITATION: Tag = (0xFFFE, 0xE0DD);
+const UNDEFINED_LENGTH: u32 = 0xFFFF_FFFF;
+
+/// Attributes of the dataset that are needed to decode the pixels.
+#[derive(Default)]
+struct Attributes<'a> {
+    samples_per_pixel: Option<u16>,
+    rows: Option<u16>,
+    columns: Option<u16>,
+    bits_allocated: Option<u16>,
+    pixel_representation: Option<u16>,
+    number_of_frames: Option<usize>,
+    rescale_slope: Option<f64>,
+    rescale_intercept: Option<f64>,
+    instance_number: Option<i64>,
+    pixel_data: Option<&'a [u8]>,
+}
+
+/// Cursor over the bytes of a DICOM file.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    explicit_vr: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], ParseError> {
+        let end = self.pos.checked_add(n).ok_or(ParseError::Truncated)?;
+        let bytes = self.data.get(self.pos..end).ok_or(ParseError::Truncated)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, ParseError> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, ParseError> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn peek_group(&self) -> Option<u16> {
+        let b = self.data.get(self.pos..self.pos + 2)?;
+        Some(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn is_done(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    /// Read the next data element.
+    /// Returns its tag and its value, which is `None` for elements of undefined length.
+    /// Those (sequences and items) are skipped entirely.
+    fn element(&mut self) -> Result<(Tag, Option<&'a [u8]>), ParseError> {
+        let tag = (self.u16()?, self.u16()?);
+        let length = if tag.0 == 0xFFFE {
+            // Items and delimiters never have a VR.
+            self.u32()?
+        } else if self.explicit_vr {
+            let vr = self.bytes(2)?;
+            match vr {
+                b"OB" | b"OD" | b"OF" | b"OL" | b"OV" | b"OW" | b"SQ" | b"SV" | b"UC" | b"UN"
+                | b"UR" | b"UT" | b"UV" => {
+                    self.bytes(2)?;
+                    self.u32()?
+                }
+                _ => self.u16()? as u32,
+            }
+        } else {
+            self.u32()?
+        };
+        if length == UNDEFINED_LENGTH {
+            let delimiter = if tag == ITEM {
+                ITEM_DELIMITATION
+            } else {
+                SEQUENCE_DELIMITATION
+            };
+            self.skip_until(tag, delimiter)?;
+            Ok((tag, None))
+        } else {
+            Ok((tag, Some(self.bytes(length as usize)?)))
+        }
+    }
+
+    /// Skip all elements until the given delimiter.
+    fn skip_until(&mut self, tag: Tag, delimiter: Tag) -> Result<(), ParseError> {
+        if tag == (0x7FE0, 0x0010) {
+            return Err(ParseError::UnsupportedPixels(
+                "encapsulated (compressed) pixel data".to_string(),
+            ));
+        }
+        loop {
+            let (inner_tag, _) = self.element()?;
+            if inner_tag == delimiter {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Parse the file meta information and the attributes of the dataset.
+fn parse_attributes(data: &[u8]) -> Result<Attributes<'_>, ParseError> {
+    if data.get(128..132) != Some(b"DICM") {
+        return Err(ParseError::NotDicom);
+    }
+    let mut reader = Reader {
+        data,
+        pos: 132,
+        explicit_vr: true,
+    };
+
+    // The file meta information (group 0002) is always explicit VR little endian.
+    let mut transfer_syntax = None;
+    while reader.peek_group() == Some(0x0002) {
+        if let ((0x0002, 0x0010), Some(value)) = reader.element()? {
+            transfer_syntax = Some(string_value(value));
+        }
+    }
+    let transfer_syntax =
+        transfer_syntax.ok_or(ParseError::MissingAttribute("TransferSyntaxUID"))?;
+    reader.explicit_vr = match transfer_syntax.as_str() {
+        IMPLICIT_VR_LE => false,
+        EXPLICIT_VR_LE => true,
+        _ => return Err(ParseError::UnsupportedTransferSyntax(transfer_syntax)),
+    };
+
+    // Dataset.
+    let mut attr = Attributes::default();
+    while !reader.is_done() {
+        let (tag, value) = reader.element()?;
+        let value = match value {
+            None => continue,
+            Some(v) => v,
+        };
+        match tag {
+            (0x0020, 0x0013) => attr.instance_number = parse_number(value),
+            (0x0028, 0x0002) => attr.samples_per_pixel = Some(us_value(value, "SamplesPerPixel")?),
+            (0x0028, 0x0008) => attr.number_of_frames = parse_number(value),
+            (0x0028, 0x0010) => attr.rows = Some(us_value(value, "Rows")?),
+            (0x0028, 0x0011) => attr.columns = Some(us_value(value, "Columns")?),
+            (0x0028, 0x0100) => attr.bits_allocated = Some(us_value(value, "BitsAllocated")?),
+            (0x0028, 0x0103) => {
+                attr.pixel_representation = Some(us_value(value, "PixelRepresentation")?)
+            }
+            (0x0028, 0x1052) => attr.rescale_intercept = parse_number(value),
+            (0x0028, 0x1053) => attr.rescale_slope = parse_number(value),
+            (0x7FE0, 0x0010) => {
+                attr.pixel_data = Some(value);
+                break;
+            }
+            _ => {}
+        }
+    }
+    Ok(attr)
+}
+
+impl<'a> Attributes<'a> {
+    /// Decode the pixel data into frames.
+    fn into_slice(self) -> Result<Slice, ParseError> {
+        if self.samples_per_pixel.unwrap_or(1) != 1 {
+            return Err(ParseError::UnsupportedPixels(format!(
+                "{} samples per pixel",
+                self.samples_per_pixel.unwrap_or(1)
+            )));
+        }
+        let rows = self.rows.ok_or(ParseError::MissingAttribute("Rows"))? as usize;
+        let columns = self
+            .columns
+            .ok_or(ParseError::MissingAttribute("Columns"))? as usize;
+        let bits = self
+            .bits_allocated
+            .ok_or(ParseError::MissingAttribute("BitsAllocated"))?;
+        let signed = self.pixel_representation.unwrap_or(0) == 1;
+        let frames_count = self.number_of_frames.unwrap_or(1);
+        let slope = self.rescale_slope.unwrap_or(1.0);
+        let intercept = self.rescale_intercept.unwrap_or(0.0);
+        let pixel_data = self
+            .pixel_data
+            .ok_or(ParseError::MissingAttribute("PixelData"))?;
+        if rows == 0 {
+            return Err(ParseError::InvalidValue("Rows"));
+        }
+        if columns == 0 {
+            return Err(ParseError::InvalidValue("Columns"));
+        }
+
+        // Check that all pixels of all frames are in the pixel data before decoding them.
+        let pixels_count = (rows * columns)
+            .checked_mul(frames_count)
+            .ok_or(ParseError::InvalidValue("NumberOfFrames"))?;
+        let bytes_per_pixel = if bits == 16 { 2 } else { 1 };
+        let expected = pixels_count
+            .checked_mul(bytes_per_pixel)
+            .ok_or(ParseError::InvalidValue("NumberOfFrames"))?;
+        if pixel_data.len() < expected {
+            return Err(ParseError::ShortPixelData {
+                expected,
+                actual: pixel_data.len(),
+            });
+        }
+        let raw: Vec<f64> = match (bits, signed) {
+            (8, false) => pixel_data.iter().map(|&p| p as f64).collect(),
+            (8, true) => pixel_data.iter().map(|&p| p as i8 as f64).collect(),
+            (16, false) => pixel_data
+                .chunks_exact(2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]]) as f64)
+                .collect(),
+            (16, true) => pixel_data
+                .chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]) as f64)
+                .collect(),
+            _ => {
+                return Err(ParseError::UnsupportedPixels(format!(
+                    "{} bits allocated",
+                    bits
+                )))
+            }
+        };
+
+        // Apply the rescale and build one row major matrix per frame.
+        let rescale = |x: &f64| (x * slope + intercept).round().clamp(0.0, u16::MAX as f64) as u16;
+        let frames = raw[..pixels_count]
+            .chunks_exact(rows * columns)
+            .map(|frame| {
+                let frame: Vec<u16> = frame.iter().map(rescale).collect();
+                DMatrix::from_row_slice(rows, columns, &frame)
+            })
+            .collect();
+        Ok(Slice {
+            instance_number: self.instance_number,
+            frames,
+        })
+    }
+}
+
+/// Value of an attribute with a text VR (UI, IS, DS, ...),
+/// trimmed of its padding spaces and null characters.
+fn string_value(value: &[u8]) -> String {
+    String::from_utf8_lossy(value)
+        .trim_matches(|c: char| c == '\0' || c.is_whitespace())
+        .to_string()
+}
+
+/// Parse the first value of a multi-valued IS or DS attribute.
+fn parse_number<T: std::str::FromStr>(value: &[u8]) -> Option<T> {
+    let s = string_value(value);
+    s.split('\\').next()?.trim().parse().ok()
+}
+
+/// Value of an US (unsigned short) attribute.
+fn us_value(value: &[u8], name: &'static str) -> Result<u16, ParseError> {
+    match value {
+        [a, b, ..] => Ok(u16::from_le_bytes([*a, *b])),
+        _ => Err(ParseError::InvalidValue(name)),
+    }
+}
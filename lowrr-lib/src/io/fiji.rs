@@ -0,0 +1,296 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Exchange of motions with the ImageJ / Fiji plugins StackReg, MultiStackReg and TrackMate.
+//!
+//! - MultiStackReg transformation files hold, for each slice of a stack,
+//!   landmarks of the aligned frame (target points) and their positions
+//!   in the slice (source points), as used by TurboReg.
+//!   Slices are numbered from 1, and the first one is the reference of lowrr.
+//! - TrackMate spots are positions of tracked objects in each frame.
+//!   lowrr exports the track of the image center (see [save_trackmate]),
+//!   and reads the tracks of a TrackMate CSV export as translations (see [load_trackmate]).
+
+use nalgebra::{Matrix3, Vector3, Vector6};
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::affine2d::{projection_mat, projection_params};
+
+#[derive(Error, Debug)]
+pub enum FijiError {
+    #[error("Failed to read {path} with the following error: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Failed to write {path} with the following error: {source}")]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Line {line} of {path} is not a valid line of a {format} file")]
+    Parse {
+        path: PathBuf,
+        line: usize,
+        format: &'static str,
+    },
+    #[error("The landmarks of slice {0} do not define a transformation")]
+    Degenerate(usize),
+    #[error("{path} has no {column} column")]
+    MissingColumn { path: PathBuf, column: &'static str },
+    #[error("Frame {0} has no spot of a track starting in the first frame")]
+    MissingFrame(usize),
+}
+
+/// First line of MultiStackReg transformation files.
+pub const MULTISTACKREG_HEADER: &str = "MultiStackReg Transformation File";
+
+/// Save motions in a MultiStackReg transformation file,
+/// for images of the given (height, width).
+/// Transformations are affine, with the landmarks of TurboReg.
+pub fn save_multistackreg<P: AsRef<Path>>(
+    path: P,
+    motion_vec: &[Vector6<f32>],
+    shape: (usize, usize),
+) -> Result<(), FijiError> {
+    let targets = affine_landmarks(shape);
+    let mut file = format!("{}\nFile Version 1.0\n0\n", MULTISTACKREG_HEADER);
+    for (i, motion) in motion_vec.iter().enumerate().skip(1) {
+        let mat = projection_mat(motion);
+        let _ = writeln!(file, "AFFINE\nSource img: {} Target img: 1", i + 1);
+        for &(x, y) in targets.iter() {
+            let p = mat * Vector3::new(x, y, 1.0);
+            let _ = writeln!(file, "{}\t{}", p.x, p.y);
+        }
+        file.push('\n');
+        for &(x, y) in targets.iter() {
+            let _ = writeln!(file, "{}\t{}", x, y);
+        }
+        file.push('\n');
+    }
+    std::fs::write(path.as_ref(), file).map_err(|source| FijiError::Write {
+        path: path.as_ref().to_path_buf(),
+        source,
+    })
+}
+
+/// Load the motions of `count` slices from a MultiStackReg transformation file.
+///
+/// Translation, rigid body, scaled rotation and affine transformations are supported,
+/// with one, three, two and three landmarks.
+/// Slices without transformation, such as the target slice, keep the identity.
+pub fn load_multistackreg<P: AsRef<Path>>(
+    path: P,
+    count: usize,
+) -> Result<Vec<Vector6<f32>>, FijiError> {
+    let path = path.as_ref();
+    let content = read(path)?;
+    let parse_error = |line: usize| FijiError::Parse {
+        path: path.to_path_buf(),
+        line: line + 1,
+        format: "MultiStackReg",
+    };
+    let mut motion_vec = vec![Vector6::zeros(); count];
+    let lines: Vec<&str> = content.lines().map(str::trim).collect();
+    let mut index = 0;
+    while index < lines.len() {
+        let landmarks = match lines[index] {
+            "TRANSLATION" => 1,
+            "SCALED_ROTATION" => 2,
+            "RIGID_BODY" | "AFFINE" => 3,
+            _ => {
+                index += 1;
+                continue;
+            }
+        };
+        // Source img: s Target img: t
+        let words: Vec<&str> = lines
+            .get(index + 1)
+            .unwrap_or(&"")
+            .split_whitespace()
+            .collect();
+        let slice: usize = match words.as_slice() {
+            ["Source", "img:", s, "Target", "img:", _] => {
+                s.parse().map_err(|_| parse_error(index + 1))?
+            }
+            _ => return Err(parse_error(index + 1)),
+        };
+        // Three source points, an empty line, and three target points.
+        let point = |line: usize| -> Result<(f32, f32), FijiError> {
+            let values: Vec<f32> = lines
+                .get(line)
+                .ok_or_else(|| parse_error(line))?
+                .split_whitespace()
+                .map(|v| v.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|_| parse_error(line))?;
+            match *values.as_slice() {
+                [x, y] => Ok((x, y)),
+                _ => Err(parse_error(line)),
+            }
+        };
+        let sources: Vec<_> = (0..landmarks)
+            .map(|k| point(index + 2 + k))
+            .collect::<Result<_, _>>()?;
+        let targets: Vec<_> = (0..landmarks)
+            .map(|k| point(index + 6 + k))
+            .collect::<Result<_, _>>()?;
+        if (1..=count).contains(&slice) {
+            motion_vec[slice - 1] =
+                motion_from_landmarks(&targets, &sources).ok_or(FijiError::Degenerate(slice))?;
+        }
+        index += 9;
+    }
+    Ok(motion_vec)
+}
+
+/// Save the track of the image center in a CSV file of TrackMate spots,
+/// for images of the given (height, width).
+/// Positions are scaled by the pixel size, in the calibrated unit of the images.
+#[allow(clippy::cast_precision_loss)]
+pub fn save_trackmate<P: AsRef<Path>>(
+    path: P,
+    motion_vec: &[Vector6<f32>],
+    shape: (usize, usize),
+    pixel_size: f32,
+) -> Result<(), FijiError> {
+    let (height, width) = shape;
+    let center = Vector3::new((width as f32 - 1.0) / 2.0, (height as f32 - 1.0) / 2.0, 1.0);
+    let mut csv =
+        String::from("LABEL,ID,TRACK_ID,POSITION_X,POSITION_Y,POSITION_Z,POSITION_T,FRAME\n");
+    for (frame, motion) in motion_vec.iter().enumerate() {
+        let p = projection_mat(motion) * center * pixel_size;
+        let _ = writeln!(csv, "ID{0},{0},0,{1},{2},0,{0},{0}", frame, p.x, p.y);
+    }
+    std::fs::write(path.as_ref(), csv).map_err(|source| FijiError::Write {
+        path: path.as_ref().to_path_buf(),
+        source,
+    })
+}
+
+/// Load the translations of `count` frames from a CSV file of TrackMate spots,
+/// with positions divided by the pixel size.
+///
+/// The translation of a frame is the mean displacement, since the first frame,
+/// of the tracks with a spot in both frames.
+/// The extra header lines of TrackMate exports, with the long names and units
+/// of the columns, are skipped.
+pub fn load_trackmate<P: AsRef<Path>>(
+    path: P,
+    count: usize,
+    pixel_size: f32,
+) -> Result<Vec<Vector6<f32>>, FijiError> {
+    let path = path.as_ref();
+    let content = read(path)?;
+    let mut lines = content.lines();
+    let header: Vec<&str> = lines
+        .next()
+        .unwrap_or("")
+        .split(',')
+        .map(str::trim)
+        .collect();
+    let column = |name: &'static str| {
+        header
+            .iter()
+            .position(|&c| c == name)
+            .ok_or_else(|| FijiError::MissingColumn {
+                path: path.to_path_buf(),
+                column: name,
+            })
+    };
+    let (track_col, x_col, y_col, frame_col) = (
+        column("TRACK_ID")?,
+        column("POSITION_X")?,
+        column("POSITION_Y")?,
+        column("FRAME")?,
+    );
+    // Spots (track, frame, x, y), skipping the lines that are not spots.
+    let spots: Vec<(usize, usize, f32, f32)> = lines
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            let field = |col: usize| fields.get(col).copied().unwrap_or("");
+            Some((
+                field(track_col).parse().ok()?,
+                field(frame_col).parse().ok()?,
+                field(x_col).parse::<f32>().ok()? / pixel_size,
+                field(y_col).parse::<f32>().ok()? / pixel_size,
+            ))
+        })
+        .collect();
+    let first_position = |track: usize| {
+        spots
+            .iter()
+            .find(|s| s.0 == track && s.1 == 0)
+            .map(|s| (s.2, s.3))
+    };
+    let mut motion_vec = vec![Vector6::zeros(); count];
+    for (frame, motion) in motion_vec.iter_mut().enumerate().skip(1) {
+        let displacements: Vec<(f32, f32)> = spots
+            .iter()
+            .filter(|s| s.1 == frame)
+            .filter_map(|s| first_position(s.0).map(|(x0, y0)| (s.2 - x0, s.3 - y0)))
+            .collect();
+        if displacements.is_empty() {
+            return Err(FijiError::MissingFrame(frame));
+        }
+        let n = displacements.len() as f32;
+        motion[4] = displacements.iter().map(|d| d.0).sum::<f32>() / n;
+        motion[5] = displacements.iter().map(|d| d.1).sum::<f32>() / n;
+    }
+    Ok(motion_vec)
+}
+
+/// Landmarks of affine transformations in TurboReg, for images of the given (height, width).
+#[allow(clippy::cast_precision_loss)]
+fn affine_landmarks((height, width): (usize, usize)) -> [(f32, f32); 3] {
+    let (w, h) = (width as f32, height as f32);
+    [
+        (0.5 * w, 0.25 * h),
+        (0.25 * w, 0.75 * h),
+        (0.75 * w, 0.75 * h),
+    ]
+}
+
+/// Motion mapping each target point to its source point.
+/// One point gives a translation, two points a rotation with a scaling,
+/// and three points an affine motion.
+fn motion_from_landmarks(targets: &[(f32, f32)], sources: &[(f32, f32)]) -> Option<Vector6<f32>> {
+    let (t, s) = (targets, sources);
+    match t.len() {
+        1 => Some(Vector6::new(
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+            s[0].0 - t[0].0,
+            s[0].1 - t[0].1,
+        )),
+        2 => {
+            // Third points completing both pairs into right isosceles triangles.
+            let third = |p: &[(f32, f32)]| {
+                let (dx, dy) = (p[1].0 - p[0].0, p[1].1 - p[0].1);
+                (p[0].0 - dy, p[0].1 + dx)
+            };
+            let targets = [t[0], t[1], third(t)];
+            let sources = [s[0], s[1], third(s)];
+            motion_from_landmarks(&targets, &sources)
+        }
+        _ => {
+            let points = |p: &[(f32, f32)]| {
+                Matrix3::new(
+                    p[0].0, p[1].0, p[2].0, p[0].1, p[1].1, p[2].1, 1.0, 1.0, 1.0,
+                )
+            };
+            let mat = points(s) * points(t).try_inverse()?;
+            Some(projection_params(&mat))
+        }
+    }
+}
+
+fn read(path: &Path) -> Result<String, FijiError> {
+    std::fs::read_to_string(path).map_err(|source| FijiError::Read {
+        path: path.to_path_buf(),
+        source,
+    })
+}
@@ -0,0 +1,93 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Passthrough of the ICC color profiles of PNG images.
+//!
+//! The `image` crate ignores color profiles, so registered images would lose the profile
+//! of the captured ones, and be displayed with different colors.
+//! The `iCCP` chunk of an input PNG file is kept as is, still compressed,
+//! and inserted in the encoded PNG files of the outputs.
+
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Signature at the start of all PNG files.
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+/// Type of the chunk of the ICC profile.
+const ICC_CHUNK: [u8; 4] = *b"iCCP";
+
+#[derive(Error, Debug)]
+pub enum IccError {
+    #[error("Failed to read {path} with the following error: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("{0} is not a valid PNG file")]
+    NotPng(PathBuf),
+}
+
+/// Raw data of the `iCCP` chunk of a PNG file, None if it has no profile.
+pub fn read_png_profile<P: AsRef<Path>>(path: P) -> Result<Option<Vec<u8>>, IccError> {
+    let path = path.as_ref();
+    let bytes = std::fs::read(path).map_err(|source| IccError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let not_png = || IccError::NotPng(path.to_path_buf());
+    if !bytes.starts_with(&PNG_SIGNATURE) {
+        return Err(not_png());
+    }
+    let mut chunks = &bytes[PNG_SIGNATURE.len()..];
+    // Each chunk has a length, a type, the data and a CRC.
+    while chunks.len() >= 12 {
+        let length = u32::from_be_bytes([chunks[0], chunks[1], chunks[2], chunks[3]]) as usize;
+        let chunk_type = &chunks[4..8];
+        if chunks.len() < 12 + length {
+            return Err(not_png());
+        }
+        match chunk_type {
+            b"iCCP" => return Ok(Some(chunks[8..8 + length].to_vec())),
+            // The profile must come before the image data.
+            b"IDAT" | b"IEND" => return Ok(None),
+            _ => chunks = &chunks[12 + length..],
+        }
+    }
+    Err(not_png())
+}
+
+/// Insert an ICC profile, given as the raw data of an `iCCP` chunk,
+/// in an encoded PNG file, just after its header chunk.
+/// The file is returned unchanged if it is not a valid PNG file.
+pub fn insert_png_profile(png: Vec<u8>, profile: &[u8]) -> Vec<u8> {
+    // The header chunk has 13 bytes of data.
+    let header_end = PNG_SIGNATURE.len() + 12 + 13;
+    if !png.starts_with(&PNG_SIGNATURE) || png.len() < header_end {
+        return png;
+    }
+    let mut chunk = Vec::with_capacity(12 + profile.len());
+    #[allow(clippy::cast_possible_truncation)]
+    chunk.extend_from_slice(&(profile.len() as u32).to_be_bytes());
+    chunk.extend_from_slice(&ICC_CHUNK);
+    chunk.extend_from_slice(profile);
+    chunk.extend_from_slice(&crc32(&chunk[4..]).to_be_bytes());
+    let mut with_profile = Vec::with_capacity(png.len() + chunk.len());
+    with_profile.extend_from_slice(&png[..header_end]);
+    with_profile.extend_from_slice(&chunk);
+    with_profile.extend_from_slice(&png[header_end..]);
+    with_profile
+}
+
+/// CRC of PNG chunks, computed on their type and data.
+/// It is the same CRC-32 as the one of zip archives.
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffff_u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
@@ -0,0 +1,27 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! # Input / Output
+//!
+//! This module is a namespace for submodules reading and writing
+//! image stacks in file formats not handled by the `image` crate.
+//! Each format is optional and enabled with the cargo feature of the same name.
+//! Motions can also be exported in the text formats of other tools,
+//! and registrations checkpointed to be resumed later.
+//! Written files go through an output sink, which may not be the local filesystem.
+
+#[cfg(feature = "archive")]
+pub mod archive;
+pub mod checkpoint;
+#[cfg(feature = "dicom")]
+pub mod dicom;
+pub mod fiji;
+pub mod icc;
+pub mod motions;
+#[cfg(feature = "npy")]
+pub mod npy;
+pub mod sink;
+pub mod tie_points;
+#[cfg(feature = "tiff")]
+pub mod tiff;
+#[cfg(any(feature = "archive", feature = "npy"))]
+mod zip;
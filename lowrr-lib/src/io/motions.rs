@@ -0,0 +1,166 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Text files of motions, in the conventions of lowrr, OpenCV or Matlab.
+//!
+//! Each line holds the 6 parameters of the motion of one image,
+//! separated by commas or spaces. Empty lines and lines starting with `#` are ignored.
+//!
+//! - lowrr: the motion vector, mapping the registered frame into the image,
+//!   as printed by the command line program.
+//! - opencv: the 2x3 matrix of `cv::warpAffine` in row-major order,
+//!   mapping the image into the registered frame
+//!   (`np.loadtxt(path, delimiter=",").reshape(-1, 2, 3)` in Python).
+//! - matlab: the first two columns of the matrix `T` of `affine2d` in row-major order,
+//!   for `imwarp` with coordinates starting at 1
+//!   (`T = [reshape(line, 2, 3)', [0; 0; 1]]` in Matlab).
+
+use nalgebra::{Matrix2x3, Matrix3, Vector6};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::affine2d;
+
+#[derive(Error, Debug)]
+pub enum MotionsError {
+    #[error("Failed to read {path} with the following error: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Failed to write {path} with the following error: {source}")]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Line {line} of {path} does not contain 6 numbers")]
+    Parse { path: PathBuf, line: usize },
+    #[error("The motion of image {0} is not invertible")]
+    NotInvertible(usize),
+}
+
+/// Convention of the parameters of motions files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Convention {
+    Lowrr,
+    OpenCv,
+    Matlab,
+}
+
+impl std::str::FromStr for Convention {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lowrr" => Ok(Convention::Lowrr),
+            "opencv" => Ok(Convention::OpenCv),
+            "matlab" => Ok(Convention::Matlab),
+            _ => Err(format!(
+                "Unknown motions convention \"{}\", expecting lowrr, opencv or matlab",
+                s
+            )),
+        }
+    }
+}
+
+impl Convention {
+    /// Parameters of a motion in this convention, None if it is not invertible.
+    pub fn params(self, motion: &Vector6<f32>) -> Option<[f32; 6]> {
+        let m = |i: usize| motion[i];
+        match self {
+            Convention::Lowrr => Some([m(0), m(1), m(2), m(3), m(4), m(5)]),
+            Convention::OpenCv => {
+                let cv = affine2d::to_opencv(motion)?;
+                Some([cv.m11, cv.m12, cv.m13, cv.m21, cv.m22, cv.m23])
+            }
+            Convention::Matlab => {
+                let t = affine2d::to_matlab(motion)?;
+                Some([t.m11, t.m12, t.m21, t.m22, t.m31, t.m32])
+            }
+        }
+    }
+
+    /// Motion from its parameters in this convention, None if it is not invertible.
+    pub fn motion(self, p: &[f32; 6]) -> Option<Vector6<f32>> {
+        match self {
+            Convention::Lowrr => Some(Vector6::new(p[0], p[1], p[2], p[3], p[4], p[5])),
+            Convention::OpenCv => {
+                affine2d::from_opencv(&Matrix2x3::new(p[0], p[1], p[2], p[3], p[4], p[5]))
+            }
+            Convention::Matlab => affine2d::from_matlab(&Matrix3::new(
+                p[0], p[1], 0.0, p[2], p[3], 0.0, p[4], p[5], 1.0,
+            )),
+        }
+    }
+
+    /// Line of a motions file, without the line break.
+    pub fn format(self, motion: &Vector6<f32>) -> Option<String> {
+        let p = self.params(motion)?;
+        Some(format!(
+            "{}, {}, {}, {}, {}, {}",
+            p[0], p[1], p[2], p[3], p[4], p[5]
+        ))
+    }
+}
+
+/// Save motions in a text file, one line per image.
+pub fn save<P: AsRef<Path>>(
+    path: P,
+    convention: Convention,
+    motions: &[Vector6<f32>],
+) -> Result<(), MotionsError> {
+    let lines = to_text(convention, motions)?;
+    std::fs::write(path.as_ref(), lines).map_err(|source| MotionsError::Write {
+        path: path.as_ref().to_path_buf(),
+        source,
+    })
+}
+
+/// Content of the text file of [save], one line per image.
+pub fn to_text(convention: Convention, motions: &[Vector6<f32>]) -> Result<String, MotionsError> {
+    let mut lines = String::new();
+    for (i, motion) in motions.iter().enumerate() {
+        let line = convention
+            .format(motion)
+            .ok_or(MotionsError::NotInvertible(i))?;
+        lines.push_str(&line);
+        lines.push('\n');
+    }
+    Ok(lines)
+}
+
+/// Load the motions of a text file, one line per image.
+pub fn load<P: AsRef<Path>>(
+    path: P,
+    convention: Convention,
+) -> Result<Vec<Vector6<f32>>, MotionsError> {
+    let path = path.as_ref();
+    let content = std::fs::read_to_string(path).map_err(|source| MotionsError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let mut motions = Vec::new();
+    for (line_index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parse_error = || MotionsError::Parse {
+            path: path.to_path_buf(),
+            line: line_index + 1,
+        };
+        let values: Vec<f32> = line
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| parse_error())?;
+        let params = match *values.as_slice() {
+            [a, b, c, d, e, f] => [a, b, c, d, e, f],
+            _ => return Err(parse_error()),
+        };
+        let motion = convention
+            .motion(&params)
+            .ok_or(MotionsError::NotInvertible(motions.len()))?;
+        motions.push(motion);
+    }
+    Ok(motions)
+}
@@ -0,0 +1,379 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Reading and writing of NumPy `.npy` and `.npz` arrays.
+//!
+//! This makes it possible to exchange image stacks and motion vectors
+//! with Python code without going through lossy image files.
+//! Stacks are 3D arrays of unsigned integers, with either the layout (N,H,W)
+//! or (H,W,N) where N is the number of images.
+//! Only the first array of an `.npz` archive is read.
+
+use nalgebra::{DMatrix, Scalar, Vector6};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use thiserror::Error;
+
+use super::zip;
+
+const MAGIC: &[u8] = b"\x93NUMPY";
+
+#[derive(Error, Debug)]
+pub enum NpyError {
+    #[error("Failed to read {path} with the following error: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Failed to write {path} with the following error: {source}")]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Failed to read the archive {path} with the following error: {source}")]
+    Zip {
+        path: PathBuf,
+        source: zip::ZipError,
+    },
+    #[error("The archive {0} does not contain any .npy array")]
+    EmptyArchive(PathBuf),
+    #[error("Invalid npy data in {path}: {reason}")]
+    Parse { path: PathBuf, reason: String },
+    #[error("Unsupported dtype {descr} in {path}, expecting uint8 or uint16")]
+    UnsupportedDtype { path: PathBuf, descr: String },
+    #[error("Array in {path} has shape {shape:?} but a 3D image stack is expected")]
+    Shape { path: PathBuf, shape: Vec<usize> },
+    #[error("Images to save do not all have the same dimensions")]
+    DimensionsMismatch,
+}
+
+/// Position of the images axis in a 3D array stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// Shape (N,H,W), images are the first axis.
+    Nhw,
+    /// Shape (H,W,N), images are the last axis.
+    Hwn,
+}
+
+impl FromStr for Layout {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "nhw" => Ok(Layout::Nhw),
+            "hwn" => Ok(Layout::Hwn),
+            _ => Err(format!("Unknown layout \"{}\", expecting nhw or hwn", s)),
+        }
+    }
+}
+
+/// Image stack loaded from a npy array.
+pub enum NpyStack {
+    U8(Vec<DMatrix<u8>>),
+    U16(Vec<DMatrix<u16>>),
+}
+
+/// Load a 3D array of a `.npy` or `.npz` file as a stack of images.
+pub fn read_stack<P: AsRef<Path>>(path: P, layout: Layout) -> Result<NpyStack, NpyError> {
+    let path = path.as_ref();
+    let bytes = std::fs::read(path).map_err(|source| NpyError::Read {
+        path: PathBuf::from(path),
+        source,
+    })?;
+    let is_npz = path
+        .extension()
+        .map(|e| e.eq_ignore_ascii_case("npz"))
+        .unwrap_or(false);
+    if is_npz {
+        let zip_err = |source| NpyError::Zip {
+            path: PathBuf::from(path),
+            source,
+        };
+        let entries = zip::entries(&bytes).map_err(zip_err)?;
+        let entry = entries
+            .iter()
+            .find(|e| e.name.ends_with(".npy"))
+            .ok_or_else(|| NpyError::EmptyArchive(PathBuf::from(path)))?;
+        log::debug!("Reading array {} of {}", entry.name, path.display());
+        let npy = entry.decompress().map_err(zip_err)?;
+        parse_stack(path, &npy, layout)
+    } else {
+        parse_stack(path, &bytes, layout)
+    }
+}
+
+/// Save a stack of images as a `.npy` array of shape (N,H,W),
+/// or (N,H,W,3) for RGB images.
+pub fn save_stack<P: AsRef<Path>, T: NpyElement>(
+    path: P,
+    imgs: &[DMatrix<T>],
+) -> Result<(), NpyError> {
+    let (height, width) = imgs.first().map(|im| im.shape()).unwrap_or((0, 0));
+    if imgs.iter().any(|im| im.shape() != (height, width)) {
+        return Err(NpyError::DimensionsMismatch);
+    }
+    let mut shape = vec![imgs.len(), height, width];
+    if T::CHANNELS > 1 {
+        shape.push(T::CHANNELS);
+    }
+    let mut data = Vec::with_capacity(imgs.len() * height * width * T::CHANNELS * 2);
+    for img in imgs.iter() {
+        // npy data is in row-major order while DMatrix is column-major.
+        for row in img.row_iter() {
+            row.iter().for_each(|x| x.write_le(&mut data));
+        }
+    }
+    write_npy(path.as_ref(), T::DESCR, &shape, &data)
+}
+
+/// Save motion vectors as a `.npy` array of shape (N,6) of float32.
+pub fn save_motions<P: AsRef<Path>>(path: P, motions: &[Vector6<f32>]) -> Result<(), NpyError> {
+    let mut data = Vec::with_capacity(motions.len() * 6 * 4);
+    for x in motions.iter().flat_map(|m| m.iter()) {
+        data.extend_from_slice(&x.to_le_bytes());
+    }
+    write_npy(path.as_ref(), "<f4", &[motions.len(), 6], &data)
+}
+
+/// Pixel types that can be saved in a npy array.
+pub trait NpyElement: Scalar {
+    /// NumPy dtype description, such as "<u2".
+    const DESCR: &'static str;
+    /// Number of channels, stored as the last axis of the array.
+    const CHANNELS: usize;
+    /// Append the little endian bytes of all channels.
+    fn write_le(&self, out: &mut Vec<u8>);
+}
+
+impl NpyElement for u8 {
+    const DESCR: &'static str = "|u1";
+    const CHANNELS: usize = 1;
+    fn write_le(&self, out: &mut Vec<u8>) {
+        out.push(*self);
+    }
+}
+
+impl NpyElement for u16 {
+    const DESCR: &'static str = "<u2";
+    const CHANNELS: usize = 1;
+    fn write_le(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl NpyElement for (u8, u8, u8) {
+    const DESCR: &'static str = "|u1";
+    const CHANNELS: usize = 3;
+    fn write_le(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&[self.0, self.1, self.2]);
+    }
+}
+
+impl NpyElement for (u16, u16, u16) {
+    const DESCR: &'static str = "<u2";
+    const CHANNELS: usize = 3;
+    fn write_le(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.0.to_le_bytes());
+        out.extend_from_slice(&self.1.to_le_bytes());
+        out.extend_from_slice(&self.2.to_le_bytes());
+    }
+}
+
+impl NpyElement for f32 {
+    const DESCR: &'static str = "<f4";
+    const CHANNELS: usize = 1;
+    fn write_le(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl NpyElement for (f32, f32, f32) {
+    const DESCR: &'static str = "<f4";
+    const CHANNELS: usize = 3;
+    fn write_le(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.0.to_le_bytes());
+        out.extend_from_slice(&self.1.to_le_bytes());
+        out.extend_from_slice(&self.2.to_le_bytes());
+    }
+}
+
+// Reading ###########################################################
+
+/// Header of a npy array.
+struct Header {
+    descr: String,
+    fortran_order: bool,
+    shape: Vec<usize>,
+}
+
+/// Parse npy bytes into a stack of images.
+fn parse_stack(path: &Path, bytes: &[u8], layout: Layout) -> Result<NpyStack, NpyError> {
+    let parse_err = |reason: &str| NpyError::Parse {
+        path: PathBuf::from(path),
+        reason: reason.to_string(),
+    };
+    let (header, data) = parse_header(bytes).map_err(parse_err)?;
+    if header.shape.len() != 3 {
+        return Err(NpyError::Shape {
+            path: PathBuf::from(path),
+            shape: header.shape,
+        });
+    }
+    let (n, height, width) = match layout {
+        Layout::Nhw => (header.shape[0], header.shape[1], header.shape[2]),
+        Layout::Hwn => (header.shape[2], header.shape[0], header.shape[1]),
+    };
+    let elem_size = match header.descr.as_str() {
+        "|u1" | "<u1" | ">u1" => 1,
+        "<u2" | ">u2" => 2,
+        _ => {
+            return Err(NpyError::UnsupportedDtype {
+                path: PathBuf::from(path),
+                descr: header.descr,
+            })
+        }
+    };
+    let count: usize = header.shape.iter().product();
+    if data.len() < count * elem_size {
+        return Err(parse_err("data is shorter than the array shape"));
+    }
+
+    // Strides (in elements) of the three axes, in the order of the shape.
+    let s = &header.shape;
+    let strides = if header.fortran_order {
+        [1, s[0], s[0] * s[1]]
+    } else {
+        [s[1] * s[2], s[2], 1]
+    };
+    let [stride_n, stride_y, stride_x] = match layout {
+        Layout::Nhw => strides,
+        Layout::Hwn => [strides[2], strides[0], strides[1]],
+    };
+    let offset = |i, y, x| i * stride_n + y * stride_y + x * stride_x;
+    log::info!(
+        "Loading {} images of {}x{} from {}",
+        n,
+        width,
+        height,
+        path.display()
+    );
+    match elem_size {
+        1 => Ok(NpyStack::U8(
+            (0..n)
+                .map(|i| DMatrix::from_fn(height, width, |y, x| data[offset(i, y, x)]))
+                .collect(),
+        )),
+        _ => {
+            let big_endian = header.descr.starts_with('>');
+            let read_u16 = |k: usize| {
+                let b = [data[2 * k], data[2 * k + 1]];
+                if big_endian {
+                    u16::from_be_bytes(b)
+                } else {
+                    u16::from_le_bytes(b)
+                }
+            };
+            Ok(NpyStack::U16(
+                (0..n)
+                    .map(|i| DMatrix::from_fn(height, width, |y, x| read_u16(offset(i, y, x))))
+                    .collect(),
+            ))
+        }
+    }
+}
+
+/// Parse the header of a npy file and return it with the remaining data bytes.
+fn parse_header(bytes: &[u8]) -> Result<(Header, &[u8]), &'static str> {
+    if bytes.len() < 10 || &bytes[0..6] != MAGIC {
+        return Err("missing the npy magic string");
+    }
+    let (header_len, header_start) = match bytes[6] {
+        1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+        2 | 3 => {
+            let b = bytes.get(8..12).ok_or("truncated header")?;
+            (u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize, 12)
+        }
+        _ => return Err("unsupported npy format version"),
+    };
+    let data_start = header_start + header_len;
+    let dict = bytes
+        .get(header_start..data_start)
+        .ok_or("truncated header")?;
+    let dict = std::str::from_utf8(dict).map_err(|_| "header is not valid text")?;
+
+    let descr = dict_value(dict, "descr").ok_or("missing descr in header")?;
+    let descr = descr
+        .trim_start_matches(['\'', '"'])
+        .split(['\'', '"'])
+        .next()
+        .unwrap_or("")
+        .to_string();
+    let fortran_order = dict_value(dict, "fortran_order")
+        .ok_or("missing fortran_order in header")?
+        .starts_with("True");
+    let shape = dict_value(dict, "shape").ok_or("missing shape in header")?;
+    let shape = shape
+        .strip_prefix('(')
+        .and_then(|s| s.split(')').next())
+        .ok_or("invalid shape in header")?;
+    let shape = shape
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| s.trim_end_matches('L').parse())
+        .collect::<Result<Vec<usize>, _>>()
+        .map_err(|_| "invalid shape in header")?;
+    let header = Header {
+        descr,
+        fortran_order,
+        shape,
+    };
+    Ok((header, &bytes[data_start..]))
+}
+
+/// Retrieve the text following the given key in the header dictionary.
+fn dict_value<'a>(dict: &'a str, key: &str) -> Option<&'a str> {
+    ["'", "\""].iter().find_map(|quote| {
+        let pattern = format!("{}{}{}", quote, key, quote);
+        let start = dict.find(&pattern)? + pattern.len();
+        let rest = dict[start..].trim_start().strip_prefix(':')?;
+        Some(rest.trim_start())
+    })
+}
+
+// Writing ###########################################################
+
+/// Write a C-ordered npy file (format version 1.0).
+fn write_npy(path: &Path, descr: &str, shape: &[usize], data: &[u8]) -> Result<(), NpyError> {
+    let shape_str = match shape {
+        [n] => format!("({},)", n),
+        _ => {
+            let dims: Vec<_> = shape.iter().map(|d| d.to_string()).collect();
+            format!("({})", dims.join(", "))
+        }
+    };
+    let mut header = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+        descr, shape_str
+    );
+    // The total header length must be a multiple of 64, ending with a newline.
+    let unpadded = MAGIC.len() + 4 + header.len() + 1;
+    header.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+    header.push('\n');
+
+    let write_err = |source| NpyError::Write {
+        path: PathBuf::from(path),
+        source,
+    };
+    let file = File::create(path).map_err(write_err)?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(MAGIC).map_err(write_err)?;
+    writer.write_all(&[1, 0]).map_err(write_err)?;
+    writer
+        .write_all(&(header.len() as u16).to_le_bytes())
+        .map_err(write_err)?;
+    writer.write_all(header.as_bytes()).map_err(write_err)?;
+    writer.write_all(data).map_err(write_err)?;
+    writer.flush().map_err(write_err)
+}
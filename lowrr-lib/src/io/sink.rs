@@ -0,0 +1,411 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Destinations of the written files, such as registered images.
+//!
+//! Outputs are written through an [OutputSink] instead of the filesystem,
+//! so that environments without a writable local disk (containers, lambdas)
+//! can still collect results: in memory, in a tar or zip archive,
+//! or in an S3 bucket with the `s3` cargo feature.
+//! All sinks can be shared by the threads of an [ImgWriter](crate::utils::ImgWriter).
+
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum SinkError {
+    #[error("Failed to write {path} with the following error: {source}")]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("The name of {0} is too long for a tar archive")]
+    NameTooLong(PathBuf),
+    #[error("{0} is too large for an archive without zip64 extensions")]
+    TooLarge(PathBuf),
+    #[error("The archive is already finished, {0} cannot be added")]
+    Finished(PathBuf),
+    #[error("A thread writing to the sink panicked")]
+    Poisoned,
+    #[cfg(feature = "s3")]
+    #[error("Invalid S3 location \"{0}\", expecting s3://bucket/prefix")]
+    S3Url(String),
+    #[cfg(feature = "s3")]
+    #[error("Failed to upload {key} to S3 with the following error: {message}")]
+    Upload { key: String, message: String },
+}
+
+/// Destination of written files, given by their path relative to the root of the sink.
+pub trait OutputSink: Send + Sync {
+    /// Write the whole content of a file, replacing any previous file at that path.
+    fn write(&self, path: &Path, content: &[u8]) -> Result<(), SinkError>;
+
+    /// Complete the writing, after which no file can be added.
+    /// Archives are only valid once finished.
+    fn finish(&self) -> Result<(), SinkError> {
+        Ok(())
+    }
+}
+
+/// Files written in a directory of the filesystem, creating missing directories.
+///
+/// Absolute paths are written as is, and the default root is the current directory.
+#[derive(Debug, Clone, Default)]
+pub struct DirSink {
+    root: PathBuf,
+}
+
+impl DirSink {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        DirSink { root: root.into() }
+    }
+}
+
+impl OutputSink for DirSink {
+    fn write(&self, path: &Path, content: &[u8]) -> Result<(), SinkError> {
+        let path = self.root.join(path);
+        let write_error = |source| SinkError::Write {
+            path: path.clone(),
+            source,
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(write_error)?;
+        }
+        std::fs::write(&path, content).map_err(write_error)
+    }
+}
+
+/// Files kept in memory, in the order they were written.
+#[derive(Debug, Default)]
+pub struct MemorySink {
+    files: Mutex<Vec<(PathBuf, Vec<u8>)>>,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take the files written so far, leaving the sink empty.
+    pub fn take(&self) -> Vec<(PathBuf, Vec<u8>)> {
+        match self.files.lock() {
+            Ok(mut files) => std::mem::take(&mut *files),
+            Err(poisoned) => std::mem::take(&mut *poisoned.into_inner()),
+        }
+    }
+}
+
+impl OutputSink for MemorySink {
+    fn write(&self, path: &Path, content: &[u8]) -> Result<(), SinkError> {
+        let mut files = self.files.lock().map_err(|_| SinkError::Poisoned)?;
+        files.retain(|(p, _)| p != path);
+        files.push((path.to_path_buf(), content.to_vec()));
+        Ok(())
+    }
+}
+
+/// Files appended to a tar archive (POSIX ustar format).
+///
+/// Files are streamed to the writer as they come,
+/// and the end of the archive is written by [OutputSink::finish].
+pub struct TarSink<W> {
+    /// Writer, and whether the archive is finished.
+    writer: Mutex<(W, bool)>,
+}
+
+impl<W: Write + Send> TarSink<W> {
+    pub fn new(writer: W) -> Self {
+        TarSink {
+            writer: Mutex::new((writer, false)),
+        }
+    }
+
+    /// Writer of the archive, None if a writing thread panicked.
+    pub fn into_inner(self) -> Option<W> {
+        self.writer.into_inner().ok().map(|(writer, _)| writer)
+    }
+}
+
+/// Size of the blocks of tar archives.
+const TAR_BLOCK: usize = 512;
+
+impl<W: Write + Send> OutputSink for TarSink<W> {
+    fn write(&self, path: &Path, content: &[u8]) -> Result<(), SinkError> {
+        let header = tar_header(path, content.len())?;
+        let mut guard = self.writer.lock().map_err(|_| SinkError::Poisoned)?;
+        let (writer, finished) = &mut *guard;
+        if *finished {
+            return Err(SinkError::Finished(path.to_path_buf()));
+        }
+        let padding = (TAR_BLOCK - content.len() % TAR_BLOCK) % TAR_BLOCK;
+        writer
+            .write_all(&header)
+            .and_then(|_| writer.write_all(content))
+            .and_then(|_| writer.write_all(&[0; TAR_BLOCK][..padding]))
+            .map_err(|source| SinkError::Write {
+                path: path.to_path_buf(),
+                source,
+            })
+    }
+
+    /// Write the two empty blocks ending the archive, and flush the writer.
+    fn finish(&self) -> Result<(), SinkError> {
+        let mut guard = self.writer.lock().map_err(|_| SinkError::Poisoned)?;
+        let (writer, finished) = &mut *guard;
+        if !*finished {
+            writer
+                .write_all(&[0; 2 * TAR_BLOCK])
+                .and_then(|_| writer.flush())
+                .map_err(|source| SinkError::Write {
+                    path: PathBuf::from("tar archive"),
+                    source,
+                })?;
+            *finished = true;
+        }
+        Ok(())
+    }
+}
+
+/// Header block of a regular file in a ustar archive.
+fn tar_header(path: &Path, size: usize) -> Result<[u8; TAR_BLOCK], SinkError> {
+    let name = archive_name(path);
+    let too_long = || SinkError::NameTooLong(path.to_path_buf());
+    // Long names are split in a prefix of 155 bytes and a name of 100 bytes, at a slash.
+    let (prefix, name) = if name.len() <= 100 {
+        ("", name.as_str())
+    } else {
+        let split = name.as_bytes()[..name.len().min(156)]
+            .iter()
+            .rposition(|&b| b == b'/')
+            .filter(|&i| name.len() - i - 1 <= 100)
+            .ok_or_else(too_long)?;
+        (&name[..split], &name[split + 1..])
+    };
+    if size as u64 >= 8_u64.pow(11) {
+        return Err(SinkError::TooLarge(path.to_path_buf()));
+    }
+    let mut header = [0; TAR_BLOCK];
+    let mut field = |offset: usize, value: &[u8]| {
+        header[offset..offset + value.len()].copy_from_slice(value);
+    };
+    field(0, name.as_bytes());
+    field(100, b"0000644\0"); // mode
+    field(108, b"0000000\0"); // uid
+    field(116, b"0000000\0"); // gid
+    field(124, format!("{:011o}\0", size).as_bytes());
+    field(136, b"00000000000\0"); // mtime
+    field(148, b"        "); // checksum, computed with spaces
+    field(156, b"0"); // regular file
+    field(257, b"ustar\000");
+    field(345, prefix.as_bytes());
+    let checksum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    Ok(header)
+}
+
+/// Files stored uncompressed in a zip archive.
+///
+/// Images are already compressed, so entries are only stored.
+/// Files are streamed to the writer as they come,
+/// and the central directory is written by [OutputSink::finish].
+pub struct ZipSink<W> {
+    state: Mutex<ZipState<W>>,
+}
+
+struct ZipState<W> {
+    writer: W,
+    finished: bool,
+    /// Bytes written so far, the offset of the next entry.
+    offset: u64,
+    /// Central directory records of the written entries.
+    directory: Vec<u8>,
+    entries: usize,
+}
+
+impl<W: Write + Send> ZipSink<W> {
+    pub fn new(writer: W) -> Self {
+        ZipSink {
+            state: Mutex::new(ZipState {
+                writer,
+                finished: false,
+                offset: 0,
+                directory: Vec::new(),
+                entries: 0,
+            }),
+        }
+    }
+
+    /// Writer of the archive, None if a writing thread panicked.
+    pub fn into_inner(self) -> Option<W> {
+        self.state.into_inner().ok().map(|state| state.writer)
+    }
+}
+
+/// Date of the entries, 1980-01-01 in MS-DOS format.
+const ZIP_DATE: u16 = (1 << 5) | 1;
+
+impl<W: Write + Send> OutputSink for ZipSink<W> {
+    #[allow(clippy::cast_possible_truncation)]
+    fn write(&self, path: &Path, content: &[u8]) -> Result<(), SinkError> {
+        let name = archive_name(path);
+        let mut state = self.state.lock().map_err(|_| SinkError::Poisoned)?;
+        if state.finished {
+            return Err(SinkError::Finished(path.to_path_buf()));
+        }
+        let too_large = || SinkError::TooLarge(path.to_path_buf());
+        if content.len() >= u32::MAX as usize || state.offset >= u64::from(u32::MAX) {
+            return Err(too_large());
+        }
+        if state.entries >= u16::MAX as usize {
+            return Err(too_large());
+        }
+        let crc = crate::io::icc::crc32(content).to_le_bytes();
+        let size = (content.len() as u32).to_le_bytes();
+        let name_len = (name.len() as u16).to_le_bytes();
+        let date = ZIP_DATE.to_le_bytes();
+
+        let mut local = Vec::with_capacity(30 + name.len());
+        local.extend_from_slice(&0x0403_4b50_u32.to_le_bytes());
+        local.extend_from_slice(&[20, 0, 0, 0, 0, 0, 0, 0]); // version, flags, method, time
+        local.extend_from_slice(&date);
+        local.extend_from_slice(&crc);
+        local.extend_from_slice(&size); // compressed
+        local.extend_from_slice(&size); // uncompressed
+        local.extend_from_slice(&name_len);
+        local.extend_from_slice(&[0, 0]); // extra field
+        local.extend_from_slice(name.as_bytes());
+
+        let offset = (state.offset as u32).to_le_bytes();
+        let writer = &mut state.writer;
+        writer
+            .write_all(&local)
+            .and_then(|_| writer.write_all(content))
+            .map_err(|source| SinkError::Write {
+                path: path.to_path_buf(),
+                source,
+            })?;
+        state.offset += (local.len() + content.len()) as u64;
+
+        let directory = &mut state.directory;
+        directory.extend_from_slice(&0x0201_4b50_u32.to_le_bytes());
+        directory.extend_from_slice(&[20, 3, 20, 0, 0, 0, 0, 0, 0, 0]); // versions (unix), flags, method, time
+        directory.extend_from_slice(&date);
+        directory.extend_from_slice(&crc);
+        directory.extend_from_slice(&size);
+        directory.extend_from_slice(&size);
+        directory.extend_from_slice(&name_len);
+        directory.extend_from_slice(&[0; 8]); // extra, comment, disk, internal attributes
+        directory.extend_from_slice(&[0, 0, 0xa4, 0x81]); // external attributes, -rw-r--r--
+        directory.extend_from_slice(&offset);
+        directory.extend_from_slice(name.as_bytes());
+        state.entries += 1;
+        Ok(())
+    }
+
+    /// Write the central directory ending the archive, and flush the writer.
+    #[allow(clippy::cast_possible_truncation)]
+    fn finish(&self) -> Result<(), SinkError> {
+        let mut state = self.state.lock().map_err(|_| SinkError::Poisoned)?;
+        let state = &mut *state;
+        let mut end = Vec::with_capacity(22);
+        end.extend_from_slice(&0x0605_4b50_u32.to_le_bytes());
+        end.extend_from_slice(&[0; 4]); // disks
+        end.extend_from_slice(&(state.entries as u16).to_le_bytes());
+        end.extend_from_slice(&(state.entries as u16).to_le_bytes());
+        end.extend_from_slice(&(state.directory.len() as u32).to_le_bytes());
+        end.extend_from_slice(&(state.offset as u32).to_le_bytes());
+        end.extend_from_slice(&[0, 0]); // comment
+        if !state.finished {
+            let writer = &mut state.writer;
+            writer
+                .write_all(&state.directory)
+                .and_then(|_| writer.write_all(&end))
+                .and_then(|_| writer.flush())
+                .map_err(|source| SinkError::Write {
+                    path: PathBuf::from("zip archive"),
+                    source,
+                })?;
+            state.finished = true;
+        }
+        Ok(())
+    }
+}
+
+/// Name of a file in an archive, with the normal components of its path separated by slashes.
+fn archive_name(path: &Path) -> String {
+    let names: Vec<_> = path
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect();
+    names.join("/")
+}
+
+/// Files uploaded to an S3 bucket, or an S3-compatible object storage,
+/// with keys starting with a prefix.
+///
+/// Credentials are read from the `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`
+/// and `AWS_SESSION_TOKEN` environment variables, the region from `AWS_REGION`,
+/// and other storages are reached with the endpoint of `AWS_ENDPOINT_URL`.
+#[cfg(feature = "s3")]
+pub struct S3Sink {
+    bucket: rusty_s3::Bucket,
+    credentials: Option<rusty_s3::Credentials>,
+    prefix: String,
+}
+
+#[cfg(feature = "s3")]
+impl S3Sink {
+    /// Validity of the signed upload requests.
+    const SIGNATURE_VALIDITY: std::time::Duration = std::time::Duration::from_secs(3600);
+
+    /// Sink of an `s3://bucket/prefix` location.
+    pub fn from_url(location: &str) -> Result<Self, SinkError> {
+        let invalid = || SinkError::S3Url(location.to_string());
+        let (bucket, prefix) = match location.strip_prefix("s3://") {
+            Some(path) => path.split_once('/').unwrap_or((path, "")),
+            None => return Err(invalid()),
+        };
+        let region = std::env::var("AWS_REGION").unwrap_or_else(|_| "us-east-1".to_string());
+        let endpoint = std::env::var("AWS_ENDPOINT_URL")
+            .unwrap_or_else(|_| format!("https://s3.{}.amazonaws.com", region));
+        let endpoint: url::Url = endpoint.parse().map_err(|_| invalid())?;
+        let bucket = rusty_s3::Bucket::new(
+            endpoint,
+            rusty_s3::UrlStyle::Path,
+            bucket.to_string(),
+            region,
+        )
+        .map_err(|_| invalid())?;
+        Ok(S3Sink {
+            bucket,
+            credentials: rusty_s3::Credentials::from_env(),
+            prefix: prefix.trim_end_matches('/').to_string(),
+        })
+    }
+}
+
+#[cfg(feature = "s3")]
+impl OutputSink for S3Sink {
+    fn write(&self, path: &Path, content: &[u8]) -> Result<(), SinkError> {
+        use rusty_s3::S3Action;
+        let key = match (self.prefix.as_str(), archive_name(path)) {
+            ("", name) => name,
+            (prefix, name) => format!("{}/{}", prefix, name),
+        };
+        let url = self
+            .bucket
+            .put_object(self.credentials.as_ref(), &key)
+            .sign(Self::SIGNATURE_VALIDITY);
+        ureq::put(url.as_str())
+            .send_bytes(content)
+            .map(|_| ())
+            .map_err(|err| SinkError::Upload {
+                key,
+                message: err.to_string(),
+            })
+    }
+}
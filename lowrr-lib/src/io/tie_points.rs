@@ -0,0 +1,137 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Export of the motions as tie points, for panorama and stitching software.
+//!
+//! Tie points are pairs of corresponding points in two images.
+//! They are sampled on a regular grid of the registered frame,
+//! and mapped into each image with its motion.
+//! Points falling outside of an image are dropped.
+
+use nalgebra::{Vector3, Vector6};
+use std::fmt::Write as _;
+use std::path::Path;
+
+use crate::affine2d::projection_mat;
+
+/// Number of points along each axis of the grid of tie points.
+pub const DEFAULT_GRID_SIZE: usize = 8;
+
+/// File format of exported tie points.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// CSV file with one tie point per line (see [save_csv]).
+    Csv,
+    /// Hugin project (see [save_pto]).
+    Pto,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "pto" => Ok(Format::Pto),
+            _ => Err(format!(
+                "Unknown tie points format \"{}\", expecting csv or pto",
+                s
+            )),
+        }
+    }
+}
+
+/// Point of the registered frame, and its position in one of the images.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TiePoint {
+    pub image: usize,
+    /// (x, y) in the registered frame.
+    pub reference: (f32, f32),
+    /// (x, y) in the image.
+    pub position: (f32, f32),
+}
+
+/// Tie points of a grid of `grid_size x grid_size` points,
+/// for images of the given (height, width).
+#[allow(clippy::cast_precision_loss)]
+pub fn tie_points(
+    motion_vec: &[Vector6<f32>],
+    shape: (usize, usize),
+    grid_size: usize,
+) -> Vec<TiePoint> {
+    let (height, width) = (shape.0 as f32, shape.1 as f32);
+    let coordinate = |i: usize, length: f32| (i as f32 + 0.5) * length / grid_size as f32;
+    let grid: Vec<(f32, f32)> = (0..grid_size)
+        .flat_map(|j| {
+            (0..grid_size).map(move |i| (coordinate(i, width), coordinate(j, height)))
+        })
+        .collect();
+    let inside = |(x, y): (f32, f32)| x >= 0.0 && y >= 0.0 && x < width && y < height;
+    let mut points = Vec::new();
+    for (image, motion) in motion_vec.iter().enumerate() {
+        let mat = projection_mat(motion);
+        for &(x, y) in grid.iter() {
+            let p = mat * Vector3::new(x, y, 1.0);
+            let position = (p.x, p.y);
+            if inside(position) {
+                points.push(TiePoint {
+                    image,
+                    reference: (x, y),
+                    position,
+                });
+            }
+        }
+    }
+    points
+}
+
+/// Save tie points in a CSV file, with the header `image,x_ref,y_ref,x,y`.
+pub fn save_csv<P: AsRef<Path>>(path: P, points: &[TiePoint]) -> std::io::Result<()> {
+    let mut csv = String::from("image,x_ref,y_ref,x,y\n");
+    for p in points.iter() {
+        let _ = writeln!(
+            csv,
+            "{},{},{},{},{}",
+            p.image, p.reference.0, p.reference.1, p.position.0, p.position.1
+        );
+    }
+    std::fs::write(path, csv)
+}
+
+/// Save tie points as a Hugin project (.pto),
+/// with control points between the first image and each other image,
+/// given the paths of the images and their (height, width).
+///
+/// The field of view of the lens is unknown and set to 50 degrees,
+/// to be optimized in Hugin with the other lens parameters.
+pub fn save_pto<P: AsRef<Path>, Q: AsRef<Path>>(
+    path: P,
+    images: &[Q],
+    shape: (usize, usize),
+    points: &[TiePoint],
+) -> std::io::Result<()> {
+    let (height, width) = shape;
+    let mut pto = String::from("# hugin project file\n");
+    let _ = writeln!(pto, "p f0 w{} h{} v50 n\"TIFF_m c:LZW\"", width, height);
+    pto.push_str("m i0\n\n");
+    for image in images.iter() {
+        let _ = writeln!(
+            pto,
+            "i w{} h{} f0 v50 r0 p0 y0 n\"{}\"",
+            width,
+            height,
+            image.as_ref().display()
+        );
+    }
+    pto.push('\n');
+    // Control points link the positions of the same reference point in two images.
+    let first: Vec<&TiePoint> = points.iter().filter(|p| p.image == 0).collect();
+    for p in points.iter().filter(|p| p.image != 0) {
+        if let Some(q) = first.iter().find(|q| q.reference == p.reference) {
+            let _ = writeln!(
+                pto,
+                "c n0 N{} x{} y{} X{} Y{} t0",
+                p.image, q.position.0, q.position.1, p.position.0, p.position.1
+            );
+        }
+    }
+    std::fs::write(path, pto)
+}
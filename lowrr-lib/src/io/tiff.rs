@@ -0,0 +1,319 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Reading and writing of multi-page TIFF stacks.
+//!
+//! Microscopy stacks commonly come as one TIFF file containing all images as pages.
+//! The `image` crate only decodes the first page, so we use the `tiff` crate directly.
+//! It cannot compress the pages it writes, so compressed pages are written tag by tag,
+//! with strips compressed by `weezl` (LZW) or `miniz_oxide` (deflate).
+
+use image::{DynamicImage, GenericImageView, ImageBuffer};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Cursor, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::encoder::TiffEncoder;
+use tiff::tags::{CompressionMethod, PhotometricInterpretation, Predictor, SampleFormat, Tag};
+use tiff::{ColorType, TiffError};
+
+use crate::interop::ToImage;
+
+#[derive(Error, Debug)]
+pub enum TiffStackError {
+    #[error("Failed to open {path} with the following error: {source}")]
+    Open {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Failed to create {path} with the following error: {source}")]
+    Create {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Failed to decode page {page} of {path} with the following error: {source}")]
+    Decode {
+        path: PathBuf,
+        page: usize,
+        source: tiff::TiffError,
+    },
+    #[error("Failed to encode page {page} with the following error: {source}")]
+    Encode {
+        page: usize,
+        source: tiff::TiffError,
+    },
+    #[error("Unsupported color type {color_type:?} in page {page} of {path}")]
+    UnsupportedColorType {
+        path: PathBuf,
+        page: usize,
+        color_type: ColorType,
+    },
+    #[error("Decoded buffer of page {page} of {path} does not match its dimensions")]
+    BufferSize { path: PathBuf, page: usize },
+    #[error("Image {0} is neither a gray nor an RGB image, it cannot be saved in a TIFF stack")]
+    UnsupportedImage(usize),
+}
+
+/// Compression of the pages of saved TIFF files.
+///
+/// Compressed pages use the horizontal differencing predictor,
+/// which makes smooth images much more compressible.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TiffCompression {
+    None,
+    /// LZW, the most widely supported compression.
+    #[default]
+    Lzw,
+    /// Deflate at its fastest level, usually smaller than LZW.
+    /// The `tiff` crate cannot decode it, so [read_pages] cannot read these files back.
+    Deflate,
+}
+
+impl std::str::FromStr for TiffCompression {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(TiffCompression::None),
+            "lzw" => Ok(TiffCompression::Lzw),
+            "deflate" => Ok(TiffCompression::Deflate),
+            _ => Err(format!(
+                "Unknown TIFF compression \"{}\", expecting none, lzw or deflate",
+                s
+            )),
+        }
+    }
+}
+
+/// Maximum size of uncompressed strips, like in the `tiff` crate.
+const STRIP_BYTES: usize = 1_000_000;
+
+/// Decode all pages of a (potentially multi-page) TIFF file.
+///
+/// Pages are returned in the order they appear in the file.
+/// Only gray, RGB and RGBA pages with 8 or 16 bits per channel are supported.
+pub fn read_pages<P: AsRef<Path>>(path: P) -> Result<Vec<DynamicImage>, TiffStackError> {
+    let path = path.as_ref();
+    let file = File::open(path).map_err(|source| TiffStackError::Open {
+        path: PathBuf::from(path),
+        source,
+    })?;
+    decode_pages(BufReader::new(file), path)
+}
+
+/// Same as [read_pages] for a TIFF file already in memory,
+/// such as the entry of an archive, named `path` in errors.
+pub fn read_pages_from_memory<P: AsRef<Path>>(
+    bytes: &[u8],
+    path: P,
+) -> Result<Vec<DynamicImage>, TiffStackError> {
+    decode_pages(Cursor::new(bytes), path.as_ref())
+}
+
+/// Decode all pages of a TIFF file, named `path` in errors.
+fn decode_pages<R: Read + Seek>(
+    reader: R,
+    path: &Path,
+) -> Result<Vec<DynamicImage>, TiffStackError> {
+    let decode_err = |page, source| TiffStackError::Decode {
+        path: PathBuf::from(path),
+        page,
+        source,
+    };
+    let mut decoder = Decoder::new(reader).map_err(|e| decode_err(0, e))?;
+    let mut pages = Vec::new();
+    loop {
+        let page = pages.len();
+        let color_type = decoder.colortype().map_err(|e| decode_err(page, e))?;
+        let (width, height) = decoder.dimensions().map_err(|e| decode_err(page, e))?;
+        let buffer = decoder.read_image().map_err(|e| decode_err(page, e))?;
+        let img = match (color_type, buffer) {
+            (ColorType::Gray(8), DecodingResult::U8(buf)) => {
+                ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageLuma8)
+            }
+            (ColorType::Gray(16), DecodingResult::U16(buf)) => {
+                ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageLuma16)
+            }
+            (ColorType::RGB(8), DecodingResult::U8(buf)) => {
+                ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgb8)
+            }
+            (ColorType::RGB(16), DecodingResult::U16(buf)) => {
+                ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgb16)
+            }
+            (ColorType::RGBA(8), DecodingResult::U8(buf)) => {
+                ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgba8)
+            }
+            (ColorType::RGBA(16), DecodingResult::U16(buf)) => {
+                ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgba16)
+            }
+            (color_type, _) => {
+                return Err(TiffStackError::UnsupportedColorType {
+                    path: PathBuf::from(path),
+                    page,
+                    color_type,
+                })
+            }
+        };
+        let img = img.ok_or_else(|| TiffStackError::BufferSize {
+            path: PathBuf::from(path),
+            page,
+        })?;
+        pages.push(img);
+        if !decoder.more_images() {
+            break;
+        }
+        decoder.next_image().map_err(|e| decode_err(page + 1, e))?;
+    }
+    Ok(pages)
+}
+
+/// Save a bunch of images into a single multi-page TIFF file.
+pub fn save_stack<P: AsRef<Path>, I: ToImage>(
+    path: P,
+    imgs: &[I],
+    compression: TiffCompression,
+) -> Result<(), TiffStackError> {
+    let pb = crate::verbosity::progress_bar(imgs.len() as u64);
+    let path = path.as_ref();
+    let file = File::create(path).map_err(|source| TiffStackError::Create {
+        path: PathBuf::from(path),
+        source,
+    })?;
+    let mut encoder = TiffEncoder::new(BufWriter::new(file))
+        .map_err(|source| TiffStackError::Encode { page: 0, source })?;
+    for (page, img) in imgs.iter().enumerate() {
+        write_page(&mut encoder, &img.to_image(), compression, page)?;
+        pb.inc(1);
+    }
+    pb.finish();
+    Ok(())
+}
+
+/// Save an image into a single-page TIFF file.
+pub fn save_image<P: AsRef<Path>>(
+    path: P,
+    img: &DynamicImage,
+    compression: TiffCompression,
+) -> Result<(), TiffStackError> {
+    let path = path.as_ref();
+    let file = File::create(path).map_err(|source| TiffStackError::Create {
+        path: PathBuf::from(path),
+        source,
+    })?;
+    let mut encoder = TiffEncoder::new(BufWriter::new(file))
+        .map_err(|source| TiffStackError::Encode { page: 0, source })?;
+    write_page(&mut encoder, img, compression, 0)
+}
+
+/// Encode an image into the bytes of a single-page TIFF file.
+pub fn encode_image(
+    img: &DynamicImage,
+    compression: TiffCompression,
+) -> Result<Vec<u8>, TiffStackError> {
+    let mut bytes = Cursor::new(Vec::new());
+    let mut encoder = TiffEncoder::new(&mut bytes)
+        .map_err(|source| TiffStackError::Encode { page: 0, source })?;
+    write_page(&mut encoder, img, compression, 0)?;
+    drop(encoder);
+    Ok(bytes.into_inner())
+}
+
+/// Write an image as the next page of a TIFF file.
+fn write_page<W: Write + Seek>(
+    encoder: &mut TiffEncoder<W>,
+    img: &DynamicImage,
+    compression: TiffCompression,
+    page: usize,
+) -> Result<(), TiffStackError> {
+    let (samples, photometric) = match img {
+        DynamicImage::ImageLuma8(_) | DynamicImage::ImageLuma16(_) => {
+            (1, PhotometricInterpretation::BlackIsZero)
+        }
+        DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgb16(_) => {
+            (3, PhotometricInterpretation::RGB)
+        }
+        _ => return Err(TiffStackError::UnsupportedImage(page)),
+    };
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    let bytes_per_sample = match img {
+        DynamicImage::ImageLuma16(_) | DynamicImage::ImageRgb16(_) => 2,
+        _ => 1,
+    };
+    // Bytes in native order, which is also the byte order of the file.
+    let mut bytes = img.as_bytes().to_vec();
+    if compression != TiffCompression::None {
+        horizontal_differencing(&mut bytes, width * samples, samples, bytes_per_sample);
+    }
+
+    // Compress each strip, then write the tags pointing to them.
+    let row_bytes = width * samples * bytes_per_sample;
+    let rows_per_strip = STRIP_BYTES.div_ceil(row_bytes).min(height).max(1);
+    let encode = |source| TiffStackError::Encode { page, source };
+    let mut dir = encoder.new_directory().map_err(encode)?;
+    let mut strip_offsets = Vec::new();
+    let mut strip_byte_counts = Vec::new();
+    for strip in bytes.chunks(rows_per_strip * row_bytes) {
+        let compressed = match compression {
+            TiffCompression::None => strip.to_vec(),
+            TiffCompression::Lzw => {
+                let mut out = Vec::new();
+                weezl::encode::Encoder::with_tiff_size_switch(weezl::BitOrder::Msb, 8)
+                    .into_stream(&mut out)
+                    .encode_all(strip)
+                    .status
+                    .map_err(|e| encode(TiffError::IoError(e)))?;
+                out
+            }
+            TiffCompression::Deflate => miniz_oxide::deflate::compress_to_vec_zlib(strip, 1),
+        };
+        let offset = dir.write_data(&compressed[..]).map_err(encode)?;
+        strip_offsets.push(offset as u32);
+        strip_byte_counts.push(compressed.len() as u32);
+    }
+    let (compression_method, predictor) = match compression {
+        TiffCompression::None => (CompressionMethod::None, Predictor::None),
+        TiffCompression::Lzw => (CompressionMethod::LZW, Predictor::Horizontal),
+        TiffCompression::Deflate => (CompressionMethod::Deflate, Predictor::Horizontal),
+    };
+    let bits_per_sample = vec![8 * bytes_per_sample as u16; samples];
+    let sample_format = vec![SampleFormat::Uint.to_u16(); samples];
+    // Tags are written in a closure to map all their errors at once.
+    let write_tags = move || {
+        dir.write_tag(Tag::ImageWidth, width as u32)?;
+        dir.write_tag(Tag::ImageLength, height as u32)?;
+        dir.write_tag(Tag::BitsPerSample, &bits_per_sample[..])?;
+        dir.write_tag(Tag::Compression, compression_method.to_u16())?;
+        dir.write_tag(Tag::PhotometricInterpretation, photometric.to_u16())?;
+        dir.write_tag(Tag::StripOffsets, &strip_offsets[..])?;
+        dir.write_tag(Tag::SamplesPerPixel, samples as u16)?;
+        dir.write_tag(Tag::RowsPerStrip, rows_per_strip as u32)?;
+        dir.write_tag(Tag::StripByteCounts, &strip_byte_counts[..])?;
+        dir.write_tag(Tag::Predictor, predictor.to_u16())?;
+        dir.write_tag(Tag::SampleFormat, &sample_format[..])?;
+        dir.finish()
+    };
+    write_tags().map_err(encode)
+}
+
+/// Replace each sample by its difference with the same sample of the previous pixel,
+/// for rows of `row_samples` samples of `bytes_per_sample` bytes in native order.
+fn horizontal_differencing(
+    bytes: &mut [u8],
+    row_samples: usize,
+    samples_per_pixel: usize,
+    bytes_per_sample: usize,
+) {
+    for row in bytes.chunks_mut(row_samples * bytes_per_sample) {
+        if bytes_per_sample == 2 {
+            for i in (samples_per_pixel..row_samples).rev() {
+                let sample =
+                    |row: &[u8], i: usize| u16::from_ne_bytes([row[2 * i], row[2 * i + 1]]);
+                let diff = sample(row, i).wrapping_sub(sample(row, i - samples_per_pixel));
+                row[2 * i..2 * i + 2].copy_from_slice(&diff.to_ne_bytes());
+            }
+        } else {
+            for i in (samples_per_pixel..row_samples).rev() {
+                row[i] = row[i].wrapping_sub(row[i - samples_per_pixel]);
+            }
+        }
+    }
+}
@@ -0,0 +1,106 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Minimal reader of zip archives.
+//!
+//! Only supports what is needed to read archives produced by common tools,
+//! such as numpy `.npz` files: stored and deflated entries, without zip64 extensions.
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ZipError {
+    #[error("End of central directory not found, this is not a zip archive")]
+    NotZip,
+    #[error("Unexpected end of data")]
+    Truncated,
+    #[error("Invalid signature in entry {0}")]
+    InvalidSignature(String),
+    #[error("Unsupported compression method {method} for entry {name}")]
+    UnsupportedCompression { name: String, method: u16 },
+    #[error("Entry {0} uses zip64 extensions which are not supported")]
+    Zip64(String),
+    #[error("Failed to inflate entry {0}")]
+    Inflate(String),
+}
+
+/// One file entry of a zip archive.
+pub struct Entry<'a> {
+    pub name: String,
+    method: u16,
+    data: &'a [u8],
+}
+
+impl Entry<'_> {
+    /// Decompress the content of this entry.
+    pub fn decompress(&self) -> Result<Vec<u8>, ZipError> {
+        match self.method {
+            0 => Ok(self.data.to_vec()),
+            8 => miniz_oxide::inflate::decompress_to_vec(self.data)
+                .map_err(|_| ZipError::Inflate(self.name.clone())),
+            method => Err(ZipError::UnsupportedCompression {
+                name: self.name.clone(),
+                method,
+            }),
+        }
+    }
+}
+
+/// List the file entries of a zip archive, in the order of its central directory.
+pub fn entries(archive: &[u8]) -> Result<Vec<Entry<'_>>, ZipError> {
+    // The end of central directory record is at least 22 bytes long,
+    // and may be followed by a comment of up to 65535 bytes.
+    let min_start = archive.len().saturating_sub(22 + u16::MAX as usize);
+    let eocd = (min_start..archive.len().saturating_sub(21))
+        .rev()
+        .find(|&i| read_u32(archive, i) == Some(0x0605_4b50))
+        .ok_or(ZipError::NotZip)?;
+    let entries_count = read_u16(archive, eocd + 10).ok_or(ZipError::Truncated)? as usize;
+    let mut pos = read_u32(archive, eocd + 16).ok_or(ZipError::Truncated)? as usize;
+
+    let mut entries = Vec::with_capacity(entries_count);
+    for _ in 0..entries_count {
+        // Central directory file header.
+        if read_u32(archive, pos) != Some(0x0201_4b50) {
+            return Err(ZipError::InvalidSignature(format!("#{}", entries.len())));
+        }
+        let field = |offset| read_u16(archive, pos + offset).ok_or(ZipError::Truncated);
+        let method = field(10)?;
+        let compressed_size = read_u32(archive, pos + 20).ok_or(ZipError::Truncated)?;
+        let name_len = field(28)? as usize;
+        let extra_len = field(30)? as usize;
+        let comment_len = field(32)? as usize;
+        let local_offset = read_u32(archive, pos + 42).ok_or(ZipError::Truncated)?;
+        let name_bytes = archive
+            .get(pos + 46..pos + 46 + name_len)
+            .ok_or(ZipError::Truncated)?;
+        let name = String::from_utf8_lossy(name_bytes).to_string();
+        if compressed_size == u32::MAX || local_offset == u32::MAX {
+            return Err(ZipError::Zip64(name));
+        }
+        pos += 46 + name_len + extra_len + comment_len;
+
+        // Local file header, its extra field may differ from the central one.
+        let local = local_offset as usize;
+        if read_u32(archive, local) != Some(0x0403_4b50) {
+            return Err(ZipError::InvalidSignature(name));
+        }
+        let local_name_len = read_u16(archive, local + 26).ok_or(ZipError::Truncated)? as usize;
+        let local_extra_len = read_u16(archive, local + 28).ok_or(ZipError::Truncated)? as usize;
+        let start = local + 30 + local_name_len + local_extra_len;
+        let data = archive
+            .get(start..start + compressed_size as usize)
+            .ok_or(ZipError::Truncated)?;
+        entries.push(Entry { name, method, data });
+    }
+    Ok(entries)
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Option<u16> {
+    let b = data.get(pos..pos + 2)?;
+    Some(u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Option<u32> {
+    let b = data.get(pos..pos + 4)?;
+    Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
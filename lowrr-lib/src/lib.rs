@@ -7,7 +7,17 @@
 // #![warn(missing_docs)]
 
 pub mod affine2d;
+pub mod dataset;
+pub mod drift;
 pub mod img;
 pub mod interop;
+pub mod io;
 pub mod optimizer;
+pub mod pipeline;
+pub mod svd;
+pub mod tune;
+pub mod units;
 pub mod utils;
+pub mod verbosity;
+
+pub use pipeline::{register_stack, StackParams, StackPixel};
@@ -0,0 +1,508 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! # Registration pipeline
+//!
+//! Steps around the core registration shared by the command line program,
+//! the Python module and the web application:
+//! crop the working area, downscale and equalize the images, register them,
+//! and express the motions in the frame of the full images at original resolution.
+//! Loading and saving images is left to each of them.
+//!
+//! Library users with already decoded images can call [register_stack],
+//! which also converts RGB images into the gray images used for registration.
+
+use nalgebra::{DMatrix, Scalar, Vector6};
+use std::future::Future;
+use thiserror::Error;
+
+use crate::img::bayer::{self, CfaPattern};
+use crate::img::crop::{crop, crop_motion, recover_original_motion, Crop, CropError, CropSpec};
+use crate::img::deformable::Deformation;
+use crate::img::multires::{mean_pyramid, Bigger};
+use crate::img::normalization::{detect_max_level, stretch, CanNormalize};
+use crate::img::registration::{
+    self, CanRegister, CancelToken, Checkpoint, Config, Preview, Progress, Registered,
+    RegistrationError,
+};
+use crate::interop::{coerce, ToImage};
+use crate::utils::{split_channels, to_gray, CanEqualize, GrayConversion};
+
+#[derive(Error, Debug)]
+pub enum PipelineError {
+    #[error("Failed to crop images: {0}")]
+    Crop(#[from] CropError),
+    #[error("Failed to register images: {0}")]
+    Registration(#[from] RegistrationError),
+}
+
+/// Parameters of the registration pipeline.
+#[derive(Debug, Clone, Copy)]
+pub struct Pipeline {
+    pub config: Config,
+    /// Mean intensity in [0,1] given to all cropped images before registration.
+    pub equalize: Option<f32>,
+    /// Working area of the registration.
+    pub crop: Option<Crop>,
+    /// Number of times the cropped images are halved before registration.
+    /// Motions are estimated faster on the downscaled images,
+    /// with a precision of about one pixel at original resolution.
+    /// The downscaling replaces the finest levels of the registration,
+    /// so `config.levels` is reduced by the same number.
+    pub halvings: usize,
+    /// Color filter array of RAW Bayer mosaics, registered on their green plane
+    /// of half resolution instead of being demosaiced (see [bayer]).
+    pub bayer: Option<CfaPattern>,
+}
+
+/// Number of halvings of the images for an estimation at the given scale,
+/// which must be a power of two in (0, 1], such as 0.5 or 0.25.
+pub fn halvings(scale: f32) -> Result<usize, String> {
+    let halvings = -scale.log2();
+    if !(scale > 0.0 && scale <= 1.0) || halvings.fract() != 0.0 {
+        return Err(format!(
+            "Invalid estimation scale {}, expecting a power of two such as 1, 0.5 or 0.25",
+            scale
+        ));
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    Ok(halvings as usize)
+}
+
+/// Result of the registration pipeline.
+pub struct Output<T: Scalar> {
+    /// Motion of each image, in the frame of the full images.
+    pub motion_vec: Vec<Vector6<f32>>,
+    /// Registration of the cropped, downscaled and equalized images,
+    /// with motions in the frame of the downscaled crop.
+    pub registered: Registered<T>,
+}
+
+impl Pipeline {
+    /// Crop, downscale, stretch (see [Config::input_max]) and equalize the images
+    /// used for registration, after extracting the green plane of Bayer mosaics.
+    pub fn prepare<T: CanEqualize + CanNormalize + Bigger>(
+        &self,
+        imgs: Vec<DMatrix<T>>,
+    ) -> Result<Vec<DMatrix<T>>, CropError> {
+        let mut cropped_imgs = match self.crop {
+            None => imgs,
+            Some(frame) => {
+                log::info!("Cropping images ...");
+                imgs.iter()
+                    .map(|im| crop(frame, im))
+                    .collect::<Result<_, _>>()?
+            }
+        };
+        if self.bayer.is_some() {
+            log::info!("Extracting the green plane of Bayer mosaics ...");
+            cropped_imgs = cropped_imgs
+                .into_iter()
+                .map(|im| self.green_plane(im))
+                .collect();
+        }
+        if self.halvings > 0 {
+            log::info!("Downscaling images {} times ...", self.halvings);
+            cropped_imgs = cropped_imgs
+                .into_iter()
+                .map(|im| self.downscale(im))
+                .collect();
+        }
+        let max_level = match self.config.input_max {
+            max if max > 0.0 => max,
+            _ => {
+                let detected = detect_max_level(&cropped_imgs) as f32;
+                log::info!("Detected maximum intensity: {}", detected);
+                detected
+            }
+        };
+        if max_level != (T::LEVELS - 1) as f32 {
+            log::info!("Stretching intensities up to {} ...", max_level);
+            stretch(max_level, &mut cropped_imgs);
+        }
+        if let Some(mean_intensity) = self.equalize {
+            log::info!("Equalizing images mean intensities ...");
+            crate::utils::equalize_mean(mean_intensity, &mut cropped_imgs);
+        }
+        Ok(cropped_imgs)
+    }
+
+    /// Crop, equalize and register images,
+    /// given as their separate channels if `channels` is more than 1
+    /// (see [registration::multichannel_affine_detailed]).
+    pub fn register<T: CanEqualize + CanRegister>(
+        &self,
+        imgs: Vec<DMatrix<T>>,
+        channels: usize,
+        sparse_diff_threshold: T::Bigger,
+    ) -> Result<Output<T>, PipelineError>
+    where
+        DMatrix<T>: ToImage,
+    {
+        self.register_previews(imgs, channels, sparse_diff_threshold, |_| {})
+    }
+
+    /// Same as [Pipeline::register], also calling `on_preview` at the end of each level
+    /// with registered thumbnails of the cropped images.
+    pub fn register_previews<T: CanEqualize + CanRegister>(
+        &self,
+        imgs: Vec<DMatrix<T>>,
+        channels: usize,
+        sparse_diff_threshold: T::Bigger,
+        on_preview: impl FnMut(Preview<T>),
+    ) -> Result<Output<T>, PipelineError>
+    where
+        DMatrix<T>: ToImage,
+    {
+        self.register_checkpoints(
+            imgs,
+            channels,
+            sparse_diff_threshold,
+            |_| {},
+            on_preview,
+            None,
+            |_| {},
+        )
+    }
+
+    /// Same as [Pipeline::register_previews], also reporting the progress of each iteration,
+    /// and saving and resuming checkpoints of the registration of the cropped images
+    /// (see [registration::multichannel_affine_checkpoints]).
+    #[allow(clippy::too_many_arguments)]
+    pub fn register_checkpoints<T: CanEqualize + CanRegister>(
+        &self,
+        imgs: Vec<DMatrix<T>>,
+        channels: usize,
+        sparse_diff_threshold: T::Bigger,
+        on_progress: impl FnMut(Progress),
+        on_preview: impl FnMut(Preview<T>),
+        resume: Option<&Checkpoint>,
+        on_checkpoint: impl FnMut(&Checkpoint),
+    ) -> Result<Output<T>, PipelineError>
+    where
+        DMatrix<T>: ToImage,
+    {
+        let cropped_imgs = self.prepare(imgs)?;
+        log::info!("Registration of images ...");
+        let registered = registration::multichannel_affine_checkpoints(
+            self.registration_config(),
+            channels,
+            cropped_imgs,
+            sparse_diff_threshold,
+            on_progress,
+            on_preview,
+            resume,
+            on_checkpoint,
+        )?;
+        Ok(self.output(registered))
+    }
+
+    /// Async version of [Pipeline::register] for gray images,
+    /// with the progress and cancellation of [registration::async_gray_affine_cancellable].
+    pub async fn async_register<T, FB>(
+        &self,
+        imgs: Vec<DMatrix<T>>,
+        sparse_diff_threshold: T::Bigger,
+        should_stop: fn(&'static str, Option<u32>) -> FB,
+        on_progress: impl FnMut(Progress),
+        cancel: &CancelToken,
+    ) -> Result<Output<T>, PipelineError>
+    where
+        T: CanEqualize + CanRegister,
+        FB: Future<Output = bool>,
+        DMatrix<T>: ToImage,
+    {
+        self.async_register_previews(
+            imgs,
+            sparse_diff_threshold,
+            should_stop,
+            on_progress,
+            |_| {},
+            cancel,
+        )
+        .await
+    }
+
+    /// Same as [Pipeline::async_register], also calling `on_preview` at the end of each level
+    /// (see [registration::async_gray_affine_previews]).
+    pub async fn async_register_previews<T, FB>(
+        &self,
+        imgs: Vec<DMatrix<T>>,
+        sparse_diff_threshold: T::Bigger,
+        should_stop: fn(&'static str, Option<u32>) -> FB,
+        on_progress: impl FnMut(Progress),
+        on_preview: impl FnMut(Preview<T>),
+        cancel: &CancelToken,
+    ) -> Result<Output<T>, PipelineError>
+    where
+        T: CanEqualize + CanRegister,
+        FB: Future<Output = bool>,
+        DMatrix<T>: ToImage,
+    {
+        let cropped_imgs = self.prepare(imgs)?;
+        log::info!("Registration of images ...");
+        let registered = registration::async_gray_affine_previews(
+            self.registration_config(),
+            cropped_imgs,
+            sparse_diff_threshold,
+            should_stop,
+            on_progress,
+            on_preview,
+            cancel,
+        )
+        .await?;
+        Ok(self.output(registered))
+    }
+
+    /// Config of the registration of the downscaled images, with fewer levels.
+    pub fn registration_config(&self) -> Config {
+        Config {
+            levels: self.config.levels.saturating_sub(self.halvings).max(1),
+            ..self.config
+        }
+    }
+
+    /// Green plane of a cropped Bayer mosaic, or the image itself without `bayer`.
+    pub fn green_plane<T: Scalar + Copy + Bigger>(&self, img: DMatrix<T>) -> DMatrix<T> {
+        match self.bayer {
+            None => img,
+            Some(pattern) => {
+                // Cropping at odd coordinates changes the color of the first pixel.
+                let (left, top) = self.crop.map_or((0, 0), |frame| (frame.left, frame.top));
+                bayer::green_plane(pattern.shifted(left, top), &img)
+            }
+        }
+    }
+
+    /// Image at the resolution of the registration, halved `halvings` times.
+    pub fn downscale<T: Scalar + Copy + Bigger>(&self, img: DMatrix<T>) -> DMatrix<T> {
+        if self.halvings == 0 {
+            return img;
+        }
+        mean_pyramid(self.halvings + 1, img)
+            .pop()
+            .expect("There is at least one level")
+    }
+
+    /// Size of a pixel of the registered images, in pixels of the original images.
+    pub fn pixel_size(&self) -> f32 {
+        let green_plane_size = if self.bayer.is_some() { 2 } else { 1 };
+        (green_plane_size << self.halvings) as f32
+    }
+
+    /// Motions in the frame of the full images, from the ones in the frame of the downscaled crop.
+    pub fn original_motion(&self, motion_vec_crop: &[Vector6<f32>]) -> Vec<Vector6<f32>> {
+        // Like between the levels of the registration, only translations depend on the scale.
+        let pixel_size = (1 << self.halvings) as f32;
+        let motion_vec_crop: Vec<Vector6<f32>> = motion_vec_crop
+            .iter()
+            .map(|m| {
+                let mut motion = *m;
+                motion[4] *= pixel_size;
+                motion[5] *= pixel_size;
+                match self.bayer {
+                    None => motion,
+                    // The green plane is centered on the 2x2 blocks of the mosaic.
+                    Some(_) => bayer::mosaic_motion(&motion),
+                }
+            })
+            .collect();
+        match self.crop {
+            None => motion_vec_crop,
+            Some(frame) => recover_original_motion(frame, &motion_vec_crop),
+        }
+    }
+
+    /// Checkpoint starting the registration of the downscaled crop from motions of the full images,
+    /// such as motions estimated by another tool, instead of the identity.
+    /// This is the inverse of [Pipeline::original_motion], at the resolution
+    /// of the level below the coarsest one of the registration.
+    pub fn initial_checkpoint(&self, motion_vec: &[Vector6<f32>]) -> Checkpoint {
+        let levels = self.registration_config().levels;
+        let scale = ((1 << self.halvings) << levels) as f32;
+        let motion_vec_crop = match self.crop {
+            None => motion_vec.to_vec(),
+            Some(frame) => crop_motion(frame, motion_vec),
+        };
+        let motion_vec = motion_vec_crop
+            .iter()
+            .map(|m| {
+                let mut motion = match self.bayer {
+                    None => *m,
+                    Some(_) => bayer::green_motion(m),
+                };
+                motion[4] /= scale;
+                motion[5] /= scale;
+                motion
+            })
+            .collect();
+        Checkpoint {
+            levels,
+            level: levels,
+            motion_vec,
+            outliers: Vec::new(),
+        }
+    }
+
+    /// Deformation in the frame of the full images, from one estimated on the downscaled crop
+    /// (see [deformable](crate::img::deformable)).
+    /// Bayer mosaics are not supported, their planes would need a deformation each.
+    pub fn original_deformation(&self, deformation: &Deformation) -> Deformation {
+        let pixel_size = (1 << self.halvings) as f32;
+        let offset = self
+            .crop
+            .map_or((0.0, 0.0), |frame| (frame.left as f32, frame.top as f32));
+        deformation.transformed(pixel_size, offset)
+    }
+
+    fn output<T: Scalar>(&self, registered: Registered<T>) -> Output<T> {
+        Output {
+            motion_vec: self.original_motion(&registered.motion_vec),
+            registered,
+        }
+    }
+}
+
+/// Parameters of [register_stack], with the same defaults as the command line program.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StackParams {
+    pub config: Config,
+    /// Mean intensity in [0,1] given to all cropped images before registration.
+    pub equalize: Option<f32>,
+    /// Working area of the registration, resolved with the size of the images.
+    pub crop: Option<CropSpec>,
+    /// Number of times the cropped images are halved before registration (see [Pipeline]).
+    pub halvings: usize,
+    /// Conversion of RGB images into the gray images used for registration.
+    pub gray: GrayConversion,
+    /// Register all the channels of RGB images jointly instead of their gray conversion.
+    pub joint_channels: bool,
+}
+
+/// Pixel types of the stacks accepted by [register_stack].
+pub trait StackPixel: Scalar + Copy
+where
+    DMatrix<Self::Gray>: ToImage,
+{
+    /// Pixel type of the images used for registration.
+    type Gray: CanEqualize + CanRegister;
+    /// Threshold of the sparse pixels selection, the same as the command line program.
+    fn sparse_diff_threshold() -> <Self::Gray as CanRegister>::Bigger;
+    /// Images used for registration, with their number of channels per image.
+    fn registration_imgs(
+        imgs: &[DMatrix<Self>],
+        params: &StackParams,
+    ) -> (Vec<DMatrix<Self::Gray>>, usize);
+}
+
+impl StackPixel for u8 {
+    type Gray = u8;
+    fn sparse_diff_threshold() -> u16 {
+        40
+    }
+    fn registration_imgs(imgs: &[DMatrix<u8>], _: &StackParams) -> (Vec<DMatrix<u8>>, usize) {
+        (imgs.to_vec(), 1)
+    }
+}
+
+impl StackPixel for u16 {
+    type Gray = u16;
+    fn sparse_diff_threshold() -> u32 {
+        10 * 256
+    }
+    fn registration_imgs(imgs: &[DMatrix<u16>], _: &StackParams) -> (Vec<DMatrix<u16>>, usize) {
+        (imgs.to_vec(), 1)
+    }
+}
+
+/// Floating point images are registered with 16 bits precision.
+impl StackPixel for f32 {
+    type Gray = u16;
+    fn sparse_diff_threshold() -> u32 {
+        10 * 256
+    }
+    fn registration_imgs(imgs: &[DMatrix<f32>], _: &StackParams) -> (Vec<DMatrix<u16>>, usize) {
+        (imgs.iter().map(coerce).collect(), 1)
+    }
+}
+
+impl StackPixel for (u8, u8, u8) {
+    type Gray = u8;
+    fn sparse_diff_threshold() -> u16 {
+        40
+    }
+    fn registration_imgs(
+        imgs: &[DMatrix<Self>],
+        params: &StackParams,
+    ) -> (Vec<DMatrix<u8>>, usize) {
+        rgb_registration_imgs(imgs, params)
+    }
+}
+
+impl StackPixel for (u16, u16, u16) {
+    type Gray = u16;
+    fn sparse_diff_threshold() -> u32 {
+        10 * 256
+    }
+    fn registration_imgs(
+        imgs: &[DMatrix<Self>],
+        params: &StackParams,
+    ) -> (Vec<DMatrix<u16>>, usize) {
+        rgb_registration_imgs(imgs, params)
+    }
+}
+
+/// Floating point images are registered with 16 bits precision.
+impl StackPixel for (f32, f32, f32) {
+    type Gray = u16;
+    fn sparse_diff_threshold() -> u32 {
+        10 * 256
+    }
+    fn registration_imgs(
+        imgs: &[DMatrix<Self>],
+        params: &StackParams,
+    ) -> (Vec<DMatrix<u16>>, usize) {
+        let imgs_u16: Vec<DMatrix<(u16, u16, u16)>> = imgs.iter().map(coerce).collect();
+        rgb_registration_imgs(&imgs_u16, params)
+    }
+}
+
+/// Either all the channels of RGB images, or their gray conversion.
+fn rgb_registration_imgs<T: CanNormalize>(
+    imgs: &[DMatrix<(T, T, T)>],
+    params: &StackParams,
+) -> (Vec<DMatrix<T>>, usize) {
+    if params.joint_channels {
+        (split_channels(imgs), 3)
+    } else {
+        (imgs.iter().map(|im| to_gray(params.gray, im)).collect(), 1)
+    }
+}
+
+/// Register a stack of already decoded images of the same size.
+///
+/// RGB images are converted into gray images, or split into their channels,
+/// then cropped, downscaled and equalized, and registered.
+/// The motions of the output are in the frame of the full images at original resolution,
+/// and can be applied with [registration::reproject].
+pub fn register_stack<T: StackPixel>(
+    imgs: &[DMatrix<T>],
+    params: &StackParams,
+) -> Result<Output<T::Gray>, PipelineError>
+where
+    DMatrix<T::Gray>: ToImage,
+{
+    let crop = match (params.crop, imgs.first()) {
+        (Some(spec), Some(img)) => Some(spec.resolve(img.shape())?),
+        _ => None,
+    };
+    let pipeline = Pipeline {
+        config: params.config,
+        equalize: params.equalize,
+        crop,
+        halvings: params.halvings,
+        bayer: None,
+    };
+    let (registration_imgs, channels) = T::registration_imgs(imgs, params);
+    pipeline.register(registration_imgs, channels, T::sparse_diff_threshold())
+}
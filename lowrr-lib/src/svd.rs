@@ -0,0 +1,339 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Singular value decompositions of the low-rank approximation.
+//!
+//! Dense decompositions are computed by a [SvdBackend],
+//! so that other linear algebra libraries can replace the one of nalgebra:
+//! faer with the `faer` feature, and LAPACK with the `lapack` feature.
+//!
+//! [IncrementalSvd] is the thin SVD of a matrix whose columns change over time.
+//! Instead of recomputing the decomposition from scratch,
+//! the columns that changed are applied as a low-rank modification of the current one,
+//! following M. Brand, "Fast low-rank modifications of the thin singular value decomposition",
+//! Linear Algebra and its Applications, 2006.
+//! The cost of an update mostly depends on the rank and on the number of changed columns,
+//! so it pays off when few columns change between two updates.
+
+use nalgebra::{DMatrix, DVector, RealField};
+
+/// Dense singular value decomposition, implemented by a linear algebra library.
+pub trait SvdBackend<F: RealField> {
+    /// Thin SVD `U * diag(S) * V^T` of a matrix, returned as (U, S, V^T).
+    /// The singular values are not necessarily sorted.
+    fn svd(&self, mat: DMatrix<F>) -> (DMatrix<F>, DVector<F>, DMatrix<F>);
+}
+
+/// SVD of nalgebra, in pure Rust.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Nalgebra;
+
+impl<F: RealField> SvdBackend<F> for Nalgebra {
+    fn svd(&self, mat: DMatrix<F>) -> (DMatrix<F>, DVector<F>, DMatrix<F>) {
+        let svd = mat.svd(true, true);
+        let u = svd.u.expect("U was computed");
+        let v_t = svd.v_t.expect("V^T was computed");
+        (u, svd.singular_values, v_t)
+    }
+}
+
+/// SVD of faer, in pure Rust, computed in double precision.
+#[cfg(feature = "faer")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Faer;
+
+#[cfg(feature = "faer")]
+impl<F: RealField> SvdBackend<F> for Faer {
+    fn svd(&self, mat: DMatrix<F>) -> (DMatrix<F>, DVector<F>, DMatrix<F>) {
+        let (nrows, ncols) = mat.shape();
+        let mat: DMatrix<f64> = mat.map(nalgebra::convert_unchecked);
+        let svd = faer::Mat::from_fn(nrows, ncols, |i, j| mat[(i, j)])
+            .thin_svd()
+            .expect("SVD of faer did not converge");
+        let (u, s, v) = (svd.U(), svd.S().column_vector(), svd.V());
+        let rank = s.nrows();
+        (
+            DMatrix::from_fn(nrows, rank, |i, j| nalgebra::convert(u[(i, j)])),
+            DVector::from_fn(rank, |i, _| nalgebra::convert(s[i])),
+            DMatrix::from_fn(rank, ncols, |i, j| nalgebra::convert(v[(j, i)])),
+        )
+    }
+}
+
+/// SVD of LAPACK (`dgesdd`), computed in double precision.
+///
+/// The LAPACK implementation is not bundled,
+/// the final program must link to one, for example with the `lapack-src` crate.
+#[cfg(feature = "lapack")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lapack;
+
+#[cfg(feature = "lapack")]
+impl<F: RealField> SvdBackend<F> for Lapack {
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_possible_wrap,
+        clippy::cast_sign_loss
+    )]
+    fn svd(&self, mat: DMatrix<F>) -> (DMatrix<F>, DVector<F>, DMatrix<F>) {
+        let (nrows, ncols) = mat.shape();
+        let rank = nrows.min(ncols);
+        let (m, n, k) = (nrows as i32, ncols as i32, rank as i32);
+        let mut a: Vec<f64> = mat
+            .iter()
+            .cloned()
+            .map(nalgebra::convert_unchecked)
+            .collect();
+        let mut s = vec![0.0; rank];
+        let mut u = vec![0.0; nrows * rank];
+        let mut v_t = vec![0.0; rank * ncols];
+        let mut iwork = vec![0; 8 * rank];
+        let mut info = 0;
+        // Query of the optimal size of the workspace, then decomposition.
+        // Safety: the buffers have the sizes required by the thin SVD (job 'S').
+        let mut lwork = [0.0];
+        unsafe {
+            lapack::dgesdd(
+                b'S',
+                m,
+                n,
+                &mut a,
+                m.max(1),
+                &mut s,
+                &mut u,
+                m.max(1),
+                &mut v_t,
+                k.max(1),
+                &mut lwork,
+                -1,
+                &mut iwork,
+                &mut info,
+            );
+        }
+        let mut work = vec![0.0; lwork[0] as usize];
+        let lwork = work.len() as i32;
+        unsafe {
+            lapack::dgesdd(
+                b'S',
+                m,
+                n,
+                &mut a,
+                m.max(1),
+                &mut s,
+                &mut u,
+                m.max(1),
+                &mut v_t,
+                k.max(1),
+                &mut work,
+                lwork,
+                &mut iwork,
+                &mut info,
+            );
+        }
+        assert_eq!(info, 0, "SVD of LAPACK failed with info = {}", info);
+        (
+            DMatrix::from_iterator(nrows, rank, u.into_iter().map(nalgebra::convert)),
+            DVector::from_iterator(rank, s.into_iter().map(nalgebra::convert)),
+            DMatrix::from_iterator(rank, ncols, v_t.into_iter().map(nalgebra::convert)),
+        )
+    }
+}
+
+/// Number of incremental updates after which the decomposition is recomputed,
+/// to get rid of the loss of orthogonality of the singular vectors.
+const MAX_UPDATES: usize = 10;
+
+/// Thin SVD `U * diag(S) * V^T` approximating a tracked matrix.
+///
+/// Singular values under the tolerance are dropped,
+/// and columns changing by less than the tolerance (in L2 norm) are not updated.
+/// Ignored changes are not lost: they are still part of the difference
+/// with the tracked matrix at the next update.
+pub struct IncrementalSvd<F: RealField> {
+    u: DMatrix<F>,
+    singular_values: DVector<F>,
+    v: DMatrix<F>,
+    tolerance: F,
+    updates: usize,
+}
+
+impl<F: RealField + Copy> IncrementalSvd<F> {
+    /// Decomposition of a matrix, keeping the singular values above `tolerance`.
+    pub fn new(backend: &impl SvdBackend<F>, mat: DMatrix<F>, tolerance: F) -> Self {
+        let (u, singular_values, v_t) = backend.svd(mat);
+        let mut decomposition = Self {
+            u,
+            singular_values,
+            v: v_t.transpose(),
+            tolerance,
+            updates: 0,
+        };
+        decomposition.truncate();
+        decomposition
+    }
+
+    /// Number of singular values kept.
+    pub fn rank(&self) -> usize {
+        self.singular_values.len()
+    }
+
+    pub fn singular_values(&self) -> &DVector<F> {
+        &self.singular_values
+    }
+
+    /// Recompose `U * diag(f(S)) * V^T` into `out`, with singular values mapped by `f`.
+    pub fn recompose_into(&self, f: impl Fn(F) -> F, out: &mut DMatrix<F>) {
+        let mut u_s = self.u.clone();
+        for (mut u_col, &s) in u_s.column_iter_mut().zip(self.singular_values.iter()) {
+            u_col *= f(s);
+        }
+        out.gemm(F::one(), &u_s, &self.v.transpose(), F::zero());
+    }
+
+    /// Update the decomposition to track a new matrix, of the same shape as the previous one.
+    /// The matrix is left with its difference to the previous approximation.
+    ///
+    /// The decomposition is recomputed when more than half of the columns changed,
+    /// or after too many incremental updates.
+    /// Return the number of updated columns, all of them if it was recomputed.
+    pub fn update(&mut self, backend: &impl SvdBackend<F>, mat: &mut DMatrix<F>) -> usize {
+        let mut u_s = self.u.clone();
+        for (mut u_col, &s) in u_s.column_iter_mut().zip(self.singular_values.iter()) {
+            u_col *= s;
+        }
+        let v_t = self.v.transpose();
+        mat.gemm(-F::one(), &u_s, &v_t, F::one());
+        let changed: Vec<usize> = (0..mat.ncols())
+            .filter(|&j| mat.column(j).norm() > self.tolerance)
+            .collect();
+        if changed.is_empty() {
+            return 0;
+        }
+        if self.rank() == 0
+            || self.updates >= MAX_UPDATES
+            || 2 * changed.len() > mat.ncols()
+            || self.rank() + changed.len() > mat.nrows()
+        {
+            let diff = mat.clone();
+            mat.gemm(F::one(), &u_s, &v_t, F::one());
+            *self = Self::new(backend, std::mem::replace(mat, diff), self.tolerance);
+            return mat.ncols();
+        }
+        self.add_columns(backend, &changed, &mat.select_columns(&changed));
+        self.updates += 1;
+        changed.len()
+    }
+
+    /// Add `diff` to the columns `changed` of the approximation:
+    /// `U S V^T + C E^T`, where C holds the differences and E selects their columns.
+    fn add_columns(&mut self, backend: &impl SvdBackend<F>, changed: &[usize], diff: &DMatrix<F>) {
+        let (rank, count) = (self.rank(), changed.len());
+
+        // Components of the differences orthogonal to U.
+        let diff_u = self.u.tr_mul(diff);
+        let qr = (diff - &self.u * &diff_u).qr();
+        let (p, r_a) = (qr.q(), qr.r());
+
+        // Components of the column selectors orthogonal to V.
+        let selectors_v = DMatrix::from_fn(rank, count, |i, k| self.v[(changed[k], i)]);
+        let mut selectors = DMatrix::zeros(self.v.nrows(), count);
+        for (k, &j) in changed.iter().enumerate() {
+            selectors[(j, k)] = F::one();
+        }
+        let qr = (selectors - &self.v * &selectors_v).qr();
+        let (q, r_b) = (qr.q(), qr.r());
+
+        // Small (rank + count) square matrix between the extended bases.
+        let stack = |top: &DMatrix<F>, bottom: &DMatrix<F>| {
+            let mut stacked = DMatrix::zeros(rank + count, count);
+            stacked.rows_mut(0, rank).copy_from(top);
+            stacked.rows_mut(rank, count).copy_from(bottom);
+            stacked
+        };
+        let mut middle = stack(&diff_u, &r_a) * stack(&selectors_v, &r_b).transpose();
+        for (i, &s) in self.singular_values.iter().enumerate() {
+            middle[(i, i)] += s;
+        }
+
+        // Rotate the extended bases by the singular vectors of the small matrix.
+        let (u_mid, singular_values, v_mid_t) = backend.svd(middle);
+        let v_mid = v_mid_t.transpose();
+        self.u = &self.u * u_mid.rows(0, rank) + p * u_mid.rows(rank, count);
+        self.v = &self.v * v_mid.rows(0, rank) + q * v_mid.rows(rank, count);
+        self.singular_values = singular_values;
+        self.truncate();
+    }
+
+    /// Drop the singular values under the tolerance, with their singular vectors.
+    fn truncate(&mut self) {
+        let kept: Vec<usize> = (0..self.rank())
+            .filter(|&i| self.singular_values[i] > self.tolerance)
+            .collect();
+        if kept.len() < self.rank() {
+            self.u = self.u.select_columns(&kept);
+            self.v = self.v.select_columns(&kept);
+            self.singular_values =
+                DVector::from_iterator(kept.len(), kept.iter().map(|&i| self.singular_values[i]));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::img::synthetic::translated_stack;
+
+    /// Pixels x images matrix of a synthetic stack, with intensities in [0,1].
+    fn stack_matrix(count: usize, seed: u64) -> DMatrix<f64> {
+        let (imgs, _) = translated_stack(32, 32, count, seed);
+        let pixels: Vec<f64> = imgs.iter().flatten().map(|&x| x as f64 / 255.0).collect();
+        DMatrix::from_vec(32 * 32, count, pixels)
+    }
+
+    #[test]
+    fn incremental_update_matches_full_svd() {
+        let mut mat = stack_matrix(6, 42);
+        let mut svd = IncrementalSvd::new(&Nalgebra, mat.clone(), 1e-9);
+
+        // Replace two columns, few enough for an incremental update.
+        let other = stack_matrix(2, 7);
+        mat.set_column(1, &other.column(0));
+        mat.set_column(4, &other.column(1));
+        let mut tracked = mat.clone();
+        assert_eq!(svd.update(&Nalgebra, &mut tracked), 2);
+
+        let mut recomposed = DMatrix::zeros(mat.nrows(), mat.ncols());
+        svd.recompose_into(|s| s, &mut recomposed);
+        assert!((&recomposed - &mat).amax() < 1e-6);
+
+        let mut expected: Vec<f64> = mat.singular_values().iter().cloned().collect();
+        let mut actual: Vec<f64> = svd.singular_values().iter().cloned().collect();
+        expected.sort_by(|a, b| b.total_cmp(a));
+        actual.sort_by(|a, b| b.total_cmp(a));
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(&expected) {
+            assert!((a - e).abs() < 1e-6, "singular values {} and {}", a, e);
+        }
+    }
+
+    /// Recomposition `U * diag(S) * V^T` of a backend SVD matches the matrix.
+    fn check_backend(backend: &impl SvdBackend<f64>) {
+        let mat = stack_matrix(6, 42);
+        let (mut u, s, v_t) = backend.svd(mat.clone());
+        assert_eq!((u.shape(), s.len(), v_t.shape()), ((32 * 32, 6), 6, (6, 6)));
+        for (mut col, &s) in u.column_iter_mut().zip(s.iter()) {
+            col *= s;
+        }
+        assert!((u * v_t - mat).amax() < 1e-9);
+    }
+
+    #[test]
+    fn nalgebra_recomposes_the_matrix() {
+        check_backend(&Nalgebra);
+    }
+
+    #[cfg(feature = "faer")]
+    #[test]
+    fn faer_recomposes_the_matrix() {
+        check_backend(&Faer);
+    }
+}
@@ -0,0 +1,233 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! # Tuning of lambda and rho
+//!
+//! Grid search of the `lambda` and `rho` parameters of the registration.
+//! Each pair of the grid registers a downsampled center crop of the images,
+//! using only the coarse levels of the pyramid, which is fast enough to try many of them.
+//!
+//! Pairs are scored by the nuclear + L1 objective `||A||_* + lambda * ||E||_1`
+//! of the robust low-rank decomposition `A + E` of their registered images.
+//! The objectives of all pairs use the same lambda, the one of the base config,
+//! otherwise a lower lambda would always look better by moving everything into the sparse errors.
+//! A lower objective means the registered images are closer to a low-rank matrix,
+//! so better aligned.
+
+use nalgebra::{DMatrix, DVector, Scalar, Vector6};
+use thiserror::Error;
+
+use crate::img::interpolation::CanLinearInterpolate;
+use crate::img::multires::{mean_pyramid, Bigger};
+use crate::img::registration::{self, CanRegister, Config, RegistrationError, SvdLibrary};
+use crate::interop::ToImage;
+use crate::svd::SvdBackend;
+
+#[derive(Error, Debug)]
+pub enum TuneError {
+    #[error("The grid of parameters is empty")]
+    EmptyGrid,
+    #[error("Failed to register images with lambda = {lambda} and rho = {rho}: {source}")]
+    Registration {
+        lambda: f32,
+        rho: f32,
+        source: RegistrationError,
+    },
+}
+
+/// Number of pyramid levels registered for each pair of parameters.
+pub const TUNED_LEVELS: usize = 2;
+
+/// Maximum width and height of the crop registered for each pair of parameters,
+/// at the resolution of the coarse levels.
+pub const TUNED_CROP_SIZE: usize = 256;
+
+/// Iterations of the robust low-rank decomposition scoring the registered images.
+const OBJECTIVE_ITERATIONS: usize = 50;
+
+/// Values of lambda and rho tried, all their combinations being registered.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grid {
+    pub lambdas: Vec<f32>,
+    pub rhos: Vec<f32>,
+}
+
+/// Default factors of `Grid::around`.
+pub const DEFAULT_GRID_FACTORS: [f32; 5] = [0.25, 0.5, 1.0, 2.0, 4.0];
+
+impl Grid {
+    /// Grid of the lambda and rho of the config multiplied by each factor.
+    pub fn around(config: &Config, factors: &[f32]) -> Self {
+        Self {
+            lambdas: factors.iter().map(|f| f * config.lambda).collect(),
+            rhos: factors.iter().map(|f| f * config.rho).collect(),
+        }
+    }
+}
+
+/// Score of one pair of parameters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Candidate {
+    pub lambda: f32,
+    pub rho: f32,
+    /// Nuclear + L1 objective of the registered images, the lower the better.
+    pub objective: f64,
+}
+
+/// Scores of all the pairs of a grid.
+#[derive(Debug, Clone)]
+pub struct Tuning {
+    /// Candidates in the order of the grid, by lambda then rho.
+    pub candidates: Vec<Candidate>,
+}
+
+impl Tuning {
+    /// Candidate with the lowest objective.
+    pub fn best(&self) -> Candidate {
+        *self
+            .candidates
+            .iter()
+            .min_by(|a, b| a.objective.total_cmp(&b.objective))
+            .expect("There is at least one candidate")
+    }
+}
+
+/// Score each pair of (lambda, rho) of the grid,
+/// the `channels` consecutive images of `imgs` being the channels of one image.
+///
+/// Other parameters are the ones of `config`, except that only its
+/// `TUNED_LEVELS` coarsest levels are registered, on a crop of at most
+/// `TUNED_CROP_SIZE` pixels in the center of the images.
+pub fn tune<T: CanRegister>(
+    config: Config,
+    channels: usize,
+    imgs: Vec<DMatrix<T>>,
+    sparse_diff_threshold: T::Bigger,
+    grid: &Grid,
+) -> Result<Tuning, TuneError>
+where
+    DMatrix<T>: ToImage,
+{
+    if grid.lambdas.is_empty() || grid.rhos.is_empty() {
+        return Err(TuneError::EmptyGrid);
+    }
+    let skipped_levels = config.levels.saturating_sub(TUNED_LEVELS);
+    let imgs: Vec<DMatrix<T>> = imgs
+        .into_iter()
+        .map(|img| center_crop(coarsest(skipped_levels, img)))
+        .collect();
+    let levels = config.levels - skipped_levels;
+
+    let mut candidates = Vec::with_capacity(grid.lambdas.len() * grid.rhos.len());
+    for &lambda in &grid.lambdas {
+        for &rho in &grid.rhos {
+            log::info!("Tuning with lambda = {} and rho = {} ...", lambda, rho);
+            let candidate_config = Config {
+                lambda,
+                rho,
+                levels,
+                profile: false,
+                ..config
+            };
+            let registered = registration::multichannel_affine_detailed(
+                candidate_config,
+                channels,
+                imgs.clone(),
+                sparse_diff_threshold,
+            )
+            .map_err(|source| TuneError::Registration {
+                lambda,
+                rho,
+                source,
+            })?;
+            let objective = registered_objective(
+                &config.svd_library,
+                config.lambda,
+                channels,
+                &registered.imgs,
+                &registered.motion_vec,
+            );
+            log::info!("Objective: {}", objective);
+            candidates.push(Candidate {
+                lambda,
+                rho,
+                objective,
+            });
+        }
+    }
+    Ok(Tuning { candidates })
+}
+
+/// Lowest resolution of an image halved `halvings` times, or less if it gets too small.
+fn coarsest<T: Scalar + Copy + Bigger>(halvings: usize, img: DMatrix<T>) -> DMatrix<T> {
+    mean_pyramid(halvings + 1, img)
+        .pop()
+        .expect("There is at least one level")
+}
+
+/// Crop of at most `TUNED_CROP_SIZE` pixels in the center of an image.
+fn center_crop<T: Scalar>(img: DMatrix<T>) -> DMatrix<T> {
+    let (height, width) = img.shape();
+    if height <= TUNED_CROP_SIZE && width <= TUNED_CROP_SIZE {
+        return img;
+    }
+    let (crop_height, crop_width) = (height.min(TUNED_CROP_SIZE), width.min(TUNED_CROP_SIZE));
+    let start = ((height - crop_height) / 2, (width - crop_width) / 2);
+    img.slice(start, (crop_height, crop_width)).into_owned()
+}
+
+/// Objective of the robust low-rank decomposition of the images warped by their motions.
+fn registered_objective<T: Scalar + Copy + CanLinearInterpolate<f32, f32>>(
+    library: &SvdLibrary,
+    lambda: f32,
+    channels: usize,
+    imgs: &[DMatrix<T>],
+    motion_vec: &[Vector6<f32>],
+) -> f64 {
+    let (height, width) = imgs[0].shape();
+    let mut mat = DMatrix::zeros(height * width, imgs.len());
+    for (j, img) in imgs.iter().enumerate() {
+        let registered: DMatrix<f32> = registration::warp(img, &motion_vec[j / channels]);
+        mat.column_mut(j).copy_from_slice(registered.as_slice());
+    }
+    low_rank_objective(library, lambda, mat)
+}
+
+/// Nuclear + L1 objective of the robust low-rank decomposition `A + E` of a matrix,
+/// with lambda scaled by the number of rows like in the registration.
+///
+/// The decomposition uses the inexact augmented Lagrange multiplier method of
+/// Lin, Chen and Ma, "The augmented Lagrange multiplier method for exact recovery
+/// of corrupted low-rank matrices", 2010.
+fn low_rank_objective(library: &SvdLibrary, lambda: f32, mat: DMatrix<f32>) -> f64 {
+    let lambda = lambda / (mat.nrows() as f32).sqrt();
+    let spectral_norm = library.svd(mat.clone()).1.max();
+    if spectral_norm == 0.0 {
+        return 0.0;
+    }
+    let mut mu = 1.25 / spectral_norm;
+    let mut errors = DMatrix::zeros(mat.nrows(), mat.ncols());
+    let mut lagrange_mult = DMatrix::zeros(mat.nrows(), mat.ncols());
+    let mut singular_values = DVector::zeros(0);
+    for _ in 0..OBJECTIVE_ITERATIONS {
+        // Singular value thresholding of the low-rank part.
+        let (u, s, v_t) = library.svd(&mat - &errors + &lagrange_mult / mu);
+        singular_values = s.map(|x| (x - 1.0 / mu).max(0.0));
+        let mut u_s = u;
+        for (mut col, &s) in u_s.column_iter_mut().zip(singular_values.iter()) {
+            col *= s;
+        }
+        let low_rank = u_s * v_t;
+        // Shrinkage of the sparse errors.
+        errors = (&mat - &low_rank + &lagrange_mult / mu).map(|x| shrink(lambda / mu, x));
+        lagrange_mult += (&mat - &low_rank - &errors) * mu;
+        mu *= 1.5;
+    }
+    let nuclear_norm = singular_values.iter().map(|&s| s as f64).sum::<f64>();
+    let l1_norm = errors.iter().map(|&x| x.abs() as f64).sum::<f64>();
+    nuclear_norm + lambda as f64 * l1_norm
+}
+
+/// Soft thresholding of x by alpha.
+fn shrink(alpha: f32, x: f32) -> f32 {
+    x.signum() * (x.abs() - alpha).max(0.0)
+}
@@ -0,0 +1,123 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Motions in physical units, with the calibrated size of a pixel.
+//!
+//! Microscopes are calibrated with the pixel pitch in the specimen plane,
+//! and telescopes with the plate scale, the angle of sky seen by a pixel.
+//! Translations in such units characterize the drift of an acquisition
+//! independently of the camera and its binning.
+
+use nalgebra::{Vector2, Vector3, Vector6};
+
+use crate::affine2d::projection_mat;
+
+/// Unit of the size of a pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Nanometer,
+    Micrometer,
+    Millimeter,
+    Arcsecond,
+    Arcminute,
+    Degree,
+}
+
+impl Unit {
+    /// Short symbol of the unit, as accepted by [PixelSize::from_str](std::str::FromStr).
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Unit::Nanometer => "nm",
+            Unit::Micrometer => "um",
+            Unit::Millimeter => "mm",
+            Unit::Arcsecond => "arcsec",
+            Unit::Arcminute => "arcmin",
+            Unit::Degree => "deg",
+        }
+    }
+}
+
+/// Calibrated size of a pixel, its pitch or plate scale.
+/// For non-square pixels, this is the size of their height.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PixelSize {
+    pub size: f32,
+    pub unit: Unit,
+}
+
+impl std::str::FromStr for PixelSize {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid pixel size \"{}\", expecting a positive number followed by nm, um, mm, arcsec, arcmin or deg, such as 0.65um",
+                s
+            )
+        };
+        let split = s
+            .find(|c: char| c.is_ascii_alphabetic() && c != 'e' && c != 'E')
+            .ok_or_else(invalid)?;
+        let (size, unit) = s.split_at(split);
+        let size: f32 = size.trim().parse().map_err(|_| invalid())?;
+        let unit = match unit {
+            "nm" => Unit::Nanometer,
+            "um" | "µm" => Unit::Micrometer,
+            "mm" => Unit::Millimeter,
+            "arcsec" | "as" => Unit::Arcsecond,
+            "arcmin" => Unit::Arcminute,
+            "deg" => Unit::Degree,
+            _ => return Err(invalid()),
+        };
+        if size > 0.0 && size.is_finite() {
+            Ok(PixelSize { size, unit })
+        } else {
+            Err(invalid())
+        }
+    }
+}
+
+impl std::fmt::Display for PixelSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}{}", self.size, self.unit.symbol())
+    }
+}
+
+/// Geometry of a motion, with translations in pixels or in the unit of a pixel size.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MotionGeometry {
+    /// Translation (x, y) of the motion, the displacement of the top left corner.
+    pub translation: Vector2<f32>,
+    /// Displacement (x, y) of the center of the image.
+    pub center_drift: Vector2<f32>,
+    /// Angle of the closest rotation to the linear part of the motion, in degrees.
+    pub rotation_deg: f32,
+}
+
+impl MotionGeometry {
+    /// Geometry of a motion of square pixels,
+    /// with the drift of the given center of the image (see [image_center]).
+    pub fn new(motion: &Vector6<f32>, center: Vector2<f32>) -> Self {
+        let mat = projection_mat(motion);
+        let moved_center = mat * Vector3::new(center.x, center.y, 1.0);
+        let rotation = (mat.m21 - mat.m12).atan2(mat.m11 + mat.m22);
+        MotionGeometry {
+            translation: Vector2::new(motion[4], motion[5]),
+            center_drift: Vector2::new(moved_center.x - center.x, moved_center.y - center.y),
+            rotation_deg: rotation.to_degrees(),
+        }
+    }
+
+    /// Same geometry with translations in the unit of the pixel size.
+    pub fn in_units(&self, pixel_size: &PixelSize) -> Self {
+        MotionGeometry {
+            translation: self.translation * pixel_size.size,
+            center_drift: self.center_drift * pixel_size.size,
+            ..*self
+        }
+    }
+}
+
+/// Center (x, y) of an image of the given (height, width).
+#[allow(clippy::cast_precision_loss)]
+pub fn image_center((height, width): (usize, usize)) -> Vector2<f32> {
+    Vector2::new((width as f32 - 1.0) / 2.0, (height as f32 - 1.0) / 2.0)
+}
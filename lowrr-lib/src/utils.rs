@@ -2,27 +2,37 @@
 
 //! Helper module for functions that didn't fit anywhere else.
 
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::{DynamicImage, GenericImageView, ImageEncoder};
 use nalgebra::base::dimension::{Dim, Dynamic};
 use nalgebra::base::{Scalar, VecStorage};
 use nalgebra::{DMatrix, Matrix};
 use std::ops::Mul;
 use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
 use thiserror::Error;
 
+use crate::img::normalization::CanNormalize;
 use crate::interop::ToImage;
+use crate::io::sink::{DirSink, OutputSink, SinkError};
 
 #[derive(Error, Debug)]
 pub enum UtilsError {
-    #[error("Failed to create directory {dir} with the following error: {source}")]
-    CreateDir {
-        dir: PathBuf,
-        source: std::io::Error,
-    },
     #[error("Failed to save image {path} with the following error: {source}")]
     SavingImg {
         path: PathBuf,
         source: image::ImageError,
     },
+    #[error("Failed to encode image with the following error: {0}")]
+    EncodingImg(image::ImageError),
+    #[error("Failed to write image: {0}")]
+    Sink(#[from] SinkError),
+    #[cfg(feature = "tiff")]
+    #[error("Failed to save TIFF image: {0}")]
+    SavingTiff(#[from] crate::io::tiff::TiffStackError),
+    #[error("A thread writing images panicked")]
+    WriterPanic,
 }
 
 /// Same as rgb2gray matlab function, but for u8.
@@ -40,6 +50,78 @@ pub fn rgb_to_gray(red: &DMatrix<u8>, green: &DMatrix<u8>, blue: &DMatrix<u8>) -
     )
 }
 
+/// Conversion of RGB images into the gray images used for registration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GrayConversion {
+    /// Green channel only, the least noisy one on Bayer sensors.
+    #[default]
+    Green,
+    /// Luminance, with the Rec. 601 weights also used by `rgb_to_gray`.
+    Luma,
+    /// Average of the three channels.
+    Average,
+    /// Single channel, 0 for red, 1 for green and 2 for blue.
+    Channel(usize),
+}
+
+impl std::str::FromStr for GrayConversion {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let unknown = || {
+            format!(
+                "Unknown gray conversion \"{}\", expecting green, luma, average or channel:N with N in 0, 1, 2",
+                s
+            )
+        };
+        match s {
+            "green" => Ok(GrayConversion::Green),
+            "luma" => Ok(GrayConversion::Luma),
+            "average" => Ok(GrayConversion::Average),
+            _ => match s.strip_prefix("channel:").map(|n| n.parse()) {
+                Some(Ok(n)) if n < 3 => Ok(GrayConversion::Channel(n)),
+                _ => Err(unknown()),
+            },
+        }
+    }
+}
+
+/// Convert an RGB image into a gray image.
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_precision_loss)]
+#[allow(clippy::cast_sign_loss)]
+pub fn to_gray<T: CanNormalize>(
+    conversion: GrayConversion,
+    img: &DMatrix<(T, T, T)>,
+) -> DMatrix<T> {
+    let weighted = |(wr, wg, wb): (f32, f32, f32)| {
+        img.map(|(r, g, b)| {
+            let v = wr * r.to_level() as f32 + wg * g.to_level() as f32 + wb * b.to_level() as f32;
+            T::from_level(v.round() as usize)
+        })
+    };
+    match conversion {
+        GrayConversion::Green | GrayConversion::Channel(1) => img.map(|(_r, g, _b)| g),
+        GrayConversion::Channel(0) => img.map(|(r, _g, _b)| r),
+        GrayConversion::Channel(_) => img.map(|(_r, _g, b)| b),
+        GrayConversion::Luma => weighted((0.2989, 0.5870, 0.1140)),
+        GrayConversion::Average => weighted((1.0 / 3.0, 1.0 / 3.0, 1.0 / 3.0)),
+    }
+}
+
+/// Split RGB images into their channels, the three channels of an image being consecutive,
+/// as expected by the joint registration of all channels.
+pub fn split_channels<T: Scalar + Copy>(imgs: &[DMatrix<(T, T, T)>]) -> Vec<DMatrix<T>> {
+    imgs.iter()
+        .flat_map(|im| {
+            [
+                im.map(|(r, _g, _b)| r),
+                im.map(|(_r, g, _b)| g),
+                im.map(|(_r, _g, b)| b),
+            ]
+        })
+        .collect()
+}
+
 /// Reshapes `self` in-place such that it has dimensions `nrows × ncols`.
 ///
 /// The values are not copied or moved. This function will panic if
@@ -77,7 +159,7 @@ pub fn transpose<T: Clone>(v: Vec<Vec<T>>) -> Vec<Vec<T>> {
     // Normal case.
     let mut v_transposed = vec![Vec::new(); transposed_len];
     for vi in v.into_iter() {
-        for (v_tj, vj) in v_transposed.iter_mut().zip(vi.into_iter()) {
+        for (v_tj, vj) in v_transposed.iter_mut().zip(vi) {
             v_tj.push(vj);
         }
     }
@@ -86,30 +168,316 @@ pub fn transpose<T: Clone>(v: Vec<Vec<T>>) -> Vec<Vec<T>> {
 
 /// Save a bunch of images into the given directory.
 pub fn save_all_imgs<P: AsRef<Path>, I: ToImage>(dir: P, imgs: &[I]) -> Result<(), UtilsError> {
-    let pb = if log::log_enabled!(log::Level::Info) {
-        indicatif::ProgressBar::new(imgs.len() as u64)
-    } else {
-        indicatif::ProgressBar::hidden()
-    };
+    save_all_imgs_as(dir, imgs, ImgFormat::default())
+}
+
+/// Same as [save_all_imgs] in the given file format.
+pub fn save_all_imgs_as<P: AsRef<Path>, I: ToImage>(
+    dir: P,
+    imgs: &[I],
+    format: ImgFormat,
+) -> Result<(), UtilsError> {
+    save_all_imgs_to(&DirSink::default(), dir, imgs, format)
+}
+
+/// Same as [save_all_imgs_as], writing the images in the given directory of a sink
+/// (see [sink](crate::io::sink)).
+pub fn save_all_imgs_to<P: AsRef<Path>, I: ToImage>(
+    sink: &dyn OutputSink,
+    dir: P,
+    imgs: &[I],
+    format: ImgFormat,
+) -> Result<(), UtilsError> {
+    let pb = crate::verbosity::progress_bar(imgs.len() as u64);
     let dir = dir.as_ref();
-    std::fs::create_dir_all(dir).map_err(|source| UtilsError::CreateDir {
-        dir: PathBuf::from(dir),
-        source,
-    })?;
     for (i, img) in imgs.iter().enumerate() {
-        let img_path = dir.join(format!("{}.png", i));
-        img.to_image()
-            .save(&img_path)
-            .map_err(|source| UtilsError::SavingImg {
-                path: img_path,
-                source,
-            })?;
+        let encoded = encode_img(&img.to_image(), format, None)?;
+        sink.write(&format.path(dir, i), &encoded)?;
         pb.inc(1);
     }
     pb.finish();
     Ok(())
 }
 
+/// File format of saved images, with its compression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImgFormat {
+    Png(PngCompression),
+    #[cfg(feature = "tiff")]
+    Tiff(crate::io::tiff::TiffCompression),
+}
+
+impl Default for ImgFormat {
+    fn default() -> Self {
+        ImgFormat::Png(PngCompression::default())
+    }
+}
+
+impl ImgFormat {
+    /// Extension of the files in this format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImgFormat::Png(_) => "png",
+            #[cfg(feature = "tiff")]
+            ImgFormat::Tiff(_) => "tif",
+        }
+    }
+
+    /// Path of the image of index `i` in the given directory, such as "dir/3.png".
+    pub fn path(&self, dir: &Path, i: usize) -> PathBuf {
+        dir.join(format!("{}.{}", i, self.extension()))
+    }
+}
+
+/// Save an image in the given file format.
+pub fn save_img(path: &Path, img: &DynamicImage, format: ImgFormat) -> Result<(), UtilsError> {
+    save_img_with_profile(path, img, format, None)
+}
+
+/// Same as [save_img], also inserting the ICC profile, if any, in PNG images
+/// (see [icc](crate::io::icc)).
+pub fn save_img_with_profile(
+    path: &Path,
+    img: &DynamicImage,
+    format: ImgFormat,
+    profile: Option<&[u8]>,
+) -> Result<(), UtilsError> {
+    match (format, profile) {
+        (ImgFormat::Png(compression), None) => save_png(path, img, compression),
+        (ImgFormat::Png(compression), Some(profile)) => {
+            save_png_with_profile(path, img, compression, profile)
+        }
+        #[cfg(feature = "tiff")]
+        (ImgFormat::Tiff(compression), _) => {
+            Ok(crate::io::tiff::save_image(path, img, compression)?)
+        }
+    }
+}
+
+/// Encode an image in the given file format, with the ICC profile, if any, of PNG images
+/// (see [icc](crate::io::icc)).
+pub fn encode_img(
+    img: &DynamicImage,
+    format: ImgFormat,
+    profile: Option<&[u8]>,
+) -> Result<Vec<u8>, UtilsError> {
+    match format {
+        ImgFormat::Png(compression) => {
+            let mut png = Vec::new();
+            let encoder =
+                PngEncoder::new_with_quality(&mut png, compression.into(), FilterType::Sub);
+            let (width, height) = img.dimensions();
+            encoder
+                .write_image(img.as_bytes(), width, height, img.color())
+                .map_err(UtilsError::EncodingImg)?;
+            Ok(match profile {
+                None => png,
+                Some(profile) => crate::io::icc::insert_png_profile(png, profile),
+            })
+        }
+        #[cfg(feature = "tiff")]
+        ImgFormat::Tiff(compression) => Ok(crate::io::tiff::encode_image(img, compression)?),
+    }
+}
+
+/// Compression of saved PNG images, trading encoding time for file size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PngCompression {
+    /// Fast and minimal compression, the default of the `image` crate.
+    #[default]
+    Fast,
+    /// Default compression level of zlib.
+    Default,
+    /// Smallest files, slowest encoding.
+    Best,
+    /// Huffman coding only.
+    Huffman,
+    /// Run-length encoding only.
+    Rle,
+}
+
+impl std::str::FromStr for PngCompression {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fast" => Ok(PngCompression::Fast),
+            "default" => Ok(PngCompression::Default),
+            "best" => Ok(PngCompression::Best),
+            "huffman" => Ok(PngCompression::Huffman),
+            "rle" => Ok(PngCompression::Rle),
+            _ => Err(format!(
+                "Unknown PNG compression \"{}\", expecting fast, default, best, huffman or rle",
+                s
+            )),
+        }
+    }
+}
+
+impl From<PngCompression> for CompressionType {
+    fn from(compression: PngCompression) -> Self {
+        match compression {
+            PngCompression::Fast => CompressionType::Fast,
+            PngCompression::Default => CompressionType::Default,
+            PngCompression::Best => CompressionType::Best,
+            PngCompression::Huffman => CompressionType::Huffman,
+            PngCompression::Rle => CompressionType::Rle,
+        }
+    }
+}
+
+/// Save an image in the PNG format with the given compression.
+pub fn save_png(
+    path: &Path,
+    img: &DynamicImage,
+    compression: PngCompression,
+) -> Result<(), UtilsError> {
+    let saving_error = |source| UtilsError::SavingImg {
+        path: path.to_path_buf(),
+        source,
+    };
+    let file = std::fs::File::create(path).map_err(|e| saving_error(e.into()))?;
+    let encoder = PngEncoder::new_with_quality(
+        std::io::BufWriter::new(file),
+        compression.into(),
+        FilterType::Sub,
+    );
+    let (width, height) = img.dimensions();
+    encoder
+        .write_image(img.as_bytes(), width, height, img.color())
+        .map_err(saving_error)
+}
+
+/// Save an image in the PNG format with the given compression and ICC profile.
+fn save_png_with_profile(
+    path: &Path,
+    img: &DynamicImage,
+    compression: PngCompression,
+    profile: &[u8],
+) -> Result<(), UtilsError> {
+    let png = encode_img(img, ImgFormat::Png(compression), Some(profile))?;
+    std::fs::write(path, png).map_err(|e| UtilsError::SavingImg {
+        path: path.to_path_buf(),
+        source: e.into(),
+    })
+}
+
+/// Pool of threads encoding and writing images in the background,
+/// so that saving images overlaps with the computation of the next ones.
+///
+/// At most one image per thread waits in the queue, which bounds the memory used.
+/// Errors are reported by [ImgWriter::finish], after which no image is written anymore.
+/// Images are written to the filesystem, or to another sink (see [ImgWriter::with_sink]).
+pub struct ImgWriter {
+    format: ImgFormat,
+    sink: Arc<dyn OutputSink>,
+    sender: mpsc::SyncSender<(PathBuf, DynamicImage)>,
+    workers: Vec<JoinHandle<Result<usize, UtilsError>>>,
+}
+
+impl ImgWriter {
+    /// Start `threads` writing threads, at least one.
+    pub fn new(threads: usize, format: ImgFormat) -> Self {
+        Self::with_profile(threads, format, None)
+    }
+
+    /// Same as [ImgWriter::new], also inserting an ICC profile in the written PNG images,
+    /// given as the raw data of an `iCCP` chunk (see [icc](crate::io::icc)).
+    pub fn with_profile(threads: usize, format: ImgFormat, profile: Option<Vec<u8>>) -> Self {
+        Self::with_sink(threads, format, profile, Arc::new(DirSink::default()))
+    }
+
+    /// Same as [ImgWriter::with_profile], writing the encoded images to the given sink
+    /// instead of the filesystem (see [sink](crate::io::sink)).
+    /// The sink is not finished by [ImgWriter::finish], other files may still be added to it.
+    pub fn with_sink(
+        threads: usize,
+        format: ImgFormat,
+        profile: Option<Vec<u8>>,
+        sink: Arc<dyn OutputSink>,
+    ) -> Self {
+        let threads = threads.max(1);
+        let profile: Option<Arc<[u8]>> = profile.map(Arc::from);
+        let (sender, receiver) = mpsc::sync_channel::<(PathBuf, DynamicImage)>(threads);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..threads)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                let profile = profile.clone();
+                let sink = Arc::clone(&sink);
+                std::thread::spawn(move || {
+                    let mut written = 0;
+                    loop {
+                        // The lock is released before encoding, for other threads to take the next image.
+                        let job = receiver.lock().map_err(|_| UtilsError::WriterPanic)?.recv();
+                        match job {
+                            Ok((path, img)) => {
+                                let encoded = encode_img(&img, format, profile.as_deref())?;
+                                sink.write(&path, &encoded)?;
+                            }
+                            Err(mpsc::RecvError) => return Ok(written),
+                        }
+                        written += 1;
+                    }
+                })
+            })
+            .collect();
+        Self {
+            format,
+            sink,
+            sender,
+            workers,
+        }
+    }
+
+    /// File format of the written images.
+    pub fn format(&self) -> ImgFormat {
+        self.format
+    }
+
+    /// Sink of the written images.
+    pub fn sink(&self) -> &Arc<dyn OutputSink> {
+        &self.sink
+    }
+
+    /// Queue an image to be saved at the given path,
+    /// waiting if all threads are busy and the queue is full.
+    pub fn save(&self, path: PathBuf, img: DynamicImage) {
+        // Sending only fails when all threads stopped on an error, reported by finish.
+        let _ = self.sender.send((path, img));
+    }
+
+    /// Queue a bunch of images to be saved in the given directory, like [save_all_imgs].
+    pub fn save_all<P: AsRef<Path>, I: ToImage>(
+        &self,
+        dir: P,
+        imgs: &[I],
+    ) -> Result<(), UtilsError> {
+        let dir = dir.as_ref();
+        for (i, img) in imgs.iter().enumerate() {
+            self.save(self.format.path(dir, i), img.to_image());
+        }
+        Ok(())
+    }
+
+    /// Wait for all queued images to be written, and return how many were.
+    pub fn finish(self) -> Result<usize, UtilsError> {
+        drop(self.sender);
+        let mut written = 0;
+        let mut first_error = None;
+        for worker in self.workers {
+            match worker.join() {
+                Ok(Ok(count)) => written += count,
+                Ok(Err(err)) => first_error = first_error.or(Some(err)),
+                Err(_) => first_error = first_error.or(Some(UtilsError::WriterPanic)),
+            }
+        }
+        match first_error {
+            None => Ok(written),
+            Some(err) => Err(err),
+        }
+    }
+}
+
 // Helper functions to play with coordinates iterators.
 
 /// Retrieve the coordinates of selected pixels in a binary mask.
@@ -134,7 +502,7 @@ pub fn coords_row_major(shape: (usize, usize)) -> impl Iterator<Item = (usize, u
 // Helper functions to equalize the mean intensity of a collection of images.
 
 /// Only work for gray images for now.
-pub trait CanEqualize: Scalar + Copy + Mul + Into<f32> {
+pub trait CanEqualize: Scalar + Copy + Into<f32> {
     /// Convert the target, set as a float in [0,1], into an equivalent value for current type.
     fn target_mean(target: f32) -> f32;
     /// Convert the scaled f32 value back into the current type.
@@ -146,7 +514,7 @@ impl CanEqualize for u8 {
         u8::MAX as f32 * target
     }
     fn from_as(f: f32) -> Self {
-        f.max(0.0).min(255.0) as Self
+        f.clamp(0.0, 255.0) as Self
     }
 }
 
@@ -155,7 +523,7 @@ impl CanEqualize for u16 {
         u16::MAX as f32 * target
     }
     fn from_as(f: f32) -> Self {
-        f.max(0.0).min(65535.0) as Self
+        f.clamp(0.0, 65535.0) as Self
     }
 }
 
@@ -0,0 +1,90 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! # Verbosity
+//!
+//! Verbosity shared by the executables, set once from their `-v` and `--quiet` flags.
+//! It is the maximum level of the `log` crate, so the logs of the library follow it,
+//! progress bars are only drawn from [Verbosity::Info] on (see [progress_bar]),
+//! and results printed on stdout are silenced in [Verbosity::Quiet].
+
+use log::LevelFilter;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Verbosity of a program, numbered from 0 like the levels of stderrlog.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Verbosity {
+    /// Only errors, without progress bars nor results on stdout.
+    Quiet,
+    /// Warnings and results, the default of the executables.
+    #[default]
+    Normal,
+    /// Steps of the program, with progress bars (-v).
+    Info,
+    /// Diagnostics of the registration (-vv).
+    Debug,
+    /// Everything (-vvv).
+    Trace,
+}
+
+/// Verbosity of the program, as its index.
+static CURRENT: AtomicUsize = AtomicUsize::new(Verbosity::Normal as usize);
+
+impl Verbosity {
+    /// Verbosity of `verbose` occurrences of `-v`, or of `--quiet`.
+    pub fn from_flags(verbose: u64, quiet: bool) -> Self {
+        if quiet {
+            Verbosity::Quiet
+        } else {
+            Self::from_index(1 + verbose)
+        }
+    }
+
+    /// Verbosity of the given index, from 0 for [Verbosity::Quiet] to 4 and above for [Verbosity::Trace].
+    pub fn from_index(index: u64) -> Self {
+        match index {
+            0 => Verbosity::Quiet,
+            1 => Verbosity::Normal,
+            2 => Verbosity::Info,
+            3 => Verbosity::Debug,
+            _ => Verbosity::Trace,
+        }
+    }
+
+    /// Maximum level of the logs.
+    pub fn level_filter(self) -> LevelFilter {
+        match self {
+            Verbosity::Quiet => LevelFilter::Error,
+            Verbosity::Normal => LevelFilter::Warn,
+            Verbosity::Info => LevelFilter::Info,
+            Verbosity::Debug => LevelFilter::Debug,
+            Verbosity::Trace => LevelFilter::Trace,
+        }
+    }
+
+    /// Make this the verbosity of the whole program, including the maximum level of the logs.
+    /// Loggers initialized afterwards may reset the maximum level of the logs,
+    /// so this is called once the logger is set.
+    pub fn set(self) {
+        CURRENT.store(self as usize, Ordering::Relaxed);
+        log::set_max_level(self.level_filter());
+    }
+
+    /// Verbosity of the program, [Verbosity::Normal] unless set otherwise.
+    pub fn current() -> Self {
+        Self::from_index(CURRENT.load(Ordering::Relaxed) as u64)
+    }
+
+    /// Whether results are printed on stdout.
+    pub fn prints_results() -> bool {
+        Self::current() > Verbosity::Quiet
+    }
+}
+
+/// Progress bar of `len` steps on stderr, hidden unless info logs are enabled.
+pub fn progress_bar(len: u64) -> indicatif::ProgressBar {
+    if log::log_enabled!(log::Level::Info) {
+        indicatif::ProgressBar::new(len)
+    } else {
+        indicatif::ProgressBar::hidden()
+    }
+}
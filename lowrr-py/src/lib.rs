@@ -0,0 +1,318 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Python bindings of the lowrr registration.
+//!
+//! Image stacks are numpy arrays of uint8 or uint16 with shape (N,H,W) for gray images,
+//! or (N,H,W,3) for RGB images. Motions are float32 arrays of shape (N,6).
+
+use nalgebra::{DMatrix, Scalar, Vector3, Vector6};
+use ndarray::{Array2, Array3, Array4, ArrayViewD};
+use numpy::{Element, IntoPyArray, PyArrayDyn, PyReadonlyArray2};
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use pyo3::wrap_pyfunction;
+use std::convert::TryFrom;
+use std::ops::{Add, Mul};
+
+use lowrr::img::crop::{Crop, CropSpec};
+use lowrr::img::interpolation::CanLinearInterpolate;
+use lowrr::img::registration;
+use lowrr::interop::ToImage;
+use lowrr::pipeline::{self, PipelineError, StackParams, StackPixel};
+
+#[pymodule]
+fn lowrr_py(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(register, m)?)?;
+    m.add_function(wrap_pyfunction!(apply, m)?)?;
+    Ok(())
+}
+
+/// register(images, config=None)
+/// --
+///
+/// Register a stack of images and return the tuple (motions, registered).
+/// The config dict may contain the keys of the registration config
+/// (lambda, rho, max_iterations, threshold, sparse_ratio_threshold, levels, verbosity)
+/// as well as "equalize" (float in [0,1]), "crop" ((left, top, right, bottom) in pixels,
+/// or a string such as "10%,10%,90%,90%" or "center:50%,50%,400,300"),
+/// "estimate_scale" (power of two such as 0.5, to estimate motions on downscaled images),
+/// "gray" (conversion of RGB images for registration: "green", "luma", "average" or "channel:N")
+/// and "joint_channels" (register all the channels of RGB images jointly instead).
+#[pyfunction]
+fn register(py: Python, images: &PyAny, config: Option<&PyDict>) -> PyResult<(PyObject, PyObject)> {
+    let params = params_from_dict(config)?;
+    if let Ok(arr) = images.extract::<&PyArrayDyn<u8>>() {
+        match to_dataset(arr.readonly().as_array())? {
+            Dataset::Gray(imgs) => register_imgs::<_, f32>(py, params, imgs, gray_to_py),
+            Dataset::Rgb(imgs) => register_imgs::<_, Vector3<f32>>(py, params, imgs, rgb_to_py),
+        }
+    } else if let Ok(arr) = images.extract::<&PyArrayDyn<u16>>() {
+        match to_dataset(arr.readonly().as_array())? {
+            Dataset::Gray(imgs) => register_imgs::<_, f32>(py, params, imgs, gray_to_py),
+            Dataset::Rgb(imgs) => register_imgs::<_, Vector3<f32>>(py, params, imgs, rgb_to_py),
+        }
+    } else {
+        Err(PyValueError::new_err(
+            "images must be a numpy array of uint8 or uint16",
+        ))
+    }
+}
+
+/// apply(images, motions)
+/// --
+///
+/// Warp a stack of images with the given (N,6) motions and return the registered stack.
+#[pyfunction]
+fn apply(py: Python, images: &PyAny, motions: PyReadonlyArray2<f32>) -> PyResult<PyObject> {
+    let motion_vec = to_motions(motions)?;
+    if let Ok(arr) = images.extract::<&PyArrayDyn<u8>>() {
+        match to_dataset(arr.readonly().as_array())? {
+            Dataset::Gray(imgs) => reproject(&imgs, &motion_vec).map(|r| gray_to_py(py, r)),
+            Dataset::Rgb(imgs) => reproject(&imgs, &motion_vec).map(|r| rgb_to_py(py, r)),
+        }
+    } else if let Ok(arr) = images.extract::<&PyArrayDyn<u16>>() {
+        match to_dataset(arr.readonly().as_array())? {
+            Dataset::Gray(imgs) => reproject(&imgs, &motion_vec).map(|r| gray_to_py(py, r)),
+            Dataset::Rgb(imgs) => reproject(&imgs, &motion_vec).map(|r| rgb_to_py(py, r)),
+        }
+    } else {
+        Err(PyValueError::new_err(
+            "images must be a numpy array of uint8 or uint16",
+        ))
+    }
+}
+
+/// Read the parameters from a Python dict, with the same defaults as the CLI.
+fn params_from_dict(dict: Option<&PyDict>) -> PyResult<StackParams> {
+    let mut params = StackParams::default();
+    let dict = match dict {
+        None => return Ok(params),
+        Some(dict) => dict,
+    };
+    for (key, value) in dict.iter() {
+        let key: &str = key.extract()?;
+        match key {
+            "lambda" => params.config.lambda = value.extract()?,
+            "rho" => params.config.rho = value.extract()?,
+            "max_iterations" => params.config.max_iterations = value.extract()?,
+            "threshold" => params.config.threshold = value.extract()?,
+            "motion_threshold" => params.config.motion_threshold = value.extract()?,
+            "damping" => params.config.damping = value.extract()?,
+            "line_search" => params.config.line_search = value.extract()?,
+            "compositional" => {
+                let compositional: &str = value.extract()?;
+                params.config.compositional =
+                    compositional.parse().map_err(PyValueError::new_err)?;
+            }
+            "max_translation" => params.config.max_translation = value.extract()?,
+            "max_deformation" => params.config.max_deformation = value.extract()?,
+            "sparse_ratio_threshold" => params.config.sparse_ratio_threshold = value.extract()?,
+            "levels" => params.config.levels = value.extract()?,
+            "verbosity" => params.config.verbosity = value.extract()?,
+            "sparse_strategy" => {
+                let strategy: &str = value.extract()?;
+                params.config.sparse_strategy = strategy.parse().map_err(PyValueError::new_err)?;
+            }
+            "sparse_fraction" => params.config.sparse_fraction = value.extract()?,
+            "pixel_budget" => params.config.pixel_budget = value.extract()?,
+            "normalization" => {
+                let normalization: &str = value.extract()?;
+                params.config.normalization =
+                    normalization.parse().map_err(PyValueError::new_err)?;
+            }
+            "data_term" => {
+                let data_term: &str = value.extract()?;
+                params.config.data_term = data_term.parse().map_err(PyValueError::new_err)?;
+            }
+            "gain_bias" => params.config.gain_bias = value.extract()?,
+            "pack_observations" => params.config.pack_observations = value.extract()?,
+            "denoise" => {
+                let denoise: &str = value.extract()?;
+                params.config.denoise = denoise.parse().map_err(PyValueError::new_err)?;
+            }
+            "denoise_levels" => params.config.denoise_levels = value.extract()?,
+            "temporal_smoothness" => params.config.temporal_smoothness = value.extract()?,
+            "outlier_threshold" => params.config.outlier_threshold = value.extract()?,
+            "chunk_size" => params.config.chunk_size = value.extract()?,
+            "pixel_aspect_ratio" => params.config.pixel_aspect_ratio = value.extract()?,
+            "input_max" => params.config.input_max = value.extract()?,
+            "do_image_correction" => params.config.do_image_correction = value.extract()?,
+            "mode" => {
+                let mode: &str = value.extract()?;
+                params.config.mode = mode.parse().map_err(PyValueError::new_err)?;
+            }
+            "svd_backend" => {
+                let svd_backend: &str = value.extract()?;
+                params.config.svd_backend = svd_backend.parse().map_err(PyValueError::new_err)?;
+            }
+            "svd_library" => {
+                let svd_library: &str = value.extract()?;
+                params.config.svd_library = svd_library.parse().map_err(PyValueError::new_err)?;
+            }
+            "precision" => {
+                let precision: &str = value.extract()?;
+                params.config.precision = precision.parse().map_err(PyValueError::new_err)?;
+            }
+            "equalize" => {
+                let equalize: Option<f32> = value.extract()?;
+                if let Some(x) = equalize {
+                    if !(0.0..=1.0).contains(&x) {
+                        return Err(PyValueError::new_err(format!(
+                            "Expecting an equalize value in [0,1], got {}",
+                            x
+                        )));
+                    }
+                }
+                params.equalize = equalize;
+            }
+            "estimate_scale" => {
+                let scale: f32 = value.extract()?;
+                params.halvings = pipeline::halvings(scale).map_err(PyValueError::new_err)?;
+            }
+            "gray" => {
+                let gray: &str = value.extract()?;
+                params.gray = gray.parse().map_err(PyValueError::new_err)?;
+            }
+            "joint_channels" => params.joint_channels = value.extract()?,
+            "crop" => {
+                params.crop = if value.is_none() {
+                    None
+                } else if let Ok(spec) = value.extract::<&str>() {
+                    let spec = CropSpec::try_from(spec)
+                        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+                    Some(spec)
+                } else {
+                    let (left, top, right, bottom) = value.extract()?;
+                    Some(CropSpec::from(Crop {
+                        left,
+                        top,
+                        right,
+                        bottom,
+                    }))
+                };
+            }
+            _ => {
+                return Err(PyValueError::new_err(format!(
+                    "Unknown config key: {}",
+                    key
+                )))
+            }
+        }
+    }
+    Ok(params)
+}
+
+enum Dataset<T: Scalar + Copy> {
+    Gray(Vec<DMatrix<T>>),
+    Rgb(Vec<DMatrix<(T, T, T)>>),
+}
+
+/// Convert a (N,H,W) or (N,H,W,3) array into a vector of images.
+fn to_dataset<T: Element + Scalar + Copy>(arr: ArrayViewD<T>) -> PyResult<Dataset<T>> {
+    match *arr.shape() {
+        [n, height, width] => Ok(Dataset::Gray(
+            (0..n)
+                .map(|i| DMatrix::from_fn(height, width, |y, x| arr[[i, y, x]]))
+                .collect(),
+        )),
+        [n, height, width, 3] => Ok(Dataset::Rgb(
+            (0..n)
+                .map(|i| {
+                    DMatrix::from_fn(height, width, |y, x| {
+                        (arr[[i, y, x, 0]], arr[[i, y, x, 1]], arr[[i, y, x, 2]])
+                    })
+                })
+                .collect(),
+        )),
+        _ => Err(PyValueError::new_err(format!(
+            "images must have a (N,H,W) or (N,H,W,3) shape, got {:?}",
+            arr.shape()
+        ))),
+    }
+}
+
+/// Convert a (N,6) array into motion vectors.
+fn to_motions(motions: PyReadonlyArray2<f32>) -> PyResult<Vec<Vector6<f32>>> {
+    let motions = motions.as_array();
+    if motions.ncols() != 6 {
+        return Err(PyValueError::new_err(format!(
+            "motions must have a (N,6) shape, got {:?}",
+            motions.shape()
+        )));
+    }
+    Ok(motions
+        .rows()
+        .into_iter()
+        .map(|row| Vector6::from_iterator(row.iter().cloned()))
+        .collect())
+}
+
+fn motions_to_py(py: Python, motions: &[Vector6<f32>]) -> PyObject {
+    Array2::from_shape_fn((motions.len(), 6), |(i, k)| motions[i][k])
+        .into_pyarray(py)
+        .to_object(py)
+}
+
+fn gray_to_py<T: Element + Scalar + Copy>(py: Python, imgs: Vec<DMatrix<T>>) -> PyObject {
+    let (height, width) = imgs.first().map(|im| im.shape()).unwrap_or((0, 0));
+    Array3::from_shape_fn((imgs.len(), height, width), |(i, y, x)| imgs[i][(y, x)])
+        .into_pyarray(py)
+        .to_object(py)
+}
+
+fn rgb_to_py<T: Element + Scalar + Copy>(py: Python, imgs: Vec<DMatrix<(T, T, T)>>) -> PyObject {
+    let (height, width) = imgs.first().map(|im| im.shape()).unwrap_or((0, 0));
+    let channel = |(r, g, b): (T, T, T), c| match c {
+        0 => r,
+        1 => g,
+        _ => b,
+    };
+    Array4::from_shape_fn((imgs.len(), height, width, 3), |(i, y, x, c)| {
+        channel(imgs[i][(y, x)], c)
+    })
+    .into_pyarray(py)
+    .to_object(py)
+}
+
+/// Register images, then warp them with the estimated motions.
+fn register_imgs<P, V>(
+    py: Python,
+    params: StackParams,
+    imgs: Vec<DMatrix<P>>,
+    to_py: fn(Python, Vec<DMatrix<P>>) -> PyObject,
+) -> PyResult<(PyObject, PyObject)>
+where
+    P: StackPixel + CanLinearInterpolate<V, P> + Send + Sync,
+    V: Add<Output = V>,
+    f32: Mul<V, Output = V>,
+    DMatrix<P::Gray>: ToImage,
+{
+    let (motion_vec, registered) = py.allow_threads(|| {
+        let motion_vec = match lowrr::register_stack(&imgs, &params) {
+            Ok(output) => output.motion_vec,
+            Err(e @ PipelineError::Crop(_)) => return Err(PyValueError::new_err(e.to_string())),
+            Err(e @ PipelineError::Registration(_)) => {
+                return Err(PyRuntimeError::new_err(e.to_string()))
+            }
+        };
+        reproject(&imgs, &motion_vec).map(|r| (motion_vec, r))
+    })?;
+    Ok((motions_to_py(py, &motion_vec), to_py(py, registered)))
+}
+
+fn reproject<U, V>(imgs: &[DMatrix<U>], motion_vec: &[Vector6<f32>]) -> PyResult<Vec<DMatrix<U>>>
+where
+    U: Scalar + Copy + CanLinearInterpolate<V, U>,
+    V: Add<Output = V>,
+    f32: Mul<V, Output = V>,
+{
+    if imgs.len() != motion_vec.len() {
+        return Err(PyValueError::new_err(format!(
+            "Got {} images but {} motions",
+            imgs.len(),
+            motion_vec.len()
+        )));
+    }
+    Ok(registration::reproject::<U, V, U>(imgs, motion_vec))
+}
@@ -0,0 +1,113 @@
+// SPDX-License-Identifier: MPL-2.0
+
+//! Errors thrown to JavaScript as structured objects,
+//! so that the frontend can react differently to each kind of error.
+
+use lowrr::img::registration::RegistrationError;
+use lowrr::pipeline::PipelineError;
+use serde::Serialize;
+use std::fmt::Display;
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen(typescript_custom_section)]
+const TS_LOWRR_ERROR: &'static str = r#"
+/** Error thrown by the methods of Lowrr. */
+export type LowrrError = {
+  kind:
+    | "decode"
+    | "unsupported-format"
+    | "mismatched-images"
+    | "invalid-argument"
+    | "invalid-state"
+    | "registration"
+    | "stopped"
+    | "encode"
+    | "internal";
+  message: string;
+  image_id: string | null;
+};
+"#;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorKind {
+    /// The image file could not be decoded.
+    Decode,
+    /// The image file format is not supported.
+    UnsupportedFormat,
+    /// The image is not of the same type as the previously loaded ones.
+    MismatchedImages,
+    /// Invalid parameters, image id or image index.
+    InvalidArgument,
+    /// The call is not possible yet, such as retrieving results before a run.
+    InvalidState,
+    /// The registration failed, for example when the algorithm did not converge.
+    Registration,
+    /// The algorithm was stopped by the caller.
+    Stopped,
+    /// A registered image could not be encoded.
+    Encode,
+    /// Unexpected error.
+    Internal,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub image_id: Option<String>,
+}
+
+impl Error {
+    pub fn new<E: Into<anyhow::Error>>(kind: ErrorKind, error: E) -> Self {
+        Self::msg(kind, format!("{:?}", error.into()))
+    }
+
+    pub fn msg<M: Display>(kind: ErrorKind, message: M) -> Self {
+        Self {
+            kind,
+            message: message.to_string(),
+            image_id: None,
+        }
+    }
+
+    /// Error while decoding an image file.
+    pub fn decode(error: image::ImageError) -> Self {
+        match error {
+            image::ImageError::Unsupported(_) => Self::new(ErrorKind::UnsupportedFormat, error),
+            _ => Self::new(ErrorKind::Decode, error),
+        }
+    }
+
+    /// Attach the id of the image responsible for the error.
+    pub fn with_image(mut self, id: &str) -> Self {
+        self.image_id = Some(id.to_string());
+        self
+    }
+}
+
+impl From<RegistrationError> for Error {
+    fn from(error: RegistrationError) -> Self {
+        match error {
+            RegistrationError::StoppedByCaller => Self::new(ErrorKind::Stopped, error),
+            RegistrationError::NoImages => Self::new(ErrorKind::InvalidState, error),
+            _ => Self::new(ErrorKind::Registration, error),
+        }
+    }
+}
+
+impl From<PipelineError> for Error {
+    fn from(error: PipelineError) -> Self {
+        match error {
+            PipelineError::Crop(err) => Self::new(ErrorKind::InvalidArgument, err),
+            PipelineError::Registration(err) => err.into(),
+        }
+    }
+}
+
+impl From<Error> for JsValue {
+    fn from(error: Error) -> Self {
+        log::error!("{}", &error.message);
+        JsValue::from_serde(&error).unwrap_or_else(|_| error.message.into())
+    }
+}
@@ -1,21 +1,26 @@
 // SPDX-License-Identifier: MPL-2.0
 
-use anyhow::{anyhow, Context};
-use image::DynamicImage;
-use nalgebra::{DMatrix, Vector6};
-use serde::Deserialize;
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, ImageOutputFormat};
+use nalgebra::{DMatrix, Scalar, Vector3, Vector6};
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
 use std::io::Cursor;
+use std::mem::size_of;
 use std::rc::Rc;
 use wasm_bindgen::prelude::*;
 
-use lowrr::img::crop::{crop, recover_original_motion, Crop};
-use lowrr::img::registration::{self, CanRegister};
+use lowrr::img::crop::{crop, Crop};
+use lowrr::img::registration::{self, CanRegister, CancelToken};
 use lowrr::interop::{IntoDMatrix, ToImage};
+use lowrr::pipeline::{self, Pipeline};
 use lowrr::utils::CanEqualize;
 
 #[macro_use]
 mod utils; // define console_log! macro
+mod error;
+
+use error::{Error, ErrorKind};
 
 #[wasm_bindgen(raw_module = "../worker.mjs")]
 extern "C" {
@@ -23,50 +28,147 @@ extern "C" {
     async fn should_stop(step: &str, progress: Option<u32>) -> JsValue; // bool
 }
 
+// Typed arrays and encoded images returned by these methods are copied out of the wasm memory,
+// so their buffers can be transferred with postMessage from a worker without another copy.
+//
 // This wrapper trick is because we cannot have async functions referencing &self.
 // https://github.com/rustwasm/wasm-bindgen/issues/1858
+// The cancellation token is kept outside of the RefCell
+// since the inner state is borrowed during the whole run.
 #[wasm_bindgen]
-pub struct Lowrr(Rc<RefCell<LowrrInner>>);
+pub struct Lowrr(Rc<RefCell<LowrrInner>>, CancelToken);
 
 #[wasm_bindgen]
 impl Lowrr {
     pub fn init() -> Self {
-        Lowrr(Rc::new(RefCell::new(LowrrInner::init())))
+        Lowrr(
+            Rc::new(RefCell::new(LowrrInner::init())),
+            CancelToken::new(),
+        )
     }
     pub fn load(&mut self, id: String, img_file: &[u8]) -> Result<(), JsValue> {
         let inner = Rc::clone(&self.0);
-        let result = (*inner).borrow_mut().load(id, img_file);
+        let result = (*inner).borrow_mut().load(id, img_file, None);
         result
     }
+    /// Load an image, downscaled if its largest dimension is above `max_dim`.
+    /// All images must be downscaled by the same factor, given by `scale()`.
+    pub fn load_with_max_size(
+        &mut self,
+        id: String,
+        img_file: &[u8],
+        max_dim: u32,
+    ) -> Result<(), JsValue> {
+        self.0.borrow_mut().load(id, img_file, Some(max_dim))
+    }
+    /// Scale factor from the original images to the loaded ones, 1 if they were not downscaled.
+    pub fn scale(&self) -> f32 {
+        self.0.borrow().scale
+    }
+    /// Set a function called after each iteration of the registration
+    /// with the arguments `(level, iteration, residual)`.
+    pub fn set_progress_callback(&mut self, callback: js_sys::Function) {
+        self.0.borrow_mut().progress_callback = Some(callback);
+    }
+    pub fn clear_progress_callback(&mut self) {
+        self.0.borrow_mut().progress_callback = None;
+    }
+    /// Set a function called at the end of each level of the registration
+    /// with the arguments `(level, previews)`, where previews are
+    /// registered thumbnails of the cropped images encoded in PNG: [Uint8Array].
+    pub fn set_preview_callback(&mut self, callback: js_sys::Function) {
+        self.0.borrow_mut().preview_callback = Some(callback);
+    }
+    pub fn clear_preview_callback(&mut self) {
+        self.0.borrow_mut().preview_callback = None;
+    }
+    /// Remove the image with that id.
+    pub fn remove(&mut self, id: &str) -> Result<(), JsValue> {
+        self.0.borrow_mut().remove(id)
+    }
+    /// Remove all images.
+    pub fn clear(&mut self) {
+        self.0.borrow_mut().clear()
+    }
+    /// Change the order of images, the first one being the reference.
+    /// The ids are those of all loaded images: [string].
+    pub fn reorder(&mut self, ids: JsValue) -> Result<(), JsValue> {
+        self.0.borrow_mut().reorder(ids)
+    }
+    /// Run the registration, resolving with the motions of all images in a Float32Array.
     pub fn run(&mut self, params: JsValue) -> js_sys::Promise {
         let inner = Rc::clone(&self.0);
-        wasm_bindgen_futures::future_to_promise(async_run_rc(inner, params))
+        self.1.reset();
+        let cancel = self.1.clone();
+        wasm_bindgen_futures::future_to_promise(async_run_rc(inner, params, cancel))
+    }
+    /// Stop the current run as soon as possible.
+    /// The run then resolves with the motions estimated so far.
+    pub fn cancel(&self) {
+        self.1.cancel();
+    }
+    /// Whether the last run was cancelled, and thus only has partial results.
+    pub fn cancelled(&self) -> bool {
+        self.1.is_cancelled()
     }
     pub fn image_ids(&self) -> Result<JsValue, JsValue> {
         self.0.borrow().image_ids()
     }
+    /// Bytes held by the loaded images and by the outputs of the last run:
+    /// { dataset, outputs, total }.
+    pub fn memory_usage(&self) -> Result<JsValue, JsValue> {
+        self.0.borrow().memory_usage()
+    }
+    /// Release the registered images of the last run, keeping its motions.
+    pub fn free_outputs(&mut self) {
+        self.0.borrow_mut().free_outputs()
+    }
+    /// Release the loaded images, keeping the outputs of the last run.
+    pub fn free_dataset(&mut self) {
+        self.0.borrow_mut().free_dataset()
+    }
+    /// Motions of the last run, 6 values per image.
+    pub fn motions(&self) -> Option<js_sys::Float32Array> {
+        self.0.borrow().motions()
+    }
+    /// Motions of the last run in the frame of the original images, before downscaling.
+    pub fn original_motions(&self) -> Option<js_sys::Float32Array> {
+        self.0.borrow().original_motions()
+    }
+    /// All cropped registered images, encoded in PNG: [Uint8Array].
+    pub fn cropped_img_files(&self) -> Result<js_sys::Array, JsValue> {
+        self.0.borrow().cropped_img_files()
+    }
     pub fn cropped_img_file(&self, i: usize) -> Result<Box<[u8]>, JsValue> {
         self.0.borrow().cropped_img_file(i)
     }
     pub fn register_and_save(&self, i: usize) -> Result<Box<[u8]>, JsValue> {
-        self.0.borrow().register_and_save(i)
+        self.0.borrow().registered_img_file(i, "png")
+    }
+    /// Registered image at full resolution, encoded in the given format ("png" or "jpeg").
+    pub fn registered_img_file(&self, i: usize, format: &str) -> Result<Box<[u8]>, JsValue> {
+        self.0.borrow().registered_img_file(i, format)
     }
 }
 
 async fn async_run_rc(
     mutself: Rc<RefCell<LowrrInner>>,
     params: JsValue,
+    cancel: CancelToken,
 ) -> Result<JsValue, JsValue> {
     let mut inner = (*mutself).borrow_mut();
-    let result = inner.run(params);
+    let result = inner.run(params, &cancel);
     result.await
 }
 
 struct LowrrInner {
     image_ids: Vec<String>,
     dataset: Dataset,
-    crop_registered: Vec<DMatrix<u8>>,
+    crop_registered: Vec<DynamicImage>,
     motion_vec: Option<Vec<Vector6<f32>>>,
+    progress_callback: Option<js_sys::Function>,
+    preview_callback: Option<js_sys::Function>,
+    scale: f32,
 }
 
 enum Dataset {
@@ -77,6 +179,27 @@ enum Dataset {
     RgbImagesU16(Vec<DMatrix<(u16, u16, u16)>>),
 }
 
+impl Dataset {
+    /// Number of bytes of all the images.
+    fn byte_size(&self) -> usize {
+        match self {
+            Dataset::Empty => 0,
+            Dataset::GrayImages(imgs) => byte_size(imgs),
+            Dataset::GrayImagesU16(imgs) => byte_size(imgs),
+            Dataset::RgbImages(imgs) => byte_size(imgs),
+            Dataset::RgbImagesU16(imgs) => byte_size(imgs),
+        }
+    }
+}
+
+/// Memory held by a Lowrr instance, in bytes.
+#[derive(Serialize)]
+struct MemoryUsage {
+    dataset: usize,
+    outputs: usize,
+    total: usize,
+}
+
 #[wasm_bindgen]
 #[derive(Deserialize)]
 /// Type holding the algorithm parameters
@@ -96,17 +219,41 @@ impl LowrrInner {
             dataset: Dataset::Empty,
             crop_registered: Vec::new(),
             motion_vec: None,
+            progress_callback: None,
+            preview_callback: None,
+            scale: 1.0,
         }
     }
 
-    // Load and decode the images to be registered.
-    pub fn load(&mut self, id: String, img_file: &[u8]) -> Result<(), JsValue> {
+    // Load and decode the images to be registered, downscaled above a maximum dimension.
+    pub fn load(
+        &mut self,
+        id: String,
+        img_file: &[u8],
+        max_dim: Option<u32>,
+    ) -> Result<(), JsValue> {
         console_log!("Loading an image");
         let reader = image::io::Reader::new(Cursor::new(img_file))
             .with_guessed_format()
             .expect("Cursor io never fails");
         // let image = reader.decode().expect("Error decoding the image");
-        let dyn_img = reader.decode().map_err(utils::report_error)?;
+        let dyn_img = reader
+            .decode()
+            .map_err(|err| Error::decode(err).with_image(&id))?;
+        let dyn_img = into_supported_layout(&id, dyn_img);
+        let (dyn_img, scale) = match max_dim {
+            Some(max_dim) => downscale(dyn_img, max_dim),
+            None => (dyn_img, 1.0),
+        };
+        let is_first = matches!(self.dataset, Dataset::Empty);
+        if !is_first && scale != self.scale {
+            let message = format!(
+                "Image downscaled by {} instead of {} like the other images",
+                scale, self.scale
+            );
+            let error = Error::msg(ErrorKind::MismatchedImages, message);
+            return Err(error.with_image(&id).into());
+        }
 
         match (&dyn_img, &mut self.dataset) {
             // Loading the first image (empty dataset)
@@ -153,186 +300,403 @@ impl LowrrInner {
                 imgs.push(dyn_img.into_dmatrix());
                 self.image_ids.push(id);
             }
-            (DynamicImage::ImageBgr8(_), _) => return Err("BGR order not supported".into()),
-            (DynamicImage::ImageBgra8(_), _) => return Err("BGR order not supported".into()),
-            (DynamicImage::ImageLumaA8(_), _) => return Err("Alpha channel not supported".into()),
-            (DynamicImage::ImageLumaA16(_), _) => return Err("Alpha channel not supported".into()),
-            (DynamicImage::ImageRgba8(_), _) => return Err("Alpha channel not supported".into()),
-            (DynamicImage::ImageRgba16(_), _) => return Err("Alpha channel not supported".into()),
-            _ => return Err("Images are not all of the same type".into()),
+            _ => {
+                let error = Error::msg(
+                    ErrorKind::MismatchedImages,
+                    "Images are not all of the same type",
+                );
+                return Err(error.with_image(&id).into());
+            }
         }
+        self.scale = scale;
 
         Ok(())
     }
 
+    // Remove the image with that id.
+    pub fn remove(&mut self, id: &str) -> Result<(), JsValue> {
+        let index = self.index_of(id)?;
+        let order: Vec<usize> = (0..self.image_ids.len()).filter(|&i| i != index).collect();
+        self.select(&order);
+        Ok(())
+    }
+
+    // Remove all images.
+    pub fn clear(&mut self) {
+        self.select(&[]);
+    }
+
+    // Change the order of images given all their ids: [string].
+    pub fn reorder(&mut self, ids: JsValue) -> Result<(), JsValue> {
+        let ids: Vec<String> = ids
+            .into_serde()
+            .map_err(|err| Error::new(ErrorKind::InvalidArgument, err))?;
+        if ids.len() != self.image_ids.len() {
+            let message = format!(
+                "Expected {} image ids but got {}",
+                self.image_ids.len(),
+                ids.len()
+            );
+            return Err(Error::msg(ErrorKind::InvalidArgument, message).into());
+        }
+        let order: Result<Vec<usize>, _> = ids.iter().map(|id| self.index_of(id)).collect();
+        let order = order?;
+        let mut sorted = order.clone();
+        sorted.sort_unstable();
+        sorted.dedup();
+        if sorted.len() != order.len() {
+            return Err(Error::msg(ErrorKind::InvalidArgument, "Duplicated image ids").into());
+        }
+        self.select(&order);
+        Ok(())
+    }
+
+    fn index_of(&self, id: &str) -> Result<usize, Error> {
+        self.image_ids.iter().position(|x| x == id).ok_or_else(|| {
+            Error::msg(ErrorKind::InvalidArgument, "There is no image with that id").with_image(id)
+        })
+    }
+
+    // Keep only the images at these indices, in that order.
+    // Previous registration results are discarded since they do not match anymore.
+    fn select(&mut self, order: &[usize]) {
+        select(&mut self.image_ids, order);
+        match &mut self.dataset {
+            Dataset::Empty => {}
+            Dataset::GrayImages(imgs) => select(imgs, order),
+            Dataset::GrayImagesU16(imgs) => select(imgs, order),
+            Dataset::RgbImages(imgs) => select(imgs, order),
+            Dataset::RgbImagesU16(imgs) => select(imgs, order),
+        }
+        if self.image_ids.is_empty() {
+            self.dataset = Dataset::Empty;
+        }
+        self.crop_registered.clear();
+        self.motion_vec = None;
+    }
+
     // Run the main lowrr registration algorithm.
-    //                                                 Vec<f32>
-    async fn run(&mut self, params: JsValue) -> Result<JsValue, JsValue> {
+    //                                                 Float32Array
+    async fn run(&mut self, params: JsValue, cancel: &CancelToken) -> Result<JsValue, JsValue> {
         self.motion_vec = None;
         self.crop_registered.clear();
-        let args: Args = params.into_serde().unwrap();
+        let args: Args = params
+            .into_serde()
+            .map_err(|err| Error::new(ErrorKind::InvalidArgument, err))?;
         utils::WasmLogger::setup(utils::verbosity_filter(args.config.verbosity));
+        let progress_callback = self.progress_callback.clone();
+        let on_progress = |progress| report_progress(progress_callback.as_ref(), progress);
+        let preview_callback = self.preview_callback.clone();
+        let preview_callback = preview_callback.as_ref();
 
         // Use the algorithm corresponding to the type of data.
         let motion_vec = match &self.dataset {
             Dataset::Empty => Vec::new(),
             Dataset::GrayImages(gray_imgs) => {
-                let (motion_vec_crop, cropped_eq_imgs) =
-                    crop_and_register(&args, gray_imgs.clone(), 40)
-                        .await
-                        .map_err(utils::report_error)?;
+                let output = crop_and_register(
+                    &args,
+                    gray_imgs.clone(),
+                    40,
+                    on_progress,
+                    preview_callback,
+                    cancel,
+                )
+                .await?;
                 log::info!("Applying registration on cropped images ...");
-                self.crop_registered = registration::reproject_may_stop::<u8, f32, u8, _>(
-                    &cropped_eq_imgs,
-                    &motion_vec_crop,
+                let registered = registration::reproject_may_stop::<u8, f32, u8, _>(
+                    &output.registered.imgs,
+                    &output.registered.motion_vec,
                     should_stop_bool,
                 )
                 .await
-                .map_err(utils::report_error)?;
-                original_motion(args.crop, motion_vec_crop)
+                .map_err(Error::from)?;
+                self.crop_registered = to_images(&registered);
+                output.motion_vec
             }
             Dataset::GrayImagesU16(gray_imgs) => {
-                let (motion_vec_crop, cropped_eq_imgs) =
-                    crop_and_register(&args, gray_imgs.clone(), 10 * 256)
-                        .await
-                        .map_err(utils::report_error)?;
+                let output = crop_and_register(
+                    &args,
+                    gray_imgs.clone(),
+                    10 * 256,
+                    on_progress,
+                    preview_callback,
+                    cancel,
+                )
+                .await?;
                 log::info!("Applying registration on cropped images ...");
-                let cropped_u8: Vec<_> = cropped_eq_imgs.into_iter().map(into_gray_u8).collect();
-                self.crop_registered = registration::reproject_may_stop::<u8, f32, u8, _>(
+                let cropped_u8: Vec<_> = output
+                    .registered
+                    .imgs
+                    .into_iter()
+                    .map(into_gray_u8)
+                    .collect();
+                let registered = registration::reproject_may_stop::<u8, f32, u8, _>(
                     &cropped_u8,
-                    &motion_vec_crop,
+                    &output.registered.motion_vec,
                     should_stop_bool,
                 )
                 .await
-                .map_err(utils::report_error)?;
-                original_motion(args.crop, motion_vec_crop)
+                .map_err(Error::from)?;
+                self.crop_registered = to_images(&registered);
+                output.motion_vec
             }
             Dataset::RgbImages(imgs) => {
                 let gray_imgs: Vec<_> = imgs.iter().map(|im| im.map(|(_r, g, _b)| g)).collect();
-                let (motion_vec_crop, cropped_eq_imgs) = crop_and_register(&args, gray_imgs, 40)
-                    .await
-                    .map_err(utils::report_error)?;
+                let output =
+                    crop_and_register(&args, gray_imgs, 40, on_progress, preview_callback, cancel)
+                        .await?;
                 log::info!("Applying registration on cropped images ...");
-                self.crop_registered = registration::reproject_may_stop::<u8, f32, u8, _>(
-                    &cropped_eq_imgs,
-                    &motion_vec_crop,
-                    should_stop_bool,
-                )
-                .await
-                .map_err(utils::report_error)?;
-                original_motion(args.crop, motion_vec_crop)
+                // Keep the colors of the cropped images instead of the green channel.
+                let cropped_imgs = crop_all(args.crop, imgs)?;
+                let registered: Vec<DMatrix<(u8, u8, u8)>> =
+                    registration::reproject_may_stop::<_, Vector3<f32>, _, _>(
+                        &cropped_imgs,
+                        &output.registered.motion_vec,
+                        should_stop_bool,
+                    )
+                    .await
+                    .map_err(Error::from)?;
+                self.crop_registered = to_images(&registered);
+                output.motion_vec
             }
             Dataset::RgbImagesU16(imgs) => {
                 let gray_imgs: Vec<_> = imgs.iter().map(|im| im.map(|(_r, g, _b)| g)).collect();
-                let (motion_vec_crop, cropped_eq_imgs) =
-                    crop_and_register(&args, gray_imgs, 10 * 256)
-                        .await
-                        .map_err(utils::report_error)?;
-                log::info!("Applying registration on cropped images ...");
-                let cropped_u8: Vec<_> = cropped_eq_imgs.into_iter().map(into_gray_u8).collect();
-                self.crop_registered = registration::reproject_may_stop::<u8, f32, u8, _>(
-                    &cropped_u8,
-                    &motion_vec_crop,
-                    should_stop_bool,
+                let output = crop_and_register(
+                    &args,
+                    gray_imgs,
+                    10 * 256,
+                    on_progress,
+                    preview_callback,
+                    cancel,
                 )
-                .await
-                .map_err(utils::report_error)?;
-                original_motion(args.crop, motion_vec_crop)
+                .await?;
+                log::info!("Applying registration on cropped images ...");
+                // Keep the colors of the cropped images instead of the green channel.
+                let cropped_imgs = crop_all(args.crop, imgs)?;
+                let cropped_u8: Vec<_> = cropped_imgs.into_iter().map(into_rgb_u8).collect();
+                let registered: Vec<DMatrix<(u8, u8, u8)>> =
+                    registration::reproject_may_stop::<_, Vector3<f32>, _, _>(
+                        &cropped_u8,
+                        &output.registered.motion_vec,
+                        should_stop_bool,
+                    )
+                    .await
+                    .map_err(Error::from)?;
+                self.crop_registered = to_images(&registered);
+                output.motion_vec
             }
         };
 
-        let flat_motion_vec: Vec<f32> = motion_vec.iter().flatten().cloned().collect();
         self.motion_vec = Some(motion_vec);
-        JsValue::from_serde(&flat_motion_vec).map_err(utils::report_error)
+        Ok(self.motions().into())
+    }
+
+    // Return the memory usage: { dataset, outputs, total }.
+    pub fn memory_usage(&self) -> Result<JsValue, JsValue> {
+        let dataset = self.dataset.byte_size();
+        let motions = self.motion_vec.as_ref().map_or(0, |m| m.len()) * size_of::<Vector6<f32>>();
+        let crops: usize = self
+            .crop_registered
+            .iter()
+            .map(|im| im.as_bytes().len())
+            .sum();
+        let outputs = motions + crops;
+        let usage = MemoryUsage {
+            dataset,
+            outputs,
+            total: dataset + outputs,
+        };
+        JsValue::from_serde(&usage).map_err(|err| Error::new(ErrorKind::Internal, err).into())
+    }
+
+    // Release the registered images.
+    pub fn free_outputs(&mut self) {
+        self.crop_registered = Vec::new();
+    }
+
+    // Release the loaded images.
+    pub fn free_dataset(&mut self) {
+        self.dataset = Dataset::Empty;
+    }
+
+    // Return the motions of the last run in a Float32Array.
+    pub fn motions(&self) -> Option<js_sys::Float32Array> {
+        let motion_vec = self.motion_vec.as_ref()?;
+        let flat_motion_vec: Vec<f32> = motion_vec.iter().flatten().cloned().collect();
+        Some(js_sys::Float32Array::from(flat_motion_vec.as_slice()))
+    }
+
+    // Return the motions of the last run in the frame of the original images.
+    pub fn original_motions(&self) -> Option<js_sys::Float32Array> {
+        let motion_vec = self.motion_vec.as_ref()?;
+        let original_motion_vec: Vec<Vector6<f32>> = motion_vec
+            .iter()
+            .map(|m| {
+                let mut m = *m;
+                m[4] /= self.scale;
+                m[5] /= self.scale;
+                m
+            })
+            .collect();
+        let flat_motion_vec: Vec<f32> = original_motion_vec.iter().flatten().cloned().collect();
+        Some(js_sys::Float32Array::from(flat_motion_vec.as_slice()))
+    }
+
+    // Retrieve all the cropped registered images: [Uint8Array].
+    pub fn cropped_img_files(&self) -> Result<js_sys::Array, JsValue> {
+        let files = js_sys::Array::new();
+        for (i, img) in self.crop_registered.iter().enumerate() {
+            let file = encode(i, img.clone(), ImageOutputFormat::Png)?;
+            files.push(&js_sys::Uint8Array::from(&file[..]));
+        }
+        Ok(files)
     }
 
     // Return the ids of loaded images: [string]
     pub fn image_ids(&self) -> Result<JsValue, JsValue> {
-        JsValue::from_serde(&self.image_ids).map_err(utils::report_error)
+        JsValue::from_serde(&self.image_ids)
+            .map_err(|err| Error::new(ErrorKind::Internal, err).into())
     }
 
     // Retrieve the cropped registered images.
     pub fn cropped_img_file(&self, i: usize) -> Result<Box<[u8]>, JsValue> {
-        encode(i, &self.crop_registered[i]).map_err(utils::report_error)
+        let img = self.crop_registered.get(i).ok_or_else(|| {
+            let message = format!("There is no cropped registered image {}", i);
+            Error::msg(ErrorKind::InvalidArgument, message)
+        })?;
+        Ok(encode(i, img.clone(), ImageOutputFormat::Png)?)
     }
 
-    // Register and save that image.
-    pub fn register_and_save(&self, i: usize) -> Result<Box<[u8]>, JsValue> {
+    // Register that image with the motion of the full frame and encode it.
+    pub fn registered_img_file(&self, i: usize, format: &str) -> Result<Box<[u8]>, JsValue> {
         log::info!("Registering image {}", i);
+        let format = output_format(format)?;
+        if i >= self.image_ids.len() {
+            let message = format!("There is no image {}", i);
+            return Err(Error::msg(ErrorKind::InvalidArgument, message).into());
+        }
         match (&self.motion_vec, &self.dataset) {
             (_, Dataset::Empty) => {
-                Err(anyhow!("Images not loaded yet")).map_err(utils::report_error)
+                Err(Error::msg(ErrorKind::InvalidState, "Images not loaded yet").into())
             }
             (None, _) => {
-                Err(anyhow!("Registration parameters unknown")).map_err(utils::report_error)
+                Err(Error::msg(ErrorKind::InvalidState, "Registration parameters unknown").into())
             }
             (Some(all_motion), Dataset::GrayImages(images)) => {
                 let registered: DMatrix<u8> =
                     lowrr::img::registration::warp(&images[i], &all_motion[i]);
-                encode(i, &registered).map_err(utils::report_error)
+                Ok(encode(i, registered.to_image(), format)?)
             }
             (Some(all_motion), Dataset::GrayImagesU16(images)) => {
                 let registered: DMatrix<u16> =
                     lowrr::img::registration::warp(&images[i], &all_motion[i]);
-                encode(i, &registered).map_err(utils::report_error)
+                Ok(encode(i, registered.to_image(), format)?)
             }
             (Some(all_motion), Dataset::RgbImages(images)) => {
                 let registered: DMatrix<(u8, u8, u8)> =
                     lowrr::img::registration::warp(&images[i], &all_motion[i]);
-                encode(i, &registered).map_err(utils::report_error)
+                Ok(encode(i, registered.to_image(), format)?)
             }
             (Some(all_motion), Dataset::RgbImagesU16(images)) => {
                 let registered: DMatrix<(u16, u16, u16)> =
                     lowrr::img::registration::warp(&images[i], &all_motion[i]);
-                encode(i, &registered).map_err(utils::report_error)
+                Ok(encode(i, registered.to_image(), format)?)
             }
         }
     }
 }
 
-fn encode<Im: ToImage>(i: usize, mat: &Im) -> anyhow::Result<Box<[u8]>> {
+/// Quality of the JPEG encoding of registered images.
+const JPEG_QUALITY: u8 = 90;
+
+fn output_format(format: &str) -> Result<ImageOutputFormat, Error> {
+    match format.to_lowercase().as_str() {
+        "png" => Ok(ImageOutputFormat::Png),
+        "jpeg" | "jpg" => Ok(ImageOutputFormat::Jpeg(JPEG_QUALITY)),
+        _ => {
+            let message = format!("Unsupported output image format: {}", format);
+            Err(Error::msg(ErrorKind::InvalidArgument, message))
+        }
+    }
+}
+
+fn encode(i: usize, mut img: DynamicImage, format: ImageOutputFormat) -> Result<Box<[u8]>, Error> {
     log::debug!("Encoding image {}", i);
-    let img = mat.to_image();
+    // JPEG only supports 8 bits images.
+    if let ImageOutputFormat::Jpeg(_) = format {
+        img = match img {
+            DynamicImage::ImageLuma16(_) => DynamicImage::ImageLuma8(img.to_luma8()),
+            DynamicImage::ImageRgb16(_) => DynamicImage::ImageRgb8(img.to_rgb8()),
+            _ => img,
+        };
+    }
     let mut buffer: Vec<u8> = Vec::new();
-    img.write_to(&mut buffer, image::ImageOutputFormat::Png)?;
+    img.write_to(&mut buffer, format)
+        .map_err(|err| Error::new(ErrorKind::Encode, err))?;
     Ok(buffer.into_boxed_slice())
 }
 
-#[allow(clippy::type_complexity)]
 async fn crop_and_register<T: CanEqualize + CanRegister>(
     args: &Args,
     gray_imgs: Vec<DMatrix<T>>,
     sparse_diff_threshold: <T as CanRegister>::Bigger,
-) -> anyhow::Result<(Vec<Vector6<f32>>, Vec<DMatrix<T>>)>
+    on_progress: impl FnMut(registration::Progress),
+    preview_callback: Option<&js_sys::Function>,
+    cancel: &CancelToken,
+) -> Result<pipeline::Output<T>, Error>
 where
     DMatrix<T>: ToImage,
 {
-    // Extract the cropped area from the images.
-    let cropped_imgs: Result<Vec<DMatrix<T>>, _> = match args.crop {
-        None => Ok(gray_imgs),
-        Some(frame) => {
-            log::info!("Cropping images ...");
-            gray_imgs.iter().map(|im| crop(frame, im)).collect()
-        }
+    let pipeline = Pipeline {
+        config: args.config,
+        equalize: args.equalize,
+        crop: args.crop,
+        halvings: 0,
+        bayer: None,
     };
-    let mut cropped_imgs = cropped_imgs.context("Failed to crop images")?;
-
-    // Equalize mean intensities of cropped area.
-    if let Some(mean_intensity) = args.equalize {
-        log::info!("Equalizing images mean intensities ...");
-        lowrr::utils::equalize_mean(mean_intensity, &mut cropped_imgs);
-    }
-
-    // Compute the motion of each image for registration.
-    log::info!("Registration of images ...");
-    registration::async_gray_affine(
-        args.config,
-        cropped_imgs,
-        sparse_diff_threshold,
-        should_stop_bool,
-    )
-    .await
-    .context("Failed to register images")
+    pipeline
+        .async_register_previews(
+            gray_imgs,
+            sparse_diff_threshold,
+            should_stop_bool,
+            on_progress,
+            |preview| report_preview(preview_callback, preview),
+            cancel,
+        )
+        .await
+        .map_err(Error::from)
+}
+
+fn report_progress(callback: Option<&js_sys::Function>, progress: registration::Progress) {
+    if let Some(f) = callback {
+        let level = JsValue::from(progress.level as u32);
+        let iteration = JsValue::from(progress.iteration as u32);
+        let residual = JsValue::from(progress.residual);
+        if let Err(err) = f.call3(&JsValue::NULL, &level, &iteration, &residual) {
+            log::warn!("Progress callback failed: {:?}", err);
+        }
+    }
+}
+
+fn report_preview<T: Scalar>(callback: Option<&js_sys::Function>, preview: registration::Preview<T>)
+where
+    DMatrix<T>: ToImage,
+{
+    if let Some(f) = callback {
+        let level = JsValue::from(preview.level as u32);
+        let files = js_sys::Array::new();
+        for (i, img) in preview.imgs.iter().enumerate() {
+            match encode(i, img.to_image(), ImageOutputFormat::Png) {
+                Ok(file) => {
+                    files.push(&js_sys::Uint8Array::from(&file[..]));
+                }
+                Err(err) => log::warn!("Failed to encode preview {}: {:?}", i, err),
+            }
+        }
+        if let Err(err) = f.call2(&JsValue::NULL, &level, &files) {
+            log::warn!("Preview callback failed: {:?}", err);
+        }
+    }
 }
 
 async fn should_stop_bool(step: &str, progress: Option<u32>) -> bool {
@@ -340,14 +704,76 @@ async fn should_stop_bool(step: &str, progress: Option<u32>) -> bool {
     js_bool.as_bool().unwrap()
 }
 
-fn original_motion(crop: Option<Crop>, motion_vec_crop: Vec<Vector6<f32>>) -> Vec<Vector6<f32>> {
-    // Recover motion parameters in the frame of the full image from the one in the cropped frame.
-    match crop {
-        None => motion_vec_crop,
-        Some(frame) => recover_original_motion(frame, &motion_vec_crop),
+/// Downscale an image so that its largest dimension is at most `max_dim`.
+/// Also return the scale factor from the original image to the returned one.
+fn downscale(img: DynamicImage, max_dim: u32) -> (DynamicImage, f32) {
+    let (width, height) = img.dimensions();
+    let largest = width.max(height);
+    if largest <= max_dim {
+        return (img, 1.0);
+    }
+    let scale = max_dim as f32 / largest as f32;
+    let new_width = ((width as f32 * scale).round() as u32).max(1);
+    let new_height = ((height as f32 * scale).round() as u32).max(1);
+    log::info!(
+        "Downscaling image from {}x{} to {}x{}",
+        width,
+        height,
+        new_width,
+        new_height
+    );
+    let resized = img.resize_exact(new_width, new_height, FilterType::Triangle);
+    (resized, scale)
+}
+
+/// Convert images to a supported layout, since browsers frequently produce RGBA images.
+/// See [lowrr::interop::into_supported_layout] for the conversions applied.
+fn into_supported_layout(id: &str, img: DynamicImage) -> DynamicImage {
+    let color = img.color();
+    let img = lowrr::interop::into_supported_layout(img);
+    if img.color() != color {
+        log::warn!(
+            "Converting image {} from {:?} to {:?}",
+            id,
+            color,
+            img.color()
+        );
     }
+    img
+}
+
+fn byte_size<T: Scalar>(imgs: &[DMatrix<T>]) -> usize {
+    imgs.iter().map(|im| im.len()).sum::<usize>() * size_of::<T>()
+}
+
+/// Keep only the elements at these indices, in that order, without copying them.
+fn select<T>(v: &mut Vec<T>, order: &[usize]) {
+    let mut old: Vec<Option<T>> = std::mem::take(v).into_iter().map(Some).collect();
+    *v = order.iter().filter_map(|&i| old[i].take()).collect();
 }
 
 fn into_gray_u8(m: DMatrix<u16>) -> DMatrix<u8> {
     m.map(|x| (x / 256) as u8)
 }
+
+fn into_rgb_u8(m: DMatrix<(u16, u16, u16)>) -> DMatrix<(u8, u8, u8)> {
+    m.map(|(r, g, b)| ((r / 256) as u8, (g / 256) as u8, (b / 256) as u8))
+}
+
+fn crop_all<T: Scalar>(frame: Option<Crop>, imgs: &[DMatrix<T>]) -> Result<Vec<DMatrix<T>>, Error> {
+    match frame {
+        None => Ok(imgs.to_vec()),
+        Some(frame) => imgs
+            .iter()
+            .map(|im| crop(frame, im))
+            .collect::<Result<_, _>>()
+            .map_err(|err| Error::new(ErrorKind::InvalidArgument, err)),
+    }
+}
+
+fn to_images<T: Scalar>(imgs: &[DMatrix<T>]) -> Vec<DynamicImage>
+where
+    DMatrix<T>: ToImage,
+{
+    imgs.iter().map(|im| im.to_image()).collect()
+}
@@ -1,6 +1,7 @@
 // SPDX-License-Identifier: MPL-2.0
 
 use log::{Level, LevelFilter, Metadata, Record, SetLoggerError};
+use lowrr::verbosity::Verbosity;
 use wasm_bindgen::prelude::*;
 
 pub fn set_panic_hook() {
@@ -67,18 +68,7 @@ fn level_u32(level: Level) -> u32 {
     }
 }
 
+/// Log level of the verbosity of the registration config, numbered like the executables.
 pub fn verbosity_filter(verbosity: u32) -> LevelFilter {
-    match verbosity {
-        0 => LevelFilter::Error,
-        1 => LevelFilter::Warn,
-        2 => LevelFilter::Info,
-        3 => LevelFilter::Debug,
-        _ => LevelFilter::Trace,
-    }
-}
-
-pub fn report_error<E: Into<anyhow::Error>>(error: E) -> JsValue {
-    let error_msg = format!("{:?}", error.into());
-    log::error!("{}", &error_msg);
-    error_msg.into()
+    Verbosity::from_index(u64::from(verbosity)).level_filter()
 }
@@ -6,6 +6,7 @@ use lowrr::img::registration;
 use lowrr::img::viz::IntoGray;
 use lowrr::interop::{IntoDMatrix, ToImage};
 use lowrr::utils::CanEqualize;
+use lowrr::verbosity::{self, Verbosity};
 
 use anyhow::Context;
 use glob::glob;
@@ -45,6 +46,15 @@ fn main() -> anyhow::Result<()> {
             .default_value(DEFAULT_OUT_DIR)
             .value_name("path")
             .help("Output directory to save registered images"),
+        clap::Arg::with_name("verbose")
+            .short("v")
+            .multiple(true)
+            .help("Multiple levels of verbosity (up to -vvv)"),
+        clap::Arg::with_name("quiet")
+            .short("q")
+            .long("quiet")
+            .conflicts_with("verbose")
+            .help("Only print errors, without progress bar"),
         clap::Arg::with_name("IMAGE or GLOB")
             .multiple(true)
             .required(true)
@@ -62,6 +72,19 @@ The generated translations of each image are saved in a text file: warp-gt.txt."
         )
         .args(&args)
         .get_matches();
+    // Set log verbosity.
+    let verbosity = Verbosity::from_flags(
+        matches.occurrences_of("verbose"),
+        matches.is_present("quiet"),
+    );
+    stderrlog::new()
+        .quiet(false)
+        .verbosity(verbosity as usize)
+        .show_level(false)
+        .color(stderrlog::ColorChoice::Never)
+        .init()
+        .context("Failed to initialize log verbosity")?;
+    verbosity.set();
     // Start program.
     run(get_args(&matches)?)
 }
@@ -141,7 +164,7 @@ fn run(args: Args) -> anyhow::Result<()> {
 
     // Display progress bar.
     let img_count = args.images_paths.len();
-    let pb = indicatif::ProgressBar::new(img_count as u64);
+    let pb = verbosity::progress_bar(img_count as u64);
 
     // Use the time as a random generator.
     let mut seed = std::time::SystemTime::now()
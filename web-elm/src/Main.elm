@@ -146,6 +146,7 @@ type alias Image =
 type alias Parameters =
     { crop : Maybe Crop
     , equalize : Bool
+    , imageCorrection : Bool
     , levels : Int
     , sparse : Float
     , lambda : Float
@@ -161,6 +162,7 @@ encodeParams params =
     Json.Encode.object
         [ ( "crop", encodeMaybe encodeCrop params.crop )
         , ( "equalize", Json.Encode.bool params.equalize )
+        , ( "imageCorrection", Json.Encode.bool params.imageCorrection )
         , ( "levels", Json.Encode.int params.levels )
         , ( "sparse", Json.Encode.float params.sparse )
         , ( "lambda", Json.Encode.float params.lambda )
@@ -260,6 +262,7 @@ defaultParams : Parameters
 defaultParams =
     { crop = Nothing
     , equalize = True
+    , imageCorrection = True
     , levels = 4
     , sparse = 0.5
     , lambda = 1.5
@@ -389,6 +392,7 @@ type ViewImgMsg
 
 type ParamsMsg
     = ToggleEqualize Bool
+    | ToggleImageCorrection Bool
     | ChangeMaxIter String
     | ChangeMaxVerbosity String
     | ChangeConvergenceThreshold String
@@ -1037,6 +1041,9 @@ updateParams msg ({ params, paramsForm } as model) =
         ToggleEqualize equalize ->
             { model | params = { params | equalize = equalize } }
 
+        ToggleImageCorrection imageCorrection ->
+            { model | params = { params | imageCorrection = imageCorrection } }
+
         ChangeMaxVerbosity str ->
             let
                 updatedField =
@@ -1966,6 +1973,16 @@ viewConfig ({ params, paramsForm, paramsInfo, notSeenLogs, registeredImages } as
                         ]
                     ]
 
+                -- Image correction
+                , Element.column [ spacing 10 ]
+                    [ Element.text "Image correction (shadows, highlights, occlusions):"
+                    , Element.row [ spacing 10 ]
+                        [ Element.text "off"
+                        , toggle (ParamsMsg << ToggleImageCorrection) params.imageCorrection 30 "Toggle the estimation of sparse image errors"
+                        , Element.text "on"
+                        ]
+                    ]
+
                 -- Maximum number of iterations
                 , Element.column [ spacing 10 ]
                     [ Element.row [ spacing 10 ]
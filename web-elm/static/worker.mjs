@@ -26,6 +26,7 @@ console.log("Hello from worker");
 //   - "decode-image": decode an image provided with its url
 //   - "run": run the algorithm on all images
 //   - "stop": stop the alogorithm
+//   - "cancel": stop the registration early but keep its partial results
 onmessage = async function (event) {
   console.log(`worker message: ${event.data.type}`);
   if (event.data.type == "decode-image") {
@@ -38,6 +39,9 @@ onmessage = async function (event) {
   } else if (event.data.type == "stop") {
     console.log("Received STOP in worker");
     stopOrder = true;
+  } else if (event.data.type == "cancel") {
+    console.log("Received CANCEL in worker");
+    Lowrr.cancel();
   }
 };
 
@@ -61,6 +65,7 @@ async function run(params) {
       threshold: params.convergenceThreshold,
       sparse_ratio_threshold: params.sparse,
       levels: params.levels,
+      do_image_correction: params.imageCorrection,
       verbosity: params.maxVerbosity,
     },
     equalize: 0.5,